@@ -0,0 +1,95 @@
+// Extends the renderer with a `disk` object and a `chrome` material, usable
+// from scene files like the built-in types:
+//
+//     cargo run --release --example plugin -- examples/plugin.json
+
+use anyhow::{anyhow, Result};
+use glam::Vec3;
+use raytracer::hittables::{Aabb, Hit, Hittable, Interval};
+use raytracer::json::Json;
+use raytracer::materials::{CustomMaterial, Material, Scattered};
+use raytracer::registry::Registry;
+use raytracer::render::Ray;
+use raytracer::{Color3, Point3};
+use std::sync::Arc;
+
+struct Disk {
+    center: Point3,
+    normal: Vec3,
+    radius: f32,
+    material: Material,
+}
+
+impl Hittable for Disk {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let denom = self.normal.dot(ray.dir());
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.center - ray.origin()).dot(self.normal) / denom;
+        if !ray_t.surrounds(t) {
+            return None;
+        }
+        let p = ray.at(t);
+        if (p - self.center).length_squared() > self.radius * self.radius {
+            return None;
+        }
+        Some(Hit::new(p, self.normal, ray, t, &self.material))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = Vec3::splat(self.radius);
+        Aabb::new(self.center - r, self.center + r)
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+}
+
+// A perfect mirror with a colored tint.
+struct Chrome {
+    tint: Color3,
+}
+
+impl CustomMaterial for Chrome {
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scattered> {
+        let dir = ray.dir().normalize();
+        let reflected = dir - 2.0 * dir.dot(hit.normal) * hit.normal;
+        Some(Scattered {
+            ray: Ray::new(hit.p, reflected),
+            attenuation: self.tint,
+        })
+    }
+}
+
+fn vec3(node: &Json, key: &str) -> Result<Vec3> {
+    match node.get(key).and_then(Json::as_array) {
+        Some([x, y, z]) => match (x.as_f32(), y.as_f32(), z.as_f32()) {
+            (Some(x), Some(y), Some(z)) => Ok(Vec3::new(x, y, z)),
+            _ => Err(anyhow!("'{}' must contain numbers", key)),
+        },
+        _ => Err(anyhow!("'{}' must be an array of 3 numbers", key)),
+    }
+}
+
+fn main() -> Result<()> {
+    let registry = Registry::default()
+        .hittable("disk", |node, loader| {
+            let radius = node
+                .get("radius")
+                .and_then(Json::as_f32)
+                .ok_or_else(|| anyhow!("'radius' must be a number"))?;
+            Ok(Box::new(Disk {
+                center: vec3(node, "center")?,
+                normal: vec3(node, "normal")?.normalize(),
+                radius,
+                material: loader.node_material(node)?,
+            }))
+        })
+        .material("chrome", |def, _| {
+            let tint = vec3(def, "tint")?;
+            Ok(Material::Custom(Arc::new(Chrome { tint })))
+        });
+    raytracer::run(registry)
+}
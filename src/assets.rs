@@ -0,0 +1,364 @@
+use crate::json::Json;
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+const SEARCH_PATH_ENV: &str = "RAYTRACER_ASSET_PATH";
+const TAR_BLOCK: usize = 512;
+
+pub struct AssetResolver {
+    search_paths: Vec<PathBuf>,
+    resolved: Mutex<Vec<ResolvedAsset>>,
+}
+
+#[derive(Clone)]
+pub struct ResolvedAsset {
+    pub reference: String,
+    pub path: PathBuf,
+}
+
+impl AssetResolver {
+    pub fn from_env() -> Self {
+        let search_paths = match std::env::var_os(SEARCH_PATH_ENV) {
+            Some(paths) => std::env::split_paths(&paths).collect(),
+            None => vec![],
+        };
+        Self {
+            search_paths,
+            resolved: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn add_search_path(&mut self, dir: PathBuf) {
+        self.search_paths.push(dir);
+    }
+
+    // Relative references are looked up next to the file that refers to them
+    // (`base_dir`) first, then in the search paths in order.
+    pub fn resolve(&self, reference: &str, base_dir: &Path) -> Result<PathBuf> {
+        let reference_path = Path::new(reference);
+        let path = if reference_path.is_absolute() {
+            Some(reference_path.to_path_buf()).filter(|p| p.is_file())
+        } else {
+            std::iter::once(base_dir)
+                .chain(self.search_paths.iter().map(PathBuf::as_path))
+                .map(|dir| dir.join(reference_path))
+                .find(|p| p.is_file())
+        };
+        let path = path.ok_or_else(|| anyhow!("asset '{}' not found", reference))?;
+
+        let mut resolved = self.resolved.lock().unwrap();
+        if !resolved.iter().any(|asset| asset.path == path) {
+            resolved.push(ResolvedAsset {
+                reference: reference.to_string(),
+                path: path.clone(),
+            });
+        }
+        Ok(path)
+    }
+
+    pub fn resolved(&self) -> Vec<ResolvedAsset> {
+        self.resolved.lock().unwrap().clone()
+    }
+}
+
+// Bundles a loaded scene and every asset resolved while loading it into a tar
+// archive. Files inside the scene directory keep their relative layout, so
+// unpacking the archive anywhere gives a self-contained scene directory.
+// Anything outside it is moved under `assets/` and the references to it are
+// rewritten in the packed scene files.
+pub fn pack(scene_path: &Path, resolver: &AssetResolver, output: &Path) -> Result<()> {
+    let root = scene_path.parent().unwrap_or(Path::new(""));
+
+    let mut entries = vec![];
+    let mut renames = vec![];
+    for (idx, asset) in resolver.resolved().into_iter().enumerate() {
+        let name = match archive_name(&asset.path, root) {
+            Some(name) => name,
+            None => {
+                let file_name = asset.path.file_name().unwrap_or_default().to_string_lossy();
+                let name = format!("assets/{}-{}", idx, file_name);
+                renames.push((asset.reference.clone(), name.clone()));
+                name
+            }
+        };
+        entries.push((name, asset.path));
+    }
+
+    let file = std::fs::File::create(output)
+        .with_context(|| format!("failed to create {}", output.display()))?;
+    let mut tar = TarWriter::new(std::io::BufWriter::new(file));
+    for (name, path) in entries {
+        let mut data =
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        if !renames.is_empty() && path.extension().is_some_and(|ext| ext == "json") {
+            let mut doc = Json::parse(std::str::from_utf8(&data)?)?;
+            rename_references(&mut doc, &renames);
+            data = doc.to_string().into_bytes();
+        }
+        tar.append(&name, &data)?;
+    }
+    tar.finish()?;
+    Ok(())
+}
+
+fn archive_name(path: &Path, root: &Path) -> Option<String> {
+    let mut parts = vec![];
+    for component in path.strip_prefix(root).ok()?.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(parts.join("/"))
+}
+
+fn rename_references(doc: &mut Json, renames: &[(String, String)]) {
+    match doc {
+        Json::String(s) => {
+            if let Some((_, to)) = renames.iter().find(|(from, _)| from == s) {
+                *s = to.clone();
+            }
+        }
+        Json::Array(items) => items
+            .iter_mut()
+            .for_each(|item| rename_references(item, renames)),
+        Json::Object(map) => map
+            .values_mut()
+            .for_each(|item| rename_references(item, renames)),
+        _ => {}
+    }
+}
+
+struct TarWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    fn append(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut header = [0u8; TAR_BLOCK];
+        let (prefix, name) = Self::split_name(name)?;
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        Self::octal(&mut header[100..108], 0o644);
+        Self::octal(&mut header[108..116], 0);
+        Self::octal(&mut header[116..124], 0);
+        Self::octal(&mut header[124..136], data.len() as u64);
+        Self::octal(&mut header[136..148], 0);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        header[148..156].fill(b' ');
+        let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+        Self::octal(&mut header[148..155], checksum);
+
+        self.out.write_all(&header)?;
+        self.out.write_all(data)?;
+        let padding = (TAR_BLOCK - data.len() % TAR_BLOCK) % TAR_BLOCK;
+        self.out.write_all(&vec![0; padding])?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.out.write_all(&[0; 2 * TAR_BLOCK])?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn split_name(name: &str) -> Result<(&str, &str)> {
+        if name.len() <= 100 {
+            return Ok(("", name));
+        }
+        for (idx, _) in name.match_indices('/') {
+            if idx <= 155 && name.len() - idx - 1 <= 100 {
+                return Ok((&name[..idx], &name[idx + 1..]));
+            }
+        }
+        bail!("path too long for archive: {}", name)
+    }
+
+    // Zero-padded octal followed by a NUL terminator.
+    fn octal(field: &mut [u8], value: u64) {
+        let digits = format!("{:0width$o}", value, width = field.len() - 1);
+        field[..digits.len()].copy_from_slice(digits.as_bytes());
+        field[digits.len()] = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Canvas;
+    use crate::hittables::{Hittable, Interval};
+    use crate::materials::Material;
+    use crate::registry::Registry;
+    use crate::render::Ray;
+    use crate::scene::Scene;
+    use crate::{color3, point3, Color3};
+    use glam::{Vec2, Vec3};
+
+    // An empty directory for one test.
+    fn test_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("assets-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn resolver(search_paths: Vec<PathBuf>) -> AssetResolver {
+        AssetResolver {
+            search_paths,
+            resolved: Mutex::new(vec![]),
+        }
+    }
+
+    // The files in a tar archive, checking each header's checksum.
+    fn untar(mut bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut files = vec![];
+        while bytes[0] != 0 {
+            let field = |start: usize, end: usize| {
+                let text = std::str::from_utf8(&bytes[start..end]).unwrap();
+                text.trim_end_matches(['\0', ' ']).to_string()
+            };
+            let checksum: u64 = bytes[..TAR_BLOCK]
+                .iter()
+                .enumerate()
+                .map(|(idx, &b)| if (148..156).contains(&idx) { b' ' } else { b } as u64)
+                .sum();
+            assert_eq!(u64::from_str_radix(&field(148, 155), 8).unwrap(), checksum);
+            let size = usize::from_str_radix(&field(124, 135), 8).unwrap();
+            let (prefix, name) = (field(345, 500), field(0, 100));
+            let name = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            files.push((name, bytes[TAR_BLOCK..TAR_BLOCK + size].to_vec()));
+            bytes = &bytes[TAR_BLOCK + size.div_ceil(TAR_BLOCK) * TAR_BLOCK..];
+        }
+        files
+    }
+
+    #[test]
+    fn references_resolve_next_to_the_referrer_then_along_the_search_path() {
+        let dir = test_dir("resolve");
+        let [scene, first, second] = ["scene", "first", "second"].map(|name| dir.join(name));
+        for (sub, names) in [
+            (&scene, &["a"][..]),
+            (&first, &["a", "b"]),
+            (&second, &["a", "b", "c"]),
+        ] {
+            std::fs::create_dir_all(sub).unwrap();
+            for name in names {
+                std::fs::write(sub.join(name), name).unwrap();
+            }
+        }
+        let resolver = resolver(vec![first.clone(), second.clone()]);
+        assert_eq!(resolver.resolve("a", &scene).unwrap(), scene.join("a"));
+        assert_eq!(resolver.resolve("b", &scene).unwrap(), first.join("b"));
+        assert_eq!(resolver.resolve("c", &scene).unwrap(), second.join("c"));
+        let absolute = second.join("b").to_string_lossy().into_owned();
+        assert_eq!(
+            resolver.resolve(&absolute, &scene).unwrap(),
+            second.join("b")
+        );
+        let error = resolver.resolve("d", &scene).unwrap_err();
+        assert_eq!(error.to_string(), "asset 'd' not found");
+
+        // Each file is recorded once, under the first reference to it.
+        resolver.resolve("a", &scene).unwrap();
+        let resolved: Vec<_> = resolver.resolved().into_iter().map(|a| a.path).collect();
+        assert_eq!(
+            resolved,
+            [
+                scene.join("a"),
+                first.join("b"),
+                second.join("c"),
+                second.join("b")
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn packed_scenes_load_wherever_they_are_unpacked() {
+        let dir = test_dir("pack");
+        let image = |path: PathBuf, color: Color3| {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let mut canvas = Canvas::new(2, 2);
+            (0..4).for_each(|idx| canvas.set(idx % 2, idx / 2, color));
+            canvas.save(&path).unwrap();
+        };
+        image(dir.join("scene/textures/near.png"), color3(1.0, 0.0, 0.0));
+        image(dir.join("shared/far.png"), color3(0.0, 0.0, 1.0));
+        let scene_path = dir.join("scene/scene.json");
+        std::fs::write(
+            &scene_path,
+            r#"{
+                "materials": {
+                    "near": { "type": "lambertian",
+                              "albedo": { "type": "image", "file": "textures/near.png" } },
+                    "far": { "type": "lambertian",
+                             "albedo": { "type": "image", "file": "far.png" } }
+                },
+                "objects": [
+                    { "type": "quad", "q": [-2, -1, -5], "u": [2, 0, 0], "v": [0, 2, 0],
+                      "material": "near" },
+                    { "type": "quad", "q": [0, -1, -5], "u": [2, 0, 0], "v": [0, 2, 0],
+                      "material": "far" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let load = |path: &Path, resolver: &AssetResolver| {
+            Scene::load(path, resolver, &[], &Registry::default(), None).unwrap()
+        };
+        let albedos = |scene: &Scene| {
+            [-1.0, 1.0].map(|x| {
+                let ray = Ray::new(point3(x, 0.0, 0.0), -Vec3::Z);
+                let hit = scene
+                    .world
+                    .hit(&ray, Interval::new(0.0, f32::INFINITY))
+                    .unwrap();
+                let Material::Lambertian { albedo } = hit.material else {
+                    panic!("{}", hit.material.type_name());
+                };
+                albedo.value(Vec2::splat(0.5), hit.p)
+            })
+        };
+
+        let searching = resolver(vec![dir.join("shared")]);
+        let scene = load(&scene_path, &searching);
+        let tar = dir.join("scene.tar");
+        pack(&scene.path, &searching, &tar).unwrap();
+
+        // Files from the scene directory keep their places; the one from the
+        // search path moves under assets/, and the scene refers to it there.
+        let unpacked = dir.join("unpacked");
+        let mut names = vec![];
+        for (name, data) in untar(&std::fs::read(&tar).unwrap()) {
+            let path = unpacked.join(&name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+            names.push(name);
+        }
+        names.sort();
+        assert_eq!(names.len(), 3);
+        assert!(names[0].starts_with("assets/") && names[0].ends_with("-far.png"));
+        assert_eq!(names[1..], ["scene.json", "textures/near.png"]);
+        let text = std::fs::read_to_string(unpacked.join("scene.json")).unwrap();
+        assert!(text.contains(&names[0]), "{}", text);
+
+        let unpacked_scene = load(&unpacked.join("scene.json"), &resolver(vec![]));
+        assert_eq!(albedos(&unpacked_scene), albedos(&scene));
+        assert_ne!(albedos(&scene)[0], albedos(&scene)[1]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
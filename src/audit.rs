@@ -0,0 +1,116 @@
+use crate::hittables::Hit;
+use crate::materials::{Material, Scattered};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTS: Counts = Counts::new();
+
+struct Counts {
+    scatters: AtomicU64,
+    energy_gain: AtomicU64,
+    below_surface: AtomicU64,
+}
+
+// A scatter whose attenuation exceeds 1 in any channel adds energy to the
+// path, and a reflective material sending the ray under its own surface
+// leaks light through it. Either makes an image diverge from the truth as
+// samples increase, usually long before it looks obviously wrong.
+pub fn check(hit: &Hit, scattered: Option<&Scattered>) {
+    COUNTS.check(hit, scattered);
+}
+
+pub fn report() {
+    eprint!("{}", COUNTS.report());
+}
+
+impl Counts {
+    const fn new() -> Self {
+        Self {
+            scatters: AtomicU64::new(0),
+            energy_gain: AtomicU64::new(0),
+            below_surface: AtomicU64::new(0),
+        }
+    }
+
+    fn check(&self, hit: &Hit, scattered: Option<&Scattered>) {
+        let Some(scattered) = scattered else {
+            return;
+        };
+        self.scatters.fetch_add(1, Ordering::Relaxed);
+        if scattered.attenuation.max_element() > 1.0 {
+            self.energy_gain.fetch_add(1, Ordering::Relaxed);
+        }
+        let transmits = matches!(
+            hit.material.base(),
+            Material::Dielectric { .. } | Material::Isotropic { .. }
+        );
+        if !transmits && scattered.ray.dir().dot(hit.normal) < 0.0 {
+            self.below_surface.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn report(&self) -> String {
+        let scatters = self.scatters.load(Ordering::Relaxed);
+        let percent = |count: u64| 100.0 * count as f64 / scatters.max(1) as f64;
+        let energy_gain = self.energy_gain.load(Ordering::Relaxed);
+        let below_surface = self.below_surface.load(Ordering::Relaxed);
+        format!(
+            "audit: {} scatters\n\
+             audit: {} with attenuation > 1 ({:.3}%)\n\
+             audit: {} below the surface ({:.3}%)\n",
+            scatters,
+            energy_gain,
+            percent(energy_gain),
+            below_surface,
+            percent(below_surface)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Ray;
+    use crate::{color3, point3, Color3, Point3};
+    use glam::Vec3;
+
+    #[test]
+    fn counts_scatters_that_gain_energy_or_leak_through() {
+        let counts = Counts::new();
+        let diffuse = Material::new_lambertian(0.5, 0.5, 0.5);
+        let glass = Material::new_dielectric(1.5);
+        // Hits on the top of a floor, seen from above.
+        let down = Ray::new(point3(0.0, 1.0, 0.0), -Vec3::Y);
+        let hit = |material| Hit::new(Point3::ZERO, Vec3::Y, &down, 1.0, material);
+        let scattered = |dir: Vec3, attenuation: Color3| Scattered {
+            ray: Ray::new(Point3::ZERO, dir),
+            attenuation,
+        };
+
+        counts.check(
+            &hit(&diffuse),
+            Some(&scattered(Vec3::Y, Color3::splat(0.5))),
+        );
+        counts.check(
+            &hit(&diffuse),
+            Some(&scattered(Vec3::Y, color3(0.5, 1.5, 0.5))),
+        );
+        counts.check(
+            &hit(&diffuse),
+            Some(&scattered(-Vec3::Y, Color3::splat(0.5))),
+        );
+        // Glass refracts below its surface as it should, and absorbed rays
+        // don't scatter at all.
+        counts.check(&hit(&glass), Some(&scattered(-Vec3::Y, Color3::ONE)));
+        counts.check(&hit(&diffuse), None);
+
+        assert_eq!(
+            counts.report(),
+            "audit: 4 scatters\n\
+             audit: 1 with attenuation > 1 (25.000%)\n\
+             audit: 1 below the surface (25.000%)\n"
+        );
+        assert!(Counts::new()
+            .report()
+            .contains("0 below the surface (0.000%)"));
+    }
+}
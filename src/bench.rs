@@ -0,0 +1,165 @@
+use crate::cli::BenchArgs;
+use crate::hittables::{Bvh, BvhQuality, HittableVec};
+use crate::json::Json;
+use crate::render::{self, Camera, CameraBuilder};
+use crate::tiles::{self, Order};
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// The scenes and settings never change between releases, so timings from
+// different versions and machines can be compared directly.
+struct Case {
+    name: &'static str,
+    scene: fn(&mut HittableVec, CameraBuilder) -> CameraBuilder,
+    width: u32,
+    height: u32,
+    samples: u32,
+}
+
+const CASES: [Case; 4] = [
+    Case {
+        name: "cornell_box",
+        scene: crate::cornell_box,
+        width: 256,
+        height: 256,
+        samples: 16,
+    },
+    Case {
+        name: "spheres",
+        scene: crate::spheres_scene,
+        width: 400,
+        height: 225,
+        samples: 32,
+    },
+    Case {
+        name: "random_spheres",
+        scene: crate::random_spheres,
+        width: 400,
+        height: 225,
+        samples: 16,
+    },
+    Case {
+        name: "showcase",
+        scene: crate::showcase,
+        width: 256,
+        height: 256,
+        samples: 16,
+    },
+];
+const SEED: u64 = 0;
+const MAX_DEPTH: u32 = 50;
+
+struct Measurement {
+    rays: u64,
+    seconds: f64,
+}
+
+impl Measurement {
+    fn to_json(&self) -> BTreeMap<String, Json> {
+        BTreeMap::from([
+            ("rays".to_string(), Json::Number(self.rays as f64)),
+            (
+                "seconds".to_string(),
+                Json::Number((self.seconds * 1000.0).round() / 1000.0),
+            ),
+            (
+                "rays_per_second".to_string(),
+                Json::Number((self.rays as f64 / self.seconds).round()),
+            ),
+        ])
+    }
+}
+
+pub fn run(args: BenchArgs) -> Result<()> {
+    let mut scenes = vec![];
+    let mut total = Measurement {
+        rays: 0,
+        seconds: 0.0,
+    };
+    for case in &CASES {
+        let measurement = measure(case);
+        total.rays += measurement.rays;
+        total.seconds += measurement.seconds;
+
+        let mut scene = measurement.to_json();
+        scene.insert("name".to_string(), Json::String(case.name.to_string()));
+        scene.insert("width".to_string(), Json::Number(case.width as f64));
+        scene.insert("height".to_string(), Json::Number(case.height as f64));
+        scene.insert("samples".to_string(), Json::Number(case.samples as f64));
+        scenes.push(Json::Object(scene));
+    }
+
+    let report = Json::Object(BTreeMap::from([
+        (
+            "version".to_string(),
+            Json::String(env!("CARGO_PKG_VERSION").to_string()),
+        ),
+        (
+            "threads".to_string(),
+            Json::Number(rayon::current_num_threads() as f64),
+        ),
+        ("seed".to_string(), Json::Number(SEED as f64)),
+        ("scenes".to_string(), Json::Array(scenes)),
+        ("total".to_string(), Json::Object(total.to_json())),
+    ]));
+    match args.output {
+        Some(path) => std::fs::write(&path, report.to_string())
+            .with_context(|| format!("failed to write {}", path.display())),
+        None => {
+            print!("{}", report);
+            Ok(())
+        }
+    }
+}
+
+// Scene setup and BVH construction are left out of the timing; only the
+// render itself is measured.
+fn measure(case: &Case) -> Measurement {
+    let mut world = vec![];
+    let builder = Camera::builder(case.width, case.height)
+        .samples(case.samples)
+        .max_depth(MAX_DEPTH);
+    let camera = (case.scene)(&mut world, builder).seed(Some(SEED)).build();
+    let world = Bvh::with_quality(world, BvhQuality::default());
+
+    let rays = AtomicU64::new(0);
+    let start = Instant::now();
+    tiles::render(
+        case.width,
+        case.height,
+        case.samples,
+        Order::Cost,
+        &ProgressBar::hidden(),
+        |x, y, pass, sum| {
+            let before = render::rays_traced();
+            camera.add_samples(x, y, &world, pass.samples(camera.samples()), sum);
+            rays.fetch_add(render::rays_traced() - before, Ordering::Relaxed);
+        },
+    );
+    Measurement {
+        rays: rays.into_inner(),
+        seconds: start.elapsed().as_secs_f64(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_every_camera_ray() {
+        let case = Case {
+            width: 4,
+            height: 3,
+            samples: 2,
+            ..CASES[0]
+        };
+        let measurement = measure(&case);
+        // Every sample casts a camera ray, and bounces and shadow rays add
+        // more.
+        assert!(measurement.rays > 4 * 3 * 2, "{}", measurement.rays);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::canvas::Canvas;
+use crate::cli::ListScenesArgs;
+use crate::grade::Grade;
+use crate::hittables::{Bvh, BvhQuality, HittableVec};
+use crate::registry::Registry;
+use crate::render::CameraBuilder;
+use crate::renderer::{RenderSettings, Renderer};
+use crate::scene::Scene;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+// What a listing line says about a scene, before any thumbnail is rendered.
+struct Entry {
+    name: String,
+    // Loaded scenes, or why loading failed, so one broken file doesn't hide
+    // the rest.
+    scene: Result<Loaded>,
+    thumbnail: PathBuf,
+}
+
+struct Loaded {
+    width: u32,
+    height: u32,
+    world: HittableVec,
+    camera: CameraBuilder,
+    grade: Grade,
+    bvh: BvhQuality,
+}
+
+// Lists the scenes built in code and the scene files in a directory, with
+// their size and object count, and optionally renders a thumbnail of each.
+pub fn run(args: ListScenesArgs, registry: &Registry) -> Result<()> {
+    let builtin = crate::SCENES
+        .iter()
+        .map(|&(name, scene, width, height)| {
+            let mut world = vec![];
+            let camera = scene(&mut world, crate::default_camera().size(width, height));
+            Entry {
+                name: name.to_string(),
+                scene: Ok(Loaded {
+                    width,
+                    height,
+                    world,
+                    camera,
+                    grade: Grade::default(),
+                    bvh: BvhQuality::default(),
+                }),
+                // Kept apart from the thumbnails of scene files with the same
+                // name.
+                thumbnail: args.dir.join(format!("{}.builtin.thumb.png", name)),
+            }
+        })
+        .collect();
+    print_entries("Built-in scenes:", builtin, args.thumbnails)?;
+
+    let resolver = crate::resolver(&args.asset_paths);
+    let files = scene_files(&args.dir)?
+        .into_iter()
+        .map(|path| Entry {
+            name: path.file_name().unwrap().to_string_lossy().into_owned(),
+            scene: Scene::load(&path, &resolver, &[], registry, None).map(|scene| Loaded {
+                width: scene.width,
+                height: scene.height,
+                world: scene.world,
+                camera: scene.camera,
+                grade: scene.grade,
+                bvh: scene.bvh,
+            }),
+            thumbnail: path.with_extension("thumb.png"),
+        })
+        .collect();
+    print_entries(
+        &format!("Scene files in {}:", args.dir.display()),
+        files,
+        args.thumbnails,
+    )
+}
+
+// The `.json` files directly in `dir`, by name.
+fn scene_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "json") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn print_entries(title: &str, entries: Vec<Entry>, thumbnails: bool) -> Result<()> {
+    println!("{}", title);
+    let name_width = entries.iter().map(|entry| entry.name.len()).max();
+    for entry in entries {
+        let width = name_width.unwrap_or(0);
+        match entry.scene {
+            Ok(scene) => {
+                print!(
+                    "  {:width$}  {}x{}, {} objects",
+                    entry.name,
+                    scene.width,
+                    scene.height,
+                    scene.world.len()
+                );
+                if thumbnails {
+                    save_thumbnail(scene, &entry.thumbnail)?;
+                    print!(", {}", entry.thumbnail.display());
+                }
+                println!();
+            }
+            Err(err) => println!("  {:width$}  error: {:#}", entry.name, err),
+        }
+    }
+    Ok(())
+}
+
+// Renders at the settings of `--thumbnail`, without a progress bar so the
+// listing stays readable.
+fn save_thumbnail(scene: Loaded, path: &Path) -> Result<()> {
+    let (width, height, builder) = crate::thumbnail(scene.width, scene.height, scene.camera);
+    let world = Bvh::with_quality(scene.world, scene.bvh);
+    let image = Renderer::render(&world, &builder.build(), &RenderSettings::default());
+    let mut canvas = Canvas::new(width, height).with_grade(scene.grade);
+    for y in 0..height {
+        for x in 0..width {
+            canvas.draw(x, y, image.pixel(x, y));
+        }
+    }
+    canvas.save(path)
+}
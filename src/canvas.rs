@@ -1,11 +1,14 @@
-use crate::Color3;
-use anyhow::Result;
+use crate::grade::Grade;
+use crate::jpeg;
+use crate::{color3, Color3};
+use anyhow::{bail, Context, Result};
 use glam::UVec2;
 use std::path::Path;
 
 pub struct Canvas {
     size: UVec2,
     data: Vec<u8>,
+    grade: Grade,
 }
 
 impl Canvas {
@@ -13,17 +16,97 @@ impl Canvas {
         Canvas {
             size: UVec2::new(width, height),
             data: vec![0; width as usize * height as usize * 3],
+            grade: Grade::default(),
         }
     }
 
+    pub fn with_grade(mut self, grade: Grade) -> Canvas {
+        self.grade = grade;
+        self
+    }
+
     pub fn draw(&mut self, x: u32, y: u32, color: Color3) {
+        let color = self.grade.expose(color).to_array();
+        let display = color3(
+            Self::linear_to_gamma_2(color[0]),
+            Self::linear_to_gamma_2(color[1]),
+            Self::linear_to_gamma_2(color[2]),
+        )
+        .clamp(Color3::ZERO, Color3::ONE);
+        self.set(x, y, self.grade.apply(display));
+    }
+
+    // Stores a display value as is, with no exposure, gamma or grading.
+    pub fn set(&mut self, x: u32, y: u32, display: Color3) {
         let idx = (y * self.size.x + x) as usize * 3;
-        for (num, c) in color.to_array().iter().enumerate() {
-            let c = Self::linear_to_gamma_2(*c).clamp(0.0, 1.0);
-            self.data[idx + num] = (c * 255.9999) as u8;
+        for (num, c) in display.to_array().iter().enumerate() {
+            self.data[idx + num] = (c.clamp(0.0, 1.0) * 255.9999) as u8;
         }
     }
 
+    // Reads a PNG or a baseline JPEG, told apart by their signatures.
+    pub fn load(path: &Path) -> Result<Canvas> {
+        let bytes =
+            std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::decode(&bytes).with_context(|| format!("in {}", path.display()))
+    }
+
+    // A PNG or a baseline JPEG already in memory, as for `load`.
+    pub fn decode(bytes: &[u8]) -> Result<Canvas> {
+        let (width, height, data) = if bytes.starts_with(&[0xFF, 0xD8]) {
+            jpeg::decode(bytes)
+        } else {
+            Self::decode_png(bytes)
+        }?;
+        Ok(Canvas {
+            size: UVec2::new(width, height),
+            data,
+            grade: Grade::default(),
+        })
+    }
+
+    fn decode_png(bytes: &[u8]) -> Result<(u32, u32, Vec<u8>)> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+
+        let channels = match info.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::Rgb => 3,
+            png::ColorType::Rgba => 4,
+            other => bail!("unsupported PNG color type {:?}", other),
+        };
+        let data = buf[..info.buffer_size()]
+            .chunks_exact(channels)
+            .flat_map(|px| match channels {
+                1 | 2 => [px[0], px[0], px[0]],
+                _ => [px[0], px[1], px[2]],
+            })
+            .collect();
+        Ok((info.width, info.height, data))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.size.x
+    }
+
+    pub fn height(&self) -> u32 {
+        self.size.y
+    }
+
+    pub fn memory(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Color3 {
+        let idx = (y * self.size.x + x) as usize * 3;
+        let c = |num: usize| Self::gamma_2_to_linear(self.data[idx + num] as f32 / 255.0);
+        color3(c(0), c(1), c(2))
+    }
+
     pub fn save(&self, path: &Path) -> Result<()> {
         use std::fs::File;
         use std::io::BufWriter;
@@ -42,4 +125,8 @@ impl Canvas {
     fn linear_to_gamma_2(component: f32) -> f32 {
         component.sqrt()
     }
+
+    fn gamma_2_to_linear(component: f32) -> f32 {
+        component * component
+    }
 }
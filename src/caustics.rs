@@ -0,0 +1,221 @@
+use crate::hittables::{Bvh, Hit, Hittable, HittableVec, Interval};
+use crate::materials::Material;
+use crate::onb::Onb;
+use crate::random;
+use crate::render::{leave, Ray};
+use crate::{Color3, Point3};
+use glam::{IVec3, Vec3};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::ops::Range;
+
+// Light that reached a diffuse surface through mirrors or glass.
+struct Photon {
+    p: Point3,
+    // Facing the side the photon arrived from.
+    normal: Vec3,
+    power: Color3,
+}
+
+// A caustic photon map for previews: photons shot from the lights, kept
+// where they land on a diffuse surface after bouncing off or through
+// specular ones. Their density around a point estimates the light it gets
+// that way, which paths from the camera find only rarely. The estimate is
+// blurred over `radius` but has little noise, so glass can be placed
+// without waiting for caustics to converge.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    radius: f32,
+    // The photons in each grid cell, as ranges into `photons`, which are
+    // sorted by cell. Cells are twice the radius across, so a lookup needs
+    // only the 8 cells its sphere overlaps.
+    cells: HashMap<IVec3, Range<usize>>,
+}
+
+// Whether scattering off `material` is a single direction: caustics are
+// light focused by such surfaces.
+pub fn is_specular(material: &Material) -> bool {
+    match *material.base() {
+        Material::Metal { fuzz, .. } => fuzz <= 0.0,
+        Material::Dielectric { .. } => true,
+        _ => false,
+    }
+}
+
+impl PhotonMap {
+    // Photons are shot from points on `lights` picked as camera paths pick
+    // them, seen from the center of the world's bounded objects. Seeded
+    // traces are repeatable.
+    pub fn trace(
+        world: &Bvh,
+        lights: &HittableVec,
+        count: u32,
+        max_depth: u32,
+        seed: Option<u64>,
+    ) -> PhotonMap {
+        // Wide enough to gather a few dozen photons where caustics are
+        // focused, and scaled with the scene.
+        const RELATIVE_RADIUS: f32 = 0.005;
+
+        let bounds = world.bounded_box();
+        let radius = (bounds.max - bounds.min).length() * RELATIVE_RADIUS;
+        let photons: Vec<Photon> = (0..count)
+            .into_par_iter()
+            .filter_map(|idx| {
+                if let Some(seed) = seed {
+                    // A row no image has, keeping photon streams apart from
+                    // pixel ones.
+                    random::seed_sample(seed, idx, u32::MAX, 0);
+                }
+                trace_photon(world, lights, bounds.centroid(), max_depth)
+            })
+            .map(|photon| Photon {
+                power: photon.power / count as f32,
+                ..photon
+            })
+            .collect();
+        PhotonMap::new(photons, radius)
+    }
+
+    fn new(mut photons: Vec<Photon>, radius: f32) -> PhotonMap {
+        let cell = |p: Point3| (p / (2.0 * radius)).floor().as_ivec3();
+        photons.sort_by_key(|photon| cell(photon.p).to_array());
+        let mut cells = HashMap::new();
+        let mut start = 0;
+        for end in 1..=photons.len() {
+            if end == photons.len() || cell(photons[end].p) != cell(photons[start].p) {
+                cells.insert(cell(photons[start].p), start..end);
+                start = end;
+            }
+        }
+        PhotonMap {
+            photons,
+            radius,
+            cells,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    // Light leaving a diffuse `hit` of `reflectance` that came from photons
+    // landing within the radius on the same side of the surface.
+    pub fn radiance(&self, hit: &Hit, reflectance: Color3) -> Color3 {
+        let cell = |p: Point3| (p / (2.0 * self.radius)).floor().as_ivec3();
+        let (min, max) = (cell(hit.p - self.radius), cell(hit.p + self.radius));
+        let mut power = Color3::ZERO;
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                for x in min.x..=max.x {
+                    let Some(range) = self.cells.get(&IVec3::new(x, y, z)) else {
+                        continue;
+                    };
+                    for photon in &self.photons[range.clone()] {
+                        if photon.normal.dot(hit.normal) > 0.0
+                            && photon.p.distance_squared(hit.p) <= self.radius * self.radius
+                        {
+                            power += photon.power;
+                        }
+                    }
+                }
+            }
+        }
+        reflectance / PI * power / (PI * self.radius * self.radius)
+    }
+}
+
+// Follows one photon from a light, returning it where it lands if it was
+// focused on the way. Its power is for a single photon standing for all of
+// the lights' output.
+fn trace_photon(
+    world: &dyn Hittable,
+    lights: &HittableVec,
+    origin: Point3,
+    max_depth: u32,
+) -> Option<Photon> {
+    let all = Interval::new(0.0, f32::INFINITY);
+
+    // A point on a light, and its density over the light's area.
+    let dir = lights.random(origin);
+    let pdf = lights.pdf_value(origin, dir);
+    let light_hit = lights.hit(&Ray::new(origin, dir), all)?;
+    let distance_squared = (light_hit.t * dir.length()).powi(2);
+    let cosine = dir.normalize().dot(light_hit.normal).abs();
+    let area_pdf = pdf * cosine / distance_squared;
+    if !(area_pdf > 0.0 && area_pdf.is_finite()) {
+        return None;
+    }
+    // Diffuse emitters send out pi times their radiance per unit area, in
+    // cosine-weighted directions.
+    let dir = Onb::new(light_hit.normal).to_world(random::cosine_direction());
+    let mut power = light_hit.material.emission(&light_hit, dir) * PI / area_pdf;
+    if power == Color3::ZERO {
+        return None;
+    }
+    let mut ray = Ray::new(leave(&light_hit, dir, world), dir);
+    let mut focused = false;
+    for _ in 0..max_depth {
+        let hit = world.hit(&ray, all)?;
+        if let Material::Lambertian { .. } = hit.material.base() {
+            return focused.then_some(Photon {
+                p: hit.p,
+                normal: hit.normal,
+                power,
+            });
+        }
+        if !is_specular(hit.material) {
+            return None;
+        }
+        let scattered = Material::scatter(&ray, &hit)?;
+        let dir = scattered.ray.dir();
+        power *= scattered.attenuation;
+        ray = Ray::new(leave(&hit, dir, world), dir);
+        focused = true;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::Quad;
+    use crate::point3;
+
+    #[test]
+    fn gathers_nearby_photons_on_the_same_side() {
+        let photon = |x: f32, normal: Vec3| Photon {
+            p: point3(x, 0.0, 0.0),
+            normal,
+            power: Color3::ONE,
+        };
+        let map = PhotonMap::new(
+            vec![
+                photon(0.0, Vec3::Y),
+                photon(0.5, Vec3::Y),
+                photon(0.9, Vec3::Y),
+                photon(1.5, Vec3::Y),
+                photon(0.1, -Vec3::Y),
+            ],
+            1.0,
+        );
+        assert_eq!(map.len(), 5);
+        let floor = Material::new_lambertian(1.0, 1.0, 1.0);
+        let quad = Quad::new(
+            point3(-5.0, 0.0, -5.0),
+            10.0 * Vec3::X,
+            10.0 * Vec3::Z,
+            floor,
+        );
+        let hit = quad
+            .hit(
+                &Ray::new(point3(0.0, 1.0, 0.0), -Vec3::Y),
+                Interval::new(0.0, f32::INFINITY),
+            )
+            .unwrap();
+        let radiance = map.radiance(&hit, Color3::ONE);
+        let expected = 3.0 / (PI * PI);
+        assert!((radiance.x - expected).abs() < 1e-6, "{}", radiance);
+    }
+}
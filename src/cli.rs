@@ -0,0 +1,325 @@
+use crate::memory::parse_size;
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+
+pub enum Command {
+    Render(RenderArgs),
+    Pack(PackArgs),
+    Compare(CompareArgs),
+    Bench(BenchArgs),
+    Export(ExportArgs),
+    ListScenes(ListScenesArgs),
+}
+
+pub enum Focus {
+    Center,
+    Pixel(u32, u32),
+}
+
+// Intensities mapped onto the thermal palette: the image's own range, or a
+// fixed one so a sequence of renders stays comparable.
+pub enum ThermalRange {
+    Auto,
+    Fixed(f32, f32),
+}
+
+pub struct RenderArgs {
+    pub scene: Option<PathBuf>,
+    pub output: PathBuf,
+    pub asset_paths: Vec<PathBuf>,
+    pub split: Vec<(String, String)>,
+    pub seed: Option<u64>,
+    pub debug_render: bool,
+    pub trace_pixel: Option<(u32, u32)>,
+    pub focus: Option<Focus>,
+    pub auto_frame: bool,
+    pub thumbnail: bool,
+    pub memory_budgets: Vec<(String, String)>,
+    // Bytes of decoded image textures to keep, decoding the rest as needed.
+    pub texture_cache: Option<usize>,
+    pub grade: Vec<(String, String)>,
+    pub defines: Vec<(String, String)>,
+    pub range: Option<f32>,
+    pub thermal: Option<ThermalRange>,
+    pub light_paths: bool,
+    pub denoise: bool,
+    pub caustics: Option<u32>,
+    // Mirrors the accumulation buffer here while rendering.
+    pub live: Option<PathBuf>,
+    // Prints the image this many characters wide after every pass.
+    pub preview: Option<u32>,
+}
+
+pub struct PackArgs {
+    pub scene: PathBuf,
+    pub output: PathBuf,
+    pub asset_paths: Vec<PathBuf>,
+    pub defines: Vec<(String, String)>,
+}
+
+pub struct CompareArgs {
+    pub reference: PathBuf,
+    pub test: PathBuf,
+    pub downsample: usize,
+    // Limits on each metric; exceeding any of them fails the command.
+    pub max_rmse: Option<f32>,
+    pub max_rel_mse: Option<f32>,
+    pub max_flip: Option<f32>,
+}
+
+pub struct BenchArgs {
+    // The JSON report goes to stdout without one.
+    pub output: Option<PathBuf>,
+}
+
+pub struct ExportArgs {
+    // One of the scenes built in code.
+    pub scene: String,
+    // The scene file goes to stdout without one.
+    pub output: Option<PathBuf>,
+}
+
+pub struct ListScenesArgs {
+    // Searched for scene files, not recursively.
+    pub dir: PathBuf,
+    pub thumbnails: bool,
+    pub asset_paths: Vec<PathBuf>,
+}
+
+impl Command {
+    pub fn parse() -> Result<Command> {
+        let mut args = std::env::args().skip(1).peekable();
+        match args.peek().map(String::as_str) {
+            Some("pack") => {
+                args.next();
+                Self::parse_pack(args)
+            }
+            Some("compare") => {
+                args.next();
+                Self::parse_compare(args)
+            }
+            Some("bench") => {
+                args.next();
+                Self::parse_bench(args)
+            }
+            Some("export") => {
+                args.next();
+                Self::parse_export(args)
+            }
+            Some("list-scenes") => {
+                args.next();
+                Self::parse_list_scenes(args)
+            }
+            _ => Self::parse_render(args),
+        }
+    }
+
+    fn parse_render(mut args: impl Iterator<Item = String>) -> Result<Command> {
+        let mut render = RenderArgs {
+            scene: None,
+            output: PathBuf::from("output.png"),
+            asset_paths: vec![],
+            split: vec![],
+            seed: None,
+            debug_render: false,
+            trace_pixel: None,
+            focus: None,
+            auto_frame: false,
+            thumbnail: false,
+            memory_budgets: vec![],
+            texture_cache: None,
+            grade: vec![],
+            defines: vec![],
+            range: None,
+            thermal: None,
+            light_paths: false,
+            denoise: false,
+            caustics: None,
+            live: None,
+            preview: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" | "--output" => render.output = value(&mut args, &arg)?.into(),
+                "--asset-path" => render.asset_paths.push(value(&mut args, &arg)?.into()),
+                "-D" | "--define" => render.defines.push(key_value(&value(&mut args, &arg)?)?),
+                "--split" => render.split.push(key_value(&value(&mut args, &arg)?)?),
+                "--grade" => render.grade.push(key_value(&value(&mut args, &arg)?)?),
+                "--seed" => render.seed = Some(parse(&value(&mut args, &arg)?, &arg)?),
+                "--debug-render" => render.debug_render = true,
+                "--auto-frame" => render.auto_frame = true,
+                "--thumbnail" => render.thumbnail = true,
+                "--light-paths" => render.light_paths = true,
+                "--denoise" => render.denoise = true,
+                "--range" => render.range = Some(parse(&value(&mut args, &arg)?, &arg)?),
+                "--caustics" => render.caustics = Some(parse(&value(&mut args, &arg)?, &arg)?),
+                "--live" => render.live = Some(value(&mut args, &arg)?.into()),
+                "--preview" => render.preview = Some(parse(&value(&mut args, &arg)?, &arg)?),
+                "--memory-budget" => render
+                    .memory_budgets
+                    .push(key_value(&value(&mut args, &arg)?)?),
+                "--texture-cache" => {
+                    let value = value(&mut args, &arg)?;
+                    render.texture_cache = Some(
+                        parse_size(&value)
+                            .with_context(|| format!("invalid size '{}' for {}", value, arg))?,
+                    )
+                }
+                "--trace-pixel" => {
+                    render.trace_pixel = Some(pixel(&value(&mut args, &arg)?, &arg)?)
+                }
+                "--thermal" => {
+                    render.thermal = Some(match value(&mut args, &arg)?.as_str() {
+                        "auto" => ThermalRange::Auto,
+                        value => {
+                            let (min, max) = value
+                                .split_once(',')
+                                .with_context(|| format!("expected MIN,MAX, got '{}'", value))?;
+                            ThermalRange::Fixed(parse(min, &arg)?, parse(max, &arg)?)
+                        }
+                    })
+                }
+                "--focus" => {
+                    render.focus = Some(match value(&mut args, &arg)?.as_str() {
+                        "center" => Focus::Center,
+                        value => {
+                            let (x, y) = pixel(value, &arg)?;
+                            Focus::Pixel(x, y)
+                        }
+                    })
+                }
+                _ if arg.starts_with('-') => bail!("unknown option '{}'", arg),
+                _ => render.scene = Some(arg.into()),
+            }
+        }
+        Ok(Command::Render(render))
+    }
+
+    fn parse_pack(mut args: impl Iterator<Item = String>) -> Result<Command> {
+        let mut scene = None;
+        let mut output = None;
+        let mut asset_paths = vec![];
+        let mut defines = vec![];
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" | "--output" => output = Some(value(&mut args, &arg)?.into()),
+                "--asset-path" => asset_paths.push(value(&mut args, &arg)?.into()),
+                "-D" | "--define" => defines.push(key_value(&value(&mut args, &arg)?)?),
+                _ if arg.starts_with('-') => bail!("unknown option '{}'", arg),
+                _ => scene = Some(PathBuf::from(arg)),
+            }
+        }
+        let scene = scene.context("usage: raytracer pack SCENE [-o ARCHIVE.tar]")?;
+        let output = output.unwrap_or_else(|| scene.with_extension("tar"));
+        Ok(Command::Pack(PackArgs {
+            scene,
+            output,
+            asset_paths,
+            defines,
+        }))
+    }
+
+    fn parse_compare(mut args: impl Iterator<Item = String>) -> Result<Command> {
+        let mut paths = vec![];
+        let mut downsample = 1;
+        let (mut max_rmse, mut max_rel_mse, mut max_flip) = (None, None, None);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--downsample" => downsample = parse(&value(&mut args, &arg)?, &arg)?,
+                "--max-rmse" => max_rmse = Some(parse(&value(&mut args, &arg)?, &arg)?),
+                "--max-rel-mse" => max_rel_mse = Some(parse(&value(&mut args, &arg)?, &arg)?),
+                "--max-flip" => max_flip = Some(parse(&value(&mut args, &arg)?, &arg)?),
+                _ if arg.starts_with('-') => bail!("unknown option '{}'", arg),
+                _ => paths.push(PathBuf::from(arg)),
+            }
+        }
+        match <[PathBuf; 2]>::try_from(paths) {
+            Ok([reference, test]) => Ok(Command::Compare(CompareArgs {
+                reference,
+                test,
+                downsample,
+                max_rmse,
+                max_rel_mse,
+                max_flip,
+            })),
+            Err(_) => bail!(
+                "usage: raytracer compare REFERENCE.png TEST.png [--downsample N] \
+                 [--max-rmse X] [--max-rel-mse X] [--max-flip X]"
+            ),
+        }
+    }
+
+    fn parse_bench(mut args: impl Iterator<Item = String>) -> Result<Command> {
+        let mut output = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" | "--output" => output = Some(value(&mut args, &arg)?.into()),
+                _ => bail!("usage: raytracer bench [-o REPORT.json]"),
+            }
+        }
+        Ok(Command::Bench(BenchArgs { output }))
+    }
+
+    fn parse_export(mut args: impl Iterator<Item = String>) -> Result<Command> {
+        let mut scene = None;
+        let mut output = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" | "--output" => output = Some(value(&mut args, &arg)?.into()),
+                _ if arg.starts_with('-') || scene.is_some() => {
+                    bail!("usage: raytracer export SCENE [-o SCENE.json]")
+                }
+                _ => scene = Some(arg),
+            }
+        }
+        let scene = scene.context("usage: raytracer export SCENE [-o SCENE.json]")?;
+        Ok(Command::Export(ExportArgs { scene, output }))
+    }
+
+    fn parse_list_scenes(mut args: impl Iterator<Item = String>) -> Result<Command> {
+        let mut dir = None;
+        let mut thumbnails = false;
+        let mut asset_paths = vec![];
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--thumbnails" => thumbnails = true,
+                "--asset-path" => asset_paths.push(value(&mut args, &arg)?.into()),
+                _ if arg.starts_with('-') || dir.is_some() => {
+                    bail!("usage: raytracer list-scenes [DIR] [--thumbnails] [--asset-path DIR]")
+                }
+                _ => dir = Some(PathBuf::from(arg)),
+            }
+        }
+        Ok(Command::ListScenes(ListScenesArgs {
+            dir: dir.unwrap_or_else(|| PathBuf::from("scenes")),
+            thumbnails,
+            asset_paths,
+        }))
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<String> {
+    args.next()
+        .with_context(|| format!("missing value for {}", option))
+}
+
+fn parse<T: std::str::FromStr>(value: &str, option: &str) -> Result<T> {
+    value
+        .parse()
+        .ok()
+        .with_context(|| format!("invalid value '{}' for {}", value, option))
+}
+
+fn pixel(value: &str, option: &str) -> Result<(u32, u32)> {
+    let (x, y) = value
+        .split_once(',')
+        .with_context(|| format!("expected X,Y, got '{}'", value))?;
+    Ok((parse(x, option)?, parse(y, option)?))
+}
+
+fn key_value(arg: &str) -> Result<(String, String)> {
+    match arg.split_once('=') {
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => bail!("expected KEY=VALUE, got '{}'", arg),
+    }
+}
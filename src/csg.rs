@@ -0,0 +1,201 @@
+use crate::hittables::{Aabb, Hit, Hittable, Interval};
+use crate::json::Json;
+use crate::render::Ray;
+
+// How the insides of two solids combine.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Operation {
+    Union,
+    Intersection,
+    // The first solid with the second cut out of it.
+    Difference,
+}
+
+impl Operation {
+    pub fn parse(name: &str) -> Option<Operation> {
+        Some(match name {
+            "union" => Operation::Union,
+            "intersection" => Operation::Intersection,
+            "difference" => Operation::Difference,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Union => "union",
+            Operation::Intersection => "intersection",
+            Operation::Difference => "difference",
+        }
+    }
+
+    fn inside(self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            Operation::Union => in_a || in_b,
+            Operation::Intersection => in_a && in_b,
+            Operation::Difference => in_a && !in_b,
+        }
+    }
+}
+
+// Constructive solid geometry: the solid whose inside is the children's
+// insides combined by `operation`. The children must be closed, with their
+// front faces outward, so each crossing of their surfaces tells whether the
+// ray enters or leaves them; planes count as the half-space behind them.
+// Surfaces keep their own object's material, so a hole cut by a difference
+// shows the cutter's.
+pub struct Csg {
+    operation: Operation,
+    a: Box<dyn Hittable>,
+    b: Box<dyn Hittable>,
+}
+
+impl Csg {
+    pub fn new(operation: Operation, a: Box<dyn Hittable>, b: Box<dyn Hittable>) -> Csg {
+        Csg { operation, a, b }
+    }
+}
+
+impl Hittable for Csg {
+    // Walks the children's crossings along the ray in order, tracking
+    // whether it is inside each, until the combination's inside changes.
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        // Enough for any sensible solid; stops rays caught in a degenerate
+        // one going round forever.
+        const MAX_CROSSINGS: usize = 64;
+
+        let mut a = crossing(self.a.as_ref(), ray, ray_t.min);
+        let mut b = crossing(self.b.as_ref(), ray, ray_t.min);
+        let mut in_a = a.as_ref().is_some_and(|hit| !hit.front_face);
+        let mut in_b = b.as_ref().is_some_and(|hit| !hit.front_face);
+        for _ in 0..MAX_CROSSINGS {
+            let from_a = match (&a, &b) {
+                (Some(hit_a), Some(hit_b)) => hit_a.t <= hit_b.t,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => return None,
+            };
+            let next = if from_a { &mut a } else { &mut b };
+            let hit = next.take().unwrap();
+            if hit.t > ray_t.max {
+                return None;
+            }
+            let was_inside = self.operation.inside(in_a, in_b);
+            if from_a {
+                in_a = hit.front_face;
+            } else {
+                in_b = hit.front_face;
+            }
+            let inside = self.operation.inside(in_a, in_b);
+            if inside != was_inside {
+                // The normal already faces the ray; only which side is the
+                // front follows the combination.
+                return Some(Hit {
+                    front_face: inside,
+                    ..hit
+                });
+            }
+            let object = if from_a { &self.a } else { &self.b };
+            *next = crossing(object.as_ref(), ray, hit.t.next_up());
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let (a, b) = (self.a.bounding_box(), self.b.bounding_box());
+        match self.operation {
+            Operation::Union => a.union(b),
+            Operation::Intersection => a.intersection(b),
+            Operation::Difference => a,
+        }
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>() + self.a.memory() + self.b.memory()
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(Json::object([
+            ("type", self.operation.name().into()),
+            (
+                "children",
+                Json::Array(vec![self.a.to_json()?, self.b.to_json()?]),
+            ),
+        ]))
+    }
+}
+
+// The first crossing of `object`'s surface from `min` on. Those past the end
+// of the ray's interval still tell whether it starts inside.
+fn crossing<'a>(object: &'a dyn Hittable, ray: &Ray, min: f32) -> Option<Hit<'a>> {
+    object.hit(ray, Interval::new(min, f32::INFINITY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::{make_box, Sphere};
+    use crate::materials::Material;
+    use crate::{point3, Point3};
+    use glam::Vec3;
+
+    fn sphere(center: Point3, radius: f32) -> Box<dyn Hittable> {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        Box::new(Sphere::new(center, radius, mat))
+    }
+
+    // The distance to the first crossing along +z from `origin`, and whether
+    // it enters the solid.
+    fn first(csg: &Csg, origin: Point3) -> Option<(f32, bool)> {
+        let hit = csg.hit(
+            &Ray::new(origin, Vec3::Z),
+            Interval::new(0.001, f32::INFINITY),
+        )?;
+        Some((hit.t, hit.front_face))
+    }
+
+    fn assert_crossing(actual: Option<(f32, bool)>, t: f32, enters: bool) {
+        let (actual_t, actual_enters) = actual.expect("missed");
+        assert!((actual_t - t).abs() < 1e-4, "{} != {}", actual_t, t);
+        assert_eq!(actual_enters, enters);
+    }
+
+    #[test]
+    fn difference_cuts_a_hole() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let cube = Box::new(make_box(-Vec3::ONE, Vec3::ONE, mat));
+        let holed = Csg::new(Operation::Difference, cube, sphere(Point3::ZERO, 0.5));
+        assert_crossing(first(&holed, point3(0.0, 0.0, -3.0)), 2.0, true);
+        // From the hole, out through its wall into the cube's material.
+        assert_crossing(first(&holed, Point3::ZERO), 0.5, true);
+        // From inside the material, into the hole.
+        assert_crossing(first(&holed, point3(0.0, 0.0, -0.8)), 0.3, false);
+        assert!(holed.bounding_box().max.z >= 1.0);
+    }
+
+    #[test]
+    fn intersection_makes_a_lens() {
+        let lens = Csg::new(
+            Operation::Intersection,
+            sphere(point3(0.0, 0.0, 1.5), 2.0),
+            sphere(point3(0.0, 0.0, -1.5), 2.0),
+        );
+        // The lens spans z from -0.5 to 0.5 on its axis.
+        assert_crossing(first(&lens, point3(0.0, 0.0, -3.0)), 2.5, true);
+        assert_crossing(first(&lens, Point3::ZERO), 0.5, false);
+        // Through one sphere only.
+        assert!(first(&lens, point3(1.5, 0.0, -3.0)).is_none());
+        assert!(lens.bounding_box().max.z <= 0.6);
+    }
+
+    #[test]
+    fn union_hides_inner_surfaces() {
+        let pair = Csg::new(
+            Operation::Union,
+            sphere(point3(0.0, 0.0, -0.5), 1.0),
+            sphere(point3(0.0, 0.0, 0.5), 1.0),
+        );
+        assert_crossing(first(&pair, point3(0.0, 0.0, -3.0)), 1.5, true);
+        assert_crossing(first(&pair, Point3::ZERO), 1.5, false);
+    }
+}
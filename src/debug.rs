@@ -0,0 +1,70 @@
+use crate::canvas::Canvas;
+use crate::hittables::{Hit, Hittable};
+use crate::materials::{Material, Scattered};
+use crate::render::{Camera, Ray};
+use glam::DVec3;
+use std::cell::Cell;
+
+thread_local! {
+    static TRACING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Renders on the calling thread only, pixel by pixel in scanline order, so a
+// debugger can step through any pixel of a seeded render.
+pub fn render_sequential(camera: &Camera, world: &dyn Hittable, canvas: &mut Canvas) {
+    for y in 0..canvas.height() {
+        for x in 0..canvas.width() {
+            let mut sum = DVec3::ZERO;
+            camera.add_samples(x, y, world, 0..camera.samples(), &mut sum);
+            canvas.draw(x, y, camera.pixel_color(sum));
+        }
+    }
+}
+
+pub fn trace_pixel(camera: &Camera, world: &dyn Hittable, x: u32, y: u32) {
+    TRACING.with(|t| t.set(true));
+    eprintln!("pixel ({}, {})", x, y);
+    let mut sum = DVec3::ZERO;
+    camera.add_samples(x, y, world, 0..camera.samples(), &mut sum);
+    let color = camera.pixel_color(sum);
+    TRACING.with(|t| t.set(false));
+    eprintln!("color {:?}", color);
+}
+
+pub fn trace_bounce(bounce: u32, ray: &Ray, hit: &Hit, scattered: Option<&Scattered>) {
+    if !TRACING.with(Cell::get) {
+        return;
+    }
+    let material = match hit.material {
+        Material::Lambertian { .. } => "lambertian",
+        Material::Metal { .. } => "metal",
+        Material::Dielectric { .. } => "dielectric",
+        Material::DiffuseLight { .. } => "light",
+        Material::Isotropic { .. } => "isotropic",
+        Material::Measured { .. } => "measured",
+        Material::Custom(_) => "custom",
+        Material::Heated { .. } => "heated",
+    };
+    eprintln!(
+        "  bounce {}: origin {:?} dir {:?} -> t {:.4} p {:?} n {:?} front {} {}",
+        bounce,
+        ray.origin(),
+        ray.dir(),
+        hit.t,
+        hit.p,
+        hit.normal,
+        hit.front_face,
+        material,
+    );
+    match scattered {
+        Some(s) => eprintln!(
+            "    scattered dir {:?} attenuation {:?}",
+            s.ray.dir(),
+            s.attenuation
+        ),
+        None => eprintln!(
+            "    absorbed, emitted {:?}",
+            hit.material.emission(hit, -ray.dir())
+        ),
+    }
+}
@@ -0,0 +1,218 @@
+use crate::Color3;
+use glam::Vec3;
+use rayon::prelude::*;
+
+// What the primary ray through a pixel hit first. Pixels are only averaged
+// with neighbors that saw the same surface, so edges and corners stay sharp.
+#[derive(Clone, Copy)]
+pub struct Guide {
+    pub normal: Vec3,
+    // Infinite for rays that escaped the scene.
+    pub depth: f32,
+}
+
+impl Guide {
+    pub const BACKGROUND: Guide = Guide {
+        normal: Vec3::ZERO,
+        depth: f32::INFINITY,
+    };
+
+    fn is_background(&self) -> bool {
+        self.depth.is_infinite()
+    }
+}
+
+// The filter's footprint doubles with every pass, reaching 2^PASSES * 4
+// pixels across at the end.
+const PASSES: u32 = 5;
+// The B3 spline, from the center outwards.
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// How different neighbors may be and still count as the same surface. Colors
+// are compared after compressing them into 0..1, so bright pixels don't
+// stand out more than dark ones; the color limit halves with every pass,
+// since each pass leaves less noise.
+const SIGMA_COLOR: f32 = 1.2;
+const NORMAL_POWER: i32 = 64;
+const SIGMA_DEPTH: f32 = 1.0;
+
+// Edge-avoiding a-trous wavelet filter (Dammertz et al. 2010): repeated
+// blurs with a sparse 5x5 kernel whose taps spread further apart every pass,
+// each tap weighted by how alike it is to the center pixel.
+pub fn denoise(width: u32, height: u32, image: &mut [Color3], guides: &[Guide]) {
+    let (width, height) = (width as usize, height as usize);
+    let slopes = depth_slopes(width, height, guides);
+    clamp_fireflies(width, height, image);
+    let mut sigma_color = SIGMA_COLOR;
+    for pass in 0..PASSES {
+        let step = 1 << pass;
+        let input = image.to_vec();
+        image
+            .par_chunks_mut(width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, out) in row.iter_mut().enumerate() {
+                    let center = y * width + x;
+                    let compressed = compress(input[center]);
+                    let mut sum = Color3::ZERO;
+                    let mut total = 0.0;
+                    for dy in -2..=2_isize {
+                        for dx in -2..=2_isize {
+                            let (Some(tx), Some(ty)) = (
+                                x.checked_add_signed(dx * step),
+                                y.checked_add_signed(dy * step),
+                            ) else {
+                                continue;
+                            };
+                            if tx >= width || ty >= height {
+                                continue;
+                            }
+                            let tap = ty * width + tx;
+                            let color_diff = (compress(input[tap]) - compressed).length_squared();
+                            let distance = (dx.abs().max(dy.abs()) * step) as f32;
+                            let weight = KERNEL[dx.unsigned_abs()]
+                                * KERNEL[dy.unsigned_abs()]
+                                * (-color_diff / (sigma_color * sigma_color)).exp()
+                                * surface_weight(
+                                    &guides[center],
+                                    &guides[tap],
+                                    slopes[center] * distance,
+                                );
+                            sum += weight * input[tap];
+                            total += weight;
+                        }
+                    }
+                    // The center tap always has a positive weight.
+                    *out = sum / total;
+                }
+            });
+        sigma_color *= 0.5;
+    }
+}
+
+// A pixel much brighter than all its neighbors is a rare path that the
+// samples so far can't average out, and blurring would only spread it into a
+// blotch. It is clamped to the brightest neighbor instead.
+fn clamp_fireflies(width: usize, height: usize, image: &mut [Color3]) {
+    let input = image.to_vec();
+    image
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, out) in row.iter_mut().enumerate() {
+                let mut brightest = Color3::ZERO;
+                for ty in y.saturating_sub(1)..(y + 2).min(height) {
+                    for tx in x.saturating_sub(1)..(x + 2).min(width) {
+                        if (tx, ty) != (x, y) {
+                            brightest = brightest.max(input[ty * width + tx]);
+                        }
+                    }
+                }
+                *out = out.min(brightest);
+            }
+        });
+}
+
+// Maps HDR colors into 0..1 for comparing them.
+fn compress(color: Color3) -> Color3 {
+    color / (Color3::ONE + color)
+}
+
+fn surface_weight(center: &Guide, tap: &Guide, expected_depth_change: f32) -> f32 {
+    match (center.is_background(), tap.is_background()) {
+        (true, true) => 1.0,
+        (false, false) => {
+            let normal = center.normal.dot(tap.normal).max(0.0).powi(NORMAL_POWER);
+            let depth_diff = (center.depth - tap.depth).abs();
+            let depth = (-depth_diff / (SIGMA_DEPTH * expected_depth_change + 1e-4)).exp();
+            normal * depth
+        }
+        _ => 0.0,
+    }
+}
+
+// How fast depth changes per pixel around each pixel, so surfaces seen at a
+// grazing angle aren't split up by their own slope.
+fn depth_slopes(width: usize, height: usize, guides: &[Guide]) -> Vec<f32> {
+    let depth = |x: usize, y: usize| guides[y * width + x].depth;
+    let slope = |a: f32, b: f32, c: f32| {
+        // One-sided differences at image borders and silhouettes, where the
+        // other side isn't on the surface.
+        [b - a, c - b]
+            .into_iter()
+            .filter(|d| d.is_finite())
+            .map(f32::abs)
+            .fold(f32::INFINITY, f32::min)
+    };
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let center = depth(x, y);
+            let dx = slope(
+                depth(x.saturating_sub(1), y),
+                center,
+                depth((x + 1).min(width - 1), y),
+            );
+            let dy = slope(
+                depth(x, y.saturating_sub(1)),
+                center,
+                depth(x, (y + 1).min(height - 1)),
+            );
+            match dx.max(dy) {
+                s if s.is_finite() => s,
+                _ => 0.0,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+
+    const SIZE: u32 = 32;
+
+    fn variance(pixels: impl Iterator<Item = Color3>) -> f32 {
+        let values: Vec<f32> = pixels.map(|c| c.x).collect();
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32
+    }
+
+    #[test]
+    fn smooths_noise_on_a_surface() {
+        let flat = Guide {
+            normal: Vec3::Z,
+            depth: 1.0,
+        };
+        let guides = vec![flat; (SIZE * SIZE) as usize];
+        random::seed_sample(0, 0, 0, 0);
+        let mut image: Vec<Color3> = (0..SIZE * SIZE)
+            .map(|_| Color3::splat(0.5 + 0.2 * (random::f32() - 0.5)))
+            .collect();
+        let before = variance(image.iter().copied());
+        denoise(SIZE, SIZE, &mut image, &guides);
+        let after = variance(image.iter().copied());
+        assert!(after < before / 20.0, "{} -> {}", before, after);
+    }
+
+    #[test]
+    fn keeps_edges_between_surfaces() {
+        // A dark wall on the left meeting a bright one on the right at a
+        // right angle.
+        let left = |x: u32| x < SIZE / 2;
+        let guides: Vec<Guide> = (0..SIZE * SIZE)
+            .map(|i| Guide {
+                normal: if left(i % SIZE) { Vec3::X } else { Vec3::Z },
+                depth: 1.0,
+            })
+            .collect();
+        let mut image: Vec<Color3> = (0..SIZE * SIZE)
+            .map(|i| Color3::splat(if left(i % SIZE) { 0.1 } else { 0.9 }))
+            .collect();
+        let original = image.clone();
+        denoise(SIZE, SIZE, &mut image, &guides);
+        for (denoised, original) in image.iter().zip(&original) {
+            assert!((*denoised - *original).abs().max_element() < 1e-3);
+        }
+    }
+}
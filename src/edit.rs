@@ -0,0 +1,501 @@
+use crate::hittables::{
+    build_nodes, parents, sah_cost, traverse, Aabb, BvhNode, BvhQuality, Hit, Hittable,
+    HittableVec, Interval, Transform, BVH_REBUILD_COST,
+};
+use crate::render::Ray;
+use anyhow::{bail, Result};
+use glam::Affine3A;
+use std::sync::Arc;
+
+// Edited objects the hierarchy puts up with before it is rebuilt: this many
+// plus an eighth of the objects in it. Rebuilding that rarely keeps its cost
+// per edit to about log n.
+const STALE_EDITS: usize = 8;
+
+// An object in an `EditableWorld`. Ids aren't reused, so one stays valid
+// until its object is removed and never refers to another.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ObjectId(usize);
+
+struct Slot {
+    object: Arc<dyn Hittable>,
+    to_world: Affine3A,
+    light: bool,
+    // `object` placed by `to_world`, as it is traced.
+    placed: Box<dyn Hittable>,
+    bbox: Aabb,
+    // Whether the hierarchy has it rather than the loose list, and in
+    // which leaf.
+    in_tree: bool,
+    leaf: u32,
+}
+
+// A world that objects and lights can be added to, removed from and moved
+// around in after it is built, for interactive editors. Edits don't rebuild
+// the hierarchy: objects moved or removed have the boxes above them refit,
+// objects added are tested on every ray instead, and the hierarchy is only
+// rebuilt once enough of those have piled up, or moves made it
+// BVH_REBUILD_COST times as costly to trace as it was built.
+pub struct EditableWorld {
+    slots: Vec<Option<Slot>>,
+    quality: BvhQuality,
+    // Ids in the order the hierarchy's leaves refer to them.
+    order: Vec<usize>,
+    nodes: Vec<BvhNode>,
+    // Objects edited since the last rebuild, tested on every ray.
+    loose: Vec<usize>,
+    // Objects without finite bounds, such as planes, which are always tested.
+    unbounded: Vec<usize>,
+    // Ids in `order` whose objects have since been removed, or lost their
+    // bounds.
+    stale: usize,
+    // Each node's parent, to refit the boxes above a leaf.
+    parents: Vec<u32>,
+    // The nodes' `BvhNode::cost`s summed, kept up to date by refits, and
+    // `sah_cost` when the hierarchy was built.
+    cost: f64,
+    built_cost: f32,
+}
+
+impl EditableWorld {
+    pub fn new(quality: BvhQuality) -> EditableWorld {
+        EditableWorld {
+            slots: vec![],
+            quality,
+            order: vec![],
+            nodes: vec![],
+            loose: vec![],
+            unbounded: vec![],
+            stale: 0,
+            parents: vec![],
+            cost: 0.0,
+            built_cost: 0.0,
+        }
+    }
+
+    // A world holding `objects`, with the hierarchy built over them.
+    pub fn with_objects(objects: HittableVec, quality: BvhQuality) -> EditableWorld {
+        let mut world = EditableWorld::new(quality);
+        for object in objects {
+            world.insert(Arc::from(object), false);
+        }
+        world.rebuild();
+        world
+    }
+
+    pub fn add(&mut self, object: Box<dyn Hittable>) -> ObjectId {
+        self.insert(Arc::from(object), false)
+    }
+
+    // Adds an object that is also sampled directly as a light; see `lights`.
+    pub fn add_light(&mut self, object: Box<dyn Hittable>) -> ObjectId {
+        self.insert(Arc::from(object), true)
+    }
+
+    pub fn remove(&mut self, id: ObjectId) -> Result<()> {
+        let Some(slot) = self.slots.get_mut(id.0).and_then(Option::take) else {
+            bail!("no object {:?}", id);
+        };
+        self.unfile(id.0, slot.in_tree);
+        if slot.in_tree {
+            self.refit(slot.leaf);
+        }
+        self.maybe_rebuild();
+        Ok(())
+    }
+
+    // Places the object as added by `to_world`, replacing where it was put
+    // before. `to_world` must be invertible.
+    pub fn set_transform(&mut self, id: ObjectId, to_world: Affine3A) -> Result<()> {
+        let determinant = to_world.matrix3.determinant();
+        if !(determinant.is_finite() && determinant != 0.0 && to_world.is_finite()) {
+            bail!("transforms must be invertible");
+        }
+        let Some(slot) = self.slots.get_mut(id.0).and_then(Option::as_mut) else {
+            bail!("no object {:?}", id);
+        };
+        slot.to_world = to_world;
+        slot.placed = place(&slot.object, to_world);
+        slot.bbox = slot.placed.bounding_box();
+        let bounded = slot.bbox.is_finite() || slot.bbox.is_empty();
+        let (in_tree, leaf) = (slot.in_tree, slot.leaf);
+        // Objects stay in the hierarchy while they have bounds.
+        if !(in_tree && bounded) {
+            slot.in_tree = false;
+            self.unfile(id.0, in_tree);
+            self.file(id.0);
+        }
+        if in_tree {
+            self.refit(leaf);
+        }
+        self.maybe_rebuild();
+        Ok(())
+    }
+
+    pub fn transform(&self, id: ObjectId) -> Option<Affine3A> {
+        Some(self.slots.get(id.0)?.as_ref()?.to_world)
+    }
+
+    // The object as placed in the world.
+    pub fn get(&self, id: ObjectId) -> Option<&dyn Hittable> {
+        Some(self.slots.get(id.0)?.as_ref()?.placed.as_ref())
+    }
+
+    // Copies of the lights where they are now, for `CameraBuilder::lights`.
+    // Cameras keep their own, so they need building again after lights are
+    // edited.
+    pub fn lights(&self) -> HittableVec {
+        self.slots
+            .iter()
+            .flatten()
+            .filter(|slot| slot.light)
+            .map(|slot| place(&slot.object, slot.to_world))
+            .collect()
+    }
+
+    // Builds the hierarchy afresh over all objects. Edits do this by
+    // themselves every so often; editors may also call it while idle.
+    pub fn rebuild(&mut self) {
+        let mut items = vec![];
+        self.unbounded.clear();
+        for (idx, slot) in self.slots.iter_mut().enumerate() {
+            let Some(slot) = slot else { continue };
+            slot.in_tree = slot.bbox.is_finite() || slot.bbox.is_empty();
+            if slot.in_tree {
+                items.push((slot.bbox, idx));
+            } else {
+                self.unbounded.push(idx);
+            }
+        }
+        self.nodes = build_nodes(&mut items, self.quality);
+        self.order = items.into_iter().map(|(_, idx)| idx).collect();
+        for (node, leaf) in self.nodes.iter().enumerate() {
+            if let BvhNode::Leaf { start, count, .. } = *leaf {
+                for &idx in &self.order[start as usize..(start + count) as usize] {
+                    self.slots[idx].as_mut().unwrap().leaf = node as u32;
+                }
+            }
+        }
+        self.parents = parents(&self.nodes);
+        self.cost = self.nodes.iter().map(|node| node.cost() as f64).sum();
+        self.built_cost = sah_cost(&self.nodes);
+        self.loose.clear();
+        self.stale = 0;
+    }
+
+    fn insert(&mut self, object: Arc<dyn Hittable>, light: bool) -> ObjectId {
+        let placed = place(&object, Affine3A::IDENTITY);
+        self.slots.push(Some(Slot {
+            bbox: placed.bounding_box(),
+            object,
+            to_world: Affine3A::IDENTITY,
+            light,
+            placed,
+            in_tree: false,
+            leaf: 0,
+        }));
+        let idx = self.slots.len() - 1;
+        self.file(idx);
+        self.maybe_rebuild();
+        ObjectId(idx)
+    }
+
+    // Puts an object the hierarchy doesn't have on the list it belongs on.
+    fn file(&mut self, idx: usize) {
+        match self.slots[idx].as_ref() {
+            Some(slot) if slot.bbox.is_finite() || slot.bbox.is_empty() => self.loose.push(idx),
+            _ => self.unbounded.push(idx),
+        }
+    }
+
+    // Takes an object off whichever list has it.
+    fn unfile(&mut self, idx: usize, in_tree: bool) {
+        if in_tree {
+            self.stale += 1;
+        } else {
+            self.loose.retain(|&other| other != idx);
+            self.unbounded.retain(|&other| other != idx);
+        }
+    }
+
+    // Fits the boxes of `leaf` and the nodes above it to the objects in the
+    // hierarchy now.
+    fn refit(&mut self, leaf: u32) {
+        let mut node = leaf as usize;
+        loop {
+            let bbox = match self.nodes[node] {
+                BvhNode::Leaf { start, count, .. } => self.order
+                    [start as usize..(start + count) as usize]
+                    .iter()
+                    .filter_map(|&idx| self.slots[idx].as_ref())
+                    .filter(|slot| slot.in_tree)
+                    .fold(Aabb::EMPTY, |acc, slot| acc.union(slot.bbox)),
+                BvhNode::Interior { right, .. } => {
+                    let right = self.nodes[right as usize].bbox();
+                    self.nodes[node + 1].bbox().union(*right)
+                }
+            };
+            self.cost -= self.nodes[node].cost() as f64;
+            *self.nodes[node].bbox_mut() = bbox;
+            self.cost += self.nodes[node].cost() as f64;
+            if node == 0 {
+                break;
+            }
+            node = self.parents[node] as usize;
+        }
+    }
+
+    // `sah_cost` of the hierarchy as it is now.
+    fn sah_cost(&self) -> f32 {
+        let root = self
+            .nodes
+            .first()
+            .map_or(0.0, |node| node.bbox().surface_area());
+        if !(root.is_finite() && root > 0.0) {
+            return 0.0;
+        }
+        (self.cost / root as f64) as f32
+    }
+
+    fn maybe_rebuild(&mut self) {
+        if self.loose.len() + self.stale > STALE_EDITS + self.order.len() / 8
+            || self.sah_cost() > BVH_REBUILD_COST * self.built_cost
+        {
+            self.rebuild();
+        }
+    }
+
+    // The closest hit within `ray_t` among the objects at `ids` that are in
+    // the hierarchy, or that aren't if `tree` is false.
+    fn hit_listed(&self, ids: &[usize], ray: &Ray, ray_t: Interval, tree: bool) -> Option<Hit<'_>> {
+        let mut closest_hit = None;
+        let mut closest_t = ray_t.max;
+        for &idx in ids {
+            let Some(slot) = &self.slots[idx] else {
+                continue;
+            };
+            if slot.in_tree != tree {
+                continue;
+            }
+            if let Some(hit) = slot.placed.hit(ray, Interval::new(ray_t.min, closest_t)) {
+                closest_t = hit.t;
+                closest_hit = Some(hit);
+            }
+        }
+        closest_hit
+    }
+}
+
+fn place(object: &Arc<dyn Hittable>, to_world: Affine3A) -> Box<dyn Hittable> {
+    if to_world == Affine3A::IDENTITY {
+        Box::new(object.clone())
+    } else {
+        Box::new(Transform::new(to_world, object.clone()))
+    }
+}
+
+impl Hittable for EditableWorld {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        // As in a Bvh, objects tested on every ray go first so their hits
+        // cull the traversal.
+        let mut closest_t = ray_t.max;
+        let mut closest_hit = None;
+        for ids in [&self.unbounded, &self.loose] {
+            if let Some(hit) = self.hit_listed(ids, ray, Interval::new(ray_t.min, closest_t), false)
+            {
+                closest_t = hit.t;
+                closest_hit = Some(hit);
+            }
+        }
+        let ray_t = Interval::new(ray_t.min, closest_t);
+        let tree = traverse(&self.nodes, ray, ray_t, |range, ray_t| {
+            self.hit_listed(&self.order[range], ray, ray_t, true)
+        });
+        tree.or(closest_hit)
+    }
+
+    // Boxes in the hierarchy don't shrink until it is rebuilt, so this may
+    // be larger than the objects now need.
+    fn bounding_box(&self) -> Aabb {
+        let root = self.nodes.first().map_or(Aabb::EMPTY, |node| *node.bbox());
+        self.loose
+            .iter()
+            .chain(&self.unbounded)
+            .filter_map(|&idx| self.slots[idx].as_ref())
+            .fold(root, |acc, slot| acc.union(slot.bbox))
+    }
+
+    fn bounded_box(&self) -> Aabb {
+        let root = self.nodes.first().map_or(Aabb::EMPTY, |node| *node.bbox());
+        self.loose
+            .iter()
+            .filter_map(|&idx| self.slots[idx].as_ref())
+            .fold(root, |acc, slot| acc.union(slot.bbox))
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.slots.capacity() * std::mem::size_of::<Option<Slot>>()
+            + self
+                .slots
+                .iter()
+                .flatten()
+                .map(|slot| slot.object.memory() + slot.placed.memory())
+                .sum::<usize>()
+            + self.nodes.capacity() * std::mem::size_of::<BvhNode>()
+            + (self.order.capacity() + self.loose.capacity() + self.unbounded.capacity())
+                * std::mem::size_of::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::{Plane, Sphere};
+    use crate::materials::Material;
+    use crate::{point3, Point3};
+    use glam::{vec3, Vec3};
+
+    fn sphere(center: Point3) -> Box<dyn Hittable> {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        Box::new(Sphere::new(center, 0.3, mat))
+    }
+
+    // Where rays down onto a grid over the scene first hit it.
+    fn heights(world: &dyn Hittable) -> Vec<Option<f32>> {
+        let mut heights = vec![];
+        for x in -20..20 {
+            for z in -20..20 {
+                let origin = point3(x as f32 / 4.0, 10.0, z as f32 / 4.0);
+                let ray = Ray::new(origin, -Vec3::Y);
+                let hit = world.hit(&ray, Interval::new(0.001, f32::INFINITY));
+                heights.push(hit.map(|hit| hit.p.y));
+            }
+        }
+        heights
+    }
+
+    fn assert_same(actual: Vec<Option<f32>>, expected: Vec<Option<f32>>) {
+        for (actual, expected) in actual.into_iter().zip(expected) {
+            match (actual, expected) {
+                (Some(a), Some(b)) => assert!((a - b).abs() < 1e-4, "{} != {}", a, b),
+                (a, b) => assert_eq!(a, b),
+            }
+        }
+    }
+
+    #[test]
+    fn edits_match_a_world_built_from_scratch() {
+        let mut world = EditableWorld::new(BvhQuality::default());
+        let mut ids = vec![];
+        for idx in 0..40 {
+            let x = (idx % 8) as f32 - 4.0;
+            let z = (idx / 8) as f32 - 2.0;
+            ids.push(world.add(sphere(point3(x, 0.0, z))));
+        }
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        world.add(Box::new(
+            Plane::new(point3(0.0, -1.0, 0.0), Vec3::Y, mat).unwrap(),
+        ));
+        // Every third sphere removed, and every fourth raised and shifted.
+        let mut expected: HittableVec = vec![Box::new(
+            Plane::new(
+                point3(0.0, -1.0, 0.0),
+                Vec3::Y,
+                Material::new_lambertian(1.0, 1.0, 1.0),
+            )
+            .unwrap(),
+        )];
+        for (idx, &id) in ids.iter().enumerate() {
+            let x = (idx % 8) as f32 - 4.0;
+            let z = (idx / 8) as f32 - 2.0;
+            if idx % 3 == 0 {
+                world.remove(id).unwrap();
+            } else if idx % 4 == 0 {
+                let offset = Vec3::new(0.5, 1.0 + idx as f32 / 10.0, 0.0);
+                world
+                    .set_transform(id, Affine3A::from_translation(offset))
+                    .unwrap();
+                expected.push(sphere(point3(x, 0.0, z) + offset));
+            } else {
+                expected.push(sphere(point3(x, 0.0, z)));
+            }
+        }
+        assert_same(heights(&world), heights(&expected));
+        // Edits piled up enough for a rebuild, which changes nothing.
+        assert!(world.loose.len() + world.stale <= STALE_EDITS + world.order.len() / 8);
+        world.rebuild();
+        assert_same(heights(&world), heights(&expected));
+
+        // Removed objects stay gone.
+        assert!(world.remove(ids[0]).is_err());
+        assert!(world.get(ids[0]).is_none());
+        assert!(world.get(ids[1]).is_some());
+    }
+
+    #[test]
+    fn moves_refit_the_hierarchy_until_it_degrades() {
+        let grid = |idx: usize| point3((idx % 8) as f32, 0.0, (idx / 8) as f32) - Vec3::splat(4.0);
+        let objects = (0..64).map(|idx| sphere(grid(idx))).collect();
+        let mut world = EditableWorld::with_objects(objects, BvhQuality::default());
+        let built = world.order.clone();
+        let ids: Vec<_> = (0..64).map(ObjectId).collect();
+
+        // Nudged objects stay in the hierarchy, which grows to cover them.
+        let nudge = vec3(0.2, 0.5, 0.1);
+        for &id in &ids[..10] {
+            world
+                .set_transform(id, Affine3A::from_translation(nudge))
+                .unwrap();
+        }
+        assert!(world.loose.is_empty() && world.stale == 0);
+        assert_eq!(world.order, built);
+        let mut expected: HittableVec = (0..64)
+            .map(|idx| match idx < 10 {
+                true => sphere(grid(idx) + nudge),
+                false => sphere(grid(idx)),
+            })
+            .collect();
+        assert_same(heights(&world), heights(&expected));
+
+        // Swapping them around ruins the grouping, until it's rebuilt.
+        for (idx, &id) in ids.iter().enumerate() {
+            let other = idx * 37 % 64;
+            let offset = grid(other) - grid(idx);
+            world
+                .set_transform(id, Affine3A::from_translation(offset))
+                .unwrap();
+            assert!(world.sah_cost() <= BVH_REBUILD_COST * world.built_cost);
+            expected[other] = sphere(grid(other));
+        }
+        assert_ne!(world.order, built);
+        assert_same(heights(&world), heights(&expected));
+
+        // Removing the back row shrinks the boxes around what's left.
+        for (idx, &id) in ids.iter().enumerate() {
+            if idx * 37 % 64 >= 56 {
+                world.remove(id).unwrap();
+            }
+        }
+        assert!(world.stale > 0);
+        assert!((world.bounded_box().max.z - 2.3).abs() < 1e-5);
+    }
+
+    #[test]
+    fn lights_follow_their_objects() {
+        let mut world = EditableWorld::new(BvhQuality::default());
+        world.add(sphere(Point3::ZERO));
+        let lamp = world.add_light(sphere(point3(0.0, 2.0, 0.0)));
+        assert_eq!(world.lights().len(), 1);
+        let raised = Affine3A::from_translation(Vec3::Y);
+        world.set_transform(lamp, raised).unwrap();
+        assert_eq!(world.transform(lamp), Some(raised));
+        let light = &world.lights()[0];
+        assert!((light.bounding_box().centroid() - point3(0.0, 3.0, 0.0)).length() < 1e-5);
+        assert!(world
+            .set_transform(lamp, Affine3A::from_scale(Vec3::ZERO))
+            .is_err());
+        world.remove(lamp).unwrap();
+        assert!(world.lights().is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+use crate::{exr, hdr, random, Color3};
+use anyhow::{Context, Result};
+use glam::{vec3, Quat, Vec3};
+use std::f32::consts::{PI, TAU};
+use std::path::Path;
+use std::sync::Arc;
+
+// The radiance arriving along rays that escape the scene.
+#[derive(Clone)]
+pub enum Environment {
+    Constant(Color3),
+    Map {
+        map: Arc<EnvironmentMap>,
+        // Turns the map's directions into the scene's.
+        rotation: Quat,
+        scale: f32,
+    },
+}
+
+impl Environment {
+    // A map turned by `azimuth` degrees about the vertical axis, which moves
+    // its center from -z towards -x, and brightened by `exposure` stops, so
+    // the lighting can be art-directed without editing the image.
+    pub fn map(map: Arc<EnvironmentMap>, azimuth: f32, exposure: f32) -> Self {
+        Environment::Map {
+            map,
+            rotation: Quat::from_rotation_y(azimuth.to_radians()),
+            scale: exposure.exp2(),
+        }
+    }
+
+    pub fn radiance(&self, dir: Vec3) -> Color3 {
+        match self {
+            Environment::Constant(color) => *color,
+            Environment::Map {
+                map,
+                rotation,
+                scale,
+            } => map.radiance(rotation.inverse() * dir) * *scale,
+        }
+    }
+
+    // A direction chosen in proportion to the map's brightness, along with
+    // its density over solid angle; None for constant backgrounds and black
+    // maps.
+    pub fn sample(&self) -> Option<(Vec3, f32)> {
+        match self {
+            Environment::Constant(_) => None,
+            Environment::Map { map, rotation, .. } => {
+                let (dir, pdf) = map.sample()?;
+                Some((*rotation * dir, pdf))
+            }
+        }
+    }
+
+    // The density `sample` picks `dir` with.
+    pub fn pdf(&self, dir: Vec3) -> f32 {
+        match self {
+            Environment::Constant(_) => 0.0,
+            Environment::Map { map, rotation, .. } => map.pdf(rotation.inverse() * dir),
+        }
+    }
+}
+
+// An equirectangular (latitude-longitude) image: up is the top row, and the
+// image center lies along -z with +x to its right.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color3>,
+    // Picks a row by its share of the map's brightness, then a pixel within
+    // the row. Each pixel is weighted by the solid angle it covers, which
+    // shrinks towards the poles, and by its average brightness once filtered,
+    // which blends in its neighbors.
+    rows: Distribution,
+    columns: Vec<Distribution>,
+}
+
+impl EnvironmentMap {
+    // Reads a Radiance .hdr or an OpenEXR file, told apart by signature.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes =
+            std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let (width, height, pixels) = if exr::is_exr(&bytes) {
+            exr::decode(&bytes)
+        } else {
+            hdr::decode(&bytes)
+        }
+        .with_context(|| format!("in {}", path.display()))?;
+        Ok(Self::new(width as usize, height as usize, pixels))
+    }
+
+    pub(crate) fn new(width: usize, height: usize, pixels: Vec<Color3>) -> Self {
+        // How much of each neighbor bilinear filtering averages into a pixel.
+        const FOOTPRINT: [(isize, f32); 3] = [(-1, 0.125), (0, 0.75), (1, 0.125)];
+        let filtered = |x: usize, y: usize| {
+            let mut sum = 0.0;
+            for (dy, wy) in FOOTPRINT {
+                let ny = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+                for (dx, wx) in FOOTPRINT {
+                    let nx = (x as isize + dx).rem_euclid(width as isize) as usize;
+                    sum += wy * wx * luminance(pixels[ny * width + nx]);
+                }
+            }
+            sum
+        };
+        let columns: Vec<_> = (0..height)
+            .map(|y| {
+                let sin_theta = ((y as f32 + 0.5) / height as f32 * PI).sin();
+                Distribution::new((0..width).map(|x| filtered(x, y) * sin_theta))
+            })
+            .collect();
+        let rows = Distribution::new(columns.iter().map(|row| row.total));
+        Self {
+            width,
+            height,
+            pixels,
+            rows,
+            columns,
+        }
+    }
+
+    // A copy blurred by about `degrees` in every direction, which softens
+    // the lighting and reflections of a detailed map. Box filters, first
+    // along each row over a width that grows towards the poles so it spans
+    // the same angle, then along each column.
+    pub fn blurred(&self, degrees: f32) -> Self {
+        let (width, height) = (self.width, self.height);
+        let radius = degrees.max(0.0).to_radians();
+        let mut rows = Vec::with_capacity(self.pixels.len());
+        for (y, row) in self.pixels.chunks(width).enumerate() {
+            let sin_theta = ((y as f32 + 0.5) / height as f32 * PI).sin();
+            let r = (radius / (TAU / width as f32) / sin_theta).round() as usize;
+            if 2 * r + 1 >= width {
+                let mean = row.iter().sum::<Color3>() / width as f32;
+                rows.extend(std::iter::repeat_n(mean, width));
+                continue;
+            }
+            // Running sums over the row twice over, for windows that wrap.
+            let mut sums = vec![Color3::ZERO];
+            for x in 0..2 * width {
+                sums.push(sums[x] + row[x % width]);
+            }
+            rows.extend((0..width).map(|x| {
+                let start = (x + width - r) % width;
+                (sums[start + 2 * r + 1] - sums[start]) / (2 * r + 1) as f32
+            }));
+        }
+        let r = (radius / (PI / height as f32)).round() as isize;
+        let mut pixels = Vec::with_capacity(rows.len());
+        for y in 0..height as isize {
+            for x in 0..width {
+                let sum: Color3 = (y - r..=y + r)
+                    .map(|ny| rows[ny.clamp(0, height as isize - 1) as usize * width + x])
+                    .sum();
+                pixels.push(sum / (2 * r + 1) as f32);
+            }
+        }
+        Self::new(width, height, pixels)
+    }
+
+    pub fn memory(&self) -> usize {
+        let cdfs: usize = self.columns.iter().map(|row| row.cdf.capacity()).sum();
+        std::mem::size_of::<Self>()
+            + self.pixels.capacity() * std::mem::size_of::<Color3>()
+            + (self.rows.cdf.capacity() + cdfs) * std::mem::size_of::<f32>()
+            + self.columns.capacity() * std::mem::size_of::<Distribution>()
+    }
+
+    // A direction chosen in proportion to the map's brightness, along with
+    // its density over solid angle; None for a black map.
+    pub fn sample(&self) -> Option<(Vec3, f32)> {
+        let y = self.rows.sample(random::f32())?;
+        let x = self.columns[y].sample(random::f32())?;
+        let u = (x as f32 + random::f32()) / self.width as f32;
+        let v = (y as f32 + random::f32()) / self.height as f32;
+        let (sin_phi, cos_phi) = ((u - 0.5) * TAU).sin_cos();
+        let (sin_theta, cos_theta) = (v * PI).sin_cos();
+        let dir = vec3(sin_theta * sin_phi, cos_theta, -sin_theta * cos_phi);
+        let pdf = self.pdf(dir);
+        (pdf > 0.0).then_some((dir, pdf))
+    }
+
+    // The density `sample` picks `dir` with.
+    pub fn pdf(&self, dir: Vec3) -> f32 {
+        let (u, v) = Self::uv(dir);
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        // Uniform within the pixel, which spans 2 PI / width by PI / height
+        // in longitude and latitude.
+        let pixel = self.rows.probability(y) * self.columns[y].probability(x);
+        pixel * (self.width * self.height) as f32 / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn uv(dir: Vec3) -> (f32, f32) {
+        let dir = dir.normalize();
+        let u = 0.5 + dir.x.atan2(-dir.z) / TAU;
+        let v = dir.y.clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    // Filtered bilinearly, wrapping around horizontally.
+    fn radiance(&self, dir: Vec3) -> Color3 {
+        let (u, v) = Self::uv(dir);
+        let x = u * self.width as f32 - 0.5;
+        let y = (v * self.height as f32 - 0.5).clamp(0.0, (self.height - 1) as f32);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let pixel = |dx: i32, dy: usize| {
+            let px = (x0 as i32 + dx).rem_euclid(self.width as i32) as usize;
+            let py = (y0 as usize + dy).min(self.height - 1);
+            self.pixels[py * self.width + px]
+        };
+        let top = pixel(0, 0).lerp(pixel(1, 0), fx);
+        let bottom = pixel(0, 1).lerp(pixel(1, 1), fx);
+        top.lerp(bottom, fy)
+    }
+}
+
+// A discrete distribution over the indices of a list of weights.
+struct Distribution {
+    // Running totals, normalized to end at one.
+    cdf: Vec<f32>,
+    total: f32,
+}
+
+impl Distribution {
+    fn new(weights: impl Iterator<Item = f32>) -> Self {
+        let mut total = 0.0;
+        let mut cdf: Vec<f32> = weights
+            .map(|weight| {
+                total += weight;
+                total
+            })
+            .collect();
+        if total > 0.0 {
+            cdf.iter_mut().for_each(|c| *c /= total);
+        }
+        Self { cdf, total }
+    }
+
+    // `u` is uniform in [0, 1); never picks an index with zero weight.
+    fn sample(&self, u: f32) -> Option<usize> {
+        if self.total <= 0.0 {
+            return None;
+        }
+        let idx = self.cdf.partition_point(|&c| c <= u);
+        Some(idx.min(self.cdf.len() - 1))
+    }
+
+    fn probability(&self, idx: usize) -> f32 {
+        if self.total <= 0.0 {
+            return 0.0;
+        }
+        self.cdf[idx] - if idx > 0 { self.cdf[idx - 1] } else { 0.0 }
+    }
+}
+
+fn luminance(c: Color3) -> f32 {
+    c.dot(vec3(0.2126, 0.7152, 0.0722))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color3;
+
+    #[test]
+    fn map_is_looked_up_by_direction() {
+        // Four columns over a black bottom row.
+        let colors = [Vec3::X, Vec3::Y, Vec3::Z, Vec3::ONE];
+        let mut pixels = colors.to_vec();
+        pixels.extend([Color3::ZERO; 4]);
+        let map = EnvironmentMap::new(4, 2, pixels);
+        // 45 degrees up lands on the centers of the top row's pixels; the
+        // cardinal directions lie between columns.
+        let s = 0.5f32.sqrt();
+        let at = |x, z| map.radiance(Vec3::new(x, s, z));
+        assert!(at(0.0, -s).distance(color3(0.0, 0.5, 0.5)) < 1e-5);
+        assert!(at(s, 0.0).distance(color3(0.5, 0.5, 1.0)) < 1e-5);
+        assert!(at(0.0, s).distance(color3(1.0, 0.5, 0.5)) < 1e-5);
+        assert!(at(-0.5, 0.5).distance(colors[0]) < 1e-5);
+        // The horizon is halfway to the bottom row, which covers straight
+        // down.
+        assert!(map.radiance(-Vec3::Z).distance(color3(0.0, 0.25, 0.25)) < 1e-5);
+        assert_eq!(map.radiance(-Vec3::Y), Color3::ZERO);
+    }
+
+    #[test]
+    fn sampling_follows_brightness() {
+        // A dim sky with one pixel a thousand times brighter.
+        let mut pixels = vec![Color3::ONE; 8 * 4];
+        pixels[8 + 5] = Color3::splat(1000.0);
+        let map = EnvironmentMap::new(8, 4, pixels);
+
+        // The density integrates to one over the sphere.
+        let (n_theta, n_phi) = (200, 400);
+        let mut total = 0.0;
+        for i in 0..n_theta {
+            let theta = (i as f32 + 0.5) / n_theta as f32 * PI;
+            for j in 0..n_phi {
+                let phi = (j as f32 + 0.5) / n_phi as f32 * TAU;
+                let dir = vec3(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                total += map.pdf(dir) * theta.sin() * (PI / n_theta as f32) * (TAU / n_phi as f32);
+            }
+        }
+        assert!((total - 1.0).abs() < 1e-2, "{}", total);
+
+        // Nearly every sample lands on or next to the bright pixel, with the
+        // density pdf reports.
+        random::seed_sample(0, 0, 0, 0);
+        let bright = (0..1000)
+            .filter(|_| {
+                let (dir, pdf) = map.sample().unwrap();
+                assert!((pdf - map.pdf(dir)).abs() <= 1e-4 * pdf);
+                let (u, v) = EnvironmentMap::uv(dir);
+                (4..=6).contains(&((u * 8.0) as usize)) && (v * 4.0) < 3.0
+            })
+            .count();
+        assert!(bright > 950, "{}", bright);
+
+        let black = EnvironmentMap::new(2, 2, vec![Color3::ZERO; 4]);
+        assert!(black.sample().is_none());
+        assert_eq!(black.pdf(Vec3::Y), 0.0);
+    }
+
+    #[test]
+    fn maps_turn_and_brighten() {
+        // Red from the left through ahead, along -z, to the right; green from
+        // the right to behind.
+        let mut pixels = vec![Color3::ZERO; 4 * 2];
+        pixels[1] = Vec3::X;
+        pixels[2] = Vec3::X;
+        pixels[3] = Vec3::Y;
+        let map = Arc::new(EnvironmentMap::new(4, 2, pixels));
+        let s = 0.5f32.sqrt();
+        let ahead = vec3(0.0, s, -s);
+        let right = vec3(s, s, 0.0);
+        let left = vec3(-s, s, 0.0);
+
+        let plain = Environment::map(map.clone(), 0.0, 0.0);
+        assert!(plain.radiance(right).distance(map.radiance(right)) < 1e-6);
+        // A quarter turn moves what was ahead to the left, and what was to
+        // the right ahead.
+        let turned = Environment::map(map.clone(), 90.0, 1.0);
+        assert!(turned.radiance(left).distance(2.0 * map.radiance(ahead)) < 1e-5);
+        assert!(turned.radiance(ahead).distance(2.0 * map.radiance(right)) < 1e-5);
+
+        // Samples turn with the map, at the same density.
+        let back = Quat::from_rotation_y(-90f32.to_radians());
+        random::seed_sample(0, 0, 0, 0);
+        for _ in 0..100 {
+            let (dir, pdf) = turned.sample().unwrap();
+            assert!((pdf - turned.pdf(dir)).abs() <= 1e-4 * pdf);
+            assert!((pdf - plain.pdf(back * dir)).abs() <= 1e-4 * pdf);
+        }
+        assert!(Environment::Constant(Color3::ONE).sample().is_none());
+    }
+
+    #[test]
+    fn blurring_spreads_light_over_the_angle() {
+        // One bright pixel on the equator of a dark 10-degree grid.
+        let (width, height) = (36, 18);
+        let mut pixels = vec![Color3::ZERO; width * height];
+        pixels[9 * width + 18] = Color3::splat(100.0);
+        let map = EnvironmentMap::new(width, height, pixels);
+
+        let unblurred = map.blurred(0.0);
+        assert!(unblurred.pixels == map.pixels);
+
+        // 10 degrees reaches one pixel to each side on the equator.
+        let blurred = map.blurred(10.0);
+        let at = |x: usize, y: usize| blurred.pixels[y * width + x].x;
+        assert!((at(18, 9) - 100.0 / 9.0).abs() < 1e-4, "{}", at(18, 9));
+        assert_eq!(at(17, 8), at(19, 10));
+        assert_eq!(at(20, 9), 0.0);
+        assert_eq!(at(18, 11), 0.0);
+        // Away from the poles, nothing is lost.
+        let total: f32 = blurred.pixels.iter().map(|p| p.x).sum();
+        assert!((total - 100.0).abs() < 1e-3, "{}", total);
+        // The sampling distribution follows the blurred pixels.
+        let dir = vec3(0.0, 0.0, -1.0);
+        assert!(blurred.pdf(dir) < map.pdf(dir));
+        assert!(blurred.pdf(vec3(0.2, 0.2, -1.0)) > 0.0);
+
+        // A uniform map stays as it was, whatever the blur.
+        let sky = EnvironmentMap::new(8, 4, vec![Color3::ONE; 32]).blurred(60.0);
+        assert!(sky
+            .pixels
+            .iter()
+            .all(|p| (*p - Color3::ONE).length() < 1e-5));
+    }
+}
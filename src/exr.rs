@@ -0,0 +1,364 @@
+use crate::Color3;
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::Read;
+
+const MAGIC: [u8; 4] = [0x76, 0x2F, 0x31, 0x01];
+
+// Compression methods, as stored in the header.
+const NO_COMPRESSION: u8 = 0;
+const RLE_COMPRESSION: u8 = 1;
+const ZIPS_COMPRESSION: u8 = 2;
+const ZIP_COMPRESSION: u8 = 3;
+
+pub fn is_exr(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+// Decodes a single-part scanline OpenEXR image into linear colors, rows from
+// the top. Reads R, G and B channels, or Y for grayscale; the rest are
+// skipped. Only uncompressed, RLE and ZIP compressed files are supported.
+pub fn decode(bytes: &[u8]) -> Result<(u32, u32, Vec<Color3>)> {
+    if !is_exr(bytes) {
+        bail!("not an OpenEXR file");
+    }
+    let mut reader = Reader { bytes, pos: 4 };
+    let flags = reader.u32()?;
+    if flags & 0x200 != 0 {
+        bail!("tiled OpenEXR images are not supported");
+    }
+    if flags & 0x1800 != 0 {
+        bail!("multi-part and deep OpenEXR images are not supported");
+    }
+
+    let mut channels = vec![];
+    let mut compression = None;
+    let mut window = None;
+    loop {
+        let name = reader.string()?;
+        if name.is_empty() {
+            break;
+        }
+        let _kind = reader.string()?;
+        let size = reader.u32()? as usize;
+        let mut value = Reader {
+            bytes: reader.take(size)?,
+            pos: 0,
+        };
+        match name {
+            "channels" => loop {
+                let name = value.string()?;
+                if name.is_empty() {
+                    break;
+                }
+                let kind = match value.u32()? {
+                    1 => Sample::Half,
+                    2 => Sample::Float,
+                    0 => Sample::Uint,
+                    other => bail!("unknown channel type {}", other),
+                };
+                value.take(4)?;
+                if (value.u32()?, value.u32()?) != (1, 1) {
+                    bail!("subsampled channels are not supported");
+                }
+                channels.push((name.to_string(), kind));
+            },
+            "compression" => compression = Some(value.take(1)?[0]),
+            "dataWindow" => {
+                let [x_min, y_min, x_max, y_max] = [(); 4].map(|_| value.u32().map(|v| v as i32));
+                window = Some((x_min?, y_min?, x_max?, y_max?));
+            }
+            _ => {}
+        }
+    }
+    let (x_min, y_min, x_max, y_max) = window.ok_or_else(|| anyhow!("missing dataWindow"))?;
+    let width = (x_max - x_min + 1).max(0) as usize;
+    let height = (y_max - y_min + 1).max(0) as usize;
+    if width == 0 || height == 0 {
+        bail!("image has no pixels");
+    }
+    let lines_per_chunk = match compression.ok_or_else(|| anyhow!("missing compression"))? {
+        NO_COMPRESSION | RLE_COMPRESSION | ZIPS_COMPRESSION => 1,
+        ZIP_COMPRESSION => 16,
+        other => bail!("unsupported OpenEXR compression {}", other),
+    };
+    let compression = compression.unwrap();
+
+    // Channels are stored in the header's (alphabetical) order; each maps to
+    // the color components it feeds.
+    let targets: Vec<&[usize]> = channels
+        .iter()
+        .map(|(name, _)| match name.as_str() {
+            "R" => &[0][..],
+            "G" => &[1][..],
+            "B" => &[2][..],
+            "Y" if !channels.iter().any(|(name, _)| name == "G") => &[0, 1, 2][..],
+            _ => &[][..],
+        })
+        .collect();
+    if targets.iter().all(|t| t.is_empty()) {
+        bail!("no R, G, B or Y channels");
+    }
+    let line_size: usize = channels.iter().map(|(_, kind)| kind.size() * width).sum();
+
+    let mut pixels = vec![Color3::ZERO; width * height];
+    let chunks = height.div_ceil(lines_per_chunk);
+    for _ in 0..chunks {
+        let offset = reader.u64()? as usize;
+        let mut chunk = Reader { bytes, pos: offset };
+        let y = chunk.u32()? as i32 - y_min;
+        let size = chunk.u32()? as usize;
+        let lines = lines_per_chunk.min(height.saturating_sub(y.max(0) as usize));
+        if y < 0 || lines == 0 {
+            bail!("chunk outside the data window");
+        }
+        let packed = chunk.take(size)?;
+        let expected = line_size * lines;
+        let data = if size == expected {
+            packed.to_vec()
+        } else {
+            match compression {
+                RLE_COMPRESSION => predicted(unrun(packed, expected)?),
+                ZIPS_COMPRESSION | ZIP_COMPRESSION => predicted(inflate(packed, expected)?),
+                _ => bail!("chunk has the wrong size"),
+            }
+        };
+        if data.len() != expected {
+            bail!("chunk has the wrong size");
+        }
+        let mut data = Reader {
+            bytes: &data,
+            pos: 0,
+        };
+        for row in y as usize..y as usize + lines {
+            for ((_, kind), target) in channels.iter().zip(&targets) {
+                for pixel in &mut pixels[row * width..(row + 1) * width] {
+                    let value = kind.read(&mut data)?;
+                    for &component in *target {
+                        pixel[component] = value;
+                    }
+                }
+            }
+        }
+    }
+    Ok((width as u32, height as u32, pixels))
+}
+
+#[derive(Copy, Clone)]
+enum Sample {
+    Uint,
+    Half,
+    Float,
+}
+
+impl Sample {
+    fn size(self) -> usize {
+        match self {
+            Sample::Half => 2,
+            Sample::Uint | Sample::Float => 4,
+        }
+    }
+
+    fn read(self, reader: &mut Reader) -> Result<f32> {
+        Ok(match self {
+            Sample::Uint => reader.u32()? as f32,
+            Sample::Half => half_to_f32(u16::from_le_bytes(reader.take(2)?.try_into()?)),
+            Sample::Float => f32::from_bits(reader.u32()?),
+        })
+    }
+}
+
+fn half_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = (half >> 10 & 0x1F) as i32;
+    let mantissa = (half & 0x3FF) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        31 if mantissa == 0.0 => f32::INFINITY,
+        31 => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+// Undoes OpenEXR's run-length encoding: a negative count is followed by that
+// many literal bytes, any other by one byte repeated count + 1 times.
+fn unrun(packed: &[u8], expected: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(expected);
+    let mut rest = packed;
+    while let [count, ref tail @ ..] = *rest {
+        let count = count as i8;
+        if count < 0 {
+            let len = -(count as i32) as usize;
+            out.extend(tail.get(..len).ok_or_else(|| anyhow!("truncated run"))?);
+            rest = &tail[len..];
+        } else {
+            let value = *tail.first().ok_or_else(|| anyhow!("truncated run"))?;
+            out.extend(std::iter::repeat_n(value, count as usize + 1));
+            rest = &tail[1..];
+        }
+    }
+    Ok(out)
+}
+
+fn inflate(packed: &[u8], expected: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(expected);
+    flate2::read::ZlibDecoder::new(packed)
+        .read_to_end(&mut out)
+        .context("invalid ZIP data")?;
+    Ok(out)
+}
+
+// Compressed data is stored as byte deltas, with the even and odd bytes of
+// the original split into two halves.
+fn predicted(mut data: Vec<u8>) -> Vec<u8> {
+    for idx in 1..data.len() {
+        data[idx] = data[idx - 1].wrapping_add(data[idx]).wrapping_sub(128);
+    }
+    let (first, second) = data.split_at(data.len().div_ceil(2));
+    let mut out = Vec::with_capacity(data.len());
+    for idx in 0..data.len() {
+        out.push(if idx % 2 == 0 {
+            first[idx / 2]
+        } else {
+            second[idx / 2]
+        });
+    }
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let slice = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("unexpected end of file"))?;
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn string(&mut self) -> Result<&'a str> {
+        let len = self.bytes[self.pos..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| anyhow!("unterminated string"))?;
+        let text = std::str::from_utf8(self.take(len)?)?;
+        self.pos += 1;
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color3;
+    use std::io::Write;
+
+    // A 2x2 image with half float B, G and R channels, written with the
+    // given compression.
+    fn image(compression: u8, deflate: bool) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(2u32.to_le_bytes());
+        let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+            bytes.extend(name.bytes().chain([0]));
+            bytes.extend(kind.bytes().chain([0]));
+            bytes.extend((value.len() as u32).to_le_bytes());
+            bytes.extend(value);
+        };
+        let mut channels = vec![];
+        for name in ["B", "G", "R"] {
+            channels.extend(name.bytes().chain([0]));
+            channels.extend(1u32.to_le_bytes());
+            channels.extend([0; 4]);
+            channels.extend(1u32.to_le_bytes());
+            channels.extend(1u32.to_le_bytes());
+        }
+        channels.push(0);
+        attribute("channels", "chlist", &channels);
+        attribute("compression", "compression", &[compression]);
+        let window: Vec<u8> = [0u32, 0, 1, 1]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        attribute("dataWindow", "box2i", &window);
+        attribute("displayWindow", "box2i", &window);
+        bytes.push(0);
+
+        // Halves: 0, 0.5, 1 and 2.
+        let rows: [[[u16; 2]; 3]; 2] = [
+            [[0x0000, 0x3800], [0x3C00, 0x3C00], [0x4000, 0x0000]],
+            [[0x3800, 0x3800], [0x0000, 0x3C00], [0x3C00, 0x4000]],
+        ];
+        let lines: Vec<Vec<u8>> = rows
+            .iter()
+            .map(|row| row.iter().flatten().flat_map(|v| v.to_le_bytes()).collect())
+            .collect();
+        let lines_per_chunk = if compression == ZIP_COMPRESSION { 2 } else { 1 };
+        let chunks: Vec<Vec<u8>> = lines.chunks(lines_per_chunk).map(|c| c.concat()).collect();
+        let mut offset = bytes.len() + 8 * chunks.len();
+        let mut table = vec![];
+        let mut data = vec![];
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let packed = if deflate {
+                // Splits and delta encodes, the reverse of `predicted`.
+                let mut split: Vec<u8> = chunk.iter().step_by(2).copied().collect();
+                split.extend(chunk.iter().skip(1).step_by(2));
+                let mut deltas = split.clone();
+                for idx in 1..split.len() {
+                    deltas[idx] = split[idx].wrapping_sub(split[idx - 1]).wrapping_add(128);
+                }
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(&deltas).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                chunk.clone()
+            };
+            table.extend((offset as u64).to_le_bytes());
+            data.extend(((idx * lines_per_chunk) as u32).to_le_bytes());
+            data.extend((packed.len() as u32).to_le_bytes());
+            data.extend(&packed);
+            offset += 8 + packed.len();
+        }
+        bytes.extend(table);
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn reads_uncompressed_and_zip() {
+        let expected = [
+            color3(2.0, 1.0, 0.0),
+            color3(0.0, 1.0, 0.5),
+            color3(1.0, 0.0, 0.5),
+            color3(2.0, 1.0, 0.5),
+        ];
+        for (compression, deflate) in [
+            (NO_COMPRESSION, false),
+            (ZIPS_COMPRESSION, true),
+            (ZIP_COMPRESSION, true),
+        ] {
+            let (width, height, pixels) = decode(&image(compression, deflate)).unwrap();
+            assert_eq!((width, height), (2, 2));
+            assert_eq!(pixels, expected, "compression {}", compression);
+        }
+        assert!(decode(&image(4, false)).is_err());
+    }
+
+    #[test]
+    fn undoes_runs() {
+        assert_eq!(unrun(&[2, 7, 0xFE, 1, 2], 5).unwrap(), [7, 7, 7, 1, 2]);
+        assert!(unrun(&[0xFD, 1], 3).is_err());
+    }
+}
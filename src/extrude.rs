@@ -0,0 +1,332 @@
+use crate::hittables::Mesh;
+use crate::materials::Material;
+use anyhow::{bail, Result};
+use glam::{Vec2, Vec3};
+
+// Extrudes closed 2D outlines in the xy plane into a prism from z = 0 to
+// z = `depth`. Contours may be nested in any orientation: those inside an
+// odd number of others are holes, as in fonts.
+pub fn extrude(contours: &[Vec<Vec2>], depth: f32, material: Material) -> Result<Mesh> {
+    let mut contours: Vec<Vec<Vec2>> = contours
+        .iter()
+        .map(|contour| dedup(contour))
+        .filter(|contour| contour.len() >= 3 && signed_area(contour) != 0.0)
+        .collect();
+    if contours.is_empty() {
+        bail!("nothing to extrude");
+    }
+    let shapes = orient(&mut contours);
+
+    // Every contour point appears twice, on the front and on the back.
+    let mut positions = vec![];
+    let mut first = vec![];
+    for contour in &contours {
+        first.push(positions.len() as u32);
+        positions.extend(contour.iter().map(|p| p.extend(0.0)));
+    }
+    let back = positions.len() as u32;
+    positions.extend(positions.clone().iter().map(|p| Vec3::new(p.x, p.y, depth)));
+
+    let mut faces = vec![];
+    for (outer, holes) in shapes {
+        let polygon = bridge(&contours, &first, outer, &holes);
+        let points: Vec<Vec2> = polygon
+            .iter()
+            .map(|&idx| positions[idx as usize].truncate())
+            .collect();
+        for [a, b, c] in ear_clip(&points) {
+            let [a, b, c] = [a, b, c].map(|k| polygon[k]);
+            // Both caps face outwards: the front towards -z.
+            faces.push([a, c, b]);
+            faces.push([a + back, b + back, c + back]);
+        }
+    }
+    for (contour, &start) in contours.iter().zip(&first) {
+        let n = contour.len() as u32;
+        for k in 0..n {
+            let (a, b) = (start + k, start + (k + 1) % n);
+            faces.push([a, b, b + back]);
+            faces.push([a, b + back, a + back]);
+        }
+    }
+    Mesh::new(positions, faces, vec![material], vec![])
+}
+
+fn dedup(contour: &[Vec2]) -> Vec<Vec2> {
+    let mut points: Vec<Vec2> = vec![];
+    for &p in contour {
+        if points.last() != Some(&p) {
+            points.push(p);
+        }
+    }
+    while points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    points
+}
+
+// Positive for counter-clockwise contours.
+fn signed_area(contour: &[Vec2]) -> f32 {
+    let n = contour.len();
+    (0..n)
+        .map(|k| contour[k].perp_dot(contour[(k + 1) % n]))
+        .sum::<f32>()
+        / 2.0
+}
+
+fn contains(contour: &[Vec2], p: Vec2) -> bool {
+    let n = contour.len();
+    let mut inside = false;
+    for k in 0..n {
+        let (a, b) = (contour[k], contour[(k + 1) % n]);
+        if (a.y > p.y) != (b.y > p.y) && p.x < a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+// Pairs every outer contour with the holes directly inside it, by how many
+// contours each one is inside of.
+fn nest(contours: &[Vec<Vec2>]) -> Vec<(usize, Vec<usize>)> {
+    let parents: Vec<Vec<usize>> = (0..contours.len())
+        .map(|idx| {
+            (0..contours.len())
+                .filter(|&other| other != idx && contains(&contours[other], contours[idx][0]))
+                .collect()
+        })
+        .collect();
+    let mut shapes: Vec<(usize, Vec<usize>)> = (0..contours.len())
+        .filter(|&idx| parents[idx].len().is_multiple_of(2))
+        .map(|idx| (idx, vec![]))
+        .collect();
+    for (idx, parents_of) in parents.iter().enumerate() {
+        if !parents_of.len().is_multiple_of(2) {
+            // The innermost enclosing contour is the one with most parents.
+            let outer = parents_of
+                .iter()
+                .copied()
+                .find(|&p| parents[p].len() == parents_of.len() - 1);
+            if let Some(shape) = shapes.iter_mut().find(|(o, _)| Some(*o) == outer) {
+                shape.1.push(idx);
+            }
+        }
+    }
+    shapes
+}
+
+// Winds outer contours counter-clockwise and holes clockwise, so the
+// solid is always on the left, and returns them as from `nest`.
+fn orient(contours: &mut [Vec<Vec2>]) -> Vec<(usize, Vec<usize>)> {
+    let shapes = nest(contours);
+    for (outer, holes) in &shapes {
+        for (idx, ccw) in std::iter::once((*outer, true)).chain(holes.iter().map(|&h| (h, false))) {
+            if (signed_area(&contours[idx]) > 0.0) != ccw {
+                contours[idx].reverse();
+            }
+        }
+    }
+    shapes
+}
+
+// Joins the holes into the outer contour with zero-width cuts, giving a
+// single counter-clockwise polygon of vertex indices to triangulate.
+// Contours must be oriented already.
+fn bridge(contours: &[Vec<Vec2>], first: &[u32], outer: usize, holes: &[usize]) -> Vec<u32> {
+    let indices = |contour: usize| -> Vec<u32> {
+        let n = contours[contour].len() as u32;
+        (first[contour]..first[contour] + n).collect()
+    };
+    let point = |idx: u32| {
+        let contour = first.partition_point(|&f| f <= idx) - 1;
+        contours[contour][(idx - first[contour]) as usize]
+    };
+
+    let mut polygon = indices(outer);
+    // Holes furthest to the right first, so earlier cuts don't block later
+    // ones.
+    let mut holes: Vec<Vec<u32>> = holes.iter().map(|&hole| indices(hole)).collect();
+    let max_x = |hole: &Vec<u32>| hole.iter().map(|&i| point(i).x).fold(f32::MIN, f32::max);
+    holes.sort_by(|a, b| max_x(b).total_cmp(&max_x(a)));
+    for (idx, hole) in holes.iter().enumerate() {
+        let h = (0..hole.len())
+            .max_by(|&a, &b| point(hole[a]).x.total_cmp(&point(hole[b]).x))
+            .unwrap();
+        let from = point(hole[h]);
+        // The nearest vertex the cut can reach without crossing an edge of
+        // the polygon or of the holes still to come.
+        let mut edges = vec![];
+        for contour in std::iter::once(&polygon).chain(&holes[idx..]) {
+            for k in 0..contour.len() {
+                edges.push((point(contour[k]), point(contour[(k + 1) % contour.len()])));
+            }
+        }
+        let mut candidates: Vec<usize> = (0..polygon.len()).collect();
+        candidates.sort_by(|&a, &b| {
+            let distance = |k: usize| point(polygon[k]).distance_squared(from);
+            distance(a).total_cmp(&distance(b))
+        });
+        let target = candidates
+            .iter()
+            .copied()
+            .find(|&k| {
+                let to = point(polygon[k]);
+                edges.iter().all(|&(a, b)| !crosses(from, to, a, b))
+            })
+            .unwrap_or(candidates[0]);
+
+        let mut cut: Vec<u32> = hole[h..].iter().chain(&hole[..=h]).copied().collect();
+        cut.push(polygon[target]);
+        polygon.splice(target + 1..target + 1, cut);
+    }
+    polygon
+}
+
+// Whether segments pq and ab cross at a point inside both.
+fn crosses(p: Vec2, q: Vec2, a: Vec2, b: Vec2) -> bool {
+    if p == a || p == b || q == a || q == b {
+        return false;
+    }
+    let side = |o: Vec2, d: Vec2, x: Vec2| (d - o).perp_dot(x - o);
+    let (d1, d2) = (side(p, q, a), side(p, q, b));
+    let (d3, d4) = (side(a, b, p), side(a, b, q));
+    d1 * d2 < 0.0 && d3 * d4 < 0.0
+}
+
+// Triangulates a counter-clockwise simple polygon, which may touch itself
+// at the cuts made by `bridge`, by clipping ears.
+fn ear_clip(points: &[Vec2]) -> Vec<[usize; 3]> {
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+    let mut triangles = vec![];
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let corner = |k: usize| {
+            [
+                remaining[(k + n - 1) % n],
+                remaining[k],
+                remaining[(k + 1) % n],
+            ]
+        };
+        let is_ear = |k: usize| {
+            let [a, b, c] = corner(k).map(|i| points[i]);
+            if (b - a).perp_dot(c - b) <= 0.0 {
+                return false;
+            }
+            remaining.iter().all(|&i| {
+                let p = points[i];
+                p == a || p == b || p == c || !in_triangle(p, a, b, c)
+            })
+        };
+        // Degenerate input may have no ear left; dropping a vertex then
+        // keeps the loop finite at the cost of a small gap.
+        let k = (0..n).find(|&k| is_ear(k)).unwrap_or(0);
+        let [a, b, c] = corner(k);
+        if (points[b] - points[a]).perp_dot(points[c] - points[b]) > 0.0 {
+            triangles.push([a, b, c]);
+        }
+        remaining.remove(k);
+    }
+    if let [a, b, c] = remaining[..] {
+        if (points[b] - points[a]).perp_dot(points[c] - points[b]) > 0.0 {
+            triangles.push([a, b, c]);
+        }
+    }
+    triangles
+}
+
+// Including the edges, so vertices touching an ear's edge block it.
+fn in_triangle(p: Vec2, a: Vec2, b: Vec2, c: Vec2) -> bool {
+    let d1 = (b - a).perp_dot(p - a);
+    let d2 = (c - b).perp_dot(p - b);
+    let d3 = (a - c).perp_dot(p - c);
+    d1 >= 0.0 && d2 >= 0.0 && d3 >= 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::{Hittable, Interval};
+    use crate::point3;
+    use crate::render::Ray;
+    use glam::vec2;
+
+    fn square(min: f32, max: f32) -> Vec<Vec2> {
+        vec![
+            vec2(min, min),
+            vec2(max, min),
+            vec2(max, max),
+            vec2(min, max),
+        ]
+    }
+
+    fn cap_area(mut contours: Vec<Vec<Vec2>>) -> f32 {
+        let contours = &mut contours;
+        let mut area = 0.0;
+        for (outer, holes) in orient(contours) {
+            let first: Vec<u32> = contours
+                .iter()
+                .scan(0, |count, c| {
+                    let start = *count;
+                    *count += c.len() as u32;
+                    Some(start)
+                })
+                .collect();
+            let polygon = bridge(contours, &first, outer, &holes);
+            let all: Vec<Vec2> = contours.iter().flatten().copied().collect();
+            let points: Vec<Vec2> = polygon.iter().map(|&i| all[i as usize]).collect();
+            for [a, b, c] in ear_clip(&points) {
+                area += (points[b] - points[a]).perp_dot(points[c] - points[a]) / 2.0;
+            }
+        }
+        area
+    }
+
+    #[test]
+    fn triangulates_holes_and_islands() {
+        // A frame with an island inside its hole, wound every which way.
+        let mut hole = square(1.0, 3.0);
+        hole.reverse();
+        let contours = vec![square(0.0, 4.0), hole, square(1.5, 2.5)];
+        assert!((cap_area(contours) - (16.0 - 4.0 + 1.0)).abs() < 1e-4);
+
+        // An L shape, which is not convex.
+        let l = vec![
+            vec2(0.0, 0.0),
+            vec2(2.0, 0.0),
+            vec2(2.0, 1.0),
+            vec2(1.0, 1.0),
+            vec2(1.0, 2.0),
+            vec2(0.0, 2.0),
+        ];
+        assert!((cap_area(vec![l]) - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn extrudes_closed_prism() {
+        let mut hole = square(1.0, 3.0);
+        hole.reverse();
+        let mesh = extrude(
+            &[square(0.0, 4.0), hole],
+            0.5,
+            Material::new_lambertian(0.5, 0.5, 0.5),
+        )
+        .unwrap();
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let hit = |origin, dir| mesh.hit(&Ray::new(origin, dir), ray_t);
+
+        let front = hit(point3(0.5, 0.5, -1.0), Vec3::Z).unwrap();
+        assert!((front.t - 1.0).abs() < 1e-5 && front.front_face);
+        let back = hit(point3(0.5, 0.5, 2.0), -Vec3::Z).unwrap();
+        assert!((back.t - 1.5).abs() < 1e-5 && back.front_face);
+        assert!(hit(point3(2.0, 2.0, -1.0), Vec3::Z).is_none());
+        // The inner wall of the hole, seen from inside it.
+        let wall = hit(point3(2.0, 2.0, 0.25), Vec3::X).unwrap();
+        assert!((wall.t - 1.0).abs() < 1e-5 && wall.front_face);
+        assert!(extrude(
+            &[vec![Vec2::ZERO, Vec2::X]],
+            1.0,
+            Material::new_dielectric(1.5)
+        )
+        .is_err());
+    }
+}
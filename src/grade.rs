@@ -0,0 +1,175 @@
+use crate::Color3;
+use anyhow::{bail, Context, Result};
+use glam::{vec3, Vec3};
+
+// Color grading of the final image. Exposure and white balance scale linear
+// radiance before tone mapping; everything else works on display values in [0, 1] after it,
+// in the order lift/gain, gamma, saturation, contrast.
+#[derive(Copy, Clone)]
+pub struct Grade {
+    exposure: f32,
+    white_balance: Vec3,
+    lift: Vec3,
+    gamma: Vec3,
+    gain: Vec3,
+    saturation: f32,
+    contrast: f32,
+}
+
+const LUMINANCE: Vec3 = Vec3::new(0.2126, 0.7152, 0.0722);
+
+impl Default for Grade {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            white_balance: Vec3::ONE,
+            lift: Vec3::ZERO,
+            gamma: Vec3::ONE,
+            gain: Vec3::ONE,
+            saturation: 1.0,
+            contrast: 1.0,
+        }
+    }
+}
+
+impl Grade {
+    // Exposure in stops.
+    pub fn exposure(mut self, stops: f32) -> Self {
+        self.exposure = stops;
+        self
+    }
+
+    // Per-channel linear multipliers.
+    pub fn white_balance(mut self, white_balance: Vec3) -> Self {
+        self.white_balance = white_balance;
+        self
+    }
+
+    // Sets exposure and white balance so a surface whose mean linear color
+    // is `card` comes out as a neutral 18% gray.
+    pub fn calibrate(self, card: Color3) -> Result<Self> {
+        const MIDDLE_GRAY: f32 = 0.18;
+        if card.min_element() <= 0.0 {
+            bail!("the gray card is black in at least one channel");
+        }
+        let luminance = card.dot(LUMINANCE);
+        Ok(self
+            .exposure((MIDDLE_GRAY / luminance).log2())
+            .white_balance(Vec3::splat(luminance) / card))
+    }
+
+    pub fn lift(mut self, lift: Vec3) -> Self {
+        self.lift = lift;
+        self
+    }
+
+    pub fn gamma(mut self, gamma: Vec3) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    pub fn gain(mut self, gain: Vec3) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    pub fn saturation(mut self, saturation: f32) -> Self {
+        self.saturation = saturation;
+        self
+    }
+
+    pub fn contrast(mut self, contrast: f32) -> Self {
+        self.contrast = contrast;
+        self
+    }
+
+    // Per-channel values are either one number for all channels or "r,g,b".
+    pub fn setting(self, key: &str, value: &str) -> Result<Self> {
+        let invalid = || format!("invalid value '{}' for {}", value, key);
+        let number = || -> Result<f32> { value.parse().ok().with_context(invalid) };
+        let channels = || -> Result<Vec3> {
+            let parts: Vec<f32> = value
+                .split(',')
+                .map(|v| v.trim().parse().ok())
+                .collect::<Option<_>>()
+                .with_context(invalid)?;
+            match parts[..] {
+                [v] => Ok(Vec3::splat(v)),
+                [r, g, b] => Ok(vec3(r, g, b)),
+                _ => bail!(invalid()),
+            }
+        };
+        Ok(match key {
+            "exposure" => self.exposure(number()?),
+            "white_balance" => self.white_balance(channels()?),
+            "lift" => self.lift(channels()?),
+            "gamma" => self.gamma(channels()?),
+            "gain" => self.gain(channels()?),
+            "saturation" => self.saturation(number()?),
+            "contrast" => self.contrast(number()?),
+            _ => bail!("unknown grading setting '{}'", key),
+        })
+    }
+
+    pub fn expose(&self, linear: Color3) -> Color3 {
+        linear * self.exposure.exp2() * self.white_balance
+    }
+
+    pub fn apply(&self, display: Color3) -> Color3 {
+        let c = (self.gain * (display + self.lift * (Vec3::ONE - display))).max(Vec3::ZERO);
+        let mut c = vec3(
+            c.x.powf(1.0 / self.gamma.x),
+            c.y.powf(1.0 / self.gamma.y),
+            c.z.powf(1.0 / self.gamma.z),
+        );
+        // Identity stages are skipped so an ungraded image is bit-exact.
+        if self.saturation != 1.0 {
+            let luma = Vec3::splat(c.dot(LUMINANCE));
+            c = luma + (c - luma) * self.saturation;
+        }
+        c = c.clamp(Vec3::ZERO, Vec3::ONE);
+        if self.contrast != 1.0 {
+            // S-curve pivoting on mid grey; steeper in the middle for
+            // contrast > 1, flatter for contrast < 1.
+            let k = self.contrast;
+            let curve = |x: f32| {
+                let (a, b) = (x.powf(k), (1.0 - x).powf(k));
+                a / (a + b)
+            };
+            c = vec3(curve(c.x), curve(c.y), curve(c.z));
+        }
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color3;
+
+    #[test]
+    fn a_stop_of_exposure_doubles_linear_values() {
+        let linear = color3(0.1, 0.25, 2.0);
+        let up = Grade::default().exposure(1.0);
+        assert_eq!(up.expose(linear), linear * 2.0);
+        assert_eq!(Grade::default().exposure(-2.0).expose(linear), linear / 4.0);
+        let parsed = Grade::default().setting("exposure", "1").unwrap();
+        assert_eq!(parsed.expose(linear), up.expose(linear));
+    }
+
+    #[test]
+    fn default_grade_leaves_images_unchanged() {
+        let grade = Grade::default();
+        let c = color3(0.0, 0.37, 1.0);
+        assert_eq!(grade.expose(c), c);
+        assert_eq!(grade.apply(c), c);
+    }
+
+    #[test]
+    fn calibrated_gray_card_comes_out_middle_gray() {
+        let card = color3(0.4, 0.2, 0.1);
+        let exposed = Grade::default().calibrate(card).unwrap().expose(card);
+        assert!(exposed.abs_diff_eq(Vec3::splat(0.18), 1e-6), "{}", exposed);
+        assert!(Grade::default().calibrate(color3(0.5, 0.0, 0.5)).is_err());
+    }
+}
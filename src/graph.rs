@@ -0,0 +1,158 @@
+use crate::hittables::Hit;
+use crate::textures::Texture;
+use crate::{Color3, Point3};
+use glam::{Vec2, Vec3};
+use std::sync::Arc;
+
+// The nodes of material graphs, which build colors out of other textures
+// rather than fixed parameters. Each node is a texture itself, and a node
+// feeding several others is shared between them, so a graph is evaluated at
+// every hit like any other texture.
+
+// The product of its inputs, channel by channel, for tinting or masking.
+pub struct Multiply {
+    inputs: Vec<Arc<dyn Texture>>,
+}
+
+impl Multiply {
+    pub fn new(inputs: Vec<Arc<dyn Texture>>) -> Self {
+        Self { inputs }
+    }
+}
+
+impl Texture for Multiply {
+    fn value(&self, uv: Vec2, p: Point3) -> Color3 {
+        self.inputs
+            .iter()
+            .fold(Color3::ONE, |product, input| product * input.value(uv, p))
+    }
+
+    fn shade(&self, hit: &Hit, dir: Vec3) -> Color3 {
+        self.inputs.iter().fold(Color3::ONE, |product, input| {
+            product * input.shade(hit, dir)
+        })
+    }
+}
+
+// `a` where `factor` is 0 and `b` where it is 1, channel by channel; the
+// factor is clamped to that range.
+pub struct Mix {
+    a: Arc<dyn Texture>,
+    b: Arc<dyn Texture>,
+    factor: Arc<dyn Texture>,
+}
+
+impl Mix {
+    pub fn new(a: Arc<dyn Texture>, b: Arc<dyn Texture>, factor: Arc<dyn Texture>) -> Self {
+        Self { a, b, factor }
+    }
+}
+
+fn mix(a: Color3, b: Color3, factor: Color3) -> Color3 {
+    a + (b - a) * factor.clamp(Color3::ZERO, Color3::ONE)
+}
+
+impl Texture for Mix {
+    fn value(&self, uv: Vec2, p: Point3) -> Color3 {
+        mix(
+            self.a.value(uv, p),
+            self.b.value(uv, p),
+            self.factor.value(uv, p),
+        )
+    }
+
+    fn shade(&self, hit: &Hit, dir: Vec3) -> Color3 {
+        mix(
+            self.a.shade(hit, dir),
+            self.b.shade(hit, dir),
+            self.factor.shade(hit, dir),
+        )
+    }
+}
+
+// The share of light a clear coat of refractive index `ior` reflects, by
+// Schlick's approximation: a few percent seen head on, rising to all of it
+// at grazing angles. Mixing towards a lighter color by it gives surfaces a
+// sheen around their silhouettes.
+pub struct Fresnel {
+    // The reflectance head on.
+    r0: f32,
+}
+
+impl Fresnel {
+    pub fn new(ior: f32) -> Self {
+        let r0 = (1.0 - ior) / (1.0 + ior);
+        Self { r0: r0 * r0 }
+    }
+}
+
+impl Texture for Fresnel {
+    // Without a view direction, the surface is taken to be seen head on.
+    fn value(&self, _uv: Vec2, _p: Point3) -> Color3 {
+        Color3::splat(self.r0)
+    }
+
+    fn shade(&self, hit: &Hit, dir: Vec3) -> Color3 {
+        let cos_theta = dir.normalize().dot(hit.shading_normal).abs().min(1.0);
+        Color3::splat(self.r0 + (1.0 - self.r0) * (1.0 - cos_theta).powi(5))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::Material;
+    use crate::render::Ray;
+    use crate::textures::SolidColor;
+    use crate::{color3, point3};
+
+    fn color(r: f32, g: f32, b: f32) -> Arc<dyn Texture> {
+        Arc::new(SolidColor::new(color3(r, g, b)))
+    }
+
+    #[test]
+    fn nodes_combine_their_inputs() {
+        let (uv, p) = (Vec2::ZERO, Point3::ZERO);
+        let tint = Multiply::new(vec![color(0.5, 1.0, 0.2), color(0.5, 0.5, 1.0)]);
+        assert_eq!(tint.value(uv, p), color3(0.25, 0.5, 0.2));
+        let mixed = Mix::new(
+            color(0.0, 1.0, 0.5),
+            color(1.0, 0.0, 0.5),
+            color(0.25, 2.0, -1.0),
+        );
+        assert_eq!(mixed.value(uv, p), color3(0.25, 0.0, 0.5));
+    }
+
+    #[test]
+    fn fresnel_rises_towards_grazing_angles() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let fresnel = Fresnel::new(1.5);
+        let reflectance = |dir: Vec3| {
+            let ray = Ray::new(point3(0.0, 1.0, 0.0) - dir, dir);
+            let hit = Hit::new(point3(0.0, 1.0, 0.0), Vec3::Y, &ray, 1.0, &mat);
+            fresnel.shade(&hit, ray.dir()).x
+        };
+        let head_on = reflectance(-Vec3::Y);
+        assert!((head_on - 0.04).abs() < 1e-6, "{}", head_on);
+        assert_eq!(fresnel.value(Vec2::ZERO, Point3::ZERO).x, head_on);
+        let oblique = reflectance(Vec3::new(1.0, -1.0, 0.0));
+        let grazing = reflectance(Vec3::new(1.0, -0.01, 0.0));
+        assert!(
+            head_on < oblique && oblique < grazing,
+            "{} {}",
+            oblique,
+            grazing
+        );
+        assert!(grazing > 0.9, "{}", grazing);
+
+        // Mixing by it works from the view as well.
+        let sheen = Mix::new(
+            color(0.0, 0.0, 0.0),
+            color(1.0, 1.0, 1.0),
+            Arc::new(fresnel),
+        );
+        let ray = Ray::new(point3(-1.0, 1.01, 0.0), Vec3::new(1.0, -0.01, 0.0));
+        let hit = Hit::new(point3(0.0, 1.0, 0.0), Vec3::Y, &ray, 1.0, &mat);
+        assert_eq!(sheen.shade(&hit, ray.dir()).x, grazing);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{color3, Color3};
+use anyhow::{anyhow, bail, Result};
+
+// Decodes a Radiance RGBE (.hdr) image into linear colors, rows from the top.
+// Only the standard -Y +X orientation is read.
+pub fn decode(bytes: &[u8]) -> Result<(u32, u32, Vec<Color3>)> {
+    if !bytes.starts_with(b"#?") {
+        bail!("not a Radiance HDR file");
+    }
+    let mut pos = 0;
+    let mut line = || -> Result<&str> {
+        let len = bytes[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| anyhow!("truncated header"))?;
+        let text = std::str::from_utf8(&bytes[pos..pos + len])?;
+        pos += len + 1;
+        Ok(text.trim_end_matches('\r'))
+    };
+    loop {
+        match line()? {
+            "" => break,
+            format if format.starts_with("FORMAT=") && format != "FORMAT=32-bit_rle_rgbe" => {
+                bail!("unsupported {}", format)
+            }
+            _ => {}
+        }
+    }
+    let (width, height) = match line()?.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", height, "+X", width] => (
+            width.parse().map_err(|_| anyhow!("invalid width"))?,
+            height.parse().map_err(|_| anyhow!("invalid height"))?,
+        ),
+        _ => bail!("only -Y +X oriented images are supported"),
+    };
+
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        pos = read_scanline(bytes, pos, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|&rgbe| from_rgbe(rgbe)));
+    }
+    Ok((width as u32, height as u32, pixels))
+}
+
+// Reads one scanline starting at `pos`, returning where the next starts.
+// Scanlines of 8 to 32767 pixels are usually run-length encoded one
+// component at a time; anything else is stored flat.
+fn read_scanline(bytes: &[u8], mut pos: usize, scanline: &mut [[u8; 4]]) -> Result<usize> {
+    let width = scanline.len();
+    let rle = (8..=0x7FFF).contains(&width) && bytes.get(pos..pos + 2) == Some(&[2, 2]);
+    let mut next = || -> Result<u8> {
+        let byte = *bytes
+            .get(pos)
+            .ok_or_else(|| anyhow!("unexpected end of file"))?;
+        pos += 1;
+        Ok(byte)
+    };
+    if !rle {
+        for pixel in scanline.iter_mut() {
+            for byte in pixel.iter_mut() {
+                *byte = next()?;
+            }
+        }
+        return Ok(pos);
+    }
+    let header = [next()?, next()?, next()?, next()?];
+    if u16::from_be_bytes([header[2], header[3]]) as usize != width {
+        bail!("scanline width mismatch");
+    }
+    for component in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = next()? as usize;
+            let (count, run) = if count > 128 {
+                (count - 128, Some(next()?))
+            } else {
+                (count, None)
+            };
+            if count == 0 || x + count > width {
+                bail!("bad run length");
+            }
+            for pixel in &mut scanline[x..x + count] {
+                pixel[component] = match run {
+                    Some(value) => value,
+                    None => next()?,
+                };
+            }
+            x += count;
+        }
+    }
+    Ok(pos)
+}
+
+// Three mantissas sharing an exponent biased by 128, the mantissas being
+// fractions in 1/256ths.
+fn from_rgbe([r, g, b, e]: [u8; 4]) -> Color3 {
+    if e == 0 {
+        return Color3::ZERO;
+    }
+    let scale = 2f32.powi(e as i32 - 136);
+    color3(r as f32, g as f32, b as f32) * scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_flat_and_run_length_encoded_scanlines() {
+        // Two rows of 8: the first stored flat, the second run-length
+        // encoded with one run and one literal per component.
+        let mut bytes = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 8\n".to_vec();
+        for x in 0..8 {
+            bytes.extend([128, 64, x * 16, 129]);
+        }
+        bytes.extend([2, 2, 0, 8]);
+        for (run, literal) in [(128, 255), (0, 255), (32, 255), (130, 136)] {
+            bytes.extend([128 + 7, run, 1, literal]);
+        }
+        let (width, height, pixels) = decode(&bytes).unwrap();
+        assert_eq!((width, height), (8, 2));
+        assert_eq!(pixels[0], color3(1.0, 0.5, 0.0));
+        assert_eq!(pixels[7], color3(1.0, 0.5, 0.875));
+        assert_eq!(pixels[8], color3(2.0, 0.0, 0.5));
+        assert_eq!(pixels[15], Color3::splat(255.0));
+
+        assert!(decode(b"#?RADIANCE\n\n+Y 2 +X 8\n").is_err());
+        bytes.truncate(bytes.len() - 1);
+        assert!(decode(&bytes).is_err());
+    }
+}
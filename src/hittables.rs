@@ -1,22 +1,90 @@
+use crate::json::Json;
 use crate::materials::Material;
+use crate::onb::Onb;
+use crate::random;
 use crate::render::Ray;
-use crate::{point3, Point3};
-use glam::{vec3, Vec3};
+use crate::textures::Texture;
+use crate::{point3, Color3, Point3};
+use anyhow::{bail, Result};
+use glam::{vec2, vec3, Affine3A, Mat3, Mat4, UVec2, Vec2, Vec3};
+use std::f32::consts::{PI, TAU};
+use std::ops::Range;
+use std::sync::Arc;
 
 pub trait Hittable: Send + Sync {
-    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit>;
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>>;
+    fn bounding_box(&self) -> Aabb;
+    // Bytes owned by this object, including everything it boxes.
+    fn memory(&self) -> usize;
+
+    // The box around the parts with finite bounds, which collections
+    // holding planes and the like have on top of their infinite one.
+    fn bounded_box(&self) -> Aabb {
+        self.bounding_box()
+    }
+
+    // Objects that can be sampled as lights pick directions from `origin`
+    // towards points on their surface with `random`, at the density over
+    // solid angle given by `pdf_value`.
+    fn pdf_value(&self, _origin: Point3, _dir: Vec3) -> f32 {
+        0.0
+    }
+
+    fn random(&self, _origin: Point3) -> Vec3 {
+        Vec3::X
+    }
+
+    // The object as a scene file node, if it can be written as one.
+    fn to_json(&self) -> Option<Json> {
+        None
+    }
 }
 
-pub struct Hit {
+pub struct Hit<'a> {
     pub p: Point3,
+    // The geometric normal, which decides which side was hit.
     pub normal: Vec3,
+    // The normal materials shade with, such as one interpolated from mesh
+    // vertex normals; the geometric normal elsewhere. On the same side.
+    pub shading_normal: Vec3,
     pub t: f32,
     pub front_face: bool,
-    pub material: Material,
+    pub material: &'a Material,
+    // Texture coordinates: spherical ones on spheres, the planar ones on
+    // quads and patches, and on triangles interpolated from the vertex
+    // coordinates (the barycentric weights of the second and third vertices
+    // when there are none).
+    pub uv: Vec2,
+    // Surface color, such as interpolated vertex colors, multiplying the
+    // material's albedo; white elsewhere.
+    pub color: Color3,
+    // The width of the ray's cone at the hit, in the units of the object
+    // hit, and how fast the texture coordinates change along its surface,
+    // per unit of length along u and v (zero where not known). Together they
+    // tell textures how much of them a ray covers.
+    pub footprint: f32,
+    pub uv_density: Vec2,
 }
 
-impl Hit {
-    fn new(p: Point3, outward_normal: Vec3, ray: &Ray, t: f32, material: Material) -> Self {
+// The shallowest angle's cosine footprints are stretched by, so rays
+// grazing a surface don't blur its texture away entirely.
+const MIN_FOOTPRINT_COS: f32 = 0.1;
+
+// Whether a normal is unit length up to the rounding of computing it in f32.
+fn is_unit(v: Vec3) -> bool {
+    (v.length_squared() - 1.0).abs() < 1e-3
+}
+
+impl<'a> Hit<'a> {
+    // `outward_normal` must be unit length; the stored normal is flipped to
+    // face against the ray.
+    pub fn new(p: Point3, outward_normal: Vec3, ray: &Ray, t: f32, material: &'a Material) -> Self {
+        debug_assert!(t.is_finite(), "hit at t = {}", t);
+        debug_assert!(
+            is_unit(outward_normal),
+            "normal {} isn't unit length",
+            outward_normal
+        );
         let front_face = ray.dir().dot(outward_normal) < 0.0;
         let normal = if front_face {
             outward_normal
@@ -26,15 +94,63 @@ impl Hit {
         Self {
             p,
             normal,
+            shading_normal: normal,
             t,
             front_face,
             material,
+            uv: Vec2::ZERO,
+            color: Color3::ONE,
+            footprint: ray.footprint(t),
+            uv_density: Vec2::ZERO,
+        }
+    }
+
+    pub fn with_uv_density(mut self, uv_density: Vec2) -> Self {
+        self.uv_density = uv_density;
+        self
+    }
+
+    // The ray's footprint measured in texture coordinates along u and v,
+    // stretched where a ray along `dir` meets the surface at a slant.
+    pub fn uv_footprint(&self, dir: Vec3) -> Vec2 {
+        if self.footprint <= 0.0 || self.uv_density == Vec2::ZERO {
+            return Vec2::ZERO;
         }
+        let cos = dir.normalize().dot(self.normal).abs();
+        self.footprint / cos.max(MIN_FOOTPRINT_COS) * self.uv_density
+    }
+
+    pub fn with_uv(mut self, uv: Vec2) -> Self {
+        self.uv = uv;
+        self
+    }
+
+    pub fn with_color(mut self, color: Color3) -> Self {
+        self.color = color;
+        self
+    }
+
+    // `shading_normal` must be unit length, and is flipped to the side that
+    // was hit.
+    pub fn with_shading_normal(mut self, shading_normal: Vec3) -> Self {
+        debug_assert!(
+            is_unit(shading_normal),
+            "shading normal {} isn't unit length",
+            shading_normal
+        );
+        self.shading_normal = if shading_normal.dot(self.normal) < 0.0 {
+            -shading_normal
+        } else {
+            shading_normal
+        };
+        self
     }
 }
 
 pub struct Sphere {
+    // The center at time 0, moving by `motion` per unit of time.
     center: Point3,
+    motion: Vec3,
     radius: f32,
     mat: Material,
 }
@@ -43,44 +159,270 @@ impl Sphere {
     pub fn new(center: Point3, radius: f32, mat: Material) -> Self {
         Self {
             center,
+            motion: Vec3::ZERO,
             radius,
             mat,
         }
     }
+
+    // A sphere moving in a straight line from `center0` at time 0 to
+    // `center1` at time 1. Only its position at time 0 is sampled as a light.
+    pub fn moving(center0: Point3, center1: Point3, radius: f32, mat: Material) -> Self {
+        Self {
+            motion: center1 - center0,
+            ..Self::new(center0, radius, mat)
+        }
+    }
+
+    fn center_at(&self, time: f32) -> Point3 {
+        self.center + self.motion * time
+    }
 }
 
-impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit> {
-        let oc = ray.origin() - self.center;
-        let a = ray.dir().length_squared();
-        let half_b = oc.dot(ray.dir());
-        let c = oc.length_squared() - self.radius * self.radius;
-        let discriminant = half_b * half_b - a * c;
-
-        if discriminant < 0.0 {
+// The nearer distance along `ray` within `ray_t` at which it meets the
+// sphere.
+fn intersect_sphere(center: Point3, radius: f32, ray: &Ray, ray_t: Interval) -> Option<f32> {
+    let oc = ray.origin() - center;
+    let a = ray.dir().length_squared();
+    let half_b = oc.dot(ray.dir());
+    // half_b^2 - a*c, rewritten with the ray's closest approach to the
+    // center so it doesn't cancel out for small spheres far away.
+    let closest = oc - half_b / a * ray.dir();
+    let discriminant = a * (radius * radius - closest.length_squared());
+
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let sqrtd = discriminant.sqrt();
+    let mut root = (-half_b - sqrtd) / a;
+    if !ray_t.surrounds(root) {
+        root = (-half_b + sqrtd) / a;
+        if !ray_t.surrounds(root) {
             return None;
         }
+    }
+    Some(root)
+}
 
-        let sqrtd = discriminant.sqrt();
-        let mut root = (-half_b - sqrtd) / a;
-        if !ray_t.surrounds(root) {
-            root = (-half_b + sqrtd) / a;
-            if !ray_t.surrounds(root) {
-                return None;
-            }
+fn sphere_hit<'a>(center: Point3, radius: f32, ray: &Ray, t: f32, mat: &'a Material) -> Hit<'a> {
+    let p = ray.at(t);
+    // Normalized as well: far from the origin, p is off by more than the
+    // radius can absorb.
+    let outward_normal = ((p - center) / radius).normalize();
+    // Around the equator and from pole to pole.
+    let uv_density = vec2(1.0 / TAU, 1.0 / PI) / radius.abs();
+    Hit::new(p, outward_normal, ray, t, mat)
+        .with_uv(sphere_uv(outward_normal))
+        .with_uv_density(uv_density)
+}
+
+// u goes around the y axis from -x, through +z, v from the bottom pole to
+// the top.
+fn sphere_uv(n: Vec3) -> Vec2 {
+    let theta = (-n.y).clamp(-1.0, 1.0).acos();
+    let phi = (-n.z).atan2(n.x) + PI;
+    vec2(phi / TAU, theta / PI)
+}
+
+impl Hittable for Sphere {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let center = self.center_at(ray.time());
+        let t = intersect_sphere(center, self.radius, ray, ray_t)?;
+        Some(sphere_hit(center, self.radius, ray, t, &self.mat))
+    }
+
+    // Covers the sphere from time 0 to 1.
+    fn bounding_box(&self) -> Aabb {
+        let r = Vec3::splat(self.radius.abs());
+        let end = self.center + self.motion;
+        Aabb::new(self.center - r, self.center + r).union(Aabb::new(end - r, end + r))
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    // Uniform over the cone of directions the sphere covers, or over all
+    // directions from inside it.
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        if self.hit(&Ray::new(origin, dir), LIGHT_RAY_T).is_none() {
+            return 0.0;
         }
+        1.0 / (TAU * self.cap(origin))
+    }
 
-        let t = root;
-        let p = ray.at(t);
-        let outward_normal = (p - self.center) / self.radius;
-        Some(Hit::new(p, outward_normal, ray, t, self.mat))
+    fn random(&self, origin: Point3) -> Vec3 {
+        let to_center = self.center - origin;
+        let axis = to_center.try_normalize().unwrap_or(Vec3::Z);
+        Onb::new(axis).to_world(random::cone_direction(self.cap(origin)))
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        let node = Json::object([
+            ("type", "sphere".into()),
+            ("center", self.center.into()),
+            ("radius", self.radius.into()),
+            ("material", self.mat.to_json()?),
+        ]);
+        Some(if self.motion == Vec3::ZERO {
+            node
+        } else {
+            node.with("center1", self.center_at(1.0).into())
+        })
+    }
+}
+
+impl Sphere {
+    // One minus the cosine of the half-angle the sphere subtends from
+    // `origin`, or 2 from inside it. Written so it doesn't round to 0 for
+    // small, distant bulbs, whose cosine is within an f32 step of 1.
+    fn cap(&self, origin: Point3) -> f32 {
+        let ratio = self.radius * self.radius / (self.center - origin).length_squared();
+        if ratio >= 1.0 {
+            2.0
+        } else {
+            ratio / (1.0 + (1.0 - ratio).sqrt())
+        }
+    }
+}
+
+// Spheres that don't move, kept in flat arrays under a BVH of their own
+// rather than boxed one by one, as a mesh keeps its triangles: each is a
+// center and a radius with an index into the shared materials. Many small
+// spheres, such as particles, take a fraction of the memory this way and
+// are built and traced without chasing a pointer per sphere.
+pub struct Spheres {
+    centers: Vec<Point3>,
+    radii: Vec<f32>,
+    sphere_materials: Vec<u32>,
+    materials: Vec<Material>,
+    nodes: Vec<BvhNode>,
+}
+
+impl Spheres {
+    // `sphere_materials` may be empty when there is a single material.
+    pub fn new(
+        centers: Vec<Point3>,
+        radii: Vec<f32>,
+        materials: Vec<Material>,
+        sphere_materials: Vec<u32>,
+    ) -> Result<Self> {
+        if radii.len() != centers.len() {
+            bail!("{} centers but {} radii", centers.len(), radii.len());
+        }
+        let sphere_materials = if sphere_materials.is_empty() && materials.len() == 1 {
+            vec![0; centers.len()]
+        } else {
+            sphere_materials
+        };
+        if sphere_materials.len() != centers.len() {
+            bail!(
+                "{} spheres but {} sphere materials",
+                centers.len(),
+                sphere_materials.len()
+            );
+        }
+        if let Some(idx) = sphere_materials
+            .iter()
+            .find(|&&idx| idx as usize >= materials.len())
+        {
+            bail!(
+                "sphere material {} but only {} materials",
+                idx,
+                materials.len()
+            );
+        }
+
+        let mut items: Vec<(Aabb, (Point3, f32, u32))> = centers
+            .into_iter()
+            .zip(radii)
+            .zip(sphere_materials)
+            .map(|((center, radius), mat)| {
+                let r = Vec3::splat(radius.abs());
+                (Aabb::new(center - r, center + r), (center, radius, mat))
+            })
+            .collect();
+        let nodes = build_nodes(&mut items, BvhQuality::Sah);
+        let centers = items.iter().map(|(_, (center, ..))| *center).collect();
+        let radii = items.iter().map(|(_, (_, radius, _))| *radius).collect();
+        let sphere_materials = items.iter().map(|(_, (.., mat))| *mat).collect();
+        Ok(Self {
+            centers,
+            radii,
+            sphere_materials,
+            materials,
+            nodes,
+        })
+    }
+}
+
+impl Hittable for Spheres {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        traverse(&self.nodes, ray, ray_t, |range, ray_t| {
+            let mut closest = None;
+            let mut closest_t = ray_t.max;
+            for idx in range {
+                let interval = Interval::new(ray_t.min, closest_t);
+                if let Some(t) = intersect_sphere(self.centers[idx], self.radii[idx], ray, interval)
+                {
+                    closest_t = t;
+                    closest = Some((idx, t));
+                }
+            }
+            let (idx, t) = closest?;
+            let material = &self.materials[self.sphere_materials[idx] as usize];
+            Some(sphere_hit(
+                self.centers[idx],
+                self.radii[idx],
+                ray,
+                t,
+                material,
+            ))
+        })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::EMPTY, |node| *node.bbox())
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.centers.capacity() * std::mem::size_of::<Point3>()
+            + self.radii.capacity() * std::mem::size_of::<f32>()
+            + self.sphere_materials.capacity() * std::mem::size_of::<u32>()
+            + self.materials.capacity() * std::mem::size_of::<Material>()
+            + self.nodes.capacity() * std::mem::size_of::<BvhNode>()
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        let children = (0..self.centers.len())
+            .map(|idx| {
+                let mat = &self.materials[self.sphere_materials[idx] as usize];
+                Some(Json::object([
+                    ("type", "sphere".into()),
+                    ("center", self.centers[idx].into()),
+                    ("radius", self.radii[idx].into()),
+                    ("material", mat.to_json()?),
+                ]))
+            })
+            .collect::<Option<_>>()?;
+        Some(Json::object([
+            ("type", "group".into()),
+            ("children", Json::Array(children)),
+        ]))
     }
 }
 
+// Planar primitive. A plain quad is the parallelogram q + a*u + b*v for a, b
+// in [0, 1]. Ellipses, disks and regular polygons are centered on q instead,
+// with u and v as semi-axes (a, b in [-1, 1]); polygons have a vertex along
+// u.
 pub struct Quad {
     q: Point3,
     u: Vec3,
     v: Vec3,
+    shape: Shape,
     mat: Material,
 
     normal: Vec3,
@@ -88,8 +430,62 @@ pub struct Quad {
     w: Vec3,
 }
 
+#[derive(Copy, Clone)]
+enum Shape {
+    Parallelogram,
+    Ellipse,
+    // An ellipse with a hole of this fraction of its size, such as a ring.
+    Annulus(f32),
+    Polygon(u32),
+}
+
 impl Quad {
     pub fn new(q: Point3, u: Vec3, v: Vec3, mat: Material) -> Self {
+        Self::with_shape(q, u, v, Shape::Parallelogram, mat)
+    }
+
+    pub fn ellipse(center: Point3, u: Vec3, v: Vec3, mat: Material) -> Self {
+        Self::with_shape(center, u, v, Shape::Ellipse, mat)
+    }
+
+    // A circle of `radius` facing `normal`, with a hole of `inner_radius`
+    // unless that is 0.
+    pub fn disk(
+        center: Point3,
+        normal: Vec3,
+        radius: f32,
+        inner_radius: f32,
+        mat: Material,
+    ) -> Result<Self> {
+        if radius.is_nan() || radius <= 0.0 {
+            bail!("a disk needs a positive radius");
+        }
+        if inner_radius.is_nan() || !(0.0..radius).contains(&inner_radius) {
+            bail!("a disk's inner radius must be at least 0 and less than its radius");
+        }
+        if normal.length_squared() == 0.0 {
+            bail!("a disk needs a nonzero normal");
+        }
+        let onb = Onb::new(normal.normalize());
+        let shape = match inner_radius {
+            0.0 => Shape::Ellipse,
+            _ => Shape::Annulus(inner_radius / radius),
+        };
+        Ok(Self::with_shape(
+            center,
+            radius * onb.u(),
+            radius * onb.v(),
+            shape,
+            mat,
+        ))
+    }
+
+    pub fn polygon(center: Point3, u: Vec3, v: Vec3, sides: u32, mat: Material) -> Self {
+        assert!(sides >= 3, "a polygon needs at least 3 sides");
+        Self::with_shape(center, u, v, Shape::Polygon(sides), mat)
+    }
+
+    fn with_shape(q: Point3, u: Vec3, v: Vec3, shape: Shape, mat: Material) -> Self {
         let n = u.cross(v);
         let normal = n.normalize();
         let d = normal.dot(q);
@@ -98,16 +494,113 @@ impl Quad {
             q,
             u,
             v,
+            shape,
             mat,
             normal,
             d,
             w,
         }
     }
+
+    fn area(&self) -> f32 {
+        let parallelogram = self.u.cross(self.v).length();
+        match self.shape {
+            Shape::Parallelogram => parallelogram,
+            Shape::Ellipse => PI * parallelogram,
+            Shape::Annulus(inner) => PI * (1.0 - inner * inner) * parallelogram,
+            Shape::Polygon(sides) => {
+                sides as f32 / 2.0 * (TAU / sides as f32).sin() * parallelogram
+            }
+        }
+    }
+
+    // Probabilities of sampling each cell of a LIGHT_CELLS by LIGHT_CELLS
+    // grid over a plain quad from `origin`, row by row along u, or None to
+    // sample the whole area uniformly. Cells count about in proportion to the
+    // solid angle they cover, judged at their centers, so a big panel just
+    // above a surface gets most samples overhead rather than far off at
+    // grazing angles, where they add noise.
+    fn light_cells(&self, origin: Point3) -> Option<[f32; LIGHT_CELLS * LIGHT_CELLS]> {
+        if !matches!(self.shape, Shape::Parallelogram) {
+            return None;
+        }
+        // From further off the cells look much alike.
+        let center = self.q + (self.u + self.v) / 2.0;
+        let size = (self.u + self.v).length().max((self.u - self.v).length());
+        if center.distance(origin) > size {
+            return None;
+        }
+        let cell_area = self.area() / (LIGHT_CELLS * LIGHT_CELLS) as f32;
+        let mut weights = [0.0; LIGHT_CELLS * LIGHT_CELLS];
+        for (idx, weight) in weights.iter_mut().enumerate() {
+            let cell = vec2((idx % LIGHT_CELLS) as f32, (idx / LIGHT_CELLS) as f32) + 0.5;
+            let to = self.q + (cell.x * self.u + cell.y * self.v) / LIGHT_CELLS as f32 - origin;
+            let distance = to.length();
+            // A cell can't cover much more than a hemisphere, however close.
+            *weight = self.normal.dot(to).abs()
+                / distance.max(f32::MIN_POSITIVE)
+                / (distance * distance).max(cell_area);
+        }
+        let total: f32 = weights.iter().sum();
+        if total.is_nan() || total <= 0.0 {
+            return None;
+        }
+        // Part spread evenly, so cells the centers undersell still get
+        // sampled now and then.
+        for weight in &mut weights {
+            *weight = 0.9 * *weight / total + 0.1 / (LIGHT_CELLS * LIGHT_CELLS) as f32;
+        }
+        Some(weights)
+    }
+
+    // Planar coordinates of a point uniformly distributed over the shape.
+    fn random_point(&self) -> Vec2 {
+        match self.shape {
+            Shape::Parallelogram => vec2(random::f32(), random::f32()),
+            Shape::Ellipse => {
+                let r = random::f32().sqrt();
+                let (sin, cos) = (TAU * random::f32()).sin_cos();
+                vec2(r * cos, r * sin)
+            }
+            Shape::Annulus(inner) => {
+                let r = (inner * inner + (1.0 - inner * inner) * random::f32()).sqrt();
+                let (sin, cos) = (TAU * random::f32()).sin_cos();
+                vec2(r * cos, r * sin)
+            }
+            Shape::Polygon(sides) => {
+                // Within the triangle between the center and a random edge.
+                let sector = ((random::f32() * sides as f32) as u32).min(sides - 1);
+                let corner = |idx: u32| {
+                    let (sin, cos) = (idx as f32 * TAU / sides as f32).sin_cos();
+                    vec2(cos, sin)
+                };
+                let (s, t) = (random::f32().sqrt(), random::f32());
+                s * (1.0 - t) * corner(sector) + s * t * corner(sector + 1)
+            }
+        }
+    }
+
+    // Whether planar coordinates (alpha, beta) fall within the shape.
+    fn inside(&self, alpha: f32, beta: f32) -> bool {
+        match self.shape {
+            Shape::Parallelogram => (0.0..=1.0).contains(&alpha) && (0.0..=1.0).contains(&beta),
+            Shape::Ellipse => alpha * alpha + beta * beta <= 1.0,
+            Shape::Annulus(inner) => (inner * inner..=1.0).contains(&(alpha * alpha + beta * beta)),
+            Shape::Polygon(sides) => {
+                // Project onto the normal of the edge facing this sector; it
+                // lies at the apothem, cos(pi / sides).
+                let sector = TAU / sides as f32;
+                let angle = beta.atan2(alpha).rem_euclid(TAU);
+                let mid = ((angle / sector).floor() + 0.5) * sector;
+                let (sin, cos) = mid.sin_cos();
+                alpha * cos + beta * sin <= (sector / 2.0).cos()
+            }
+        }
+    }
 }
 
 impl Hittable for Quad {
-    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit> {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
         const EPSILON: f32 = 1e-8;
 
         let denom = self.normal.dot(ray.dir());
@@ -125,152 +618,2968 @@ impl Hittable for Quad {
         let alpha = self.w.dot(planar_hit_vector.cross(self.v));
         let beta = self.w.dot(self.u.cross(planar_hit_vector));
 
-        if alpha < 0.0 || alpha > 1.0 || beta < 0.0 || beta > 1.0 {
+        if !self.inside(alpha, beta) {
             return None;
         }
 
-        Some(Hit::new(intersection, self.normal, ray, t, self.mat))
+        // Centered shapes span [-1, 1]; their texture coordinates span the
+        // unit square like a plain quad's.
+        let (uv, scale) = match self.shape {
+            Shape::Parallelogram => (vec2(alpha, beta), 1.0),
+            Shape::Ellipse | Shape::Annulus(_) | Shape::Polygon(_) => {
+                ((vec2(alpha, beta) + 1.0) / 2.0, 0.5)
+            }
+        };
+        let uv_density = scale / vec2(self.u.length(), self.v.length());
+        Some(
+            Hit::new(intersection, self.normal, ray, t, &self.mat)
+                .with_uv(uv)
+                .with_uv_density(uv_density),
+        )
     }
-}
 
-pub type HittableVec = Vec<Box<dyn Hittable>>;
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        let Some(hit) = self.hit(&Ray::new(origin, dir), LIGHT_RAY_T) else {
+            return 0.0;
+        };
+        let uniform = solid_angle_pdf(dir, hit.t, self.normal, self.area());
+        match self.light_cells(origin) {
+            Some(weights) => {
+                let cell = (hit.uv * LIGHT_CELLS as f32)
+                    .as_uvec2()
+                    .min(UVec2::splat(LIGHT_CELLS as u32 - 1));
+                let idx = cell.y as usize * LIGHT_CELLS + cell.x as usize;
+                uniform * weights[idx] * (LIGHT_CELLS * LIGHT_CELLS) as f32
+            }
+            None => uniform,
+        }
+    }
 
-impl Hittable for HittableVec {
-    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit> {
-        let mut closest_hit = None;
-        let mut closest_t = ray_t.max;
+    fn random(&self, origin: Point3) -> Vec3 {
+        let point = match self.light_cells(origin) {
+            Some(weights) => {
+                let mut pick = random::f32();
+                let idx = weights
+                    .iter()
+                    .position(|&weight| {
+                        pick -= weight;
+                        pick < 0.0
+                    })
+                    .unwrap_or(weights.len() - 1);
+                let cell = vec2((idx % LIGHT_CELLS) as f32, (idx / LIGHT_CELLS) as f32);
+                (cell + vec2(random::f32(), random::f32())) / LIGHT_CELLS as f32
+            }
+            None => self.random_point(),
+        };
+        self.q + point.x * self.u + point.y * self.v - origin
+    }
 
-        for obj in self {
-            if let Some(hit) = obj.hit(ray, Interval::new(ray_t.min, closest_t)) {
-                closest_t = hit.t;
-                closest_hit = Some(hit);
+    fn bounding_box(&self) -> Aabb {
+        let (q, u, v) = (self.q, self.u, self.v);
+        match self.shape {
+            Shape::Parallelogram => Aabb::new(q, q + u + v).union(Aabb::new(q + u, q + v)),
+            Shape::Ellipse | Shape::Annulus(_) => {
+                // Extent along each axis of q + u*cos(phi) + v*sin(phi).
+                let r = (u * u + v * v).powf(0.5);
+                Aabb::new(q - r, q + r)
             }
+            Shape::Polygon(sides) => (0..sides).fold(Aabb::EMPTY, |acc, idx| {
+                let (sin, cos) = (idx as f32 * TAU / sides as f32).sin_cos();
+                let p = q + u * cos + v * sin;
+                acc.union(Aabb::new(p, p))
+            }),
         }
-        closest_hit
+        .padded()
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        let (kind, corner) = match self.shape {
+            Shape::Parallelogram => ("quad", "q"),
+            Shape::Ellipse | Shape::Polygon(_) => ("ellipse", "center"),
+            // Only made by `disk`, so circular.
+            Shape::Annulus(inner) => {
+                let radius = self.u.length();
+                return Some(Json::object([
+                    ("type", "disk".into()),
+                    ("center", self.q.into()),
+                    ("normal", self.normal.into()),
+                    ("radius", radius.into()),
+                    ("inner_radius", (inner * radius).into()),
+                    ("material", self.mat.to_json()?),
+                ]));
+            }
+        };
+        let node = Json::object([
+            ("type", kind.into()),
+            (corner, self.q.into()),
+            ("u", self.u.into()),
+            ("v", self.v.into()),
+            ("material", self.mat.to_json()?),
+        ]);
+        Some(match self.shape {
+            Shape::Polygon(sides) => node
+                .with("type", "polygon".into())
+                .with("sides", sides.into()),
+            _ => node,
+        })
     }
 }
 
-pub struct Translate {
-    offset: Vec3,
-    object: Box<dyn Hittable>,
+// An infinite plane through `point` facing `normal`, for a ground that stays
+// flat to the horizon where a huge sphere would curve away. Texture
+// coordinates repeat every unit along the plane.
+pub struct Plane {
+    point: Point3,
+    onb: Onb,
+    mat: Material,
 }
 
-impl Translate {
-    pub fn new(offset: Vec3, object: Box<dyn Hittable>) -> Self {
-        Self { offset, object }
+impl Plane {
+    pub fn new(point: Point3, normal: Vec3, mat: Material) -> Result<Self> {
+        if normal.length_squared() == 0.0 {
+            bail!("a plane needs a nonzero normal");
+        }
+        Ok(Plane {
+            point,
+            onb: Onb::new(normal.normalize()),
+            mat,
+        })
     }
 }
 
-impl Hittable for Translate {
-    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit> {
-        let offset_r = Ray::new(ray.origin() - self.offset, ray.dir());
+impl Hittable for Plane {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        const EPSILON: f32 = 1e-8;
 
-        match self.object.hit(&offset_r, ray_t) {
-            Some(mut hit) => {
-                hit.p += self.offset;
-                Some(hit)
+        let normal = self.onb.w();
+        let denom = normal.dot(ray.dir());
+        if denom.abs() < EPSILON {
+            return None;
+        }
+        let t = normal.dot(self.point - ray.origin()) / denom;
+        if !ray_t.contains(t) {
+            return None;
+        }
+        let p = ray.at(t);
+        let local = self.onb.to_local(p - self.point);
+        let uv = vec2(local.x, local.y) - vec2(local.x, local.y).floor();
+        Some(
+            Hit::new(p, normal, ray, t, &self.mat)
+                .with_uv(uv)
+                .with_uv_density(Vec2::ONE),
+        )
+    }
+
+    // Unbounded, except along an axis the plane is perpendicular to.
+    fn bounding_box(&self) -> Aabb {
+        let normal = self.onb.w();
+        let mut bbox = Aabb::UNBOUNDED;
+        for axis in 0..3 {
+            if normal[axis].abs() == 1.0 {
+                bbox.min[axis] = self.point[axis];
+                bbox.max[axis] = self.point[axis];
             }
-            None => None,
         }
+        bbox.padded()
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(Json::object([
+            ("type", "plane".into()),
+            ("point", self.point.into()),
+            ("normal", self.onb.w().into()),
+            ("material", self.mat.to_json()?),
+        ]))
     }
 }
 
-pub struct RotateY {
-    sin_theta: f32,
-    cos_theta: f32,
-    object: Box<dyn Hittable>,
+pub struct Triangle {
+    p0: Point3,
+    e1: Vec3,
+    e2: Vec3,
+    normal: Vec3,
+    mat: Material,
 }
 
-impl RotateY {
-    pub fn new(angle: f32, object: Box<dyn Hittable>) -> Self {
-        let angle = angle.to_radians();
-        RotateY {
-            sin_theta: angle.sin(),
-            cos_theta: angle.cos(),
-            object,
+impl Triangle {
+    pub fn new(p0: Point3, p1: Point3, p2: Point3, mat: Material) -> Self {
+        let (e1, e2) = (p1 - p0, p2 - p0);
+        Triangle {
+            p0,
+            e1,
+            e2,
+            normal: e1.cross(e2).normalize(),
+            mat,
         }
     }
 }
 
-impl Hittable for RotateY {
-    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit> {
-        let origin = vec3(
-            self.cos_theta * ray.origin().x - self.sin_theta * ray.origin().z,
-            ray.origin().y,
-            self.sin_theta * ray.origin().x + self.cos_theta * ray.origin().z,
-        );
-        let dir = vec3(
-            self.cos_theta * ray.dir().x - self.sin_theta * ray.dir().z,
-            ray.dir().y,
-            self.sin_theta * ray.dir().x + self.cos_theta * ray.dir().z,
-        );
-        let rotated_r = Ray::new(origin, dir);
+impl Hittable for Triangle {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let (t, uv) = intersect_triangle(self.p0, self.e1, self.e2, ray, ray_t)?;
+        let uv_density = 1.0 / vec2(self.e1.length(), self.e2.length());
+        Some(
+            Hit::new(ray.at(t), self.normal, ray, t, &self.mat)
+                .with_uv(uv)
+                .with_uv_density(uv_density),
+        )
+    }
 
-        let mut hit = match self.object.hit(&rotated_r, ray_t) {
-            Some(hit) => hit,
-            None => {
-                return None;
-            }
-        };
+    fn bounding_box(&self) -> Aabb {
+        let p0 = self.p0;
+        Aabb::new(p0, p0 + self.e1)
+            .union(Aabb::new(p0, p0 + self.e2))
+            .padded()
+    }
 
-        hit.p = point3(
-            self.cos_theta * hit.p.x + self.sin_theta * hit.p.z,
-            hit.p.y,
-            -self.sin_theta * hit.p.x + self.cos_theta * hit.p.z,
-        );
-        hit.normal = vec3(
-            self.cos_theta * hit.normal.x + self.sin_theta * hit.normal.z,
-            hit.normal.y,
-            hit.normal.y - self.sin_theta * hit.normal.x + self.cos_theta * hit.normal.z,
-        );
-        Some(hit)
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        let area = self.e1.cross(self.e2).length() / 2.0;
+        match intersect_triangle(
+            self.p0,
+            self.e1,
+            self.e2,
+            &Ray::new(origin, dir),
+            LIGHT_RAY_T,
+        ) {
+            Some((t, _)) => solid_angle_pdf(dir, t, self.normal, area),
+            None => 0.0,
+        }
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        let (s, t) = (random::f32().sqrt(), random::f32());
+        self.p0 + s * (1.0 - t) * self.e1 + s * t * self.e2 - origin
     }
 }
 
-pub fn make_box(a: Point3, b: Point3, mat: Material) -> HittableVec {
-    let min = point3(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
-    let max = point3(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+// Plain quads lit from close by are sampled over this many rows and columns
+// of cells.
+const LIGHT_CELLS: usize = 8;
 
-    let dx = vec3(max.x - min.x, 0.0, 0.0);
-    let dy = vec3(0.0, max.y - min.y, 0.0);
-    let dz = vec3(0.0, 0.0, max.z - min.z);
+// Where rays towards lights look for them. Their origins are already
+// offset from the surface they leave.
+const LIGHT_RAY_T: Interval = Interval {
+    min: 0.0,
+    max: f32::INFINITY,
+};
 
-    vec![
-        Box::new(Quad::new(point3(min.x, min.y, max.z), dx, dy, mat)), // front
-        Box::new(Quad::new(point3(max.x, min.y, max.z), -dz, dy, mat)), // right
-        Box::new(Quad::new(point3(max.x, min.y, min.z), -dx, dy, mat)), // back
-        Box::new(Quad::new(point3(min.x, min.y, min.z), dz, dy, mat)), // left
-        Box::new(Quad::new(point3(min.x, max.y, max.z), dx, -dz, mat)), // top
-        Box::new(Quad::new(point3(min.x, min.y, min.z), dx, dz, mat)), // bottom
-    ]
+// Converts the density of a uniformly sampled point, one over the shape's
+// area, into the density of the direction towards it: the hit `t` along
+// `dir` away, seen at an angle to the shape's `normal`.
+fn solid_angle_pdf(dir: Vec3, t: f32, normal: Vec3, area: f32) -> f32 {
+    let distance_squared = t * t * dir.length_squared();
+    let cosine = (dir.dot(normal) / dir.length()).abs();
+    distance_squared / (cosine * area)
 }
 
-#[derive(Copy, Clone)]
-pub struct Interval {
-    pub min: f32,
-    pub max: f32,
+fn luminance(c: Color3) -> f32 {
+    c.dot(vec3(0.2126, 0.7152, 0.0722))
 }
 
-impl Interval {
-    const _EMPTY: Interval = Interval {
-        min: f32::INFINITY,
-        max: f32::NEG_INFINITY,
-    };
+// Möller–Trumbore: solves origin + t*dir = p0 + u*e1 + v*e2 directly for t
+// and the barycentric u, v.
+fn intersect_triangle(
+    p0: Point3,
+    e1: Vec3,
+    e2: Vec3,
+    ray: &Ray,
+    ray_t: Interval,
+) -> Option<(f32, Vec2)> {
+    const EPSILON: f32 = 1e-8;
 
-    const _UNIVERSE: Interval = Interval {
-        min: f32::NEG_INFINITY,
-        max: f32::INFINITY,
-    };
+    let p = ray.dir().cross(e2);
+    let det = e1.dot(p);
+    if det.abs() < EPSILON {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = ray.origin() - p0;
+    let u = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(e1);
+    let v = ray.dir().dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = e2.dot(q) * inv_det;
+    if !ray_t.contains(t) {
+        return None;
+    }
+    Some((t, vec2(u, v)))
+}
 
-    pub fn new(min: f32, max: f32) -> Self {
-        Self { min, max }
+// Triangles sharing one vertex buffer, each face three indices into it and
+// an index into the mesh's materials, with a BVH over the faces.
+pub struct Mesh {
+    positions: Vec<Point3>,
+    // Per-vertex shading normals, colors and texture coordinates, or empty.
+    normals: Vec<Vec3>,
+    colors: Vec<Color3>,
+    uvs: Vec<Vec2>,
+    faces: Vec<[u32; 3]>,
+    face_materials: Vec<u32>,
+    materials: Vec<Material>,
+    nodes: Vec<BvhNode>,
+    // The emissive faces, and the running sum of their areas times the
+    // luminance of their emission, to sample them as lights.
+    light_faces: Vec<u32>,
+    light_cdf: Vec<f32>,
+}
+
+impl Mesh {
+    // `face_materials` may be empty when there is a single material.
+    pub fn new(
+        positions: Vec<Point3>,
+        faces: Vec<[u32; 3]>,
+        materials: Vec<Material>,
+        face_materials: Vec<u32>,
+    ) -> Result<Self> {
+        if let Some(face) = faces
+            .iter()
+            .find(|face| face.iter().any(|&idx| idx as usize >= positions.len()))
+        {
+            bail!(
+                "face {:?} refers past the {} vertices",
+                face,
+                positions.len()
+            );
+        }
+        let face_materials = if face_materials.is_empty() && materials.len() == 1 {
+            vec![0; faces.len()]
+        } else {
+            face_materials
+        };
+        if face_materials.len() != faces.len() {
+            bail!(
+                "{} faces but {} face materials",
+                faces.len(),
+                face_materials.len()
+            );
+        }
+        if let Some(idx) = face_materials
+            .iter()
+            .find(|&&idx| idx as usize >= materials.len())
+        {
+            bail!(
+                "face material {} but only {} materials",
+                idx,
+                materials.len()
+            );
+        }
+
+        let mut items: Vec<(Aabb, ([u32; 3], u32))> = faces
+            .into_iter()
+            .zip(face_materials)
+            .map(|(face, mat)| {
+                let [a, b, c] = face.map(|idx| positions[idx as usize]);
+                (Aabb::new(a, b).union(Aabb::new(c, c)).padded(), (face, mat))
+            })
+            .collect();
+        let nodes = build_nodes(&mut items, BvhQuality::Sah);
+        let (faces, face_materials): (Vec<_>, Vec<_>) =
+            items.into_iter().map(|(_, face)| face).unzip();
+
+        let (mut light_faces, mut light_cdf) = (vec![], vec![]);
+        let mut total = 0.0;
+        for (idx, (face, &mat)) in faces.iter().zip(&face_materials).enumerate() {
+            let [a, b, c] = face.map(|v| positions[v as usize]);
+            let weight =
+                luminance(materials[mat as usize].emitted()) * (b - a).cross(c - a).length() / 2.0;
+            if weight > 0.0 {
+                total += weight;
+                light_faces.push(idx as u32);
+                light_cdf.push(total);
+            }
+        }
+        Ok(Self {
+            positions,
+            normals: vec![],
+            colors: vec![],
+            uvs: vec![],
+            faces,
+            face_materials,
+            materials,
+            nodes,
+            light_faces,
+            light_cdf,
+        })
     }
 
-    fn contains(&self, val: f32) -> bool {
-        self.min <= val && val <= self.max
+    // Whether any face emits light, so the mesh can be sampled as a light.
+    pub fn is_emissive(&self) -> bool {
+        !self.light_faces.is_empty()
     }
 
-    fn surrounds(&self, val: f32) -> bool {
-        self.min < val && val < self.max
+    // Normals interpolated across faces for smooth shading.
+    pub fn with_normals(mut self, normals: Vec<Vec3>) -> Result<Self> {
+        if normals.len() != self.positions.len() {
+            bail!(
+                "{} normals for {} vertices",
+                normals.len(),
+                self.positions.len()
+            );
+        }
+        self.normals = normals;
+        Ok(self)
+    }
+
+    pub fn with_colors(mut self, colors: Vec<Color3>) -> Result<Self> {
+        if colors.len() != self.positions.len() {
+            bail!(
+                "{} colors for {} vertices",
+                colors.len(),
+                self.positions.len()
+            );
+        }
+        self.colors = colors;
+        Ok(self)
+    }
+
+    pub fn with_uvs(mut self, uvs: Vec<Vec2>) -> Result<Self> {
+        if uvs.len() != self.positions.len() {
+            bail!(
+                "{} texture coordinates for {} vertices",
+                uvs.len(),
+                self.positions.len()
+            );
+        }
+        self.uvs = uvs;
+        Ok(self)
+    }
+}
+
+impl Hittable for Mesh {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        traverse(&self.nodes, ray, ray_t, |range, ray_t| {
+            let mut closest = None;
+            let mut closest_t = ray_t.max;
+            for idx in range {
+                let [a, b, c] = self.faces[idx].map(|v| self.positions[v as usize]);
+                let interval = Interval::new(ray_t.min, closest_t);
+                if let Some((t, uv)) = intersect_triangle(a, b - a, c - a, ray, interval) {
+                    closest_t = t;
+                    closest = Some((idx, t, uv));
+                }
+            }
+            // Only the closest face needs a normal.
+            let (idx, t, uv) = closest?;
+            let face = self.faces[idx].map(|v| v as usize);
+            let [a, b, c] = face.map(|v| self.positions[v]);
+            let normal = (b - a).cross(c - a).normalize();
+            let material = &self.materials[self.face_materials[idx] as usize];
+            let mut hit = Hit::new(ray.at(t), normal, ray, t, material)
+                .with_uv(uv)
+                .with_uv_density(1.0 / vec2((b - a).length(), (c - a).length()));
+            let weights = [1.0 - uv.x - uv.y, uv.x, uv.y];
+            let interpolate =
+                |values: &[Vec3]| (0..3).map(|k| values[face[k]] * weights[k]).sum::<Vec3>();
+            if !self.normals.is_empty() {
+                // Vertices without normals are zero, leaving faces made of
+                // them flat.
+                let shading = interpolate(&self.normals).normalize_or_zero();
+                if shading != Vec3::ZERO {
+                    hit = hit.with_shading_normal(shading);
+                }
+            }
+            if !self.colors.is_empty() {
+                hit.color = interpolate(&self.colors);
+            }
+            if !self.uvs.is_empty() {
+                hit.uv = (0..3).map(|k| self.uvs[face[k]] * weights[k]).sum();
+                // The same everywhere on the face, as the square root of how
+                // much it is scaled by in area.
+                let [uv_a, uv_b, uv_c] = face.map(|v| self.uvs[v]);
+                let uv_area = (uv_b - uv_a).perp_dot(uv_c - uv_a).abs();
+                let area = (b - a).cross(c - a).length();
+                hit.uv_density = Vec2::splat((uv_area / area).sqrt());
+            }
+            Some(hit)
+        })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::EMPTY, |node| *node.bbox())
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.positions.capacity() * std::mem::size_of::<Point3>()
+            + self.normals.capacity() * std::mem::size_of::<Vec3>()
+            + self.colors.capacity() * std::mem::size_of::<Color3>()
+            + self.uvs.capacity() * std::mem::size_of::<Vec2>()
+            + self.faces.capacity() * std::mem::size_of::<[u32; 3]>()
+            + self.face_materials.capacity() * std::mem::size_of::<u32>()
+            + self.materials.capacity() * std::mem::size_of::<Material>()
+            + self.nodes.capacity() * std::mem::size_of::<BvhNode>()
+            + self.light_faces.capacity() * std::mem::size_of::<u32>()
+            + self.light_cdf.capacity() * std::mem::size_of::<f32>()
+    }
+
+    // Faces are picked in proportion to their area times the luminance of
+    // their emission, then a point uniformly on the face. Over area, the
+    // density is then the luminance of the face hit over the total, so the
+    // hit alone gives it.
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        let Some(&total) = self.light_cdf.last() else {
+            return 0.0;
+        };
+        match self.hit(&Ray::new(origin, dir), LIGHT_RAY_T) {
+            Some(hit) => match luminance(hit.material.emitted()) {
+                lum if lum > 0.0 => solid_angle_pdf(dir, hit.t, hit.normal, total / lum),
+                _ => 0.0,
+            },
+            None => 0.0,
+        }
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        let Some(&total) = self.light_cdf.last() else {
+            return Vec3::X;
+        };
+        let u = random::f32() * total;
+        let idx = self.light_cdf.partition_point(|&c| c <= u);
+        let face = self.faces[self.light_faces[idx.min(self.light_faces.len() - 1)] as usize];
+        let [a, b, c] = face.map(|v| self.positions[v as usize]);
+        let (s, t) = (random::f32().sqrt(), random::f32());
+        a + s * (1.0 - t) * (b - a) + s * t * (c - a) - origin
+    }
+}
+
+// Cubic cells of edge `size` in a box from `min`, stored one byte each in
+// x, then y, then z order: 0 is empty, n is filled with `materials[n - 1]`.
+// Rays step from cell to cell (Amanatides and Woo's DDA), so the cost of a
+// hit doesn't depend on how many cells are filled.
+pub struct VoxelGrid {
+    min: Point3,
+    size: f32,
+    dims: [u32; 3],
+    cells: Vec<u8>,
+    materials: Vec<Material>,
+}
+
+impl VoxelGrid {
+    pub fn new(
+        min: Point3,
+        size: f32,
+        dims: [u32; 3],
+        cells: Vec<u8>,
+        materials: Vec<Material>,
+    ) -> Result<Self> {
+        if size.is_nan() || size <= 0.0 {
+            bail!("voxel size must be positive");
+        }
+        if dims.contains(&0) {
+            bail!("voxel grid dimensions must be positive");
+        }
+        let count = dims.iter().map(|&d| d as usize).product::<usize>();
+        if cells.len() != count {
+            bail!(
+                "{}x{}x{} voxels but {} cells",
+                dims[0],
+                dims[1],
+                dims[2],
+                cells.len()
+            );
+        }
+        if let Some(&value) = cells.iter().find(|&&v| v as usize > materials.len()) {
+            bail!(
+                "voxel material {} but only {} materials",
+                value,
+                materials.len()
+            );
+        }
+        Ok(Self {
+            min,
+            size,
+            dims,
+            cells,
+            materials,
+        })
+    }
+
+    fn cell(&self, [x, y, z]: [i32; 3]) -> u8 {
+        let [w, h, _] = self.dims.map(|d| d as usize);
+        self.cells[x as usize + w * (y as usize + h * z as usize)]
+    }
+}
+
+impl Hittable for VoxelGrid {
+    // The hit is the first cell boundary where the contents change, seen
+    // from the cell the ray starts in: entering a filled cell from an empty
+    // one, or leaving the filled region (or material) the ray starts in, as
+    // refracted rays do.
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let bounds = self.bounding_box();
+        let (origin, dir) = (ray.origin(), ray.dir());
+        let inv_dir = dir.recip();
+        let t0 = (bounds.min - origin) * inv_dir;
+        let t1 = (bounds.max - origin) * inv_dir;
+        let (near, far) = (t0.min(t1), t0.max(t1));
+        let (t_enter, t_exit) = (near.max_element(), far.min_element());
+        if t_enter > t_exit || t_exit < ray_t.min || t_enter > ray_t.max {
+            return None;
+        }
+
+        let outside = t_enter >= ray_t.min;
+        let mut t = if outside { t_enter } else { ray_t.min };
+        let mut axis = (0..3).find(|&a| near[a] == t_enter).unwrap_or(0);
+        let local = (ray.at(t) - self.min) / self.size;
+        let mut cell =
+            [0, 1, 2].map(|a| (local[a].floor() as i32).clamp(0, self.dims[a] as i32 - 1));
+        // By the sign of the inverse direction, so that along a -0.0
+        // component, whose inverse is -inf, the next boundary is at t = +inf.
+        let step = [0, 1, 2].map(|a| if inv_dir[a] < 0.0 { -1 } else { 1 });
+        let mut t_next = [0, 1, 2].map(|a| {
+            let boundary = self.min[a] + (cell[a] + (step[a] > 0) as i32) as f32 * self.size;
+            let t = (boundary - origin[a]) * inv_dir[a];
+            if t.is_nan() {
+                f32::INFINITY
+            } else {
+                t
+            }
+        });
+        let t_delta = [0, 1, 2].map(|a| (self.size * inv_dir[a]).abs());
+        let start = if outside { 0 } else { self.cell(cell) };
+
+        // The surface between a cell holding `start` and the next one along
+        // the ray, across `axis`, belongs to whichever of the two is filled,
+        // the first if both are.
+        let hit = |t: f32, axis: usize, next: u8| {
+            let (value, sign) = match start {
+                0 => (next, -step[axis] as f32),
+                _ => (start, step[axis] as f32),
+            };
+            let mut normal = Vec3::ZERO;
+            normal[axis] = sign;
+            let p = ray.at(t);
+            // Coordinates across the face, in cell units.
+            let local = (p - self.min) / self.size;
+            let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+            let uv = vec2(local[a].rem_euclid(1.0), local[b].rem_euclid(1.0));
+            let material = &self.materials[value as usize - 1];
+            Some(Hit::new(p, normal, ray, t, material).with_uv(uv))
+        };
+
+        loop {
+            let value = self.cell(cell);
+            if value != start {
+                return hit(t, axis, value);
+            }
+            axis = (0..3)
+                .min_by(|&a, &b| t_next[a].total_cmp(&t_next[b]))
+                .unwrap();
+            t = t_next[axis];
+            if t > ray_t.max {
+                return None;
+            }
+            cell[axis] += step[axis];
+            if cell[axis] < 0 || cell[axis] >= self.dims[axis] as i32 {
+                return match start {
+                    0 => None,
+                    _ => hit(t, axis, 0),
+                };
+            }
+            t_next[axis] += t_delta[axis];
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let dims = Vec3::from_array(self.dims.map(|d| d as f32));
+        Aabb::new(self.min, self.min + dims * self.size)
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.cells.capacity()
+            + self.materials.capacity() * std::mem::size_of::<Material>()
+    }
+}
+
+// Surface spanned by four corners that need not be coplanar: every point is
+// a bilinear blend of the corners, so a planar parallelogram is just a Quad.
+pub struct BilinearPatch {
+    p00: Point3,
+    p10: Point3,
+    p01: Point3,
+    p11: Point3,
+    mat: Material,
+}
+
+impl BilinearPatch {
+    pub fn new(p00: Point3, p10: Point3, p01: Point3, p11: Point3, mat: Material) -> Self {
+        Self {
+            p00,
+            p10,
+            p01,
+            p11,
+            mat,
+        }
+    }
+}
+
+impl Hittable for BilinearPatch {
+    // Solves the quadratic in u for where the ray meets the line
+    // p(u, v) = lerp(lerp(p00, p10, u), lerp(p01, p11, u), v), following
+    // Reshetov, "Cool Patches", Ray Tracing Gems (2019).
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let d = ray.dir();
+        let q00 = self.p00 - ray.origin();
+        let q10 = self.p10 - ray.origin();
+        let e10 = self.p10 - self.p00;
+        let e11 = self.p11 - self.p10;
+        let e00 = self.p01 - self.p00;
+        let qn = e10.cross(self.p01 - self.p11);
+
+        let a = q00.cross(d).dot(e00);
+        let c = qn.dot(d);
+        let b = q10.cross(d).dot(e11) - (a + c);
+        let det = b * b - 4.0 * a * c;
+        if det < 0.0 {
+            return None;
+        }
+        let det = det.sqrt();
+        let (u1, u2) = if c == 0.0 {
+            (-a / b, -1.0)
+        } else {
+            // Numerically stable roots of c u^2 + b u + a.
+            let u1 = (-b - det.copysign(b)) / 2.0;
+            (u1 / c, a / u1)
+        };
+
+        let mut closest: Option<(f32, f32, f32)> = None;
+        for u in [u1, u2] {
+            if !(0.0..=1.0).contains(&u) {
+                continue;
+            }
+            let pa = q00 + e10 * u;
+            // Direction of the v line at this u.
+            let pb = e00 + (e11 - e00) * u;
+            let n = d.cross(pb);
+            let n2 = n.length_squared();
+            let n = n.cross(pa);
+            let t = n.dot(pb) / n2;
+            let v = n.dot(d) / n2;
+            let nearer = closest.is_none_or(|(best, _, _)| t < best);
+            if (0.0..=1.0).contains(&v) && ray_t.surrounds(t) && nearer {
+                closest = Some((t, u, v));
+            }
+        }
+
+        let (t, u, v) = closest?;
+        let du = e10 + (self.p11 - self.p01 - e10) * v;
+        let dv = e00 + (e11 - e00) * u;
+        let outward_normal = du.cross(dv).normalize();
+        Some(Hit::new(ray.at(t), outward_normal, ray, t, &self.mat).with_uv(vec2(u, v)))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::new(self.p00, self.p11)
+            .union(Aabb::new(self.p10, self.p01))
+            .padded()
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+}
+
+pub type HittableVec = Vec<Box<dyn Hittable>>;
+
+impl Hittable for HittableVec {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let mut closest_hit = None;
+        let mut closest_t = ray_t.max;
+
+        for obj in self {
+            if let Some(hit) = obj.hit(ray, Interval::new(ray_t.min, closest_t)) {
+                closest_t = hit.t;
+                closest_hit = Some(hit);
+            }
+        }
+        closest_hit
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.iter()
+            .fold(Aabb::EMPTY, |acc, obj| acc.union(obj.bounding_box()))
+    }
+
+    fn bounded_box(&self) -> Aabb {
+        self.iter()
+            .map(|obj| obj.bounded_box())
+            .filter(Aabb::is_finite)
+            .fold(Aabb::EMPTY, Aabb::union)
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.capacity() * std::mem::size_of::<Box<dyn Hittable>>()
+            + self.iter().map(|obj| obj.memory()).sum::<usize>()
+    }
+
+    // Picks one of the objects uniformly.
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        let sum: f32 = self.iter().map(|obj| obj.pdf_value(origin, dir)).sum();
+        sum / self.len().max(1) as f32
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        match self.len() {
+            0 => Vec3::X,
+            len => self[((random::f32() * len as f32) as usize).min(len - 1)].random(origin),
+        }
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        let children = self
+            .iter()
+            .map(|obj| obj.to_json())
+            .collect::<Option<_>>()?;
+        Some(Json::object([
+            ("type", "group".into()),
+            ("children", Json::Array(children)),
+        ]))
+    }
+}
+
+// Lets one object be both part of the world and among the lights.
+impl<T: Hittable + ?Sized> Hittable for Arc<T> {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        (**self).hit(ray, ray_t)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        (**self).bounding_box()
+    }
+
+    fn bounded_box(&self) -> Aabb {
+        (**self).bounded_box()
+    }
+
+    fn memory(&self) -> usize {
+        (**self).memory()
+    }
+
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        (**self).pdf_value(origin, dir)
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        (**self).random(origin)
+    }
+}
+
+// Lets wrappers generic over what they hold, such as Transform, hold any
+// object.
+impl<T: Hittable + ?Sized> Hittable for Box<T> {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        (**self).hit(ray, ray_t)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        (**self).bounding_box()
+    }
+
+    fn bounded_box(&self) -> Aabb {
+        (**self).bounded_box()
+    }
+
+    fn memory(&self) -> usize {
+        (**self).memory()
+    }
+
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        (**self).pdf_value(origin, dir)
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        (**self).random(origin)
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        (**self).to_json()
+    }
+}
+
+// Bounding volume hierarchy over a set of objects, stored as a flat array of
+// nodes in depth-first order: an interior node's left child directly follows
+// it, and its right child is at `right`.
+pub struct Bvh {
+    objects: Vec<Box<dyn Hittable>>,
+    nodes: Vec<BvhNode>,
+    // Objects without finite bounds, such as planes, can't be sorted into
+    // the hierarchy; the last `unbounded` objects are tested on every ray.
+    unbounded: usize,
+    quality: BvhQuality,
+    // `sah_cost` when built, for `refit` to tell how much worse it got.
+    built_cost: f32,
+}
+
+pub(crate) enum BvhNode {
+    Leaf { bbox: Aabb, start: u32, count: u32 },
+    Interior { bbox: Aabb, right: u32, axis: u8 },
+}
+
+impl BvhNode {
+    pub(crate) fn bbox(&self) -> &Aabb {
+        match self {
+            BvhNode::Leaf { bbox, .. } | BvhNode::Interior { bbox, .. } => bbox,
+        }
+    }
+
+    pub(crate) fn bbox_mut(&mut self) -> &mut Aabb {
+        match self {
+            BvhNode::Leaf { bbox, .. } | BvhNode::Interior { bbox, .. } => bbox,
+        }
+    }
+
+    // The node's share of `sah_cost`, before dividing by the root's area.
+    pub(crate) fn cost(&self) -> f32 {
+        match self {
+            BvhNode::Leaf { bbox, count, .. } => bbox.surface_area() * *count as f32,
+            BvhNode::Interior { bbox, .. } => bbox.surface_area(),
+        }
+    }
+}
+
+// Leaves with at most this many objects aren't split further.
+const BVH_LEAF_SIZE: usize = 2;
+const BVH_BINS: usize = 12;
+// Traversal keeps the nodes still to visit in a fixed stack this deep, as
+// many as the deepest hierarchy can need.
+const BVH_STACK_SIZE: usize = 64;
+// Nodes this deep are split at the median whatever the quality, so a run of
+// lopsided SAH splits can't outgrow the stack: halving the at most 2^32
+// objects below takes another 31 levels.
+const BVH_SAH_DEPTH: usize = 32;
+// Refitted hierarchies are rebuilt once their `sah_cost` is this many times
+// what it was when they were built.
+pub(crate) const BVH_REBUILD_COST: f32 = 1.5;
+
+// How hard the BVH builder works. Median splits build fastest; the surface
+// area heuristic takes longer but traces faster, especially for uneven
+// scenes such as a few large walls around many small objects.
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub enum BvhQuality {
+    Median,
+    #[default]
+    Sah,
+}
+
+impl Bvh {
+    pub fn new(objects: HittableVec) -> Self {
+        Self::with_quality(objects, BvhQuality::default())
+    }
+
+    pub fn with_quality(objects: HittableVec, quality: BvhQuality) -> Self {
+        let (mut items, unbounded): (Vec<_>, Vec<_>) = objects
+            .into_iter()
+            .map(|obj| (obj.bounding_box(), obj))
+            .partition(|(bbox, _)| bbox.is_finite() || bbox.is_empty());
+        let nodes = build_nodes(&mut items, quality);
+        Self {
+            unbounded: unbounded.len(),
+            objects: items
+                .into_iter()
+                .chain(unbounded)
+                .map(|(_, obj)| obj)
+                .collect(),
+            quality,
+            built_cost: sah_cost(&nodes),
+            nodes,
+        }
+    }
+
+    // Lets `update` change every object, as between the frames of an
+    // animation, then fits the hierarchy's boxes to where the objects are
+    // now rather than building it again. The grouping it keeps suits the
+    // objects less the further they move, so once that makes its cost by the
+    // surface area heuristic BVH_REBUILD_COST times what it was, or objects
+    // gain or lose finite bounds, it is rebuilt instead. Returns whether it
+    // was.
+    pub fn refit(&mut self, update: impl FnMut(&mut Box<dyn Hittable>)) -> bool {
+        self.objects.iter_mut().for_each(update);
+        let bounded = self.objects.len() - self.unbounded;
+        let bounds_kept = self.objects.iter().enumerate().all(|(idx, obj)| {
+            let bbox = obj.bounding_box();
+            (bbox.is_finite() || bbox.is_empty()) == (idx < bounded)
+        });
+        if bounds_kept {
+            let objects = &self.objects;
+            refit_nodes(&mut self.nodes, |range| {
+                objects[range]
+                    .iter()
+                    .fold(Aabb::EMPTY, |acc, obj| acc.union(obj.bounding_box()))
+            });
+            if sah_cost(&self.nodes) <= BVH_REBUILD_COST * self.built_cost {
+                return false;
+            }
+        }
+        *self = Self::with_quality(std::mem::take(&mut self.objects), self.quality);
+        true
+    }
+
+    // The box around the objects with finite bounds: the size of the scene
+    // as far as framing it or scaling to it goes.
+    pub fn bounded_box(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::EMPTY, |node| *node.bbox())
+    }
+
+    fn unbounded(&self) -> &[Box<dyn Hittable>] {
+        &self.objects[self.objects.len() - self.unbounded..]
+    }
+
+    // Bytes taken by the hierarchy itself, on top of the objects.
+    pub fn node_memory(&self) -> usize {
+        self.nodes.capacity() * std::mem::size_of::<BvhNode>()
+    }
+}
+
+// Builds a hierarchy over anything with a bounding box, reordering `items`
+// so each leaf refers to a contiguous range of them.
+pub(crate) fn build_nodes<T>(items: &mut [(Aabb, T)], quality: BvhQuality) -> Vec<BvhNode> {
+    let mut nodes = vec![];
+    if !items.is_empty() {
+        split_nodes(items, 0, 0, quality, &mut nodes);
+    }
+    nodes
+}
+
+// Recomputes every box in a hierarchy from the leaves up, given the box
+// around each leaf's range of items.
+pub(crate) fn refit_nodes(nodes: &mut [BvhNode], leaf_box: impl Fn(Range<usize>) -> Aabb) {
+    // Children come after their parents, so backwards visits them first.
+    for idx in (0..nodes.len()).rev() {
+        let bbox = match nodes[idx] {
+            BvhNode::Leaf { start, count, .. } => {
+                leaf_box(start as usize..(start + count) as usize)
+            }
+            BvhNode::Interior { right, .. } => {
+                nodes[idx + 1].bbox().union(*nodes[right as usize].bbox())
+            }
+        };
+        *nodes[idx].bbox_mut() = bbox;
+    }
+}
+
+// The parent of every node but the root, which gets 0.
+pub(crate) fn parents(nodes: &[BvhNode]) -> Vec<u32> {
+    let mut parents = vec![0; nodes.len()];
+    for (idx, node) in nodes.iter().enumerate() {
+        if let BvhNode::Interior { right, .. } = node {
+            parents[idx + 1] = idx as u32;
+            parents[*right as usize] = idx as u32;
+        }
+    }
+    parents
+}
+
+// The expected cost of tracing a ray through a hierarchy by the surface area
+// heuristic, in object tests: each node is reached with the probability of
+// its area over the root's, and leaves test all of their objects.
+pub(crate) fn sah_cost(nodes: &[BvhNode]) -> f32 {
+    let root = nodes.first().map_or(0.0, |node| node.bbox().surface_area());
+    if !(root.is_finite() && root > 0.0) {
+        return 0.0;
+    }
+    nodes.iter().map(BvhNode::cost).sum::<f32>() / root
+}
+
+// Splits items (which start at `offset` in the final list) along the widest
+// axis of their centroids.
+fn split_nodes<T>(
+    items: &mut [(Aabb, T)],
+    offset: usize,
+    depth: usize,
+    quality: BvhQuality,
+    nodes: &mut Vec<BvhNode>,
+) {
+    let bbox = items
+        .iter()
+        .fold(Aabb::EMPTY, |acc, (bbox, _)| acc.union(*bbox));
+    let leaf = BvhNode::Leaf {
+        bbox,
+        start: offset as u32,
+        count: items.len() as u32,
+    };
+    if items.len() <= BVH_LEAF_SIZE {
+        nodes.push(leaf);
+        return;
+    }
+
+    let centroids = items.iter().fold(Aabb::EMPTY, |acc, (bbox, _)| {
+        let c = bbox.centroid();
+        acc.union(Aabb::new(c, c))
+    });
+    let extent = centroids.max - centroids.min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+    if extent[axis] <= 0.0 {
+        nodes.push(leaf);
+        return;
+    }
+
+    let quality = if depth < BVH_SAH_DEPTH {
+        quality
+    } else {
+        BvhQuality::Median
+    };
+    let mid = match quality {
+        BvhQuality::Median => {
+            let mid = items.len() / 2;
+            items.select_nth_unstable_by(mid, |(a, _), (b, _)| {
+                a.centroid()[axis].total_cmp(&b.centroid()[axis])
+            });
+            mid
+        }
+        BvhQuality::Sah => match sah_split(items, &bbox, &centroids, axis) {
+            Some(mid) => mid,
+            None => {
+                nodes.push(leaf);
+                return;
+            }
+        },
+    };
+    let node = nodes.len();
+    nodes.push(BvhNode::Interior {
+        bbox,
+        right: 0,
+        axis: axis as u8,
+    });
+    let (left, right) = items.split_at_mut(mid);
+    split_nodes(left, offset, depth + 1, quality, nodes);
+    let right_idx = nodes.len() as u32;
+    split_nodes(right, offset + mid, depth + 1, quality, nodes);
+    if let BvhNode::Interior { right, .. } = &mut nodes[node] {
+        *right = right_idx;
+    }
+}
+
+// Partitions items at the cheapest of the splits between BVH_BINS buckets of
+// centroids by the surface area heuristic, or returns None when no split
+// beats keeping them all in one leaf.
+fn sah_split<T>(
+    items: &mut [(Aabb, T)],
+    bbox: &Aabb,
+    centroids: &Aabb,
+    axis: usize,
+) -> Option<usize> {
+    let extent = centroids.max[axis] - centroids.min[axis];
+    let bin_of = |bbox: &Aabb| {
+        let rel = (bbox.centroid()[axis] - centroids.min[axis]) / extent;
+        ((rel * BVH_BINS as f32) as usize).min(BVH_BINS - 1)
+    };
+    let mut bins = [(Aabb::EMPTY, 0usize); BVH_BINS];
+    for (bbox, _) in items.iter() {
+        let bin = &mut bins[bin_of(bbox)];
+        bin.0 = bin.0.union(*bbox);
+        bin.1 += 1;
+    }
+    // Cost of splitting after each bin, up to a constant factor.
+    let mut best = (f32::INFINITY, 0);
+    for split in 1..BVH_BINS {
+        let side = |range: &[(Aabb, usize)]| {
+            range.iter().fold((Aabb::EMPTY, 0), |(bbox, count), bin| {
+                (bbox.union(bin.0), count + bin.1)
+            })
+        };
+        let (left, left_count) = side(&bins[..split]);
+        let (right, right_count) = side(&bins[split..]);
+        if left_count == 0 || right_count == 0 {
+            continue;
+        }
+        let cost =
+            left.surface_area() * left_count as f32 + right.surface_area() * right_count as f32;
+        if cost < best.0 {
+            best = (cost, split);
+        }
+    }
+    if best.0 >= bbox.surface_area() * items.len() as f32 {
+        return None;
+    }
+    Some(partition(items, |(bbox, _)| bin_of(bbox) < best.1))
+}
+
+// Finds the closest hit in a hierarchy; `leaf(range, ray_t)` returns the
+// closest hit among a leaf's items within `ray_t`.
+pub(crate) fn traverse<'a>(
+    nodes: &[BvhNode],
+    ray: &Ray,
+    ray_t: Interval,
+    leaf: impl Fn(Range<usize>, Interval) -> Option<Hit<'a>>,
+) -> Option<Hit<'a>> {
+    if nodes.is_empty() {
+        return None;
+    }
+    let inv_dir = ray.dir().recip();
+    let mut closest_hit = None;
+    let mut closest_t = ray_t.max;
+    let mut stack = [0u32; BVH_STACK_SIZE];
+    let mut len = 1;
+
+    while len > 0 {
+        len -= 1;
+        let idx = stack[len];
+        let node = &nodes[idx as usize];
+        if !node
+            .bbox()
+            .hit(ray.origin(), inv_dir, Interval::new(ray_t.min, closest_t))
+        {
+            continue;
+        }
+        match *node {
+            BvhNode::Leaf { start, count, .. } => {
+                let range = start as usize..(start + count) as usize;
+                if let Some(hit) = leaf(range, Interval::new(ray_t.min, closest_t)) {
+                    closest_t = hit.t;
+                    closest_hit = Some(hit);
+                }
+            }
+            BvhNode::Interior { right, axis, .. } => {
+                // Visit the nearer child first so its hits can cull the
+                // farther one.
+                let left = idx + 1;
+                let (near, far) = if inv_dir[axis as usize] < 0.0 {
+                    (right, left)
+                } else {
+                    (left, right)
+                };
+                stack[len] = far;
+                stack[len + 1] = near;
+                len += 2;
+            }
+        }
+    }
+    closest_hit
+}
+
+// Moves items matching `pred` to the front, returning how many there are.
+fn partition<T>(items: &mut [T], pred: impl Fn(&T) -> bool) -> usize {
+    let mut mid = 0;
+    for idx in 0..items.len() {
+        if pred(&items[idx]) {
+            items.swap(idx, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+impl Hittable for Bvh {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        // Unbounded objects go first, so their hits cull the traversal.
+        let mut unbounded = None;
+        let mut closest_t = ray_t.max;
+        for obj in self.unbounded() {
+            if let Some(hit) = obj.hit(ray, Interval::new(ray_t.min, closest_t)) {
+                closest_t = hit.t;
+                unbounded = Some(hit);
+            }
+        }
+        let ray_t = Interval::new(ray_t.min, closest_t);
+        let bounded = traverse(&self.nodes, ray, ray_t, |range, ray_t| {
+            let mut closest_hit = None;
+            let mut closest_t = ray_t.max;
+            for obj in &self.objects[range] {
+                if let Some(hit) = obj.hit(ray, Interval::new(ray_t.min, closest_t)) {
+                    closest_t = hit.t;
+                    closest_hit = Some(hit);
+                }
+            }
+            closest_hit
+        });
+        bounded.or(unbounded)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.unbounded()
+            .iter()
+            .fold(self.bounded_box(), |acc, obj| acc.union(obj.bounding_box()))
+    }
+
+    fn bounded_box(&self) -> Aabb {
+        Bvh::bounded_box(self)
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.objects.capacity() * std::mem::size_of::<Box<dyn Hittable>>()
+            + self.objects.iter().map(|obj| obj.memory()).sum::<usize>()
+            + self.node_memory()
+    }
+}
+
+// A scene file `node` with `transform` applied after any it already has.
+fn transformed(node: Json, transform: Json) -> Json {
+    let steps = match node.get("transform") {
+        None => return node.with("transform", transform),
+        // Translations come last within a transform object, so they can
+        // join one that has none.
+        Some(Json::Object(last))
+            if transform.get("translate").is_some() && !last.contains_key("translate") =>
+        {
+            let mut merged = last.clone();
+            if let Json::Object(translation) = transform {
+                merged.extend(translation);
+            }
+            return node.with("transform", Json::Object(merged));
+        }
+        Some(Json::Array(steps)) => steps.clone(),
+        Some(step) => vec![step.clone()],
+    };
+    node.with("transform", Json::Array([steps, vec![transform]].concat()))
+}
+
+pub struct Translate {
+    offset: Vec3,
+    object: Box<dyn Hittable>,
+}
+
+impl Translate {
+    pub fn new(offset: Vec3, object: Box<dyn Hittable>) -> Self {
+        Self { offset, object }
+    }
+}
+
+impl Hittable for Translate {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let offset_r = ray.moved(ray.origin() - self.offset, ray.dir());
+
+        match self.object.hit(&offset_r, ray_t) {
+            Some(mut hit) => {
+                hit.p += self.offset;
+                Some(hit)
+            }
+            None => None,
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let bbox = self.object.bounding_box();
+        Aabb {
+            min: bbox.min + self.offset,
+            max: bbox.max + self.offset,
+        }
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>() + self.object.memory()
+    }
+
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        self.object.pdf_value(origin - self.offset, dir)
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        self.object.random(origin - self.offset)
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(transformed(
+            self.object.to_json()?,
+            Json::object([("translate", self.offset.into())]),
+        ))
+    }
+}
+
+pub struct RotateY {
+    // In degrees.
+    angle: f32,
+    sin_theta: f32,
+    cos_theta: f32,
+    object: Box<dyn Hittable>,
+}
+
+impl RotateY {
+    pub fn new(angle: f32, object: Box<dyn Hittable>) -> Self {
+        let (sin_theta, cos_theta) = angle.to_radians().sin_cos();
+        RotateY {
+            angle,
+            sin_theta,
+            cos_theta,
+            object,
+        }
+    }
+
+    fn to_object(&self, v: Vec3) -> Vec3 {
+        vec3(
+            self.cos_theta * v.x - self.sin_theta * v.z,
+            v.y,
+            self.sin_theta * v.x + self.cos_theta * v.z,
+        )
+    }
+
+    fn to_world(&self, v: Vec3) -> Vec3 {
+        vec3(
+            self.cos_theta * v.x + self.sin_theta * v.z,
+            v.y,
+            -self.sin_theta * v.x + self.cos_theta * v.z,
+        )
+    }
+}
+
+impl Hittable for RotateY {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let rotated_r = ray.moved(self.to_object(ray.origin()), self.to_object(ray.dir()));
+
+        let mut hit = match self.object.hit(&rotated_r, ray_t) {
+            Some(hit) => hit,
+            None => {
+                return None;
+            }
+        };
+
+        hit.p = self.to_world(hit.p);
+        hit.normal = self.to_world(hit.normal);
+        hit.shading_normal = self.to_world(hit.shading_normal);
+        Some(hit)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let bbox = self.object.bounding_box();
+        if bbox.is_empty() {
+            return bbox;
+        }
+        // Corners at infinity don't transform.
+        if !bbox.is_finite() {
+            return Aabb::UNBOUNDED;
+        }
+        bbox.corners()
+            .into_iter()
+            .map(|c| self.to_world(c))
+            .fold(Aabb::EMPTY, |acc, p| acc.union(Aabb::new(p, p)))
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>() + self.object.memory()
+    }
+
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        self.object
+            .pdf_value(self.to_object(origin), self.to_object(dir))
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        self.to_world(self.object.random(self.to_object(origin)))
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(transformed(
+            self.object.to_json()?,
+            Json::object([("rotate_y", self.angle.into())]),
+        ))
+    }
+}
+
+// Any affine placement of an object: rotations about any axis, scaling and
+// their compositions as well as translations. Rays are moved into the
+// object's space; normals come back through the inverse transpose, so they
+// stay perpendicular to surfaces under uneven scaling.
+pub struct Transform<O = Box<dyn Hittable>> {
+    object: O,
+    to_world: Affine3A,
+    to_object: Affine3A,
+    normal_to_world: Mat3,
+    // How much the transform into object space scales volumes, for
+    // converting densities over directions.
+    to_object_det: f32,
+}
+
+impl<O: Hittable> Transform<O> {
+    // `to_world` must be invertible.
+    pub fn new(to_world: Affine3A, object: O) -> Self {
+        let to_object = to_world.inverse();
+        Self {
+            object,
+            to_world,
+            to_object,
+            normal_to_world: Mat3::from(to_object.matrix3).transpose(),
+            to_object_det: to_object.matrix3.determinant().abs(),
+        }
+    }
+
+    // `to_world` applied after this transform, as a single one.
+    pub fn then(self, to_world: Affine3A) -> Self {
+        Transform::new(to_world * self.to_world, self.object)
+    }
+}
+
+impl<O: Hittable> Hittable for Transform<O> {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        // The direction isn't renormalized, so t means the same in both
+        // spaces.
+        let local = ray.moved(
+            self.to_object.transform_point3(ray.origin()),
+            self.to_object.transform_vector3(ray.dir()),
+        );
+        let mut hit = self.object.hit(&local, ray_t)?;
+        hit.p = self.to_world.transform_point3(hit.p);
+        hit.normal = (self.normal_to_world * hit.normal).normalize();
+        hit.shading_normal = (self.normal_to_world * hit.shading_normal).normalize();
+        // Footprints back in world units.
+        let scale = ray.dir().length() / local.dir().length();
+        hit.footprint *= scale;
+        hit.uv_density /= scale;
+        Some(hit)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let bbox = self.object.bounding_box();
+        if bbox.is_empty() {
+            return bbox;
+        }
+        // Corners at infinity don't transform.
+        if !bbox.is_finite() {
+            return Aabb::UNBOUNDED;
+        }
+        bbox.corners()
+            .into_iter()
+            .map(|c| self.to_world.transform_point3(c))
+            .fold(Aabb::EMPTY, |acc, p| acc.union(Aabb::new(p, p)))
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>() + self.object.memory()
+    }
+
+    // A unit direction d maps to A d / |A d| in object space, which changes
+    // solid angles by |det A| / |A d|^3.
+    fn pdf_value(&self, origin: Point3, dir: Vec3) -> f32 {
+        let local = self.to_object.transform_vector3(dir.normalize());
+        let length = local.length();
+        self.object
+            .pdf_value(self.to_object.transform_point3(origin), local / length)
+            * self.to_object_det
+            / length.powi(3)
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        self.to_world
+            .transform_vector3(self.object.random(self.to_object.transform_point3(origin)))
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        let rows = (0..3)
+            .map(|row| {
+                let affine = Mat4::from(self.to_world).row(row);
+                Json::Array(affine.to_array().map(Json::from).to_vec())
+            })
+            .collect();
+        Some(transformed(
+            self.object.to_json()?,
+            Json::object([("matrix", Json::Array(rows))]),
+        ))
+    }
+}
+
+// A placement of shared geometry. Rays are moved into the geometry's own
+// space and traced through its prebuilt BVH, so any number of instances
+// share one copy of the objects and their hierarchy; a BVH over the
+// instances then forms the top level.
+pub struct Instance(Transform<Arc<Bvh>>);
+
+impl Instance {
+    pub fn new(object: Arc<Bvh>, to_world: Affine3A) -> Self {
+        Self(Transform::new(to_world, object))
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        self.0.hit(ray, ray_t)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.0.bounding_box()
+    }
+
+    // The shared geometry is split evenly between the instances using it, so
+    // it adds up to one copy.
+    fn memory(&self) -> usize {
+        let object = &self.0.object;
+        std::mem::size_of::<Self>() + object.memory() / Arc::strong_count(object)
+    }
+}
+
+// Smoke or fog filling a convex boundary. Rays travelling through it scatter
+// at random distances, more often the denser it is, off an isotropic phase
+// function with the medium's albedo. The density is constant unless a
+// density map scales it from point to point.
+pub struct Medium {
+    boundary: Box<dyn Hittable>,
+    neg_inv_density: f32,
+    // Fractions of the density, from the mean of its channels, clamped to 0
+    // to 1.
+    density_map: Option<Arc<dyn Texture>>,
+    phase_function: Material,
+}
+
+impl Medium {
+    pub fn new(boundary: Box<dyn Hittable>, density: f32, albedo: Arc<dyn Texture>) -> Self {
+        Self {
+            boundary,
+            neg_inv_density: -1.0 / density,
+            density_map: None,
+            phase_function: Material::Isotropic { albedo },
+        }
+    }
+
+    pub fn with_density_map(mut self, density_map: Arc<dyn Texture>) -> Self {
+        self.density_map = Some(density_map);
+        self
+    }
+
+    fn density_fraction(&self, p: Point3) -> f32 {
+        match self.density_map {
+            Some(ref map) => {
+                let value = map.value(Vec2::ZERO, p);
+                ((value.x + value.y + value.z) / 3.0).clamp(0.0, 1.0)
+            }
+            None => 1.0,
+        }
+    }
+}
+
+impl Hittable for Medium {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        // Where the ray's line enters and leaves the boundary, even behind
+        // the origin, so rays starting inside the medium work too.
+        let enter = self.boundary.hit(ray, Interval::UNIVERSE)?.t;
+        let ray_length = ray.dir().length();
+        let skip = self.boundary.bounding_box().ray_offset() / ray_length;
+        let exit = self
+            .boundary
+            .hit(ray, Interval::new(enter + skip, f32::INFINITY))?
+            .t;
+        let enter = enter.max(ray_t.min).max(0.0);
+        let exit = exit.min(ray_t.max);
+        if enter >= exit {
+            return None;
+        }
+
+        // Delta tracking: steps are drawn for the full density, and each
+        // collision is real with the fraction of it found there, or null and
+        // passed through. This is unbiased for any density map.
+        let distance_inside = (exit - enter) * ray_length;
+        let mut distance = 0.0;
+        loop {
+            distance += self.neg_inv_density * (1.0 - random::f32()).ln();
+            if distance > distance_inside {
+                return None;
+            }
+            let t = enter + distance / ray_length;
+            let p = ray.at(t);
+            if random::f32() < self.density_fraction(p) {
+                // Media have no surface; the normal is arbitrary.
+                return Some(Hit::new(p, Vec3::X, ray, t, &self.phase_function));
+            }
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.boundary.bounding_box()
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>() + self.boundary.memory()
+    }
+}
+
+pub fn make_box(a: Point3, b: Point3, mat: Material) -> HittableVec {
+    let min = point3(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
+    let max = point3(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+
+    let dx = vec3(max.x - min.x, 0.0, 0.0);
+    let dy = vec3(0.0, max.y - min.y, 0.0);
+    let dz = vec3(0.0, 0.0, max.z - min.z);
+
+    vec![
+        Box::new(Quad::new(point3(min.x, min.y, max.z), dx, dy, mat.clone())), // front
+        Box::new(Quad::new(point3(max.x, min.y, max.z), -dz, dy, mat.clone())), // right
+        Box::new(Quad::new(point3(max.x, min.y, min.z), -dx, dy, mat.clone())), // back
+        Box::new(Quad::new(point3(min.x, min.y, min.z), dz, dy, mat.clone())), // left
+        Box::new(Quad::new(point3(min.x, max.y, max.z), dx, -dz, mat.clone())), // top
+        Box::new(Quad::new(point3(min.x, min.y, min.z), dx, dz, mat)),         // bottom
+    ]
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Point3,
+    pub max: Point3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::INFINITY,
+        max: Vec3::NEG_INFINITY,
+    };
+    pub const UNBOUNDED: Aabb = Aabb {
+        min: Vec3::NEG_INFINITY,
+        max: Vec3::INFINITY,
+    };
+
+    pub fn new(a: Point3, b: Point3) -> Self {
+        Self {
+            min: a.min(b),
+            max: a.max(b),
+        }
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    // Empty if the boxes don't overlap.
+    pub fn intersection(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
+    // Grows flat boxes (such as a quad's) to a minimum thickness so rays
+    // grazing them still register.
+    pub fn padded(self) -> Aabb {
+        const DELTA: f32 = 1e-4;
+        let pad = Vec3::select(
+            (self.max - self.min).cmplt(Vec3::splat(DELTA)),
+            Vec3::splat(DELTA / 2.0),
+            Vec3::ZERO,
+        );
+        Aabb {
+            min: self.min - pad,
+            max: self.max + pad,
+        }
+    }
+
+    // How far rays leaving a surface inside the box start from it, so
+    // rounding errors in the hit point can't put them back behind it. Errors
+    // grow with the distances rays travel and with the size of the
+    // coordinates, so the offset is a fixed fraction of the box's diagonal
+    // or a few ulps of its farthest corner, whichever is larger. Millimeter
+    // and kilometer scale scenes render alike, wherever they are placed.
+    pub fn ray_offset(&self) -> f32 {
+        const RELATIVE: f32 = 1e-6;
+        const ULPS: f32 = 8.0;
+        // Used for empty or unbounded boxes.
+        const FALLBACK: f32 = 0.001;
+        let extent = (self.max - self.min).length() * RELATIVE;
+        let magnitude = self.min.abs().max(self.max.abs()).max_element();
+        match extent.max(magnitude * ULPS * f32::EPSILON) {
+            offset if offset.is_finite() && offset > 0.0 => offset,
+            _ => FALLBACK,
+        }
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = (self.max - self.min).max(Vec3::ZERO);
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    // Slab test: clips `ray_t` to the ray's overlap with each axis' slab.
+    // Takes the reciprocal direction so callers can compute it once per ray.
+    pub fn hit(&self, origin: Point3, inv_dir: Vec3, ray_t: Interval) -> bool {
+        let t0 = (self.min - origin) * inv_dir;
+        let t1 = (self.max - origin) * inv_dir;
+        let t_min = t0.min(t1).max_element().max(ray_t.min);
+        let t_max = t0.max(t1).min_element().min(ray_t.max);
+        t_min <= t_max
+    }
+
+    // The part of `ray_t` during which the ray is inside the box, if any.
+    pub fn clip(&self, origin: Point3, inv_dir: Vec3, ray_t: Interval) -> Option<Interval> {
+        let t0 = (self.min - origin) * inv_dir;
+        let t1 = (self.max - origin) * inv_dir;
+        let t_min = t0.min(t1).max_element().max(ray_t.min);
+        let t_max = t0.max(t1).min_element().min(ray_t.max);
+        (t_min <= t_max).then(|| Interval::new(t_min, t_max))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    // False for empty boxes too.
+    pub fn is_finite(&self) -> bool {
+        self.min.is_finite() && self.max.is_finite()
+    }
+
+    fn corners(&self) -> [Point3; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            point3(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            )
+        })
+    }
+
+    // Smallest sphere containing the box, as (center, radius).
+    pub fn bounding_sphere(&self) -> (Point3, f32) {
+        let center = (self.min + self.max) / 2.0;
+        (center, (self.max - center).length())
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Interval {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Interval {
+    const _EMPTY: Interval = Interval {
+        min: f32::INFINITY,
+        max: f32::NEG_INFINITY,
+    };
+
+    const UNIVERSE: Interval = Interval {
+        min: f32::NEG_INFINITY,
+        max: f32::INFINITY,
+    };
+
+    pub fn new(min: f32, max: f32) -> Self {
+        Self { min, max }
+    }
+
+    pub fn contains(&self, val: f32) -> bool {
+        self.min <= val && val <= self.max
+    }
+
+    pub fn surrounds(&self, val: f32) -> bool {
+        self.min < val && val < self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+    use crate::textures::SolidColor;
+
+    fn scene() -> HittableVec {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let mut world: HittableVec = vec![];
+        for idx in 0..200 {
+            random::seed_sample(1, idx, 0, 0);
+            let center = point3(
+                random::range(-10.0..10.0),
+                random::range(-10.0..10.0),
+                random::range(-10.0..10.0),
+            );
+            world.push(match idx % 3 {
+                0 => Box::new(Sphere::new(center, random::range(0.1..1.0), mat.clone())),
+                1 => Box::new(Quad::new(center, Vec3::X, Vec3::Z, mat.clone())),
+                _ => Box::new(RotateY::new(
+                    random::range(0.0..90.0),
+                    Box::new(make_box(center, center + Vec3::ONE, mat.clone())),
+                )),
+            });
+        }
+        // Tilted, so unbounded along every axis.
+        world.push(Box::new(
+            Plane::new(point3(0.0, -12.0, 0.0), vec3(0.3, 1.0, 0.1), mat).unwrap(),
+        ));
+        world
+    }
+
+    // Shoots a ray straight down at (x, z) on shapes lying in the y = 0 plane.
+    fn hits_at(shape: &Quad, x: f32, z: f32) -> bool {
+        let ray = Ray::new(point3(x, 1.0, z), -Vec3::Y);
+        shape
+            .hit(&ray, Interval::new(0.001, f32::INFINITY))
+            .is_some()
+    }
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        assert!(
+            actual.distance(expected) < 1e-5,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn sphere_ground_truth() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let sphere = Sphere::new(point3(0.0, 0.0, -5.0), 2.0, mat);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+
+        // Head on from outside: the near root, facing the ray.
+        let hit = sphere
+            .hit(&Ray::new(Point3::ZERO, -Vec3::Z), ray_t)
+            .unwrap();
+        assert_eq!(hit.t, 3.0);
+        assert_near(hit.p, point3(0.0, 0.0, -3.0));
+        assert_eq!(hit.normal, Vec3::Z);
+        assert!(hit.front_face);
+        // t is in units of the direction's length.
+        let hit = sphere.hit(&Ray::new(Point3::ZERO, -2.0 * Vec3::Z), ray_t);
+        assert_eq!(hit.unwrap().t, 1.5);
+
+        // From the center: the far side, seen from inside.
+        let ray = Ray::new(point3(0.0, 0.0, -5.0), -Vec3::Z);
+        let hit = sphere.hit(&ray, ray_t).unwrap();
+        assert_eq!(hit.t, 2.0);
+        assert_eq!(hit.normal, Vec3::Z);
+        assert!(!hit.front_face);
+
+        // Off center the near root is 5 - sqrt(r^2 - x^2).
+        let hit = sphere.hit(&Ray::new(point3(1.2, 0.0, 0.0), -Vec3::Z), ray_t);
+        assert!((hit.unwrap().t - 3.4).abs() < 1e-5);
+        assert!(sphere
+            .hit(&Ray::new(point3(2.01, 0.0, 0.0), -Vec3::Z), ray_t)
+            .is_none());
+
+        // The interval picks between the roots, or rules both out.
+        let ray = Ray::new(Point3::ZERO, -Vec3::Z);
+        let hit = sphere.hit(&ray, Interval::new(3.5, f32::INFINITY)).unwrap();
+        assert_eq!(hit.t, 7.0);
+        assert!(!hit.front_face);
+        assert!(sphere.hit(&ray, Interval::new(0.001, 2.9)).is_none());
+        assert!(sphere.hit(&ray, Interval::new(3.1, 6.9)).is_none());
+        assert!(sphere
+            .hit(&Ray::new(Point3::ZERO, Vec3::Z), ray_t)
+            .is_none());
+    }
+
+    #[test]
+    fn moving_sphere_follows_the_ray_time() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let sphere = Sphere::moving(Point3::ZERO, point3(4.0, 0.0, 0.0), 1.0, mat);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let ray = |x, time| Ray::new(point3(x, 0.0, 5.0), -Vec3::Z).with_time(time);
+        assert_eq!(sphere.hit(&ray(0.0, 0.0), ray_t).unwrap().t, 4.0);
+        assert!(sphere.hit(&ray(0.0, 1.0), ray_t).is_none());
+        assert!(sphere.hit(&ray(2.0, 0.0), ray_t).is_none());
+        let hit = sphere.hit(&ray(2.0, 0.5), ray_t).unwrap();
+        assert_eq!(hit.t, 4.0);
+        assert_eq!(hit.normal, Vec3::Z);
+        assert_eq!(sphere.hit(&ray(4.0, 1.0), ray_t).unwrap().t, 4.0);
+        // The box holds the whole path.
+        let bbox = sphere.bounding_box();
+        assert_eq!((bbox.min, bbox.max), (-Vec3::ONE, point3(5.0, 1.0, 1.0)));
+    }
+
+    #[test]
+    fn quad_edges_and_corners() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let quad = Quad::new(Point3::ZERO, Vec3::X, Vec3::Y, mat);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let hit_at = |x, y| quad.hit(&Ray::new(point3(x, y, 2.0), -Vec3::Z), ray_t);
+
+        // Edges and corners are inside.
+        for (x, y) in [(0.0, 0.0), (1.0, 1.0), (1.0, 0.0), (0.5, 0.0), (0.0, 0.5)] {
+            let hit = hit_at(x, y).unwrap();
+            assert_eq!(hit.t, 2.0);
+            assert_eq!(hit.uv, vec2(x, y));
+            assert_eq!(hit.normal, Vec3::Z);
+            assert!(hit.front_face);
+        }
+        for (x, y) in [(1.001, 0.5), (0.5, -0.001), (-0.001, -0.001), (0.5, 1.001)] {
+            assert!(hit_at(x, y).is_none(), "hit at ({}, {})", x, y);
+        }
+
+        // From behind, the normal turns to face the ray.
+        let hit = quad.hit(&Ray::new(point3(0.5, 0.5, -1.0), Vec3::Z), ray_t);
+        let hit = hit.unwrap();
+        assert_eq!(hit.normal, -Vec3::Z);
+        assert!(!hit.front_face);
+
+        // Parallel to the plane, in it or not, and pointing away.
+        assert!(quad
+            .hit(&Ray::new(point3(-1.0, 0.5, 0.0), Vec3::X), ray_t)
+            .is_none());
+        assert!(quad
+            .hit(&Ray::new(point3(0.5, 0.5, 1.0), Vec3::X), ray_t)
+            .is_none());
+        assert!(quad
+            .hit(&Ray::new(point3(0.5, 0.5, 1.0), Vec3::Z), ray_t)
+            .is_none());
+    }
+
+    #[test]
+    fn rotated_box_corners() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let unit_box = || Box::new(make_box(Point3::ZERO, Vec3::ONE, mat.clone()));
+
+        // A quarter turn takes (x, z) to (z, -x), so the box spans x in
+        // [0, 1] and z in [-1, 0].
+        let quarter = RotateY::new(90.0, unit_box());
+        let hit = quarter
+            .hit(&Ray::new(point3(-5.0, 0.5, -0.5), Vec3::X), ray_t)
+            .unwrap();
+        assert!((hit.t - 5.0).abs() < 1e-5);
+        assert_near(hit.normal, -Vec3::X);
+        let hit = quarter
+            .hit(&Ray::new(point3(0.5, 5.0, -0.5), -Vec3::Y), ray_t)
+            .unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-5);
+        assert_near(hit.normal, Vec3::Y);
+        assert!(quarter
+            .hit(&Ray::new(point3(0.5, 5.0, 0.5), -Vec3::Y), ray_t)
+            .is_none());
+
+        // An eighth turn puts the (1, 1) corner at x = sqrt(2), z = 0, between
+        // faces whose normals point 45 degrees either side of +x.
+        let eighth = RotateY::new(45.0, unit_box());
+        let corner = 2f32.sqrt();
+        let hit_at = |z| eighth.hit(&Ray::new(point3(5.0, 0.5, z), -Vec3::X), ray_t);
+        let hit = hit_at(0.0).unwrap();
+        assert!((hit.t - (5.0 - corner)).abs() < 1e-4);
+        let s = 0.5f32.sqrt();
+        assert_near(hit_at(0.01).unwrap().normal, vec3(s, 0.0, s));
+        assert_near(hit_at(-0.01).unwrap().normal, vec3(s, 0.0, -s));
+        assert_near(hit_at(0.01).unwrap().p, point3(corner - 0.01, 0.5, 0.01));
+        assert!(hit_at(0.72).is_none());
+
+        // Rotation keeps normals unit length and horizontal ones horizontal.
+        let odd = RotateY::new(30.0, unit_box());
+        let hit = odd
+            .hit(&Ray::new(point3(0.5, 0.5, 5.0), -Vec3::Z), ray_t)
+            .unwrap();
+        assert!((hit.normal.length() - 1.0).abs() < 1e-6);
+        assert_eq!(hit.normal.y, 0.0);
+        assert_near(hit.shading_normal, hit.normal);
+    }
+
+    #[test]
+    fn planar_shapes() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let ellipse = Quad::ellipse(Point3::ZERO, Vec3::X * 2.0, Vec3::Z, mat.clone());
+        assert!(hits_at(&ellipse, 1.99, 0.0));
+        assert!(hits_at(&ellipse, 0.0, -0.99));
+        assert!(!hits_at(&ellipse, 1.5, 0.9));
+
+        // Flat-topped along z: vertices at x = +-1, edges at z = +-cos(30°).
+        let hexagon = Quad::polygon(Point3::ZERO, Vec3::X, Vec3::Z, 6, mat.clone());
+        let apothem = 3f32.sqrt() / 2.0;
+        assert!(hits_at(&hexagon, 0.99, 0.0));
+        assert!(hits_at(&hexagon, 0.0, apothem - 0.01));
+        assert!(!hits_at(&hexagon, 0.0, -apothem - 0.01));
+        assert!(!hits_at(&hexagon, 0.9, 0.5));
+
+        let ring = Quad::disk(Point3::ZERO, Vec3::Y, 2.0, 1.0, mat.clone()).unwrap();
+        assert!(hits_at(&ring, 1.5, 0.0));
+        assert!(hits_at(&ring, 0.0, -1.99));
+        assert!(!hits_at(&ring, 0.5, 0.5));
+        assert!(!hits_at(&ring, 1.5, 1.5));
+        assert!(Quad::disk(Point3::ZERO, Vec3::Y, 1.0, 1.0, mat).is_err());
+    }
+
+    #[test]
+    fn bilinear_patch_ground_truth() {
+        // The saddle z = x y over the unit square.
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let patch = BilinearPatch::new(Point3::ZERO, Vec3::X, Vec3::Y, point3(1.0, 1.0, 1.0), mat);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+
+        let hit = patch
+            .hit(&Ray::new(point3(0.5, 0.5, 5.0), -Vec3::Z), ray_t)
+            .unwrap();
+        assert!((hit.t - 4.75).abs() < 1e-5, "{}", hit.t);
+        assert_near(hit.p, point3(0.5, 0.5, 0.25));
+        // The cross product of the tangents (1, 0, v) and (0, 1, u).
+        assert_near(hit.normal, vec3(-0.5, -0.5, 1.0).normalize());
+        assert!(hit.front_face);
+        assert_near(hit.uv.extend(0.0), vec3(0.5, 0.5, 0.0));
+
+        // Along a ruling of the saddle, which a single plane can't hold.
+        let hit = patch
+            .hit(&Ray::new(point3(0.8, 0.25, -5.0), Vec3::Z), ray_t)
+            .unwrap();
+        assert_near(hit.p, point3(0.8, 0.25, 0.2));
+        assert!(!hit.front_face);
+        assert!(patch
+            .hit(&Ray::new(point3(1.1, 0.5, 5.0), -Vec3::Z), ray_t)
+            .is_none());
+    }
+
+    #[test]
+    fn planes_are_found_outside_the_hierarchy() {
+        let mat = || Material::new_lambertian(1.0, 1.0, 1.0);
+        let ground = Plane::new(Point3::ZERO, Vec3::Y, mat()).unwrap();
+        let bbox = ground.bounding_box();
+        assert!(bbox.max.y < 1e-3 && bbox.min.y > -1e-3 && bbox.max.x == f32::INFINITY);
+
+        let sphere = Sphere::new(point3(0.0, 1.0, 0.0), 0.5, mat());
+        let world = Bvh::new(vec![Box::new(ground), Box::new(sphere)]);
+        assert!(world.bounded_box().is_finite());
+        assert!(!world.bounding_box().is_finite());
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let t_at = |origin: Point3| {
+            let ray = Ray::new(origin, -Vec3::Y);
+            world.hit(&ray, ray_t).map(|hit| hit.t)
+        };
+        // The sphere is in front of the plane; far from it only the plane is.
+        assert_eq!(t_at(point3(0.0, 3.0, 0.0)), Some(1.5));
+        assert_eq!(t_at(point3(1e4, 3.0, -1e4)), Some(3.0));
+
+        let tilted = RotateY::new(
+            30.0,
+            Box::new(Plane::new(Point3::ZERO, Vec3::X, mat()).unwrap()),
+        );
+        assert!(!tilted.bounding_box().is_finite());
+        let ray = Ray::new(point3(5.0, 0.0, 0.0), -Vec3::X);
+        assert!(tilted.hit(&ray, ray_t).is_some());
+    }
+
+    #[test]
+    fn texture_coordinates() {
+        let mat = || Material::new_lambertian(1.0, 1.0, 1.0);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let uv_at = |object: &dyn Hittable, origin: Point3| {
+            let hit = object.hit(&Ray::new(origin, -origin), ray_t).unwrap();
+            hit.uv
+        };
+
+        let sphere = Sphere::new(Point3::ZERO, 2.0, mat());
+        assert!(uv_at(&sphere, point3(5.0, 0.0, 0.0)).distance(vec2(0.5, 0.5)) < 1e-6);
+        assert!(uv_at(&sphere, point3(0.0, 0.0, 5.0)).distance(vec2(0.25, 0.5)) < 1e-6);
+        assert!(uv_at(&sphere, point3(0.0, 0.0, -5.0)).distance(vec2(0.75, 0.5)) < 1e-6);
+        assert!((uv_at(&sphere, point3(0.0, 5.0, 0.0)).y - 1.0).abs() < 1e-6);
+
+        let quad = Quad::new(point3(-1.0, -1.0, 0.0), 4.0 * Vec3::X, 2.0 * Vec3::Y, mat());
+        assert!(uv_at(&quad, point3(0.0, 0.0, 3.0)).distance(vec2(0.25, 0.5)) < 1e-6);
+        let ellipse = Quad::ellipse(point3(1.0, 0.0, 0.0), Vec3::X, Vec3::Y, mat());
+        assert!(uv_at(&ellipse, point3(0.0, 0.0, 3.0)).distance(vec2(0.0, 0.5)) < 1e-6);
+
+        // Vertex coordinates are interpolated across a mesh face.
+        let positions = vec![
+            point3(-1.0, -1.0, 0.0),
+            point3(1.0, -1.0, 0.0),
+            point3(-1.0, 1.0, 0.0),
+        ];
+        let uvs = vec![vec2(0.5, 0.5), vec2(1.0, 0.5), vec2(0.5, 1.0)];
+        let mesh = Mesh::new(positions, vec![[0, 1, 2]], vec![mat()], vec![])
+            .unwrap()
+            .with_uvs(uvs)
+            .unwrap();
+        assert!(uv_at(&mesh, point3(0.0, 0.0, 3.0)).distance(vec2(0.75, 0.75)) < 1e-6);
+    }
+
+    #[test]
+    fn triangle_barycentrics() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let triangle = Triangle::new(Point3::ZERO, Vec3::X, Vec3::Z, mat);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let hit_at = |x, z| triangle.hit(&Ray::new(point3(x, 2.0, z), -Vec3::Y), ray_t);
+
+        let hit = hit_at(0.25, 0.5).unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-6);
+        assert!(hit.uv.distance(vec2(0.25, 0.5)) < 1e-6);
+        assert_eq!(hit.normal, Vec3::Y);
+        assert!(hit_at(0.6, 0.6).is_none());
+        assert!(hit_at(-0.1, 0.5).is_none());
+    }
+
+    #[test]
+    fn mesh_matches_triangles() {
+        let red = Material::new_lambertian(1.0, 0.0, 0.0);
+        let blue = Material::new_lambertian(0.0, 0.0, 1.0);
+        // A bumpy 8x8 grid, two triangles per cell, alternating materials.
+        let n = 8;
+        let positions: Vec<Point3> = (0..=n)
+            .flat_map(|z| (0..=n).map(move |x| (x, z)))
+            .map(|(x, z)| point3(x as f32, ((x * 7 + z * 3) % 5) as f32 * 0.2, z as f32))
+            .collect();
+        let mut faces = vec![];
+        for z in 0..n {
+            for x in 0..n {
+                let i = z * (n + 1) + x;
+                faces.push([i, i + 1, i + n + 1]);
+                faces.push([i + 1, i + n + 2, i + n + 1]);
+            }
+        }
+        let face_materials: Vec<u32> = (0..faces.len() as u32).map(|f| f % 2).collect();
+        let mut triangles: HittableVec = vec![];
+        for (face, &mat) in faces.iter().zip(&face_materials) {
+            let [a, b, c] = face.map(|idx| positions[idx as usize]);
+            let mat = [&red, &blue][mat as usize].clone();
+            triangles.push(Box::new(Triangle::new(a, b, c, mat)));
+        }
+        let mesh = Mesh::new(positions, faces, vec![red, blue], face_materials).unwrap();
+        let hits = assert_same_hits(&triangles, &mesh, "mesh");
+        assert!(hits > 100, "only {} rays hit anything", hits);
+        // Faces without texture coordinates report barycentrics, as
+        // triangles do.
+        let ray = Ray::new(point3(2.3, 3.0, 5.6), -Vec3::Y);
+        let uv = |object: &dyn Hittable| object.hit(&ray, Interval::UNIVERSE).unwrap().uv;
+        assert!(uv(&triangles).distance(uv(&mesh)) < 1e-4);
+        assert!(Mesh::new(vec![Point3::ZERO], vec![[0, 0, 1]], vec![], vec![]).is_err());
+    }
+
+    #[test]
+    fn voxels_match_boxes() {
+        let materials = vec![
+            Material::new_lambertian(1.0, 0.0, 0.0),
+            Material::new_lambertian(0.0, 1.0, 0.0),
+        ];
+        let (min, size, dims) = (point3(-2.0, -1.0, -1.5), 0.5, [8, 4, 6]);
+        let mut cells = vec![];
+        let mut boxes: HittableVec = vec![];
+        for z in 0..dims[2] {
+            for y in 0..dims[1] {
+                for x in 0..dims[0] {
+                    random::seed_sample(9, x, y, z);
+                    let value = if random::f32() < 0.15 { 1 + z % 2 } else { 0 } as u8;
+                    cells.push(value);
+                    if value > 0 {
+                        let corner = min + vec3(x as f32, y as f32, z as f32) * size;
+                        let mat = materials[value as usize - 1].clone();
+                        boxes.push(Box::new(make_box(corner, corner + Vec3::splat(size), mat)));
+                    }
+                }
+            }
+        }
+        let grid = VoxelGrid::new(min, size, dims, cells, materials).unwrap();
+        let hits = assert_same_hits(&boxes, &grid, "voxels");
+        assert!(hits > 100, "only {} rays hit anything", hits);
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+
+        // From inside the filled region, the ray leaves it through the far
+        // side of the grid, seeing the back of that face.
+        let solid = VoxelGrid::new(
+            Vec3::ZERO,
+            1.0,
+            [3, 1, 1],
+            vec![1; 3],
+            vec![Material::new_lambertian(1.0, 1.0, 1.0)],
+        )
+        .unwrap();
+        let hit = solid
+            .hit(&Ray::new(point3(0.5, 0.5, 0.5), Vec3::X), ray_t)
+            .unwrap();
+        assert!((hit.t - 2.5).abs() < 1e-6);
+        assert!(!hit.front_face);
+        assert_eq!(hit.normal, -Vec3::X);
+    }
+
+    #[test]
+    fn instance_matches_transformed_object() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let offset = vec3(1.0, 2.0, 3.0);
+        let shape = || make_box(Point3::ZERO, vec3(1.0, 2.0, 0.5), mat.clone());
+        let wrapped = Translate::new(offset, Box::new(RotateY::new(30.0, Box::new(shape()))));
+        let instance = Instance::new(
+            Arc::new(Bvh::new(shape())),
+            Affine3A::from_translation(offset) * Affine3A::from_rotation_y(30f32.to_radians()),
+        );
+        let hits = assert_same_hits(&vec![Box::new(wrapped)], &instance, "instance");
+        assert!(hits > 100, "only {} rays hit anything", hits);
+    }
+
+    #[test]
+    fn transform_matches_translate_and_rotate_y() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let offset = vec3(1.0, 2.0, 3.0);
+        let shape = || Box::new(make_box(Point3::ZERO, vec3(1.0, 2.0, 0.5), mat.clone()));
+        let wrapped = Translate::new(offset, Box::new(RotateY::new(30.0, shape())));
+        let transform = Transform::new(
+            Affine3A::from_translation(offset) * Affine3A::from_rotation_y(30f32.to_radians()),
+            shape(),
+        );
+        let hits = assert_same_hits(&vec![Box::new(wrapped)], &transform, "transform");
+        assert!(hits > 100, "only {} rays hit anything", hits);
+    }
+
+    #[test]
+    fn transform_scales_normals_by_the_inverse_transpose() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        // An ellipsoid x^2 / 4 + y^2 + z^2 = 1, tilted and moved by a second
+        // transform.
+        let stretched = Transform::new(
+            Affine3A::from_scale(vec3(2.0, 1.0, 1.0)),
+            Box::new(Sphere::new(Point3::ZERO, 1.0, mat)),
+        );
+        let rotation = Affine3A::from_rotation_z(0.5);
+        let offset = vec3(0.0, 3.0, 0.0);
+        let ellipsoid = stretched.then(Affine3A::from_translation(offset) * rotation);
+        let to_local = rotation.inverse();
+        for idx in 0..100 {
+            random::seed_sample(4, idx, 0, 0);
+            let v = || random::range(-1.0..1.0);
+            let origin = point3(v(), v(), v()) * 5.0 + offset;
+            let ray = Ray::new(origin, offset + point3(v(), v(), v()) * 0.5 - origin);
+            let ray_t = Interval::new(0.001, f32::INFINITY);
+            let Some(hit) = ellipsoid.hit(&ray, ray_t) else {
+                continue;
+            };
+            let p = to_local.transform_point3(hit.p - offset);
+            assert!(
+                (p.x * p.x / 4.0 + p.y * p.y + p.z * p.z - 1.0).abs() < 1e-3,
+                "ray #{}",
+                idx
+            );
+            let gradient = vec3(p.x / 4.0, p.y, p.z).normalize();
+            let normal = to_local.transform_vector3(hit.normal);
+            assert!(normal.dot(gradient).abs() > 1.0 - 1e-4, "ray #{}", idx);
+            assert!(hit.normal.dot(ray.dir()) < 0.0, "ray #{}", idx);
+        }
+    }
+
+    #[test]
+    fn footprints_measure_texture_coordinates_in_world_units() {
+        // A cone 0.1 wide at the origin, growing 0.01 per unit, across a
+        // sphere of radius 2 whose near side is 8 away.
+        let mat = Material::new_lambertian(0.5, 0.5, 0.5);
+        let ray = Ray::new(point3(0.0, 0.0, 10.0), -2.0 * Vec3::Z).with_cone(0.1, 0.01);
+        let sphere = Sphere::new(Point3::ZERO, 2.0, mat.clone());
+        let hit = sphere.hit(&ray, Interval::UNIVERSE).unwrap();
+        assert!((hit.footprint - 0.18).abs() < 1e-6);
+        assert_eq!(hit.uv_density, vec2(1.0 / TAU, 1.0 / PI) / 2.0);
+        let head_on = hit.uv_footprint(ray.dir());
+        assert!(head_on.distance(0.18 * hit.uv_density) < 1e-6);
+        // Twice as long across a surface at 60 degrees.
+        let slant = vec3(3f32.sqrt(), 0.0, -1.0);
+        assert!(hit.uv_footprint(slant).distance(2.0 * head_on) < 1e-5);
+
+        // A unit sphere scaled up to the same size covers the same texture.
+        let scaled = Transform::new(
+            Affine3A::from_scale(Vec3::splat(2.0)),
+            Box::new(Sphere::new(Point3::ZERO, 1.0, mat.clone())),
+        );
+        let scaled_hit = scaled.hit(&ray, Interval::UNIVERSE).unwrap();
+        assert!((scaled_hit.footprint - hit.footprint).abs() < 1e-6);
+        assert!(scaled_hit.uv_density.distance(hit.uv_density) < 1e-6);
+
+        // A quad 4 by 2 maps each unit across onto a quarter and a half of
+        // its texture; rays without a cone cover none of it.
+        let quad = Quad::new(point3(-2.0, -1.0, 0.0), 4.0 * Vec3::X, 2.0 * Vec3::Y, mat);
+        let hit = quad.hit(&ray, Interval::UNIVERSE).unwrap();
+        assert!((hit.footprint - 0.2).abs() < 1e-6);
+        assert_eq!(hit.uv_density, vec2(0.25, 0.5));
+        let bare = Ray::new(point3(0.0, 0.0, 10.0), -Vec3::Z);
+        let hit = quad.hit(&bare, Interval::UNIVERSE).unwrap();
+        assert_eq!(hit.uv_footprint(bare.dir()), Vec2::ZERO);
+    }
+
+    #[test]
+    fn wrapped_objects_write_their_transforms() {
+        let mat = Material::new_lambertian(0.5, 0.5, 0.5);
+        let sphere = Box::new(Sphere::new(Point3::ZERO, 1.0, mat));
+        let placed = Translate::new(Vec3::X, Box::new(RotateY::new(30.0, sphere)));
+        let node = placed.to_json().unwrap();
+        assert_eq!(node.get("type"), Some(&Json::from("sphere")));
+        let transform = Json::object([("rotate_y", 30.0.into()), ("translate", Vec3::X.into())]);
+        assert_eq!(node.get("transform"), Some(&transform));
+
+        // Anything after a translation comes as a second step.
+        let scaled = Transform::new(Affine3A::from_scale(Vec3::splat(2.0)), Box::new(placed));
+        let steps = scaled.to_json().unwrap();
+        let Some(Json::Array(steps)) = steps.get("transform") else {
+            panic!("{}", steps);
+        };
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0], transform);
+        assert!(steps[1].get("matrix").is_some());
+    }
+
+    #[test]
+    fn bvh_matches_linear_scan() {
+        for quality in [BvhQuality::Median, BvhQuality::Sah] {
+            let bvh = Bvh::with_quality(scene(), quality);
+            let hits = assert_same_hits(&scene(), &bvh, &format!("{:?}", quality));
+            assert!(hits > 100, "only {} rays hit anything", hits);
+        }
+    }
+
+    // Random worlds of spheres and boxes, from a few large objects to many
+    // overlapping ones, some nested in BVHs of their own.
+    fn random_world(seed: u64) -> HittableVec {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        random::seed_sample(seed, 0, 0, 0);
+        let count = random::range(1.0..400.0) as u32;
+        let spread = random::range(0.5..50.0);
+        let mut world: HittableVec = vec![];
+        for idx in 0..count {
+            random::seed_sample(seed, idx, 1, 0);
+            let v = || random::range(-1.0..1.0);
+            let center = point3(v(), v(), v()) * spread;
+            let size = spread * random::range(0.001..0.3);
+            let object: Box<dyn Hittable> = match random::range(0.0..4.0) as u32 {
+                0 => Box::new(Sphere::new(center, size, mat.clone())),
+                1 => Box::new(make_box(
+                    center,
+                    center + point3(v(), v(), v()) * size,
+                    mat.clone(),
+                )),
+                2 => Box::new(RotateY::new(
+                    random::range(0.0..360.0),
+                    Box::new(make_box(center, center + Vec3::splat(size), mat.clone())),
+                )),
+                // A small cluster in a hierarchy of its own.
+                _ => Box::new(Bvh::new(
+                    (0..4)
+                        .map(|_| {
+                            let offset = point3(v(), v(), v()) * size;
+                            Box::new(Sphere::new(center + offset, size / 2.0, mat.clone()))
+                                as Box<dyn Hittable>
+                        })
+                        .collect(),
+                )),
+            };
+            world.push(object);
+        }
+        world
+    }
+
+    #[test]
+    fn bvh_matches_linear_scan_on_random_worlds() {
+        for seed in 0..24 {
+            for quality in [BvhQuality::Median, BvhQuality::Sah] {
+                let world = random_world(seed);
+                let bvh = Bvh::with_quality(random_world(seed), quality);
+                let hits =
+                    assert_same_hits(&world, &bvh, &format!("world {}, {:?}", seed, quality));
+                assert!(
+                    hits > 100,
+                    "world {}: only {} rays hit anything",
+                    seed,
+                    hits
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lopsided_hierarchies_fit_the_traversal_stack() {
+        // Spheres each larger and further out than all the ones before make
+        // the SAH split off one at a time.
+        let place = |i| 1e-19 * 1.6f32.powi(i);
+        let world = (0..170)
+            .map(|i| {
+                let mat = Material::new_lambertian(0.5, 0.5, 0.5);
+                Box::new(Sphere::new(
+                    point3(place(i), 0.0, 0.0),
+                    place(i) / 10.0,
+                    mat,
+                )) as Box<dyn Hittable>
+            })
+            .collect();
+        fn depth(nodes: &[BvhNode], idx: usize) -> usize {
+            match nodes[idx] {
+                BvhNode::Leaf { .. } => 0,
+                BvhNode::Interior { right, .. } => {
+                    1 + depth(nodes, idx + 1).max(depth(nodes, right as usize))
+                }
+            }
+        }
+        let bvh = Bvh::new(world);
+        let depth = depth(&bvh.nodes, 0);
+        assert!(
+            (BVH_SAH_DEPTH..BVH_STACK_SIZE).contains(&depth),
+            "{}",
+            depth
+        );
+        // Every sphere is found from straight above it; rays through the
+        // whole range of scales at once would lose the small ones to
+        // rounding.
+        for i in 0..170 {
+            let x = place(i);
+            let ray = Ray::new(point3(x, x, 0.0), -Vec3::Y);
+            let hit = bvh.hit(&ray, Interval::new(0.0, f32::INFINITY));
+            let t = hit.map_or(f32::NAN, |hit| hit.t);
+            assert!((t / (0.9 * x) - 1.0).abs() < 1e-4, "sphere {}: {}", i, t);
+        }
+    }
+
+    #[test]
+    fn spheres_match_boxed_spheres() {
+        let materials = vec![
+            Material::new_lambertian(0.8, 0.1, 0.1),
+            Material::new_metal(0.9, 0.9, 0.9, 0.0),
+            Material::new_dielectric(1.5),
+        ];
+        random::seed_sample(7, 0, 0, 0);
+        let v = || random::range(-1.0..1.0);
+        let (mut centers, mut radii, mut sphere_materials) = (vec![], vec![], vec![]);
+        let mut world: HittableVec = vec![];
+        for idx in 0..300u32 {
+            let center = point3(v(), v(), v()) * 20.0;
+            // Some hollow, as glass bubbles are.
+            let radius = random::range(0.1..2.0) * if idx % 7 == 0 { -1.0 } else { 1.0 };
+            let mat = idx % 3;
+            world.push(Box::new(Sphere::new(
+                center,
+                radius,
+                materials[mat as usize].clone(),
+            )));
+            centers.push(center);
+            radii.push(radius);
+            sphere_materials.push(mat);
+        }
+        let spheres = Spheres::new(centers, radii, materials, sphere_materials).unwrap();
+        let hits = assert_same_hits(&world, &spheres, "spheres");
+        assert!(hits > 100, "only {} rays hit anything", hits);
+        let hit = spheres
+            .hit(
+                &Ray::new(point3(0.0, 0.0, 100.0), -Vec3::Z),
+                Interval::UNIVERSE,
+            )
+            .map(|hit| (hit.t, hit.normal, hit.uv));
+        let boxed = world
+            .hit(
+                &Ray::new(point3(0.0, 0.0, 100.0), -Vec3::Z),
+                Interval::UNIVERSE,
+            )
+            .map(|hit| (hit.t, hit.normal, hit.uv));
+        assert_eq!(hit, boxed);
+
+        // A center, a radius and a material index each, rather than a box
+        // around a whole sphere and its material.
+        let bvh = Bvh::new(world);
+        assert!(spheres.memory() < bvh.memory());
+
+        let mat = || vec![Material::new_lambertian(0.5, 0.5, 0.5)];
+        assert!(Spheres::new(vec![Point3::ZERO], vec![], mat(), vec![]).is_err());
+        assert!(Spheres::new(vec![Point3::ZERO], vec![1.0], mat(), vec![1]).is_err());
+        assert!(Spheres::new(vec![], vec![], vec![], vec![]).is_ok());
+    }
+
+    #[test]
+    fn bvh_matches_linear_scan_on_builtin_scenes() {
+        // Smoke scatters at random, so the two can't agree ray by ray.
+        for (name, scene, ..) in crate::SCENES
+            .into_iter()
+            .filter(|case| case.0 != "showcase")
+        {
+            let mut world = vec![];
+            scene(&mut world, crate::render::Camera::builder(1, 1));
+            let mut copy = vec![];
+            scene(&mut copy, crate::render::Camera::builder(1, 1));
+            let hits = assert_same_hits(&world, &Bvh::new(copy), name);
+            assert!(hits > 100, "{}: only {} rays hit anything", name, hits);
+        }
+    }
+
+    #[test]
+    fn refits_follow_objects_until_rebuilding_is_worth_it() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        // Spheres told apart by their sizes, on a grid moved around by `at`.
+        let sphere = |idx: u32, at: &dyn Fn(u32) -> Point3| -> Box<dyn Hittable> {
+            Box::new(Sphere::new(at(idx), 0.5 + idx as f32 / 128.0, mat.clone()))
+        };
+        let index = |obj: &dyn Hittable| {
+            let bbox = obj.bounding_box();
+            ((bbox.max.x - bbox.min.x - 1.0) * 64.0).round() as u32
+        };
+        let world = |at: &dyn Fn(u32) -> Point3| (0..64).map(|idx| sphere(idx, at)).collect();
+        let grid = |idx: u32| vec3((idx % 4) as f32, (idx / 4 % 4) as f32, (idx / 16) as f32) * 3.0;
+        let wobbled = |idx: u32| {
+            let p = grid(idx);
+            p + vec3(p.y.sin(), p.z.sin(), p.x.sin()) * 0.3
+        };
+        let scrambled = |idx: u32| grid(idx * 37 % 64);
+
+        let mut bvh = Bvh::new(world(&grid));
+        let built_cost = bvh.built_cost;
+        assert!(!bvh.refit(|obj| *obj = sphere(index(obj.as_ref()), &wobbled)));
+        assert_same_hits(&world(&wobbled), &bvh, "wobbled");
+        // Neighbors in the hierarchy end up far apart.
+        assert!(bvh.refit(|obj| *obj = sphere(index(obj.as_ref()), &scrambled)));
+        assert!(sah_cost(&bvh.nodes) <= BVH_REBUILD_COST * built_cost);
+        assert_same_hits(&world(&scrambled), &bvh, "scrambled");
+
+        // Objects losing their bounds leave the hierarchy.
+        let plane = || Box::new(Plane::new(point3(0.0, -5.0, 0.0), Vec3::Y, mat.clone()).unwrap());
+        assert!(bvh.refit(|obj| {
+            if index(obj.as_ref()) == 0 {
+                *obj = plane();
+            }
+        }));
+        let mut expected: HittableVec = world(&scrambled);
+        expected[0] = plane();
+        assert_eq!(bvh.unbounded, 1);
+        assert_same_hits(&expected, &bvh, "plane");
+    }
+
+    // Shoots rays from in and around the objects, some along the axes and
+    // some cut short, and checks `other` finds the same nearest hit as the
+    // linear scan: as far along, facing the same way and of the same
+    // material. Returns how many hit anything.
+    fn assert_same_hits(world: &HittableVec, other: &dyn Hittable, label: &str) -> u32 {
+        let albedo = |hit: &Hit| match hit.material {
+            Material::Lambertian { albedo } => Some(albedo.value(Vec2::ZERO, Point3::ZERO)),
+            _ => None,
+        };
+        let mut hits = 0;
+        let bounds = other.bounded_box();
+        let (center, radius) = bounds.bounding_sphere();
+        for idx in 0..500 {
+            random::seed_sample(3, idx, 0, 0);
+            let v = || random::range(-1.0..1.0);
+            let origin = center + point3(v(), v(), v()) * radius * 1.5;
+            // Half aim at an object, so sparse worlds still get hit.
+            let object = &world[random::range(0.0..world.len() as f32) as usize % world.len()];
+            let target = match object.bounding_box() {
+                bbox if idx % 2 == 0 && bbox.is_finite() => bbox.centroid(),
+                _ => center + point3(v(), v(), v()) * radius,
+            };
+            let mut dir = target - origin;
+            if idx % 5 == 0 {
+                // Zero components make infinite inverse directions.
+                dir *= Vec3::AXES[idx as usize / 5 % 3];
+                if dir == Vec3::ZERO {
+                    continue;
+                }
+            }
+            let max = match idx % 3 {
+                0 => random::range(0.0..1.0),
+                _ => f32::INFINITY,
+            };
+            let ray = Ray::new(origin, dir);
+            let ray_t = Interval::new(0.001, max);
+            let expected = world.hit(&ray, ray_t);
+            let actual = other.hit(&ray, ray_t);
+            let (Some(expected), Some(actual)) = (&expected, &actual) else {
+                assert_eq!(
+                    expected.is_some(),
+                    actual.is_some(),
+                    "{}, ray #{}",
+                    label,
+                    idx
+                );
+                continue;
+            };
+            hits += 1;
+            let error = (expected.t - actual.t).abs() / expected.t.max(1.0);
+            assert!(
+                error < 1e-5,
+                "{}, ray #{}: t {} vs {}",
+                label,
+                idx,
+                expected.t,
+                actual.t
+            );
+            let turn = expected.normal.distance(actual.normal);
+            assert!(turn < 1e-4, "{}, ray #{}: normals differ", label, idx);
+            assert_eq!(albedo(expected), albedo(actual), "{}, ray #{}", label, idx);
+        }
+        hits
+    }
+
+    #[test]
+    fn light_sampling_matches_pdf() {
+        let mat = Material::new_light(1.0, 1.0, 1.0);
+        let lights: Vec<(&str, Box<dyn Hittable>)> = vec![
+            (
+                "quad",
+                Box::new(Quad::new(
+                    point3(-1.0, 1.0, -0.5),
+                    2.0 * Vec3::X,
+                    Vec3::Z,
+                    mat.clone(),
+                )),
+            ),
+            (
+                "ellipse",
+                Box::new(Quad::ellipse(
+                    point3(0.0, 1.0, 0.0),
+                    Vec3::X,
+                    Vec3::Z,
+                    mat.clone(),
+                )),
+            ),
+            (
+                "polygon",
+                Box::new(Quad::polygon(Vec3::Y, Vec3::X, Vec3::Z, 5, mat.clone())),
+            ),
+            (
+                "ring",
+                Box::new(Quad::disk(Vec3::Y, -Vec3::Y, 1.0, 0.6, mat.clone()).unwrap()),
+            ),
+            (
+                "triangle",
+                Box::new(Triangle::new(
+                    Vec3::Y,
+                    point3(1.0, 2.0, 0.0),
+                    Vec3::ONE,
+                    mat.clone(),
+                )),
+            ),
+            (
+                // Faces of different brightness, and one that doesn't emit.
+                "mesh",
+                Box::new(
+                    Mesh::new(
+                        vec![
+                            point3(-1.0, 1.0, -1.0),
+                            point3(1.0, 1.0, -1.0),
+                            point3(1.0, 1.0, 1.0),
+                            point3(-1.0, 1.0, 1.0),
+                            point3(-1.0, 1.0, 3.0),
+                        ],
+                        vec![[0, 1, 2], [0, 2, 3], [3, 2, 4]],
+                        vec![
+                            mat.clone(),
+                            Material::new_light(4.0, 4.0, 4.0),
+                            Material::new_lambertian(1.0, 1.0, 1.0),
+                        ],
+                        vec![0, 1, 2],
+                    )
+                    .unwrap(),
+                ),
+            ),
+            (
+                "sphere",
+                Box::new(Sphere::new(2.0 * Vec3::Y, 1.0, mat.clone())),
+            ),
+            (
+                "inside sphere",
+                Box::new(Sphere::new(Vec3::ZERO, 2.0, mat.clone())),
+            ),
+            (
+                "rotated",
+                Box::new(Translate::new(
+                    Vec3::Y,
+                    Box::new(RotateY::new(
+                        30.0,
+                        Box::new(Quad::new(Vec3::ZERO, Vec3::X, Vec3::Z, mat.clone())),
+                    )),
+                )),
+            ),
+            (
+                "stretched",
+                Box::new(Transform::new(
+                    Affine3A::from_translation(2.0 * Vec3::Y)
+                        * Affine3A::from_rotation_x(0.4)
+                        * Affine3A::from_scale(vec3(2.0, 0.5, 1.0)),
+                    Box::new(Sphere::new(Vec3::ZERO, 0.8, mat.clone())),
+                )),
+            ),
+        ];
+        let origin = point3(0.1, 0.0, 0.2);
+        for (name, light) in &lights {
+            // Sampled directions point at the light.
+            random::seed_sample(0, 0, 0, 0);
+            for _ in 0..100 {
+                let dir = light.random(origin);
+                assert!(light.pdf_value(origin, dir) > 0.0, "{} missed", name);
+            }
+            // The density integrates to one over all directions.
+            let (n_theta, n_phi) = (200, 400);
+            let mut total = 0.0;
+            for i in 0..n_theta {
+                let theta = (i as f32 + 0.5) / n_theta as f32 * PI;
+                for j in 0..n_phi {
+                    let phi = (j as f32 + 0.5) / n_phi as f32 * TAU;
+                    let (sin, cos) = theta.sin_cos();
+                    let dir = vec3(sin * phi.cos(), cos, sin * phi.sin());
+                    total += light.pdf_value(origin, dir) * sin;
+                }
+            }
+            total *= (PI / n_theta as f32) * (TAU / n_phi as f32);
+            assert!(
+                (total - 1.0).abs() < 0.02,
+                "{} integrates to {}",
+                name,
+                total
+            );
+        }
+    }
+
+    #[test]
+    fn small_distant_bulbs_are_sampled_over_their_solid_angle() {
+        // Its cone's cosine rounds to 1 in f32.
+        let bulb = Sphere::new(
+            point3(0.0, 1000.0, 0.0),
+            0.01,
+            Material::new_light(1.0, 1.0, 1.0),
+        );
+        let origin = Point3::ZERO;
+        // The solid angle is 2 pi (1 - cos), about pi (r / d)^2.
+        let expected = 1.0 / (PI * 1e-10);
+        random::seed_sample(0, 0, 0, 0);
+        for _ in 0..100 {
+            let dir = bulb.random(origin);
+            let pdf = bulb.pdf_value(origin, dir);
+            assert!((pdf / expected - 1.0).abs() < 1e-3, "{}", pdf);
+        }
+    }
+
+    #[test]
+    fn emissive_meshes_sample_faces_by_power() {
+        // Two faces of equal area, one four times as bright as the other, and
+        // a third that doesn't emit.
+        let mesh = |mats: Vec<Material>| {
+            Mesh::new(
+                vec![
+                    point3(-1.0, 1.0, -1.0),
+                    point3(1.0, 1.0, -1.0),
+                    point3(1.0, 1.0, 1.0),
+                    point3(-1.0, 1.0, 1.0),
+                    point3(-1.0, 1.0, 3.0),
+                ],
+                vec![[0, 1, 2], [0, 2, 3], [3, 2, 4]],
+                mats,
+                vec![0, 1, 2],
+            )
+            .unwrap()
+        };
+        let dull = Material::new_lambertian(1.0, 1.0, 1.0);
+        let sign = mesh(vec![
+            Material::new_light(1.0, 1.0, 1.0),
+            Material::new_light(4.0, 4.0, 4.0),
+            dull.clone(),
+        ]);
+        assert!(sign.is_emissive());
+
+        let origin = Point3::ZERO;
+        let mut counts = [0; 3];
+        random::seed_sample(0, 0, 0, 0);
+        for _ in 0..4000 {
+            let dir = sign.random(origin);
+            let hit = sign.hit(&Ray::new(origin, dir), LIGHT_RAY_T).unwrap();
+            let brightness = hit.material.emitted().x as usize;
+            counts[brightness.min(2)] += 1;
+            assert!(sign.pdf_value(origin, dir) > 0.0);
+        }
+        assert_eq!(counts[0], 0, "sampled a face that doesn't emit");
+        let ratio = counts[2] as f32 / counts[1] as f32;
+        assert!((ratio - 4.0).abs() < 0.4, "{}", ratio);
+
+        // Without emission it isn't a light at all.
+        let plain = mesh(vec![dull.clone(), dull.clone(), dull]);
+        assert!(!plain.is_emissive());
+        assert_eq!(plain.pdf_value(origin, Vec3::Y), 0.0);
+    }
+
+    #[test]
+    fn close_panels_are_sampled_by_cell() {
+        // A 4 by 4 ceiling panel half a unit above the receiver.
+        let panel = Quad::new(
+            point3(-2.0, 0.5, -2.0),
+            4.0 * Vec3::X,
+            4.0 * Vec3::Z,
+            Material::new_light(1.0, 1.0, 1.0),
+        );
+        let origin = Point3::ZERO;
+        // Estimates of the irradiance the receiver, facing up, gets from the
+        // panel: cosine over density, first sampled by cell, then uniformly
+        // over the area.
+        let spread = |estimates: Vec<f32>| {
+            let mean = estimates.iter().sum::<f32>() / estimates.len() as f32;
+            let variance =
+                estimates.iter().map(|e| (e - mean).powi(2)).sum::<f32>() / estimates.len() as f32;
+            (mean, variance.sqrt() / mean)
+        };
+        random::seed_sample(0, 0, 0, 0);
+        let (by_cell, by_cell_spread) = spread(
+            (0..4000)
+                .map(|_| {
+                    let dir = panel.random(origin);
+                    dir.normalize().y / panel.pdf_value(origin, dir)
+                })
+                .collect(),
+        );
+        let (_, uniform_spread) = spread(
+            (0..4000)
+                .map(|_| {
+                    let point = panel.random_point();
+                    let dir = panel.q + point.x * panel.u + point.y * panel.v - origin;
+                    let pdf = solid_angle_pdf(dir, 1.0, panel.normal, panel.area());
+                    dir.normalize().y / pdf
+                })
+                .collect(),
+        );
+        // pi times the panel's view factor, four corner rectangles of 0.2379.
+        let expected = PI * 0.9514;
+        assert!((by_cell / expected - 1.0).abs() < 0.03, "{}", by_cell);
+        assert!(
+            by_cell_spread < uniform_spread / 2.0,
+            "{} {}",
+            by_cell_spread,
+            uniform_spread
+        );
+    }
+
+    #[test]
+    fn media_let_light_through_exponentially() {
+        let albedo = Arc::new(SolidColor::new(Color3::ONE));
+        let boundary = make_box(
+            Point3::ZERO,
+            point3(1.0, 1.0, 1.0),
+            Material::new_lambertian(1.0, 1.0, 1.0),
+        );
+        let medium = Medium::new(Box::new(boundary), 2.0, albedo);
+        random::seed_sample(0, 0, 0, 0);
+        let tries = 10000;
+        let through = (0..tries)
+            .filter(|_| {
+                let ray = Ray::new(point3(0.5, 0.5, -1.0), Vec3::Z);
+                medium
+                    .hit(&ray, Interval::new(0.001, f32::INFINITY))
+                    .is_none()
+            })
+            .count();
+        // A path of length 1 through density 2.
+        let expected = (-2.0f32).exp();
+        let fraction = through as f32 / tries as f32;
+        assert!((fraction - expected).abs() < 0.02, "{}", fraction);
+
+        // Rays starting inside scatter no later than the far side.
+        let ray = Ray::new(point3(0.5, 0.5, 0.5), Vec3::Z);
+        for _ in 0..100 {
+            if let Some(hit) = medium.hit(&ray, Interval::new(0.001, f32::INFINITY)) {
+                assert!(hit.t > 0.0 && hit.t <= 0.5, "{}", hit.t);
+            }
+        }
+    }
+
+    #[test]
+    fn density_maps_thin_media_out() {
+        // Half the density everywhere lets through as much as a medium of
+        // half the density, though it takes twice as many steps.
+        let albedo = Arc::new(SolidColor::new(Color3::ONE));
+        let boundary = make_box(
+            Point3::ZERO,
+            point3(1.0, 1.0, 1.0),
+            Material::new_lambertian(1.0, 1.0, 1.0),
+        );
+        let medium = Medium::new(Box::new(boundary), 2.0, albedo)
+            .with_density_map(Arc::new(SolidColor::new(Color3::splat(0.5))));
+        random::seed_sample(0, 0, 0, 0);
+        let tries = 10000;
+        let ray = Ray::new(point3(0.5, 0.5, -1.0), Vec3::Z);
+        let through = (0..tries)
+            .filter(|_| {
+                medium
+                    .hit(&ray, Interval::new(0.0, f32::INFINITY))
+                    .is_none()
+            })
+            .count();
+        let expected = (-1.0f32).exp();
+        let fraction = through as f32 / tries as f32;
+        assert!((fraction - expected).abs() < 0.02, "{}", fraction);
     }
 }
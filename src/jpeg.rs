@@ -0,0 +1,487 @@
+use anyhow::{anyhow, bail, Result};
+use std::f32::consts::PI;
+
+// The order coefficients are stored in within a block.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Decodes a baseline JPEG, grayscale or YCbCr, into 8-bit RGB rows from the
+// top. Progressive and arithmetic coded files are rejected.
+pub fn decode(bytes: &[u8]) -> Result<(u32, u32, Vec<u8>)> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        bail!("not a JPEG file");
+    }
+    let mut quant = [[0u16; 64]; 4];
+    let mut dc_tables: [Option<Huffman>; 4] = Default::default();
+    let mut ac_tables: [Option<Huffman>; 4] = Default::default();
+    let mut frame: Option<Frame> = None;
+    let mut restart_interval = 0;
+    let mut pos = 2;
+    loop {
+        // Markers may be padded with any number of 0xFF bytes.
+        while bytes.get(pos) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *bytes
+            .get(pos)
+            .ok_or_else(|| anyhow!("missing image data"))?;
+        pos += 1;
+        if (0xD0..=0xD8).contains(&marker) {
+            continue;
+        }
+        let len = u16::from_be_bytes(
+            bytes
+                .get(pos..pos + 2)
+                .ok_or_else(|| anyhow!("unexpected end of file"))?
+                .try_into()?,
+        ) as usize;
+        let segment = bytes
+            .get(pos + 2..pos + len)
+            .ok_or_else(|| anyhow!("unexpected end of file"))?;
+        pos += len;
+        match marker {
+            0xC0 | 0xC1 => frame = Some(Frame::parse(segment)?),
+            0xC2 | 0xC6 | 0xCA | 0xCE => bail!("progressive JPEGs are not supported"),
+            0xC3 | 0xC5 | 0xC7 | 0xC9 | 0xCB | 0xCD | 0xCF => {
+                bail!("only baseline JPEGs are supported")
+            }
+            0xC4 => {
+                let mut rest = segment;
+                while let [class_id, ..] = *rest {
+                    let (table, used) = Huffman::parse(&rest[1..])?;
+                    let tables = match class_id >> 4 {
+                        0 => &mut dc_tables,
+                        _ => &mut ac_tables,
+                    };
+                    *tables
+                        .get_mut((class_id & 15) as usize)
+                        .ok_or_else(|| anyhow!("invalid Huffman table id"))? = Some(table);
+                    rest = &rest[1 + used..];
+                }
+            }
+            0xDB => {
+                let mut rest = segment;
+                while let [precision_id, ..] = *rest {
+                    let wide = precision_id >> 4 != 0;
+                    let size = if wide { 128 } else { 64 };
+                    let values = rest
+                        .get(1..1 + size)
+                        .ok_or_else(|| anyhow!("truncated quantization table"))?;
+                    let table = quant
+                        .get_mut((precision_id & 15) as usize)
+                        .ok_or_else(|| anyhow!("invalid quantization table id"))?;
+                    for (k, q) in table.iter_mut().enumerate() {
+                        *q = match wide {
+                            true => u16::from_be_bytes([values[2 * k], values[2 * k + 1]]),
+                            false => values[k] as u16,
+                        };
+                    }
+                    rest = &rest[1 + size..];
+                }
+            }
+            0xDD => {
+                restart_interval = u16::from_be_bytes(
+                    segment
+                        .get(..2)
+                        .ok_or_else(|| anyhow!("truncated restart interval"))?
+                        .try_into()?,
+                ) as usize
+            }
+            0xDA => {
+                let mut frame = frame.ok_or_else(|| anyhow!("scan before frame header"))?;
+                let count = *segment.first().unwrap_or(&0) as usize;
+                if count != frame.components.len() {
+                    bail!("only interleaved scans are supported");
+                }
+                for idx in 0..count {
+                    let (id, tables) = (segment[1 + 2 * idx], segment[2 + 2 * idx]);
+                    let component = frame
+                        .components
+                        .iter_mut()
+                        .find(|c| c.id == id)
+                        .ok_or_else(|| anyhow!("scan refers to unknown component {}", id))?;
+                    component.dc = (tables >> 4) as usize;
+                    component.ac = (tables & 15) as usize;
+                }
+                let mut reader = BitReader {
+                    bytes: &bytes[pos..],
+                    pos: 0,
+                    acc: 0,
+                    bits: 0,
+                };
+                frame.decode(
+                    &mut reader,
+                    &quant,
+                    &dc_tables,
+                    &ac_tables,
+                    restart_interval,
+                )?;
+                return frame.to_rgb();
+            }
+            0xD9 => bail!("missing image data"),
+            _ => {}
+        }
+    }
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    quant: usize,
+    dc: usize,
+    ac: usize,
+    // Decoded samples, padded out to whole MCUs.
+    stride: usize,
+    samples: Vec<u8>,
+}
+
+struct Frame {
+    width: usize,
+    height: usize,
+    components: Vec<Component>,
+}
+
+impl Frame {
+    fn parse(segment: &[u8]) -> Result<Frame> {
+        let [precision, h1, h0, w1, w0, count, ref rest @ ..] = *segment else {
+            bail!("truncated frame header");
+        };
+        if precision != 8 {
+            bail!("{}-bit JPEGs are not supported", precision);
+        }
+        let (width, height) = (
+            u16::from_be_bytes([w1, w0]) as usize,
+            u16::from_be_bytes([h1, h0]) as usize,
+        );
+        if width == 0 || height == 0 {
+            bail!("image has no pixels");
+        }
+        if count != 1 && count != 3 {
+            bail!("JPEGs with {} components are not supported", count);
+        }
+        let components = rest
+            .chunks_exact(3)
+            .take(count as usize)
+            .map(|c| Component {
+                id: c[0],
+                h: (c[1] >> 4).clamp(1, 4) as usize,
+                v: (c[1] & 15).clamp(1, 4) as usize,
+                quant: (c[2] & 3) as usize,
+                dc: 0,
+                ac: 0,
+                stride: 0,
+                samples: vec![],
+            })
+            .collect::<Vec<_>>();
+        if components.len() != count as usize {
+            bail!("truncated frame header");
+        }
+        Ok(Frame {
+            width,
+            height,
+            components,
+        })
+    }
+
+    fn decode(
+        &mut self,
+        reader: &mut BitReader,
+        quant: &[[u16; 64]; 4],
+        dc_tables: &[Option<Huffman>; 4],
+        ac_tables: &[Option<Huffman>; 4],
+        restart_interval: usize,
+    ) -> Result<()> {
+        let max_h = self.components.iter().map(|c| c.h).max().unwrap();
+        let max_v = self.components.iter().map(|c| c.v).max().unwrap();
+        // A single component scan has one block per MCU whatever its
+        // sampling factors say.
+        let (max_h, max_v) = match self.components.len() {
+            1 => {
+                self.components[0].h = 1;
+                self.components[0].v = 1;
+                (1, 1)
+            }
+            _ => (max_h, max_v),
+        };
+        let mcus_x = self.width.div_ceil(8 * max_h);
+        let mcus_y = self.height.div_ceil(8 * max_v);
+        for c in &mut self.components {
+            c.stride = mcus_x * c.h * 8;
+            c.samples = vec![0; c.stride * mcus_y * c.v * 8];
+        }
+        fn table(tables: &[Option<Huffman>; 4], idx: usize) -> Result<&Huffman> {
+            tables
+                .get(idx)
+                .and_then(Option::as_ref)
+                .ok_or_else(|| anyhow!("missing Huffman table {}", idx))
+        }
+        let mut predictions = vec![0; self.components.len()];
+        let mut coefficients = [0f32; 64];
+        for mcu in 0..mcus_x * mcus_y {
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                reader.restart()?;
+                predictions.fill(0);
+            }
+            let (mcu_x, mcu_y) = (mcu % mcus_x, mcu / mcus_x);
+            for (c, prediction) in self.components.iter_mut().zip(&mut predictions) {
+                let (dc, ac) = (table(dc_tables, c.dc)?, table(ac_tables, c.ac)?);
+                let q = &quant[c.quant];
+                for block in 0..c.h * c.v {
+                    coefficients.fill(0.0);
+                    let size = dc.decode(reader)?;
+                    *prediction += reader.receive(size);
+                    coefficients[0] = (*prediction * q[0] as i32) as f32;
+                    let mut k = 1;
+                    while k < 64 {
+                        let run_size = ac.decode(reader)?;
+                        let (run, size) = ((run_size >> 4) as usize, run_size & 15);
+                        if size == 0 {
+                            if run != 15 {
+                                break;
+                            }
+                            k += 16;
+                            continue;
+                        }
+                        k += run;
+                        if k >= 64 {
+                            bail!("coefficient index out of range");
+                        }
+                        coefficients[ZIGZAG[k]] = (reader.receive(size) * q[k] as i32) as f32;
+                        k += 1;
+                    }
+                    let x = (mcu_x * c.h + block % c.h) * 8;
+                    let y = (mcu_y * c.v + block / c.h) * 8;
+                    let pixels = idct(&coefficients);
+                    for row in 0..8 {
+                        let start = (y + row) * c.stride + x;
+                        c.samples[start..start + 8].copy_from_slice(&pixels[row * 8..row * 8 + 8]);
+                    }
+                }
+            }
+        }
+        // Sampling factors are relative to the largest, which now spans the
+        // full resolution.
+        for c in &mut self.components {
+            c.h = max_h / c.h;
+            c.v = max_v / c.v;
+        }
+        Ok(())
+    }
+
+    // Chroma is upsampled by repeating samples.
+    fn to_rgb(&self) -> Result<(u32, u32, Vec<u8>)> {
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let sample = |c: &Component| c.samples[y / c.v * c.stride + x / c.h] as f32;
+                match &self.components[..] {
+                    [gray] => rgb.extend([sample(gray) as u8; 3]),
+                    [luma, cb, cr] => {
+                        let (l, cb, cr) = (sample(luma), sample(cb) - 128.0, sample(cr) - 128.0);
+                        rgb.extend(
+                            [
+                                l + 1.402 * cr,
+                                l - 0.344136 * cb - 0.714136 * cr,
+                                l + 1.772 * cb,
+                            ]
+                            .map(|v| v.round().clamp(0.0, 255.0) as u8),
+                        );
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+        Ok((self.width as u32, self.height as u32, rgb))
+    }
+}
+
+// A separable inverse DCT, shifted back to unsigned samples.
+fn idct(coefficients: &[f32; 64]) -> [u8; 64] {
+    let basis = |x: usize, u: usize| {
+        let scale = if u == 0 { 0.5 / 2f32.sqrt() } else { 0.5 };
+        scale * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos()
+    };
+    let mut rows = [0f32; 64];
+    for v in 0..8 {
+        for x in 0..8 {
+            rows[v * 8 + x] = (0..8).map(|u| basis(x, u) * coefficients[v * 8 + u]).sum();
+        }
+    }
+    let mut pixels = [0u8; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            let value: f32 = (0..8).map(|v| basis(y, v) * rows[v * 8 + x]).sum();
+            pixels[y * 8 + x] = (value + 128.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    pixels
+}
+
+// A canonical Huffman code, decoded one bit at a time.
+struct Huffman {
+    // For each code length, the largest code (or -1 if there are none), the
+    // smallest and where its values start.
+    max_code: [i32; 17],
+    min_code: [i32; 17],
+    first_value: [usize; 17],
+    values: Vec<u8>,
+}
+
+impl Huffman {
+    // Returns the table and the number of bytes it took up.
+    fn parse(bytes: &[u8]) -> Result<(Huffman, usize)> {
+        let counts = bytes
+            .get(..16)
+            .ok_or_else(|| anyhow!("truncated Huffman table"))?;
+        let total: usize = counts.iter().map(|&c| c as usize).sum();
+        let values = bytes
+            .get(16..16 + total)
+            .ok_or_else(|| anyhow!("truncated Huffman table"))?
+            .to_vec();
+        let mut table = Huffman {
+            max_code: [-1; 17],
+            min_code: [0; 17],
+            first_value: [0; 17],
+            values,
+        };
+        let (mut code, mut first) = (0i32, 0);
+        for (len, &count) in (1..=16).zip(counts) {
+            table.first_value[len] = first;
+            table.min_code[len] = code;
+            code += count as i32;
+            first += count as usize;
+            if count > 0 {
+                table.max_code[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        Ok((table, 16 + total))
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8> {
+        let mut code = 0;
+        for len in 1..=16 {
+            code = (code << 1) | reader.bit();
+            if code <= self.max_code[len] {
+                let idx = self.first_value[len] + (code - self.min_code[len]) as usize;
+                return self
+                    .values
+                    .get(idx)
+                    .copied()
+                    .ok_or_else(|| anyhow!("invalid Huffman code"));
+            }
+        }
+        bail!("invalid Huffman code")
+    }
+}
+
+// Reads entropy coded data, undoing byte stuffing. Past the end of the data
+// or at a marker it reads zeros, as the spec asks for.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    acc: u8,
+    bits: u32,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> i32 {
+        if self.bits == 0 {
+            self.acc = match self.bytes.get(self.pos..self.pos + 2) {
+                Some([0xFF, 0x00]) => {
+                    self.pos += 2;
+                    0xFF
+                }
+                Some([0xFF, _]) => 0,
+                _ => match self.bytes.get(self.pos) {
+                    Some(&byte) => {
+                        self.pos += 1;
+                        byte
+                    }
+                    None => 0,
+                },
+            };
+            self.bits = 8;
+        }
+        self.bits -= 1;
+        ((self.acc >> self.bits) & 1) as i32
+    }
+
+    // Reads a `size` bit value and sign-extends it: values with a leading
+    // zero are negative.
+    fn receive(&mut self, size: u8) -> i32 {
+        if size == 0 {
+            return 0;
+        }
+        let value = (0..size).fold(0, |value, _| (value << 1) | self.bit());
+        if value < 1 << (size - 1) {
+            value - (1 << size) + 1
+        } else {
+            value
+        }
+    }
+
+    // Skips to the byte after the next RST marker.
+    fn restart(&mut self) -> Result<()> {
+        self.bits = 0;
+        match self.bytes.get(self.pos..self.pos + 2) {
+            Some([0xFF, 0xD0..=0xD7]) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => bail!("missing restart marker"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An 8x8 grayscale image holding a single DC coefficient, with unit
+    // quantization and one-symbol Huffman tables.
+    fn flat_gray(dc: u16) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xD8];
+        bytes.extend([0xFF, 0xDB, 0, 67, 0]);
+        bytes.extend([1; 64]);
+        bytes.extend([0xFF, 0xC0, 0, 11, 8, 0, 8, 0, 8, 1, 1, 0x11, 0]);
+        for (class, symbol) in [(0x00, 10), (0x10, 0)] {
+            bytes.extend([0xFF, 0xC4, 0, 20, class, 1]);
+            bytes.extend([0; 15]);
+            bytes.push(symbol);
+        }
+        bytes.extend([0xFF, 0xDA, 0, 8, 1, 1, 0x00, 0, 63, 0]);
+        // The DC code, 10 bits of difference, the end of block code and
+        // padding.
+        bytes.extend([(dc >> 3) as u8, ((dc & 7) as u8) << 5 | 0x0F]);
+        bytes.extend([0xFF, 0xD9]);
+        bytes
+    }
+
+    #[test]
+    fn decodes_flat_block() {
+        // A DC coefficient of 512 adds 64 to every sample.
+        let (width, height, rgb) = decode(&flat_gray(0b10_0000_0000)).unwrap();
+        assert_eq!((width, height), (8, 8));
+        assert!(rgb.iter().all(|&v| v == 192));
+        // With a leading zero the difference is negative: 0b0111111111 is
+        // -512.
+        let (_, _, rgb) = decode(&flat_gray(0b01_1111_1111)).unwrap();
+        assert!(rgb.iter().all(|&v| v == 64));
+    }
+
+    #[test]
+    fn rejects_progressive() {
+        let mut bytes = flat_gray(0);
+        let sof = bytes.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+        bytes[sof + 1] = 0xC2;
+        assert!(decode(&bytes)
+            .unwrap_err()
+            .to_string()
+            .contains("progressive"));
+    }
+}
@@ -0,0 +1,426 @@
+use anyhow::{anyhow, Result};
+use glam::Vec3;
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_ws();
+        if parser.pos != parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            Json::Number(n) => Some(*n as f32),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u32),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&BTreeMap<String, Json>> {
+        match self {
+            Json::Object(map) => Some(map),
+            _ => None,
+        }
+    }
+}
+
+// Values written into scene files.
+impl Json {
+    pub fn object<const N: usize>(entries: [(&str, Json); N]) -> Json {
+        Json::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    // The object with `key` set to `value`; anything else unchanged.
+    pub fn with(mut self, key: &str, value: Json) -> Json {
+        if let Json::Object(map) = &mut self {
+            map.insert(key.to_string(), value);
+        }
+        self
+    }
+}
+
+impl From<f32> for Json {
+    // Through the shortest decimal that reads back as the same f32, so 0.73
+    // isn't written as 0.7300000190734863; adding 0 turns -0 into 0.
+    fn from(value: f32) -> Json {
+        let value = value + 0.0;
+        Json::Number(value.to_string().parse().unwrap_or(value as f64))
+    }
+}
+
+impl From<u32> for Json {
+    fn from(value: u32) -> Json {
+        Json::Number(value as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Json {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<Vec3> for Json {
+    fn from(value: Vec3) -> Json {
+        Json::Array(value.to_array().map(Json::from).to_vec())
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)?;
+        writeln!(f)
+    }
+}
+
+impl Json {
+    fn write(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        const STEP: usize = 2;
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) if items.iter().all(Json::is_scalar) => {
+                write!(f, "[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    item.write(f, indent)?;
+                }
+                write!(f, "]")
+            }
+            Json::Array(items) => {
+                writeln!(f, "[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    write!(f, "{:1$}", "", indent + STEP)?;
+                    item.write(f, indent + STEP)?;
+                    writeln!(f, "{}", if idx + 1 < items.len() { "," } else { "" })?;
+                }
+                write!(f, "{:1$}]", "", indent)
+            }
+            Json::Object(map) if map.is_empty() => write!(f, "{{}}"),
+            Json::Object(map) => {
+                writeln!(f, "{{")?;
+                for (idx, (key, value)) in map.iter().enumerate() {
+                    write!(f, "{:1$}", "", indent + STEP)?;
+                    write_string(f, key)?;
+                    write!(f, ": ")?;
+                    value.write(f, indent + STEP)?;
+                    writeln!(f, "{}", if idx + 1 < map.len() { "," } else { "" })?;
+                }
+                write!(f, "{:1$}}}", "", indent)
+            }
+        }
+    }
+
+    fn is_scalar(&self) -> bool {
+        !matches!(self, Json::Array(_) | Json::Object(_))
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Result<Json> {
+        self.skip_ws();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        let mut map = BTreeMap::new();
+        self.expect(b'{')?;
+        self.skip_ws();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(map));
+        }
+        loop {
+            self.skip_ws();
+            let key = self.string()?;
+            self.skip_ws();
+            self.expect(b':')?;
+            let value = self.value()?;
+            map.insert(key, value);
+            self.skip_ws();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b'}') => return Ok(Json::Object(map)),
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        let mut items = vec![];
+        self.expect(b'[')?;
+        self.skip_ws();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_ws();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b']') => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while !matches!(self.peek(), Some(b'"' | b'\\') | None) {
+                self.pos += 1;
+            }
+            out.push_str(std::str::from_utf8(&self.bytes[start..self.pos])?);
+            match self.next() {
+                Some(b'"') => return Ok(out),
+                Some(b'\\') => {
+                    let c = match self.next() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    out.push(c);
+                }
+                _ => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self) -> Result<char> {
+        let hex = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error("truncated unicode escape"))?;
+        let code = u32::from_str_radix(std::str::from_utf8(hex)?, 16)?;
+        self.pos += 4;
+        char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        while matches!(
+            self.peek(),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.pos])?;
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| self.error("invalid number"))
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json> {
+        if self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("invalid literal"))
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        if self.next() != Some(byte) {
+            return Err(self.error(&format!("expected '{}'", byte as char)));
+        }
+        Ok(())
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek();
+        self.pos += 1;
+        byte
+    }
+
+    fn error(&self, msg: &str) -> anyhow::Error {
+        let line = self.bytes[..self.pos.min(self.bytes.len())]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+            + 1;
+        anyhow!("JSON parse error at line {}: {}", line, msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        Json::parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn documents_round_trip() {
+        let text = r#"{
+            "name": "quote \" slash \\ tab \t newline \n bell \u0007",
+            "nested": [[1, 2.5, -3e2], {"empty": {}, "list": []}, [{"a": null}]],
+            "flags": [true, false]
+        }"#;
+        let doc = Json::parse(text).unwrap();
+        assert_eq!(
+            doc.get("name").and_then(Json::as_str),
+            Some("quote \" slash \\ tab \t newline \n bell \u{7}")
+        );
+        let nested = doc.get("nested").and_then(Json::as_array).unwrap();
+        assert_eq!(
+            nested[0],
+            Json::Array(vec![
+                Json::Number(1.0),
+                Json::Number(2.5),
+                Json::Number(-300.0)
+            ])
+        );
+        assert_eq!(nested[1].get("empty"), Some(&Json::Object(BTreeMap::new())));
+        assert_eq!(nested[2].as_array().unwrap()[0].get("a"), Some(&Json::Null));
+        // What is written reads back as the same document.
+        assert_eq!(Json::parse(&doc.to_string()).unwrap(), doc);
+    }
+
+    #[test]
+    fn unicode_escapes_decode() {
+        let doc = Json::parse(r#""\u00e9\u4e2d\/""#).unwrap();
+        assert_eq!(doc.as_str(), Some("\u{e9}\u{4e2d}/"));
+        // Characters outside ASCII may also appear as they are.
+        assert_eq!(Json::parse("\"é\"").unwrap().as_str(), Some("é"));
+        assert!(error(r#""\u00""#).contains("unicode escape"));
+    }
+
+    #[test]
+    fn malformed_documents_are_rejected() {
+        assert!(error("{} x").contains("trailing characters"));
+        assert!(error("\"open").contains("unterminated string"));
+        assert!(error("[1, 2").contains("expected ',' or ']'"));
+        assert!(error("{\"a\" 1}").contains("expected ':'"));
+        assert!(error("\"\\q\"").contains("invalid escape"));
+        assert!(error("tru").contains("invalid literal"));
+        // Errors tell where they are.
+        assert!(
+            error("[\n1,\n?]").contains("line 3"),
+            "{}",
+            error("[\n1,\n?]")
+        );
+    }
+
+    #[test]
+    fn floats_are_written_in_shortest_form() {
+        assert_eq!(Json::from(0.73f32).to_string(), "0.73\n");
+        assert_eq!(Json::from(-0.0f32).to_string(), "0\n");
+        assert_eq!(Json::from(1e-7f32).to_string(), "0.0000001\n");
+        let third = 1.0f32 / 3.0;
+        assert_eq!(Json::from(third).as_f32(), Some(third));
+    }
+}
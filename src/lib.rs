@@ -0,0 +1,839 @@
+mod assets;
+#[cfg(feature = "audit")]
+mod audit;
+mod bench;
+mod browse;
+mod canvas;
+mod caustics;
+mod cli;
+pub mod csg;
+#[cfg(feature = "debug-render")]
+mod debug;
+mod denoise;
+pub mod edit;
+pub mod environment;
+mod exr;
+mod extrude;
+mod grade;
+pub mod graph;
+mod hdr;
+pub mod hittables;
+mod jpeg;
+pub mod json;
+mod light_paths;
+mod live;
+mod loaders;
+pub mod materials;
+mod measured;
+mod memory;
+mod metrics;
+pub mod onb;
+mod preview;
+mod random;
+mod range;
+pub mod registry;
+pub mod render;
+pub mod renderer;
+pub mod scene;
+pub mod sdf;
+pub mod shapes;
+pub mod textures;
+mod thermal;
+mod tiles;
+
+use anyhow::{Context, Result};
+use assets::AssetResolver;
+use canvas::Canvas;
+use cli::{Command, CompareArgs, ExportArgs, Focus, PackArgs, RenderArgs, ThermalRange};
+use glam::{vec3, Affine3A, DVec3, Vec2, Vec3};
+use grade::Grade;
+use hittables::{
+    make_box, Bvh, BvhQuality, Hittable, HittableVec, Instance, Medium, Plane, Quad, RotateY,
+    Sphere, Translate,
+};
+use indicatif::ProgressBar;
+use json::Json;
+use light_paths::{Component, COMPONENTS};
+use materials::Material;
+use memory::MemoryUsage;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use registry::Registry;
+use render::{Camera, CameraBuilder, SplitScreen};
+use scene::Scene;
+use std::f32::consts::{PI, TAU};
+use std::path::PathBuf;
+use std::sync::Arc;
+use textures::{ImageCache, ImageTexture, NoiseStyle, NoiseTexture, SolidColor, Texture};
+
+// Runs the command line renderer. Scene files can use the object and
+// material types in `registry` on top of the built-in ones, so a crate with
+// its own types only needs a main() that registers them and calls this.
+pub fn run(registry: Registry) -> Result<()> {
+    match Command::parse()? {
+        Command::Render(args) => {
+            render(args, &registry)?;
+            #[cfg(feature = "audit")]
+            audit::report();
+            Ok(())
+        }
+        Command::Pack(args) => pack(args, &registry),
+        Command::Compare(args) => compare(args),
+        Command::Bench(args) => bench::run(args),
+        Command::Export(args) => export(args),
+        Command::ListScenes(args) => browse::run(args, &registry),
+    }
+}
+
+fn resolver(asset_paths: &[PathBuf]) -> AssetResolver {
+    let mut resolver = AssetResolver::from_env();
+    for path in asset_paths {
+        resolver.add_search_path(path.clone());
+    }
+    resolver
+}
+
+fn pack(args: PackArgs, registry: &Registry) -> Result<()> {
+    let resolver = resolver(&args.asset_paths);
+    let scene = Scene::load(&args.scene, &resolver, &args.defines, registry, None)?;
+    assets::pack(&scene.path, &resolver, &args.output)?;
+    println!(
+        "Packed {} file(s) into {}",
+        resolver.resolved().len(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+fn compare(args: CompareArgs) -> Result<()> {
+    let reference = Canvas::load(&args.reference)?;
+    let test = Canvas::load(&args.test)?;
+    let result = metrics::compare(&reference, &test, args.downsample)?;
+    println!("RMSE:   {:.6}", result.rmse);
+    println!("relMSE: {:.6}", result.rel_mse);
+    println!("FLIP:   {:.6}", result.flip);
+    let exceeded: Vec<_> = [
+        ("RMSE", result.rmse, args.max_rmse),
+        ("relMSE", result.rel_mse, args.max_rel_mse),
+        ("FLIP", result.flip, args.max_flip),
+    ]
+    .into_iter()
+    .filter_map(|(name, value, max)| {
+        max.filter(|&max| value > max)
+            .map(|max| format!("{} {:.6} > {}", name, value, max))
+    })
+    .collect();
+    if !exceeded.is_empty() {
+        anyhow::bail!("images differ: {}", exceeded.join(", "));
+    }
+    Ok(())
+}
+
+type SceneFn = fn(&mut HittableVec, CameraBuilder) -> CameraBuilder;
+
+// The scenes built in code, as written by `export`, with their image sizes.
+const SCENES: [(&str, SceneFn, u32, u32); 4] = [
+    ("cornell_box", cornell_box, WIDTH, HEIGHT),
+    ("spheres", spheres_scene, 800, 450),
+    ("random_spheres", random_spheres, 1200, 675),
+    ("showcase", showcase, WIDTH, HEIGHT),
+];
+
+// Writes one of the scenes built in code as a scene file, with the settings
+// rendering it without a scene file uses.
+fn export(args: ExportArgs) -> Result<()> {
+    let Some(&(_, scene, width, height)) = SCENES.iter().find(|case| case.0 == args.scene) else {
+        let names: Vec<_> = SCENES.iter().map(|case| case.0).collect();
+        anyhow::bail!(
+            "unknown scene '{}', expected one of {}",
+            args.scene,
+            names.join(", ")
+        );
+    };
+    let mut world: HittableVec = vec![];
+    let camera = scene(&mut world, default_camera().size(width, height));
+    let doc = scene_json(&world, &camera).context("the scene can't be written as a scene file")?;
+    match args.output {
+        Some(path) => std::fs::write(&path, doc.to_string())
+            .with_context(|| format!("writing {}", path.display()))?,
+        None => print!("{}", doc),
+    }
+    Ok(())
+}
+
+// Lights are left out: scene files sample every emissive object.
+fn scene_json(world: &HittableVec, camera: &CameraBuilder) -> Option<Json> {
+    let objects = world
+        .iter()
+        .map(|obj| obj.to_json())
+        .collect::<Option<_>>()?;
+    Some(Json::object([
+        ("camera", camera.to_json()?),
+        ("objects", Json::Array(objects)),
+    ]))
+}
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 800;
+
+// The settings of renders without a scene file.
+fn default_camera() -> CameraBuilder {
+    Camera::builder(WIDTH, HEIGHT).samples(50).max_depth(50)
+}
+
+// Thumbnails trade everything for speed: the longer side shrinks to
+// THUMBNAIL_SIZE pixels with a handful of short paths per pixel.
+fn thumbnail(width: u32, height: u32, builder: CameraBuilder) -> (u32, u32, CameraBuilder) {
+    const THUMBNAIL_SIZE: u32 = 128;
+    let scale = THUMBNAIL_SIZE as f32 / width.max(height) as f32;
+    let width = ((width as f32 * scale).round() as u32).max(1);
+    let height = ((height as f32 * scale).round() as u32).max(1);
+    let builder = builder
+        .size(width, height)
+        .samples(4)
+        .passes(1)
+        .max_depth(8);
+    (width, height, builder)
+}
+
+fn render(args: RenderArgs, registry: &Registry) -> Result<()> {
+    let mut world: HittableVec = vec![];
+    let mut output = args.output.clone();
+    let mut texture_bytes = 0;
+    let mut grade = Grade::default();
+    let mut bvh = BvhQuality::default();
+    let mut gray_card = None;
+    let (mut width, mut height, mut builder) = match &args.scene {
+        Some(path) => {
+            let image_cache = args
+                .texture_cache
+                .map(|budget| Arc::new(ImageCache::new(budget)));
+            let scene = Scene::load(
+                path,
+                &resolver(&args.asset_paths),
+                &args.defines,
+                registry,
+                image_cache,
+            )?;
+            world = scene.world;
+            texture_bytes = scene.texture_bytes;
+            grade = scene.grade;
+            bvh = scene.bvh;
+            gray_card = scene.gray_card;
+            if args.thumbnail {
+                output = scene.path.with_extension("thumb.png");
+            }
+            (scene.width, scene.height, scene.camera)
+        }
+        None if args.thumbnail => anyhow::bail!("--thumbnail needs a scene file"),
+        None => (WIDTH, HEIGHT, cornell_box(&mut world, default_camera())),
+    };
+
+    if args.thumbnail {
+        (width, height, builder) = thumbnail(width, height, builder);
+    }
+
+    let world = Bvh::with_quality(world, bvh);
+    let usage = MemoryUsage {
+        geometry: world.memory() - world.node_memory(),
+        bvh: world.node_memory(),
+        textures: texture_bytes,
+        framebuffer: width as usize
+            * height as usize
+            * match args.range {
+                Some(_) => std::mem::size_of::<range::PixelRange>(),
+                None if args.light_paths => {
+                    std::mem::size_of::<[DVec3; COMPONENTS]>() + std::mem::size_of::<Color3>() + 3
+                }
+                None => std::mem::size_of::<Color3>() + 3,
+            }
+            + match args.live {
+                Some(_) => live::BYTES_PER_PIXEL,
+                None => 0,
+            },
+    };
+    println!("Memory: {}", usage);
+    usage.check(&args.memory_budgets)?;
+
+    let builder = if args.auto_frame {
+        builder.frame(world.bounded_box())?
+    } else {
+        builder
+    };
+
+    // The debug renderers are deterministic, so they always need a seed.
+    let debugging = args.debug_render || args.trace_pixel.is_some();
+    let seed = args.seed.or(if debugging { Some(0) } else { None });
+    let thermal = args.thermal.is_some();
+    if args.live.is_some() && (debugging || args.range.is_some() || args.light_paths) {
+        anyhow::bail!("--live only works with the tiled renderer");
+    }
+    // With --split, the right half of the image is rendered with the
+    // overridden settings.
+    let mut screen = SplitScreen::new(builder.seed(seed).thermal(thermal), &args.split)?;
+    if let Some(photons) = args.caustics {
+        let start = std::time::Instant::now();
+        let stored = screen.left.trace_caustics(&world, photons);
+        if !args.split.is_empty() {
+            screen.right.trace_caustics(&world, photons);
+        }
+        println!(
+            "Caustics: {} of {} photons stored in {:?}",
+            stored,
+            photons,
+            start.elapsed()
+        );
+    }
+
+    // Range mode writes time-of-flight data instead of an image: the mean
+    // first-hit distance per pixel and per-bounce path length histograms.
+    if let Some(bin_width) = args.range {
+        if bin_width.is_nan() || bin_width <= 0.0 {
+            anyhow::bail!("--range bin width must be positive");
+        }
+        let start = std::time::Instant::now();
+        let mut pixels = vec![range::PixelRange::default(); (width * height) as usize];
+        pixels
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    screen
+                        .left
+                        .add_ranges(x as u32, y as u32, &world, bin_width, pixel);
+                }
+            });
+        println!("Traced in {:?}", start.elapsed());
+        range::save_range_image(&output.with_extension("pfm"), width, height, &pixels)?;
+        return range::save_histograms(
+            &output.with_extension("hist"),
+            width,
+            height,
+            bin_width,
+            &pixels,
+        );
+    }
+
+    // Settings typed while rendering go on top of the command line's.
+    let cli_grade = |mut grade: Grade, typed: &[(String, String)]| -> Result<Grade> {
+        for (key, value) in args.grade.iter().chain(typed) {
+            grade = grade.setting(key, value)?;
+        }
+        Ok(grade)
+    };
+    let mut canvas = Canvas::new(width, height).with_grade(cli_grade(grade, &[])?);
+
+    if debugging {
+        #[cfg(not(feature = "debug-render"))]
+        anyhow::bail!("debug rendering requires building with `--features debug-render`");
+
+        #[cfg(feature = "debug-render")]
+        {
+            if let Some((x, y)) = args.trace_pixel {
+                debug::trace_pixel(&screen.left, &world, x, y);
+                return Ok(());
+            }
+            let start = std::time::Instant::now();
+            debug::render_sequential(&screen.left, &world, &mut canvas);
+            println!("Rendered in {:?}", start.elapsed());
+            return canvas.save(&output);
+        }
+    }
+
+    let order = match args.focus {
+        None => tiles::Order::Cost,
+        Some(Focus::Center) => tiles::Order::Focus(width / 2, height / 2),
+        Some(Focus::Pixel(x, y)) => tiles::Order::Focus(x, y),
+    };
+    let start = std::time::Instant::now();
+    let mut typed_grade = vec![];
+    let image: Vec<DVec3> = if args.light_paths {
+        // Each light path component is summed separately and written as a
+        // linear image for compositing; the image is their sum.
+        let mut sums = vec![[DVec3::ZERO; COMPONENTS]; (width * height) as usize];
+        sums.par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let camera = screen.at(x as u32);
+                    camera.add_light_paths(x as u32, y as u32, &world, pixel);
+                }
+            });
+        for component in Component::ALL {
+            let path = output.with_extension(format!("{}.pfm", component.name()));
+            light_paths::save_pfm(&path, width, height, |x, y| {
+                screen
+                    .at(x)
+                    .pixel_color(sums[(y * width + x) as usize][component as usize])
+            })?;
+        }
+        sums.iter().map(|pixel| pixel.iter().sum()).collect()
+    } else {
+        let passes = screen.left.passes().max(screen.right.passes());
+        let bar = ProgressBar::new(width as u64 * height as u64 * screen.left.samples() as u64);
+        let mut image = vec![DVec3::ZERO; (width * height) as usize];
+        let live = match &args.live {
+            Some(path) => Some(live::LiveImage::create(path, width, height)?),
+            None => None,
+        };
+        let controls = match args.preview {
+            Some(_) if passes > 1 => Some(preview::Controls::spawn()),
+            _ => None,
+        };
+        for pass in 0..passes {
+            tiles::render_onto(
+                &mut image,
+                width,
+                height,
+                screen.left.samples_per_pass(),
+                order,
+                &bar,
+                |x, y, part, sum| {
+                    let camera = screen.at(x);
+                    let samples = camera.pass_samples(pass);
+                    let part = part.samples(samples.len() as u32);
+                    let start = samples.start;
+                    camera.add_samples(x, y, &world, start + part.start..start + part.end, sum);
+                    if let Some(live) = &live {
+                        live.update(x, y, *sum, start + part.end);
+                    }
+                },
+            );
+            // Every pass but the last leaves a complete if noisier image, saved
+            // so long renders can be watched and stopped once good enough.
+            if pass + 1 < passes && args.thermal.is_none() {
+                for y in 0..height {
+                    for x in 0..width {
+                        let sum = image[(y * width + x) as usize];
+                        canvas.draw(x, y, screen.at(x).partial_color(sum, pass + 1));
+                    }
+                }
+                canvas.save(&output)?;
+                if let Some(columns) = args.preview {
+                    let stats = format!(
+                        "Pass {} of {}, {} samples per pixel, {:.1?}",
+                        pass + 1,
+                        passes,
+                        screen.left.samples_per_pass() * (pass + 1),
+                        start.elapsed()
+                    );
+                    bar.suspend(|| eprintln!("{}{}", preview::text(&canvas, columns), stats));
+                }
+            }
+            for typed in controls.iter().flat_map(preview::Controls::take) {
+                let applied = typed.and_then(|(key, value)| {
+                    if key == "exposure" {
+                        Grade::default().setting(&key, &value)?;
+                        typed_grade.push((key.clone(), value.clone()));
+                    } else {
+                        screen.left.adjust(&key, &value)?;
+                        screen.right.adjust(&key, &value)?;
+                    }
+                    Ok(format!("{}={} from the next pass", key, value))
+                });
+                match applied {
+                    Ok(message) => bar.suspend(|| eprintln!("{}", message)),
+                    Err(err) => bar.suspend(|| eprintln!("{:#}", err)),
+                }
+            }
+            canvas = canvas.with_grade(cli_grade(grade, &typed_grade)?);
+        }
+        bar.finish();
+        if let Some(live) = &live {
+            live.finish()?;
+        }
+        image
+    };
+    let mut colors: Vec<Color3> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| screen.at(x).pixel_color(image[(y * width + x) as usize]))
+        .collect();
+    if args.denoise {
+        let guides: Vec<_> = (0..height)
+            .into_par_iter()
+            .flat_map_iter(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| screen.at(x).guide(x, y, &world))
+            .collect();
+        denoise::denoise(width, height, &mut colors, &guides);
+    }
+    let pixel = |x: u32, y: u32| colors[(y * width + x) as usize];
+    // Exposure and white balance come from the gray card, so settings on the
+    // command line still apply on top.
+    if let Some(card) = &gray_card {
+        let color = gray_card_color(&screen, &world, card.as_ref(), width, height, pixel)?;
+        let calibrated = grade.calibrate(color)?;
+        canvas = canvas.with_grade(cli_grade(calibrated, &typed_grade)?);
+    }
+    match args.thermal {
+        None => {
+            for y in 0..height {
+                for x in 0..width {
+                    canvas.draw(x, y, pixel(x, y));
+                }
+            }
+        }
+        Some(range) => {
+            let intensity = |x, y| thermal::intensity(pixel(x, y));
+            let (min, max) = match range {
+                ThermalRange::Fixed(min, max) => (min, max),
+                ThermalRange::Auto => (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (x, y)))
+                    .map(|(x, y)| intensity(x, y))
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| {
+                        (min.min(v), max.max(v))
+                    }),
+            };
+            println!("Thermal range: {} to {}", min, max);
+            for y in 0..height {
+                for x in 0..width {
+                    canvas.set(x, y, thermal::false_color(intensity(x, y), min, max));
+                }
+            }
+        }
+    }
+    println!("Rendered in {:?}", start.elapsed());
+    if let Some(columns) = args.preview {
+        eprint!("{}", preview::text(&canvas, columns));
+    }
+
+    canvas.save(&output)?;
+    Ok(())
+}
+
+// Mean color of the pixels whose primary ray hits the gray card before
+// anything else, each seen by the camera that rendered it.
+fn gray_card_color(
+    screen: &SplitScreen,
+    world: &dyn Hittable,
+    card: &dyn Hittable,
+    width: u32,
+    height: u32,
+    pixel: impl Fn(u32, u32) -> Color3,
+) -> Result<Color3> {
+    let mut sum = DVec3::ZERO;
+    let mut count = 0;
+    for y in 0..height {
+        for x in 0..width {
+            if screen.at(x).sees(x, y, world, card) {
+                sum += pixel(x, y).as_dvec3();
+                count += 1;
+            }
+        }
+    }
+    if count == 0 {
+        anyhow::bail!("the gray card isn't visible");
+    }
+    let color = (sum / count as f64).as_vec3();
+    println!("Gray card: {} pixels, mean {:?}", count, color);
+    Ok(color)
+}
+
+fn spheres_scene(world: &mut HittableVec, cam_builder: CameraBuilder) -> CameraBuilder {
+    let mat_ground = Material::new_lambertian(0.8, 0.8, 0.0);
+    let mat_center = Material::new_lambertian(0.1, 0.2, 0.5);
+    let mat_left = Material::new_dielectric(1.5);
+    let mat_right = Material::new_metal(0.8, 0.6, 0.2, 0.0);
+
+    world.append(&mut vec![
+        Box::new(Plane::new(point3(0.0, -0.5, 0.0), Vec3::Y, mat_ground).unwrap()),
+        Box::new(Sphere::new(point3(0.0, 0.0, -1.0), 0.5, mat_center)),
+        Box::new(Sphere::new(point3(-1.0, 0.0, -1.0), 0.5, mat_left.clone())),
+        Box::new(Sphere::new(point3(-1.0, 0.0, -1.0), -0.4, mat_left)),
+        Box::new(Sphere::new(point3(1.0, 0.0, -1.0), 0.5, mat_right)),
+    ]);
+
+    cam_builder
+        .background(color3(0.7, 0.8, 1.0))
+        .vert_fov(20.0)
+        .look_from(point3(-2.0, 2.0, 1.0))
+        .look_at(point3(0.0, 0.0, -1.0))
+        .look_up(vec3(0.0, 1.0, 0.0))
+        .defocus_angle(3.0)
+        .focus_dist(3.4)
+}
+
+// The final scene of the first book: a field of small spheres of random
+// materials around three big ones. The seed is fixed, so it is the same
+// scene on every run.
+fn random_spheres(world: &mut HittableVec, cam_builder: CameraBuilder) -> CameraBuilder {
+    let mut rng = StdRng::seed_from_u64(0);
+    world.push(Box::new(Sphere::new(
+        point3(0.0, -1000.0, 0.0),
+        1000.0,
+        Material::new_lambertian(0.5, 0.5, 0.5),
+    )));
+    for a in -11..11 {
+        for b in -11..11 {
+            let center = point3(
+                a as f32 + 0.9 * rng.gen::<f32>(),
+                0.2,
+                b as f32 + 0.9 * rng.gen::<f32>(),
+            );
+            let choice: f32 = rng.gen();
+            // Keep clear of the metal sphere.
+            if (center - point3(4.0, 0.2, 0.0)).length() <= 0.9 {
+                continue;
+            }
+            let mat = if choice < 0.8 {
+                let albedo = Vec3::from_array(rng.gen()) * Vec3::from_array(rng.gen());
+                Material::new_lambertian(albedo.x, albedo.y, albedo.z)
+            } else if choice < 0.95 {
+                let albedo = Vec3::from_array(rng.gen()) * 0.5 + 0.5;
+                Material::new_metal(albedo.x, albedo.y, albedo.z, 0.5 * rng.gen::<f32>())
+            } else {
+                Material::new_dielectric(1.5)
+            };
+            world.push(Box::new(Sphere::new(center, 0.2, mat)));
+        }
+    }
+    world.append(&mut vec![
+        Box::new(Sphere::new(
+            point3(0.0, 1.0, 0.0),
+            1.0,
+            Material::new_dielectric(1.5),
+        )),
+        Box::new(Sphere::new(
+            point3(-4.0, 1.0, 0.0),
+            1.0,
+            Material::new_lambertian(0.4, 0.2, 0.1),
+        )),
+        Box::new(Sphere::new(
+            point3(4.0, 1.0, 0.0),
+            1.0,
+            Material::new_metal(0.7, 0.6, 0.5, 0.0),
+        )),
+    ]);
+
+    cam_builder
+        .background(color3(0.7, 0.8, 1.0))
+        .vert_fov(20.0)
+        .look_from(point3(13.0, 2.0, 3.0))
+        .look_at(point3(0.0, 0.0, 0.0))
+        .look_up(vec3(0.0, 1.0, 0.0))
+        .defocus_angle(0.6)
+        .focus_dist(10.0)
+}
+
+// The final scene of the second book, touching nearly every part of the
+// renderer: a ground of boxes of random heights, an area light, motion blur,
+// glass, metal, smoke inside glass and around everything, procedural
+// textures, an image-mapped globe and an instanced cluster of spheres.
+fn showcase(world: &mut HittableVec, cam_builder: CameraBuilder) -> CameraBuilder {
+    let mut rng = StdRng::seed_from_u64(0);
+    let light = Material::new_light(7.0, 7.0, 7.0);
+    let lamp = || {
+        Box::new(Quad::new(
+            point3(123.0, 554.0, 147.0),
+            vec3(300.0, 0.0, 0.0),
+            vec3(0.0, 0.0, 265.0),
+            light.clone(),
+        ))
+    };
+
+    let ground = Material::new_lambertian(0.48, 0.83, 0.53);
+    let mut boxes: HittableVec = vec![];
+    for i in 0..20 {
+        for j in 0..20 {
+            let min = point3(-1000.0 + i as f32 * 100.0, 0.0, -1000.0 + j as f32 * 100.0);
+            let max = min + vec3(100.0, rng.gen_range(1.0..101.0), 100.0);
+            boxes.append(&mut make_box(min, max, ground.clone()));
+        }
+    }
+
+    let mut cluster: HittableVec = vec![];
+    let white = Material::new_lambertian(0.73, 0.73, 0.73);
+    for _ in 0..1000 {
+        let center = Vec3::from_array(rng.gen()) * 165.0;
+        cluster.push(Box::new(Sphere::new(center, 10.0, white.clone())));
+    }
+    let cluster_to_world = Affine3A::from_translation(vec3(-100.0, 270.0, 395.0))
+        * Affine3A::from_rotation_y(15f32.to_radians());
+
+    let glass = Material::new_dielectric(1.5);
+    let smoke = |boundary, density, color| {
+        let albedo = Arc::new(SolidColor::new(color));
+        Box::new(Medium::new(Box::new(boundary), density, albedo))
+    };
+    let globe = ImageTexture::new(globe_map(512, 256));
+    let marble = NoiseTexture::new(NoiseStyle::Marble, 0.2, color3(1.0, 1.0, 1.0));
+
+    world.append(&mut vec![
+        Box::new(Bvh::new(boxes)),
+        lamp(),
+        Box::new(Sphere::moving(
+            point3(400.0, 400.0, 200.0),
+            point3(430.0, 400.0, 200.0),
+            50.0,
+            Material::new_lambertian(0.7, 0.3, 0.1),
+        )),
+        Box::new(Sphere::new(point3(260.0, 150.0, 45.0), 50.0, glass.clone())),
+        Box::new(Sphere::new(
+            point3(0.0, 150.0, 145.0),
+            50.0,
+            Material::new_metal(0.8, 0.8, 0.9, 1.0),
+        )),
+        // A glass ball full of blue smoke.
+        Box::new(Sphere::new(
+            point3(360.0, 150.0, 145.0),
+            70.0,
+            glass.clone(),
+        )),
+        smoke(
+            Sphere::new(point3(360.0, 150.0, 145.0), 70.0, glass.clone()),
+            0.2,
+            color3(0.2, 0.4, 0.9),
+        ),
+        // Thin mist over the whole scene.
+        smoke(
+            Sphere::new(Point3::ZERO, 5000.0, glass),
+            0.0001,
+            color3(1.0, 1.0, 1.0),
+        ),
+        Box::new(Sphere::new(
+            point3(400.0, 200.0, 400.0),
+            100.0,
+            Material::Lambertian {
+                albedo: Arc::new(globe),
+            },
+        )),
+        Box::new(Sphere::new(
+            point3(220.0, 280.0, 300.0),
+            80.0,
+            Material::Lambertian {
+                albedo: Arc::new(marble),
+            },
+        )),
+        Box::new(Instance::new(Arc::new(Bvh::new(cluster)), cluster_to_world)),
+    ]);
+
+    cam_builder
+        .background(color3(0.0, 0.0, 0.0))
+        .lights(vec![lamp()])
+        .vert_fov(40.0)
+        .look_from(point3(478.0, 278.0, -600.0))
+        .look_at(point3(278.0, 278.0, 0.0))
+        .look_up(vec3(0.0, 1.0, 0.0))
+        .defocus_angle(0.0)
+}
+
+// A made-up map of a world for the showcase's globe, as built-in scenes
+// carry no image files: seas and continents of turbulent noise with ice
+// towards the poles, laid out equirectangularly as spheres map textures.
+fn globe_map(width: u32, height: u32) -> Canvas {
+    let relief = NoiseTexture::new(NoiseStyle::Smooth, 2.0, color3(1.0, 1.0, 1.0));
+    let mut map = Canvas::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            // The point on a unit sphere whose texture coordinates these are.
+            let theta = PI * (1.0 - (y as f32 + 0.5) / height as f32);
+            let phi = TAU * (x as f32 + 0.5) / width as f32 - PI;
+            let p = vec3(
+                theta.sin() * phi.cos(),
+                -theta.cos(),
+                -theta.sin() * phi.sin(),
+            );
+            let land = relief.value(Vec2::ZERO, p).x;
+            let color = if p.y.abs() + 0.1 * land > 0.97 {
+                color3(0.9, 0.92, 0.95)
+            } else if land > 0.5 {
+                color3(0.25, 0.5, 0.2).lerp(color3(0.55, 0.45, 0.3), (land - 0.5) * 4.0)
+            } else {
+                color3(0.05, 0.15, 0.45).lerp(color3(0.1, 0.3, 0.6), land * 2.0)
+            };
+            map.set(x, y, color);
+        }
+    }
+    map
+}
+
+fn cornell_box(world: &mut HittableVec, cam_builder: CameraBuilder) -> CameraBuilder {
+    let red = Material::new_lambertian(0.65, 0.05, 0.05);
+    let white = Material::new_lambertian(0.73, 0.73, 0.73);
+    let green = Material::new_lambertian(0.12, 0.45, 0.15);
+    let light = Material::new_light(15.0, 15.0, 15.0);
+    let lamp = || {
+        Box::new(Quad::new(
+            point3(343.0, 554.0, 332.0),
+            vec3(-130.0, 0.0, 0.0),
+            vec3(0.0, 0.0, -105.0),
+            light.clone(),
+        ))
+    };
+
+    world.append(&mut vec![
+        Box::new(Quad::new(
+            point3(555.0, 0.0, 0.0),
+            vec3(0.0, 555.0, 0.0),
+            vec3(0.0, 0.0, 555.0),
+            green,
+        )),
+        Box::new(Quad::new(
+            point3(0.0, 0.0, 0.0),
+            vec3(0.0, 555.0, 0.0),
+            vec3(0.0, 0.0, 555.0),
+            red,
+        )),
+        lamp(),
+        Box::new(Quad::new(
+            point3(0.0, 0.0, 0.0),
+            vec3(555.0, 0.0, 0.0),
+            vec3(0.0, 0.0, 555.0),
+            white.clone(),
+        )),
+        Box::new(Quad::new(
+            point3(555.0, 555.0, 555.0),
+            vec3(-555.0, 0.0, 0.0),
+            vec3(0.0, 0.0, -555.0),
+            white.clone(),
+        )),
+        Box::new(Quad::new(
+            point3(0.0, 0.0, 555.0),
+            vec3(555.0, 0.0, 0.0),
+            vec3(0.0, 555.0, 0.0),
+            white.clone(),
+        )),
+        Box::new(Translate::new(
+            vec3(265.0, 0.0, 295.0),
+            Box::new(RotateY::new(
+                15.0,
+                Box::new(make_box(
+                    point3(0.0, 0.0, 0.0),
+                    point3(165.0, 330.0, 165.0),
+                    white.clone(),
+                )),
+            )),
+        )),
+        Box::new(Translate::new(
+            vec3(130.0, 0.0, 65.0),
+            Box::new(RotateY::new(
+                -18.0,
+                Box::new(make_box(
+                    point3(0.0, 0.0, 0.0),
+                    point3(165.0, 165.0, 165.0),
+                    white,
+                )),
+            )),
+        )),
+    ]);
+
+    cam_builder
+        .background(color3(0.0, 0.0, 0.0))
+        .lights(vec![lamp()])
+        .vert_fov(40.0)
+        .look_from(point3(278.0, 278.0, -800.0))
+        .look_at(point3(278.0, 278.0, 0.0))
+        .look_up(vec3(0.0, 1.0, 0.0))
+        .defocus_angle(0.0)
+}
+
+pub type Color3 = Vec3;
+pub type Point3 = Vec3;
+
+pub fn point3(x: f32, y: f32, z: f32) -> Point3 {
+    Point3::new(x, y, z)
+}
+
+pub fn color3(x: f32, y: f32, z: f32) -> Color3 {
+    Color3::new(x, y, z)
+}
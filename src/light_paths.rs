@@ -0,0 +1,127 @@
+use crate::hittables::Hit;
+use crate::materials::{Lobe, Material, Scattered};
+use crate::Color3;
+use anyhow::Result;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// What a path did at its first hit: a camera path's radiance goes to the
+// component of the lobe it scattered into there, or to emission for light
+// emitted by the surface itself (and the background, for rays that miss).
+// The components add up to the rendered image.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Component {
+    Diffuse,
+    Glossy,
+    Transmission,
+    Emission,
+}
+
+pub const COMPONENTS: usize = 4;
+
+impl Component {
+    pub const ALL: [Component; COMPONENTS] = [
+        Component::Diffuse,
+        Component::Glossy,
+        Component::Transmission,
+        Component::Emission,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Component::Diffuse => "diffuse",
+            Component::Glossy => "glossy",
+            Component::Transmission => "transmission",
+            Component::Emission => "emission",
+        }
+    }
+
+    // Anything that goes below the surface is transmission; above it, only
+    // metal and dielectric reflections are glossy. Measured and custom
+    // materials count as diffuse, since their lobes aren't known, and so does
+    // scattering in media, which have no surface to go below.
+    pub fn of_scatter(hit: &Hit, scattered: &Scattered) -> Component {
+        if let Material::Isotropic { .. } = hit.material.base() {
+            return Component::Diffuse;
+        }
+        if scattered.ray.dir().dot(hit.normal) < 0.0 {
+            return Component::Transmission;
+        }
+        match hit.material.base() {
+            Material::Metal { .. } | Material::Dielectric { .. } => Component::Glossy,
+            _ => Component::Diffuse,
+        }
+    }
+
+    // Where light sampled directly at a first hit goes.
+    pub fn of_lobe(lobe: Lobe) -> Component {
+        match lobe {
+            Lobe::Diffuse { .. } => Component::Diffuse,
+            Lobe::Glossy { .. } => Component::Glossy,
+        }
+    }
+}
+
+// Linear RGB PFM, which stores rows bottom to top.
+pub fn save_pfm(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixel: impl Fn(u32, u32) -> Color3,
+) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    // A negative scale marks the data as little-endian.
+    write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+    for y in (0..height).rev() {
+        for x in 0..width {
+            for channel in pixel(x, y).to_array() {
+                w.write_all(&channel.to_le_bytes())?;
+            }
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::{HittableVec, Sphere};
+    use crate::render::Camera;
+    use crate::{color3, point3};
+    use glam::DVec3;
+
+    #[test]
+    fn components_add_up_to_image() {
+        let mut world: HittableVec = vec![];
+        let mut add = |x, mat| world.push(Box::new(Sphere::new(point3(x, 0.0, 2.0), 0.5, mat)));
+        add(-1.0, Material::new_lambertian(0.5, 0.5, 0.5));
+        add(0.0, Material::new_metal(0.8, 0.8, 0.8, 0.0));
+        add(1.0, Material::new_dielectric(1.5));
+        add(2.0, Material::new_light(2.0, 2.0, 2.0));
+        let camera = Camera::builder(40, 10)
+            .look_from(point3(0.5, 0.0, 0.0))
+            .look_at(point3(0.5, 0.0, 2.0))
+            .background(color3(0.3, 0.4, 0.5))
+            .samples(8)
+            .seed(Some(1))
+            .build();
+
+        let mut totals = [DVec3::ZERO; COMPONENTS];
+        for y in 0..10 {
+            for x in 0..40 {
+                let mut expected = DVec3::ZERO;
+                camera.add_samples(x, y, &world, 0..camera.samples(), &mut expected);
+                let mut sums = [DVec3::ZERO; COMPONENTS];
+                camera.add_light_paths(x, y, &world, &mut sums);
+                let sum: DVec3 = sums.iter().sum();
+                assert!(sum.distance(expected) < 1e-4, "pixel ({}, {})", x, y);
+                for (total, sum) in totals.iter_mut().zip(sums) {
+                    *total += sum;
+                }
+            }
+        }
+        assert!(totals.iter().all(|total| total.min_element() > 0.0));
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use glam::DVec3;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 4] = b"RTLV";
+const VERSION: u32 = 1;
+const HEADER_BYTES: usize = 24;
+// Held in memory as well as in the file.
+pub const BYTES_PER_PIXEL: usize = 4 * std::mem::size_of::<f32>();
+// Often enough to watch tiles fill in, rarely enough that rewriting even a
+// large file costs little next to rendering.
+const INTERVAL: Duration = Duration::from_millis(500);
+
+// A copy of the accumulation buffer on disk for external viewers, rewritten
+// every INTERVAL while rendering. The file is replaced by a rename, so
+// readers always see a complete one. Little-endian layout:
+//
+//   magic "RTLV", u32 version (1), u32 width, u32 height,
+//   u32 generation (counts rewrites), u32 done (1 once rendering finished),
+//   then per pixel in row-major order from the top left, f32 red, green and
+//   blue sums of the linear radiance of its samples so far and f32 sample
+//   count. The pixel's color is its sums over the count; 0 samples means it
+//   hasn't been reached yet.
+pub struct LiveImage {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    // Four f32s per pixel, as bits so workers can store them without locking.
+    values: Vec<AtomicU32>,
+    // When the file was last rewritten and how many times.
+    written: Mutex<(Instant, u32)>,
+}
+
+impl LiveImage {
+    // Writes an empty image right away, so viewers can open it before the
+    // first samples arrive.
+    pub fn create(path: &Path, width: u32, height: u32) -> Result<LiveImage> {
+        let image = LiveImage {
+            path: path.to_path_buf(),
+            width,
+            height,
+            values: (0..width as usize * height as usize * 4)
+                .map(|_| AtomicU32::new(0))
+                .collect(),
+            written: Mutex::new((Instant::now(), 0)),
+        };
+        image.write(false, &mut image.written.lock().unwrap())?;
+        Ok(image)
+    }
+
+    // Records the pixel's running sum over `samples` samples, and rewrites the
+    // file if it is due. Workers never wait for each other here: whoever
+    // finds the file due and nobody else writing it does the rewrite.
+    pub fn update(&self, x: u32, y: u32, sum: DVec3, samples: u32) {
+        let idx = (y * self.width + x) as usize * 4;
+        let sum = sum.as_vec3();
+        for (value, v) in
+            self.values[idx..idx + 4]
+                .iter()
+                .zip([sum.x, sum.y, sum.z, samples as f32])
+        {
+            value.store(v.to_bits(), Ordering::Relaxed);
+        }
+        if let Ok(mut written) = self.written.try_lock() {
+            if written.0.elapsed() >= INTERVAL {
+                // A failed rewrite is tried again next time; finish() reports
+                // it.
+                let _ = self.write(false, &mut written);
+            }
+        }
+    }
+
+    // The final rewrite, marking the image as done.
+    pub fn finish(&self) -> Result<()> {
+        self.write(true, &mut self.written.lock().unwrap())
+    }
+
+    fn write(&self, done: bool, written: &mut (Instant, u32)) -> Result<()> {
+        let generation = written.1 + 1;
+        let mut bytes = Vec::with_capacity(HEADER_BYTES + self.values.len() * 4);
+        bytes.extend_from_slice(MAGIC);
+        for value in [VERSION, self.width, self.height, generation, done as u32] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in &self.values {
+            bytes.extend_from_slice(&value.load(Ordering::Relaxed).to_le_bytes());
+        }
+        let temp = self.path.with_extension("live.tmp");
+        std::fs::write(&temp, &bytes).with_context(|| format!("writing {}", temp.display()))?;
+        std::fs::rename(&temp, &self.path)
+            .with_context(|| format!("writing {}", self.path.display()))?;
+        *written = (Instant::now(), generation);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_the_documented_layout() {
+        let path = std::env::temp_dir().join(format!("live-{}.bin", std::process::id()));
+        let image = LiveImage::create(&path, 3, 2).unwrap();
+        image.update(2, 1, DVec3::new(1.0, 2.0, 3.0), 4);
+        image.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let word = |idx: usize| u32::from_le_bytes(bytes[idx * 4..idx * 4 + 4].try_into().unwrap());
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!([word(1), word(2), word(3), word(5)], [VERSION, 3, 2, 1]);
+        // At least the empty image and the final one were written.
+        assert!(word(4) >= 2);
+        assert_eq!(bytes.len(), HEADER_BYTES + 3 * 2 * BYTES_PER_PIXEL);
+        let pixel = |idx: usize| -> Vec<f32> {
+            let start = HEADER_BYTES / 4 + idx * 4;
+            (start..start + 4)
+                .map(|idx| f32::from_bits(word(idx)))
+                .collect()
+        };
+        assert_eq!(pixel(5), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(pixel(0), [0.0; 4]);
+    }
+}
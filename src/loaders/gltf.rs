@@ -0,0 +1,573 @@
+use super::Resolve;
+use crate::hittables::Mesh;
+use crate::json::Json;
+use crate::materials::Material;
+use crate::textures::SolidColor;
+use crate::{Color3, Point3};
+use anyhow::{anyhow, bail, Context, Result};
+use glam::{vec2, Affine3A, Mat3, Mat4, Quat, Vec2, Vec3};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const GLB_MAGIC: &[u8] = b"glTF";
+const CHUNK_JSON: u32 = 0x4E4F534A;
+const CHUNK_BIN: u32 = 0x004E4942;
+const MODE_TRIANGLES: u32 = 4;
+const UNSIGNED_BYTE: u32 = 5121;
+const UNSIGNED_SHORT: u32 = 5123;
+const UNSIGNED_INT: u32 = 5125;
+const FLOAT: u32 = 5126;
+
+// Loads the triangles of a glTF 2.0 scene (`.gltf` with its buffers, or a
+// binary `.glb`) into a single mesh, with node transforms baked into the
+// vertices. Vertex normals give smooth shading and the first set of texture
+// coordinates is kept; other primitive modes, textures and animations are
+// ignored.
+// Materials are mapped onto the built-in ones from their PBR factors;
+// primitives without a material get `default`. `resolve` turns buffer URIs
+// into paths.
+pub fn load(path: &Path, default: Material, resolve: Resolve) -> Result<Mesh> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    parse(&bytes, default, resolve).with_context(|| format!("in {}", path.display()))
+}
+
+fn parse(
+    bytes: &[u8],
+    default: Material,
+    resolve: impl Fn(&str) -> Result<PathBuf>,
+) -> Result<Mesh> {
+    let (doc, bin) = if bytes.starts_with(GLB_MAGIC) {
+        split_glb(bytes)?
+    } else {
+        (Json::parse(std::str::from_utf8(bytes)?)?, None)
+    };
+
+    let mut buffers = vec![];
+    for (idx, buffer) in items(&doc, "buffers").iter().enumerate() {
+        let data = match buffer.get("uri").and_then(Json::as_str) {
+            None => bin
+                .clone()
+                .ok_or_else(|| anyhow!("buffer #{} has no data", idx))?,
+            Some(uri) => match uri.strip_prefix("data:") {
+                Some(data) => {
+                    let (_, encoded) = data
+                        .split_once(";base64,")
+                        .ok_or_else(|| anyhow!("buffer #{} is not base64 encoded", idx))?;
+                    base64(encoded).with_context(|| format!("buffer #{}", idx))?
+                }
+                None => {
+                    let path = resolve(uri)?;
+                    std::fs::read(&path)
+                        .with_context(|| format!("failed to read {}", path.display()))?
+                }
+            },
+        };
+        buffers.push(data);
+    }
+
+    let gltf = Gltf { doc: &doc, buffers };
+    let mut materials = vec![default];
+    for (idx, material) in items(&doc, "materials").iter().enumerate() {
+        materials.push(convert_material(material).with_context(|| format!("material #{}", idx))?);
+    }
+    let mut mesh = MeshData::default();
+    for node in gltf.root_nodes()? {
+        gltf.add_node(node, Affine3A::IDENTITY, &mut mesh)
+            .with_context(|| format!("node #{}", node))?;
+    }
+    let MeshData {
+        positions,
+        normals,
+        uvs,
+        any_uvs,
+        faces,
+        face_materials,
+    } = mesh;
+    let mut mesh = Mesh::new(positions, faces, materials, face_materials)?;
+    if any_uvs {
+        mesh = mesh.with_uvs(uvs)?;
+    }
+    if normals.iter().any(|&n| n != Vec3::ZERO) {
+        mesh = mesh.with_normals(normals)?;
+    }
+    Ok(mesh)
+}
+
+// Reads the JSON chunk and the optional binary chunk of a GLB container.
+fn split_glb(bytes: &[u8]) -> Result<(Json, Option<Vec<u8>>)> {
+    let word = |pos: usize| -> Result<u32> {
+        let bytes = bytes
+            .get(pos..pos + 4)
+            .ok_or_else(|| anyhow!("truncated GLB"))?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    if word(4)? != 2 {
+        bail!("unsupported glTF version {}", word(4)?);
+    }
+    let mut doc = None;
+    let mut bin = None;
+    let mut pos = 12;
+    while pos < bytes.len() {
+        let (length, kind) = (word(pos)? as usize, word(pos + 4)?);
+        let data = bytes
+            .get(pos + 8..pos + 8 + length)
+            .ok_or_else(|| anyhow!("truncated GLB chunk"))?;
+        match kind {
+            CHUNK_JSON => doc = Some(Json::parse(std::str::from_utf8(data)?)?),
+            CHUNK_BIN => bin = Some(data.to_vec()),
+            _ => {}
+        }
+        pos += 8 + length;
+    }
+    Ok((doc.ok_or_else(|| anyhow!("GLB without JSON chunk"))?, bin))
+}
+
+#[derive(Default)]
+struct MeshData {
+    positions: Vec<Point3>,
+    // Zero for primitives without normals, which stay flat, or texture
+    // coordinates.
+    normals: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+    any_uvs: bool,
+    faces: Vec<[u32; 3]>,
+    face_materials: Vec<u32>,
+}
+
+struct Gltf<'a> {
+    doc: &'a Json,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl Gltf<'_> {
+    // The nodes of the default scene, or every node that isn't a child of
+    // another one when the file has no scenes.
+    fn root_nodes(&self) -> Result<Vec<u32>> {
+        let scenes = items(self.doc, "scenes");
+        if !scenes.is_empty() {
+            let scene = index(self.doc, "scene")?.unwrap_or(0);
+            let scene = element(scenes, scene, "scene")?;
+            return items(scene, "nodes").iter().map(to_index).collect();
+        }
+        let nodes = items(self.doc, "nodes");
+        let mut children = vec![];
+        for node in nodes {
+            for child in items(node, "children") {
+                children.push(to_index(child)?);
+            }
+        }
+        Ok((0..nodes.len() as u32)
+            .filter(|idx| !children.contains(idx))
+            .collect())
+    }
+
+    fn add_node(&self, idx: u32, parent: Affine3A, mesh: &mut MeshData) -> Result<()> {
+        let node = element(items(self.doc, "nodes"), idx, "node")?;
+        let to_world = parent * node_transform(node)?;
+        if let Some(mesh_idx) = index(node, "mesh")? {
+            let primitives = items(
+                element(items(self.doc, "meshes"), mesh_idx, "mesh")?,
+                "primitives",
+            );
+            for (idx, primitive) in primitives.iter().enumerate() {
+                self.add_primitive(primitive, to_world, mesh)
+                    .with_context(|| format!("mesh #{} primitive #{}", mesh_idx, idx))?;
+            }
+        }
+        for child in items(node, "children") {
+            let child = to_index(child)?;
+            self.add_node(child, to_world, mesh)
+                .with_context(|| format!("node #{}", child))?;
+        }
+        Ok(())
+    }
+
+    fn add_primitive(
+        &self,
+        primitive: &Json,
+        to_world: Affine3A,
+        mesh: &mut MeshData,
+    ) -> Result<()> {
+        if index(primitive, "mode")?.unwrap_or(MODE_TRIANGLES) != MODE_TRIANGLES {
+            return Ok(());
+        }
+        let attributes = primitive.get("attributes");
+        let position = attributes
+            .and_then(|attributes| attributes.get("POSITION"))
+            .ok_or_else(|| anyhow!("missing POSITION attribute"))?;
+        let positions = self.read_vec3(to_index(position)?)?;
+        let normals = match attributes.and_then(|attributes| attributes.get("NORMAL")) {
+            Some(normal) => self.read_vec3(to_index(normal)?)?,
+            None => vec![Vec3::ZERO; positions.len()],
+        };
+        if normals.len() != positions.len() {
+            bail!(
+                "{} normals for {} positions",
+                normals.len(),
+                positions.len()
+            );
+        }
+        // glTF texture coordinates run down from the top of the image; they
+        // are flipped to run up, as elsewhere.
+        let uvs = match attributes.and_then(|attributes| attributes.get("TEXCOORD_0")) {
+            Some(uv) => {
+                mesh.any_uvs = true;
+                let uvs = self.read_vectors::<2>(to_index(uv)?, "VEC2")?;
+                uvs.into_iter().map(|[u, v]| vec2(u, 1.0 - v)).collect()
+            }
+            None => vec![Vec2::ZERO; positions.len()],
+        };
+        if uvs.len() != positions.len() {
+            bail!(
+                "{} texture coordinates for {} positions",
+                uvs.len(),
+                positions.len()
+            );
+        }
+        let indices = match index(primitive, "indices")? {
+            Some(accessor) => self.read_indices(accessor)?,
+            None => (0..positions.len() as u32).collect(),
+        };
+        // Material 0 of the mesh is the default one.
+        let material = index(primitive, "material")?.map_or(0, |idx| idx + 1);
+
+        let first = mesh.positions.len() as u32;
+        mesh.positions
+            .extend(positions.iter().map(|&p| to_world.transform_point3(p)));
+        let normal_to_world = Mat3::from(to_world.matrix3).inverse().transpose();
+        mesh.normals.extend(
+            normals
+                .iter()
+                .map(|&n| (normal_to_world * n).normalize_or_zero()),
+        );
+        mesh.uvs.extend(uvs);
+        for face in indices.chunks_exact(3) {
+            mesh.faces
+                .push([face[0], face[1], face[2]].map(|idx| first + idx));
+            mesh.face_materials.push(material);
+        }
+        Ok(())
+    }
+
+    // The bytes of an accessor's elements, each `size` bytes long, with
+    // the stride between them.
+    fn accessor(&self, idx: u32, size: usize) -> Result<(&[u8], usize, usize, &Json)> {
+        let accessor = element(items(self.doc, "accessors"), idx, "accessor")?;
+        if accessor.get("sparse").is_some() {
+            bail!("sparse accessors are not supported");
+        }
+        let count = index(accessor, "count")?.ok_or_else(|| anyhow!("accessor without count"))?;
+        let view_idx = index(accessor, "bufferView")?
+            .ok_or_else(|| anyhow!("accessor #{} has no buffer view", idx))?;
+        let view = element(items(self.doc, "bufferViews"), view_idx, "buffer view")?;
+        let buffer = index(view, "buffer")?.unwrap_or(0);
+        let buffer = self
+            .buffers
+            .get(buffer as usize)
+            .ok_or_else(|| anyhow!("undefined buffer #{}", buffer))?;
+        let stride = index(view, "byteStride")?.map_or(size, |stride| stride as usize);
+        let start = index(view, "byteOffset")?.unwrap_or(0) as usize
+            + index(accessor, "byteOffset")?.unwrap_or(0) as usize;
+        let end = match count {
+            0 => start,
+            count => start + (count as usize - 1) * stride + size,
+        };
+        let data = buffer
+            .get(start..end)
+            .ok_or_else(|| anyhow!("accessor #{} runs past its buffer", idx))?;
+        Ok((data, count as usize, stride, accessor))
+    }
+
+    fn read_vec3(&self, idx: u32) -> Result<Vec<Vec3>> {
+        let vectors = self.read_vectors::<3>(idx, "VEC3")?;
+        Ok(vectors.into_iter().map(Vec3::from_array).collect())
+    }
+
+    // Texture coordinates may also be normalized unsigned bytes or shorts.
+    fn read_vectors<const N: usize>(&self, idx: u32, kind: &str) -> Result<Vec<[f32; N]>> {
+        let accessor = element(items(self.doc, "accessors"), idx, "accessor")?;
+        let normalized = accessor.get("normalized").and_then(Json::as_bool) == Some(true);
+        let size = match index(accessor, "componentType")? {
+            Some(FLOAT) => 4,
+            Some(UNSIGNED_BYTE) if normalized => 1,
+            Some(UNSIGNED_SHORT) if normalized => 2,
+            _ => 0,
+        };
+        if size == 0 || accessor.get("type").and_then(Json::as_str) != Some(kind) {
+            bail!("accessor #{} must hold float {} elements", idx, kind);
+        }
+        let (data, count, stride, _) = self.accessor(idx, N * size)?;
+        Ok((0..count)
+            .map(|n| {
+                std::array::from_fn(|c| {
+                    let pos = n * stride + size * c;
+                    let bytes = &data[pos..pos + size];
+                    match size {
+                        1 => bytes[0] as f32 / 255.0,
+                        2 => u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 65535.0,
+                        _ => f32::from_le_bytes(bytes.try_into().unwrap()),
+                    }
+                })
+            })
+            .collect())
+    }
+
+    fn read_indices(&self, idx: u32) -> Result<Vec<u32>> {
+        let accessor = element(items(self.doc, "accessors"), idx, "accessor")?;
+        let size = match index(accessor, "componentType")? {
+            Some(UNSIGNED_BYTE) => 1,
+            Some(UNSIGNED_SHORT) => 2,
+            Some(UNSIGNED_INT) => 4,
+            _ => bail!("accessor #{} must hold unsigned integer indices", idx),
+        };
+        let (data, count, stride, _) = self.accessor(idx, size)?;
+        Ok((0..count)
+            .map(|n| {
+                let bytes = &data[n * stride..n * stride + size];
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, &byte| value << 8 | byte as u32)
+            })
+            .collect())
+    }
+}
+
+// Either a column-major `matrix` or `translation`, `rotation` (a
+// quaternion) and `scale`.
+fn node_transform(node: &Json) -> Result<Affine3A> {
+    if let Some(matrix) = node.get("matrix") {
+        let values: [f32; 16] = numbers(matrix)?;
+        return Ok(Affine3A::from_mat4(Mat4::from_cols_array(&values)));
+    }
+    let vector = |key, default: Vec3| -> Result<Vec3> {
+        node.get(key)
+            .map_or(Ok(default), |value| numbers(value).map(Vec3::from_array))
+    };
+    let rotation = match node.get("rotation") {
+        Some(value) => Quat::from_array(numbers(value)?).normalize(),
+        None => Quat::IDENTITY,
+    };
+    Ok(Affine3A::from_scale_rotation_translation(
+        vector("scale", Vec3::ONE)?,
+        rotation,
+        vector("translation", Vec3::ZERO)?,
+    ))
+}
+
+// Emissive materials become lights, transmissive ones glass, metallic ones
+// metal with their roughness as fuzz, and everything else Lambertian.
+fn convert_material(material: &Json) -> Result<Material> {
+    let pbr = material.get("pbrMetallicRoughness");
+    let factor = |key| -> Result<Option<f32>> {
+        match pbr.and_then(|pbr| pbr.get(key)) {
+            Some(value) => Ok(Some(
+                value
+                    .as_f32()
+                    .ok_or_else(|| anyhow!("'{}' must be a number", key))?,
+            )),
+            None => Ok(None),
+        }
+    };
+    let base_color = match pbr.and_then(|pbr| pbr.get("baseColorFactor")) {
+        Some(value) => {
+            let [r, g, b, _]: [f32; 4] = numbers(value)?;
+            Color3::new(r, g, b)
+        }
+        None => Color3::ONE,
+    };
+    let metallic = factor("metallicFactor")?.unwrap_or(1.0);
+    let roughness = factor("roughnessFactor")?.unwrap_or(1.0);
+    let extension = |name, key| {
+        material
+            .get("extensions")
+            .and_then(|extensions| extensions.get(name))
+            .and_then(|extension| extension.get(key))
+            .and_then(Json::as_f32)
+    };
+    let emission = match material.get("emissiveFactor") {
+        Some(value) => Color3::from_array(numbers(value)?),
+        None => Color3::ZERO,
+    } * extension("KHR_materials_emissive_strength", "emissiveStrength")
+        .unwrap_or(1.0);
+    let transmission = extension("KHR_materials_transmission", "transmissionFactor").unwrap_or(0.0);
+
+    Ok(if emission.max_element() > 0.0 {
+        Material::DiffuseLight {
+            emit: emission,
+            spread: 180.0,
+        }
+    } else if transmission >= 0.5 {
+        Material::new_dielectric(extension("KHR_materials_ior", "ior").unwrap_or(1.5))
+    } else if metallic >= 0.5 {
+        Material::Metal {
+            albedo: base_color,
+            fuzz: roughness.clamp(0.0, 1.0),
+        }
+    } else {
+        Material::Lambertian {
+            albedo: Arc::new(SolidColor::new(base_color)),
+        }
+    })
+}
+
+fn items<'a>(obj: &'a Json, key: &str) -> &'a [Json] {
+    obj.get(key).and_then(Json::as_array).unwrap_or_default()
+}
+
+fn element<'a>(items: &'a [Json], idx: u32, what: &str) -> Result<&'a Json> {
+    items
+        .get(idx as usize)
+        .ok_or_else(|| anyhow!("undefined {} #{}", what, idx))
+}
+
+fn index(obj: &Json, key: &str) -> Result<Option<u32>> {
+    obj.get(key).map(to_index).transpose()
+}
+
+fn to_index(value: &Json) -> Result<u32> {
+    value
+        .as_u32()
+        .ok_or_else(|| anyhow!("expected an index, got {}", value))
+}
+
+fn numbers<const N: usize>(value: &Json) -> Result<[f32; N]> {
+    value
+        .as_array()
+        .and_then(|items| items.iter().map(Json::as_f32).collect::<Option<Vec<_>>>())
+        .and_then(|values| values.try_into().ok())
+        .ok_or_else(|| anyhow!("expected {} numbers", N))
+}
+
+fn base64(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes().filter(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => bail!("invalid base64 character '{}'", c as char),
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::{Hittable, Interval};
+    use crate::point3;
+    use crate::render::Ray;
+
+    fn no_files(uri: &str) -> Result<PathBuf> {
+        panic!("unexpected file reference '{}'", uri)
+    }
+
+    // A GLB holding one right triangle in the z = 0 plane, indexed with u16,
+    // placed by a parent node with a translation and a child with a matrix.
+    fn glb() -> Vec<u8> {
+        let mut bin = vec![];
+        for v in [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            bin.extend(v.to_le_bytes());
+        }
+        for idx in [0u16, 1, 2, 0] {
+            bin.extend(idx.to_le_bytes());
+        }
+        let json = r#"{
+            "asset": { "version": "2.0" },
+            "scene": 0,
+            "scenes": [{ "nodes": [0] }],
+            "nodes": [
+                { "translation": [0, 0, 5], "children": [1] },
+                { "mesh": 0, "matrix": [2,0,0,0, 0,2,0,0, 0,0,2,0, 1,0,0,1] }
+            ],
+            "meshes": [{ "primitives": [{
+                "attributes": { "POSITION": 0 }, "indices": 1, "material": 0
+            }] }],
+            "materials": [{ "pbrMetallicRoughness": {
+                "baseColorFactor": [0.9, 0.5, 0.1, 1], "metallicFactor": 1, "roughnessFactor": 0.25
+            } }],
+            "buffers": [{ "byteLength": 44 }],
+            "bufferViews": [
+                { "buffer": 0, "byteLength": 36 },
+                { "buffer": 0, "byteOffset": 36, "byteLength": 8 }
+            ],
+            "accessors": [
+                { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" },
+                { "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }
+            ]
+        }"#;
+        let mut json = json.as_bytes().to_vec();
+        json.resize(json.len().next_multiple_of(4), b' ');
+
+        let mut glb = GLB_MAGIC.to_vec();
+        glb.extend(2u32.to_le_bytes());
+        glb.extend((12 + 8 + json.len() as u32 + 8 + bin.len() as u32).to_le_bytes());
+        for (kind, data) in [(CHUNK_JSON, json), (CHUNK_BIN, bin)] {
+            glb.extend((data.len() as u32).to_le_bytes());
+            glb.extend(kind.to_le_bytes());
+            glb.extend(data);
+        }
+        glb
+    }
+
+    #[test]
+    fn loads_transformed_glb() {
+        let mesh = parse(&glb(), Material::new_lambertian(0.5, 0.5, 0.5), no_files).unwrap();
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let hit = |x, y| mesh.hit(&Ray::new(point3(x, y, 0.0), Vec3::Z), ray_t);
+
+        // The triangle now spans (1, 0), (3, 0) and (1, 2) at z = 5.
+        let inside = hit(1.5, 0.5).unwrap();
+        assert!((inside.t - 5.0).abs() < 1e-5);
+        assert!(matches!(inside.material, Material::Metal { fuzz, .. } if *fuzz == 0.25));
+        assert!(hit(0.5, 0.5).is_none());
+        assert!(hit(2.5, 1.0).is_none());
+    }
+
+    #[test]
+    fn decodes_embedded_buffers() {
+        assert_eq!(base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(base64("AAEC/w==").unwrap(), [0, 1, 2, 255]);
+        assert!(base64("a*b").is_err());
+
+        let gltf = r#"{
+            "nodes": [{ "mesh": 0 }],
+            "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0, "TEXCOORD_0": 1 } }] }],
+            "buffers": [{ "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAP///wAA" }],
+            "bufferViews": [
+                { "buffer": 0, "byteLength": 36 },
+                { "buffer": 0, "byteOffset": 36, "byteLength": 6 }
+            ],
+            "accessors": [
+                { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" },
+                { "bufferView": 1, "componentType": 5121, "normalized": true, "count": 3, "type": "VEC2" }
+            ]
+        }"#;
+        let mesh = parse(
+            gltf.as_bytes(),
+            Material::new_lambertian(0.5, 0.5, 0.5),
+            no_files,
+        )
+        .unwrap();
+        let hit = mesh.hit(
+            &Ray::new(point3(0.25, 0.25, -1.0), Vec3::Z),
+            Interval::new(0.001, f32::INFINITY),
+        );
+        let hit = hit.unwrap();
+        assert!(matches!(hit.material, Material::Lambertian { .. }));
+        // Normalized byte texture coordinates, flipped to run up.
+        assert!(hit.uv.distance(vec2(0.25, 0.25)) < 1e-5);
+    }
+}
@@ -0,0 +1,13 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+pub mod gltf;
+pub mod obj;
+pub mod ply;
+pub mod stl;
+pub mod ttf;
+pub mod usd;
+
+// Turns a reference inside a model file, such as an OBJ material library,
+// into the path of the file.
+pub type Resolve<'a> = &'a dyn Fn(&str) -> Result<PathBuf>;
@@ -0,0 +1,397 @@
+use super::Resolve;
+use crate::hittables::Mesh;
+use crate::materials::Material;
+use crate::textures::SolidColor;
+use crate::{color3, Color3, Point3};
+use anyhow::{anyhow, bail, Context, Result};
+use glam::{vec2, Vec2, Vec3};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+// Loads the triangles of a Wavefront OBJ file into a single mesh. Polygons
+// are split into fans; texture coordinates are kept and vertex normals give
+// smooth shading.
+// Faces use the materials of their `usemtl` statements, looked up in the
+// MTL libraries the file names, which `resolve` turns into paths; faces
+// before any `usemtl` get `default`.
+pub fn load(path: &Path, default: Material, resolve: Resolve) -> Result<Mesh> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    parse(&text, default, |reference| {
+        let path = resolve(reference)?;
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        parse_mtl(&text).with_context(|| format!("in {}", path.display()))
+    })
+    .with_context(|| format!("in {}", path.display()))
+}
+
+fn parse(
+    text: &str,
+    default: Material,
+    load_mtl: impl Fn(&str) -> Result<HashMap<String, Material>>,
+) -> Result<Mesh> {
+    let mut positions: Vec<Point3> = vec![];
+    let mut uvs: Vec<Vec2> = vec![];
+    let mut normals: Vec<Vec3> = vec![];
+    // Mesh vertices are the distinct combinations of position, texture
+    // coordinates and normal that faces use.
+    let mut vertices: HashMap<Corner, u32> = HashMap::new();
+    let mut mesh_positions = vec![];
+    let mut mesh_uvs = vec![];
+    let mut mesh_normals = vec![];
+    let mut any_uvs = false;
+    let mut faces = vec![];
+    let mut face_materials = vec![];
+    let mut library = HashMap::new();
+    // The mesh's materials in order of first use, after the default.
+    let mut materials = vec![default];
+    let mut material_idx: HashMap<&str, u32> = HashMap::new();
+    let mut current = 0;
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+        let result = match words.next() {
+            Some("v") => numbers(words).map(|[x, y, z]| positions.push(Vec3::new(x, y, z))),
+            Some("vt") => numbers(words).map(|[u, v]| uvs.push(vec2(u, v))),
+            Some("vn") => numbers(words).map(|[x, y, z]| normals.push(Vec3::new(x, y, z))),
+            Some("f") => words
+                .map(|word| Corner::parse(word, [positions.len(), uvs.len(), normals.len()]))
+                .collect::<Result<Vec<_>>>()
+                .and_then(|corners| {
+                    if corners.len() < 3 {
+                        bail!("a face needs at least 3 vertices");
+                    }
+                    let corners: Vec<u32> = corners
+                        .into_iter()
+                        .map(|corner| {
+                            *vertices.entry(corner).or_insert_with(|| {
+                                mesh_positions.push(positions[corner.position as usize]);
+                                any_uvs |= corner.uv.is_some();
+                                mesh_uvs.push(corner.uv.map_or(Vec2::ZERO, |uv| uvs[uv as usize]));
+                                // Zero leaves faces without normals flat.
+                                mesh_normals.push(
+                                    corner.normal.map_or(Vec3::ZERO, |n| normals[n as usize]),
+                                );
+                                mesh_positions.len() as u32 - 1
+                            })
+                        })
+                        .collect();
+                    for pair in corners[1..].windows(2) {
+                        faces.push([corners[0], pair[0], pair[1]]);
+                        face_materials.push(current);
+                    }
+                    Ok(())
+                }),
+            Some("usemtl") => {
+                let name = line.trim_start()["usemtl".len()..].trim();
+                match material_idx.get(name) {
+                    Some(&idx) => {
+                        current = idx;
+                        Ok(())
+                    }
+                    None => library
+                        .get(name)
+                        .cloned()
+                        .ok_or_else(|| anyhow!("undefined material '{}'", name))
+                        .map(|material| {
+                            current = materials.len() as u32;
+                            materials.push(material);
+                            material_idx.insert(name, current);
+                        }),
+                }
+            }
+            // Library names may contain spaces, so a line names one library.
+            Some("mtllib") => load_mtl(line.trim_start()["mtllib".len()..].trim())
+                .map(|materials| library.extend(materials)),
+            _ => Ok(()),
+        };
+        result.with_context(|| format!("line {}", line_idx + 1))?;
+    }
+    let mut mesh = Mesh::new(mesh_positions, faces, materials, face_materials)?;
+    if any_uvs {
+        mesh = mesh.with_uvs(mesh_uvs)?;
+    }
+    if mesh_normals.iter().any(|&n| n != Vec3::ZERO) {
+        mesh = mesh.with_normals(mesh_normals)?;
+    }
+    Ok(mesh)
+}
+
+// The indices of a face corner, written `v`, `v/vt`, `v//vn` or `v/vt/vn`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct Corner {
+    position: u32,
+    uv: Option<u32>,
+    normal: Option<u32>,
+}
+
+impl Corner {
+    // `counts` are the numbers of positions, texture coordinates and
+    // normals so far.
+    fn parse(word: &str, counts: [usize; 3]) -> Result<Corner> {
+        let mut indices = word.split('/');
+        let position = index(indices.next().unwrap_or_default(), counts[0], "vertex")?;
+        let mut optional = |count, what| match indices.next() {
+            Some(word) if !word.is_empty() => index(word, count, what).map(Some),
+            _ => Ok(None),
+        };
+        Ok(Corner {
+            position,
+            uv: optional(counts[1], "texture coordinate")?,
+            normal: optional(counts[2], "normal")?,
+        })
+    }
+}
+
+// OBJ indices count from 1, or back from the latest element when negative.
+fn index(word: &str, count: usize, what: &str) -> Result<u32> {
+    let value: i64 = word
+        .parse()
+        .map_err(|_| anyhow!("invalid {} index '{}'", what, word))?;
+    let idx = if value < 0 {
+        count as i64 + value
+    } else {
+        value - 1
+    };
+    if idx < 0 || idx >= count as i64 {
+        bail!("{} index {} out of range", what, value);
+    }
+    Ok(idx as u32)
+}
+
+fn numbers<'a, const N: usize>(words: impl Iterator<Item = &'a str>) -> Result<[f32; N]> {
+    let values = words
+        .take(N)
+        .map(|word| {
+            word.parse()
+                .map_err(|_| anyhow!("invalid number '{}'", word))
+        })
+        .collect::<Result<Vec<f32>>>()?;
+    values
+        .try_into()
+        .map_err(|_| anyhow!("expected {} numbers", N))
+}
+
+// The MTL statements that matter for picking one of the built-in materials.
+struct MtlEntry {
+    diffuse: Color3,
+    specular: Color3,
+    emission: Color3,
+    shininess: f32,
+    ior: f32,
+    dissolve: f32,
+    illum: u32,
+    // Blender's PBR extensions.
+    metallic: Option<f32>,
+    roughness: Option<f32>,
+}
+
+impl Default for MtlEntry {
+    fn default() -> Self {
+        Self {
+            diffuse: color3(0.8, 0.8, 0.8),
+            specular: Color3::ZERO,
+            emission: Color3::ZERO,
+            shininess: 0.0,
+            ior: 1.5,
+            dissolve: 1.0,
+            illum: 2,
+            metallic: None,
+            roughness: None,
+        }
+    }
+}
+
+impl MtlEntry {
+    // Emissive materials become lights, transparent or refracting ones
+    // glass, and ones that are metallic, mirrors (illum 3) or more specular
+    // than diffuse metal; everything else is Lambertian.
+    fn material(&self) -> Material {
+        // A common conversion from the Phong exponent to roughness.
+        let fuzz = self
+            .roughness
+            .unwrap_or_else(|| (2.0 / (self.shininess + 2.0)).sqrt())
+            .clamp(0.0, 1.0);
+        if self.emission.max_element() > 0.0 {
+            Material::DiffuseLight {
+                emit: self.emission,
+                spread: 180.0,
+            }
+        } else if self.dissolve < 1.0 || matches!(self.illum, 4 | 6 | 7) {
+            Material::new_dielectric(self.ior)
+        } else if self.metallic.is_some_and(|metallic| metallic >= 0.5) {
+            Material::Metal {
+                albedo: self.diffuse,
+                fuzz,
+            }
+        } else if self.illum == 3 || self.specular.max_element() > self.diffuse.max_element() {
+            Material::Metal {
+                albedo: self.specular,
+                fuzz,
+            }
+        } else {
+            Material::Lambertian {
+                albedo: Arc::new(SolidColor::new(self.diffuse)),
+            }
+        }
+    }
+}
+
+fn parse_mtl(text: &str) -> Result<HashMap<String, Material>> {
+    let mut entries: Vec<(String, MtlEntry)> = vec![];
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+        let Some(keyword) = words.next() else {
+            continue;
+        };
+        if keyword == "newmtl" {
+            let name = line.trim_start()["newmtl".len()..].trim();
+            entries.push((name.to_string(), MtlEntry::default()));
+            continue;
+        }
+        let Some((_, entry)) = entries.last_mut() else {
+            continue;
+        };
+        let color = |words| numbers(words).map(Color3::from_array);
+        let number = |words| numbers(words).map(|[value]| value);
+        let result = match keyword {
+            "Kd" => color(words).map(|c| entry.diffuse = c),
+            "Ks" => color(words).map(|c| entry.specular = c),
+            "Ke" => color(words).map(|c| entry.emission = c),
+            "Ns" => number(words).map(|v| entry.shininess = v),
+            "Ni" => number(words).map(|v| entry.ior = v),
+            "d" => number(words).map(|v| entry.dissolve = v),
+            "Tr" => number(words).map(|v| entry.dissolve = 1.0 - v),
+            "Pm" => number(words).map(|v| entry.metallic = Some(v)),
+            "Pr" => number(words).map(|v| entry.roughness = Some(v)),
+            "illum" => number(words).map(|v| entry.illum = v as u32),
+            _ => Ok(()),
+        };
+        result.with_context(|| format!("line {}", line_idx + 1))?;
+    }
+    Ok(entries
+        .into_iter()
+        .map(|(name, entry)| (name, entry.material()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::{Hittable, Interval};
+    use crate::point3;
+    use crate::render::Ray;
+
+    const MTL: &str = "
+newmtl red
+Kd 0.8 0.1 0.1
+newmtl glass
+Ni 1.45
+d 0.2
+newmtl lamp
+Ke 4 4 4
+";
+
+    // Unit squares side by side at z = 0: one without a material, a red
+    // one, and one split into a glass triangle, given by relative indices,
+    // and another red one.
+    const OBJ: &str = "
+mtllib scene.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0 # comment
+v 2 0 0
+v 2 1 0
+v 3 0 0
+v 3 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+f 1 2 3 4
+usemtl red
+f 2/1/1 5/2/1 6/3/1 3/4/1
+usemtl glass
+f -3 -1 -2
+usemtl red
+f 5 7 6
+";
+
+    #[test]
+    fn loads_faces_and_materials() {
+        let gray = Material::new_lambertian(0.5, 0.5, 0.5);
+        let mesh = parse(OBJ, gray, |name| {
+            assert_eq!(name, "scene.mtl");
+            parse_mtl(MTL)
+        })
+        .unwrap();
+
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let material = |x, y| {
+            let hit = mesh.hit(&Ray::new(point3(x, y, -1.0), Vec3::Z), ray_t);
+            match hit.map(|hit| hit.material) {
+                Some(Material::Lambertian { albedo }) => {
+                    Some(albedo.value(Vec2::ZERO, Point3::ZERO).x)
+                }
+                Some(Material::Dielectric { refract_idx }) => Some(*refract_idx),
+                Some(_) => unreachable!(),
+                None => None,
+            }
+        };
+        assert_eq!(material(0.5, 0.5), Some(0.5));
+        assert_eq!(material(1.5, 0.5), Some(0.8));
+        assert_eq!(material(2.8, 0.5), Some(1.45));
+        assert_eq!(material(2.2, 0.2), Some(0.8));
+        assert_eq!(material(3.5, 0.5), None);
+        // The second square has texture coordinates; the first doesn't.
+        let uv_at = |x, y| {
+            let hit = mesh.hit(&Ray::new(point3(x, y, -1.0), Vec3::Z), ray_t);
+            hit.unwrap().uv
+        };
+        assert!(uv_at(1.25, 0.5).distance(vec2(0.25, 0.5)) < 1e-5);
+        assert_eq!(uv_at(0.5, 0.5), Vec2::ZERO);
+
+        let lamp = &parse_mtl(MTL).unwrap()["lamp"];
+        assert!(matches!(lamp, Material::DiffuseLight { emit, .. } if *emit == Vec3::splat(4.0)));
+
+        let no_library = |_: &str| -> Result<HashMap<String, Material>> { unreachable!() };
+        let white = || Material::new_lambertian(1.0, 1.0, 1.0);
+        assert!(parse("v 0 0 0\nf 1 1 2", white(), no_library).is_err());
+        assert!(parse("usemtl missing", white(), no_library).is_err());
+    }
+
+    #[test]
+    fn interpolates_vertex_normals() {
+        // A flat square whose normals tilt outwards along x on the left and
+        // right edges, next to one without normals.
+        let obj = "
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+v 2 0 0
+v 2 1 0
+vn -0.6 0 -0.8
+vn 0.6 0 -0.8
+f 1//1 2//2 3//2 4//1
+f 2 5 6 3
+";
+        let no_library = |_: &str| -> Result<HashMap<String, Material>> { unreachable!() };
+        let mesh = parse(obj, Material::new_lambertian(1.0, 1.0, 1.0), no_library).unwrap();
+        let hit = |x| {
+            let ray = Ray::new(point3(x, 0.3, -1.0), Vec3::Z);
+            mesh.hit(&ray, Interval::new(0.001, f32::INFINITY)).unwrap()
+        };
+        assert!(hit(0.1).shading_normal.x < -0.4);
+        assert!(hit(0.5).shading_normal.distance(-Vec3::Z) < 1e-5);
+        assert_eq!(hit(1.5).shading_normal, -Vec3::Z);
+        let white = Material::new_lambertian(1.0, 1.0, 1.0);
+        assert!(parse("v 0 0 0\nf 1//1 1//1 1//1", white, no_library).is_err());
+    }
+}
@@ -0,0 +1,349 @@
+use super::Resolve;
+use crate::hittables::Mesh;
+use crate::materials::Material;
+use crate::Point3;
+use anyhow::{anyhow, bail, Context, Result};
+use glam::{vec2, Vec3};
+use std::path::Path;
+
+// Loads a PLY mesh, ASCII or binary, with a single material. Vertex normals
+// are used for smooth shading, vertex colors multiply the material's albedo
+// and texture coordinates are kept. Polygons are split into fans; elements other than vertices and
+// faces are skipped.
+pub fn load(path: &Path, material: Material, _resolve: Resolve) -> Result<Mesh> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    parse(&bytes, material).with_context(|| format!("in {}", path.display()))
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Scalar> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => bail!("unknown property type '{}'", name),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+struct Property {
+    name: String,
+    // The scalar type, or for lists the type of the count and the items.
+    kind: Scalar,
+    list_items: Option<Scalar>,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+enum Format {
+    Ascii,
+    Binary { big_endian: bool },
+}
+
+// Reads scalars from the body of the file in either format.
+struct Reader<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn read(&mut self, kind: Scalar) -> Result<f64> {
+        match self.format {
+            Format::Ascii => {
+                while self
+                    .bytes
+                    .get(self.pos)
+                    .is_some_and(u8::is_ascii_whitespace)
+                {
+                    self.pos += 1;
+                }
+                let start = self.pos;
+                while self
+                    .bytes
+                    .get(self.pos)
+                    .is_some_and(|b| !b.is_ascii_whitespace())
+                {
+                    self.pos += 1;
+                }
+                let word = std::str::from_utf8(&self.bytes[start..self.pos])?;
+                if word.is_empty() {
+                    bail!("unexpected end of file");
+                }
+                word.parse()
+                    .map_err(|_| anyhow!("invalid number '{}'", word))
+            }
+            Format::Binary { big_endian } => {
+                let size = kind.size();
+                let mut raw = self
+                    .bytes
+                    .get(self.pos..self.pos + size)
+                    .ok_or_else(|| anyhow!("unexpected end of file"))?
+                    .to_vec();
+                self.pos += size;
+                if big_endian {
+                    raw.reverse();
+                }
+                let raw = raw.as_slice();
+                Ok(match kind {
+                    Scalar::I8 => raw[0] as i8 as f64,
+                    Scalar::U8 => raw[0] as f64,
+                    Scalar::I16 => i16::from_le_bytes(raw.try_into()?) as f64,
+                    Scalar::U16 => u16::from_le_bytes(raw.try_into()?) as f64,
+                    Scalar::I32 => i32::from_le_bytes(raw.try_into()?) as f64,
+                    Scalar::U32 => u32::from_le_bytes(raw.try_into()?) as f64,
+                    Scalar::F32 => f32::from_le_bytes(raw.try_into()?) as f64,
+                    Scalar::F64 => f64::from_le_bytes(raw.try_into()?),
+                })
+            }
+        }
+    }
+}
+
+fn parse(bytes: &[u8], material: Material) -> Result<Mesh> {
+    const END_HEADER: &[u8] = b"end_header";
+    let header_end = bytes
+        .windows(END_HEADER.len())
+        .position(|w| w == END_HEADER)
+        .ok_or_else(|| anyhow!("missing end_header"))?;
+    let body = bytes[header_end..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(bytes.len(), |newline| header_end + newline + 1);
+    let header = std::str::from_utf8(&bytes[..header_end])?;
+
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        bail!("not a PLY file");
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["format", "ascii", _] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", _] => {
+                format = Some(Format::Binary { big_endian: false })
+            }
+            ["format", "binary_big_endian", _] => {
+                format = Some(Format::Binary { big_endian: true })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| anyhow!("invalid element count '{}'", count))?,
+                properties: vec![],
+            }),
+            ["property", "list", count, items, name] => elements
+                .last_mut()
+                .ok_or_else(|| anyhow!("property before any element"))?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    kind: Scalar::parse(count)?,
+                    list_items: Some(Scalar::parse(items)?),
+                }),
+            ["property", kind, name] => elements
+                .last_mut()
+                .ok_or_else(|| anyhow!("property before any element"))?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    kind: Scalar::parse(kind)?,
+                    list_items: None,
+                }),
+            _ => {}
+        }
+    }
+
+    let mut reader = Reader {
+        format: format.ok_or_else(|| anyhow!("missing format"))?,
+        bytes,
+        pos: body,
+    };
+    let mut positions: Vec<Point3> = vec![];
+    let mut normals = vec![];
+    let mut colors = vec![];
+    let mut uvs = vec![];
+    let mut faces = vec![];
+    for element in &elements {
+        let find = |name: &str| element.properties.iter().position(|p| p.name == name);
+        let position = [find("x"), find("y"), find("z")];
+        let normal = [find("nx"), find("ny"), find("nz")];
+        let color = [find("red"), find("green"), find("blue")];
+        // Exporters disagree on what to call texture coordinates.
+        let uv = [("u", "s", "texture_u"), ("v", "t", "texture_v")]
+            .map(|(a, b, c)| find(a).or_else(|| find(b)).or_else(|| find(c)));
+        let indices = find("vertex_indices").or_else(|| find("vertex_index"));
+        // Integer colors span their type's range, float ones 0 to 1.
+        let color_scale = match color[0].map(|idx| element.properties[idx].kind) {
+            Some(Scalar::U8) => 1.0 / 255.0,
+            Some(Scalar::U16) => 1.0 / 65535.0,
+            _ => 1.0,
+        };
+        let mut values = vec![0.0; element.properties.len()];
+        for _ in 0..element.count {
+            let mut list = vec![];
+            for (idx, property) in element.properties.iter().enumerate() {
+                values[idx] = reader.read(property.kind)?;
+                if let Some(items) = property.list_items {
+                    let count = values[idx] as usize;
+                    let items = (0..count)
+                        .map(|_| reader.read(items))
+                        .collect::<Result<Vec<_>>>()?;
+                    if Some(idx) == indices {
+                        list = items;
+                    }
+                }
+            }
+            let vector = |fields: [Option<usize>; 3]| -> Option<Vec3> {
+                let [x, y, z] = fields.map(|field| field.map(|idx| values[idx] as f32));
+                Some(Vec3::new(x?, y?, z?))
+            };
+            match element.name.as_str() {
+                "vertex" => {
+                    positions
+                        .push(vector(position).ok_or_else(|| anyhow!("vertices need x, y and z"))?);
+                    normals.extend(vector(normal));
+                    // Colors are stored gamma encoded; squaring matches the
+                    // gamma 2 that images are written with.
+                    colors.extend(vector(color).map(|c| (c * color_scale).powf(2.0)));
+                    if let [Some(u), Some(v)] = uv {
+                        uvs.push(vec2(values[u] as f32, values[v] as f32));
+                    }
+                }
+                "face" => {
+                    for pair in list.get(1..).unwrap_or_default().windows(2) {
+                        faces.push([list[0], pair[0], pair[1]].map(|idx| idx as u32));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if faces.is_empty() {
+        bail!("no faces; point clouds are not supported");
+    }
+    let mut mesh = Mesh::new(positions, faces, vec![material], vec![])?;
+    if !normals.is_empty() {
+        mesh = mesh.with_normals(normals)?;
+    }
+    if !colors.is_empty() {
+        mesh = mesh.with_colors(colors)?;
+    }
+    if !uvs.is_empty() {
+        mesh = mesh.with_uvs(uvs)?;
+    }
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::{Hittable, Interval};
+    use crate::point3;
+    use crate::render::Ray;
+
+    // A unit square at z = 0 as one quad, with normals tilting outwards
+    // along x and colors from black on the left to white on the right.
+    const HEADER: &str = "ply
+format FORMAT 1.0
+comment exported by a scanner
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+    const VERTICES: [[f32; 6]; 4] = [
+        [0.0, 0.0, 0.0, -0.6, 0.0, -0.8],
+        [1.0, 0.0, 0.0, 0.6, 0.0, -0.8],
+        [1.0, 1.0, 0.0, 0.6, 0.0, -0.8],
+        [0.0, 1.0, 0.0, -0.6, 0.0, -0.8],
+    ];
+    const SHADES: [u8; 4] = [0, 255, 255, 0];
+
+    fn check(mesh: &Mesh) {
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let hit = |x| {
+            mesh.hit(&Ray::new(point3(x, 0.3, -1.0), Vec3::Z), ray_t)
+                .unwrap()
+        };
+        let (left, middle) = (hit(0.1), hit(0.5));
+        assert!(left.color.distance(Vec3::splat(0.1)) < 1e-5);
+        assert!(middle.color.distance(Vec3::splat(0.5)) < 1e-5);
+        assert!(left.shading_normal.x < -0.4 && left.shading_normal.z < 0.0);
+        assert!(middle.shading_normal.distance(-Vec3::Z) < 1e-5);
+        assert_eq!(left.normal, -Vec3::Z);
+    }
+
+    #[test]
+    fn reads_ascii_and_binary() {
+        let mut ascii = HEADER.replace("FORMAT", "ascii");
+        for (vertex, shade) in VERTICES.iter().zip(SHADES) {
+            let values: Vec<String> = vertex.iter().map(f32::to_string).collect();
+            ascii += &format!("{} {} {} {}\n", values.join(" "), shade, shade, shade);
+        }
+        ascii += "4 0 1 2 3\n";
+        check(&parse(ascii.as_bytes(), Material::new_lambertian(1.0, 1.0, 1.0)).unwrap());
+
+        let mut binary = HEADER.replace("FORMAT", "binary_big_endian").into_bytes();
+        for (vertex, shade) in VERTICES.iter().zip(SHADES) {
+            for value in vertex {
+                binary.extend(value.to_be_bytes());
+            }
+            binary.extend([shade; 3]);
+        }
+        binary.push(4);
+        for idx in [0i32, 1, 2, 3] {
+            binary.extend(idx.to_be_bytes());
+        }
+        check(&parse(&binary, Material::new_lambertian(1.0, 1.0, 1.0)).unwrap());
+
+        assert!(parse(
+            &binary[..binary.len() - 1],
+            Material::new_lambertian(1.0, 1.0, 1.0)
+        )
+        .is_err());
+    }
+}
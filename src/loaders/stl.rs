@@ -0,0 +1,155 @@
+use super::Resolve;
+use crate::hittables::Mesh;
+use crate::materials::Material;
+use crate::Point3;
+use anyhow::{anyhow, bail, Context, Result};
+use glam::Vec3;
+use std::collections::HashMap;
+use std::path::Path;
+
+const HEADER_LEN: usize = 80;
+const FACET_LEN: usize = 50;
+
+// Loads a binary or ASCII STL file into a mesh with a single material.
+// STL files repeat shared vertices for every facet; they are merged so the
+// mesh stores each one once. STL files don't refer to other files.
+pub fn load(path: &Path, material: Material, _resolve: Resolve) -> Result<Mesh> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let triangles = parse(&bytes).with_context(|| format!("in {}", path.display()))?;
+
+    let mut positions = vec![];
+    let mut indices: HashMap<[u32; 3], u32> = HashMap::new();
+    let mut faces = vec![];
+    for triangle in triangles {
+        faces.push(triangle.map(|p| {
+            // Adding zero turns -0 into 0, so they merge too.
+            *indices
+                .entry(p.to_array().map(|v| (v + 0.0).to_bits()))
+                .or_insert_with(|| {
+                    positions.push(p);
+                    positions.len() as u32 - 1
+                })
+        }));
+    }
+    Mesh::new(positions, faces, vec![material], vec![])
+}
+
+fn parse(bytes: &[u8]) -> Result<Vec<[Point3; 3]>> {
+    // ASCII files start with "solid", but so do some binary ones; only the
+    // binary size check tells them apart.
+    if bytes.len() >= HEADER_LEN + 4 {
+        let count = u32::from_le_bytes(bytes[HEADER_LEN..HEADER_LEN + 4].try_into().unwrap());
+        if bytes.len() == HEADER_LEN + 4 + count as usize * FACET_LEN {
+            return Ok(parse_binary(&bytes[HEADER_LEN + 4..]));
+        }
+    }
+    if !bytes.starts_with(b"solid") {
+        bail!("not an STL file");
+    }
+    parse_ascii(std::str::from_utf8(bytes)?)
+}
+
+// Each facet is a normal, three vertices and a two byte attribute count,
+// all little-endian; the normal is recomputed from the vertices.
+fn parse_binary(facets: &[u8]) -> Vec<[Point3; 3]> {
+    facets
+        .chunks_exact(FACET_LEN)
+        .map(|facet| {
+            let value =
+                |idx: usize| f32::from_le_bytes(facet[4 * idx..4 * idx + 4].try_into().unwrap());
+            [1, 2, 3].map(|v| Vec3::new(value(3 * v), value(3 * v + 1), value(3 * v + 2)))
+        })
+        .collect()
+}
+
+fn parse_ascii(text: &str) -> Result<Vec<[Point3; 3]>> {
+    let mut triangles = vec![];
+    let mut vertices = vec![];
+    for (line_idx, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        let result = match words.next() {
+            Some("vertex") => words
+                .map(|word| {
+                    word.parse()
+                        .map_err(|_| anyhow!("invalid number '{}'", word))
+                })
+                .collect::<Result<Vec<f32>>>()
+                .and_then(|values| match values[..] {
+                    [x, y, z] => {
+                        vertices.push(Vec3::new(x, y, z));
+                        Ok(())
+                    }
+                    _ => bail!("expected 3 coordinates"),
+                }),
+            Some("endloop") => match vertices[..] {
+                [a, b, c] => {
+                    triangles.push([a, b, c]);
+                    vertices.clear();
+                    Ok(())
+                }
+                _ => Err(anyhow!(
+                    "a facet needs 3 vertices, found {}",
+                    vertices.len()
+                )),
+            },
+            _ => Ok(()),
+        };
+        result.with_context(|| format!("line {}", line_idx + 1))?;
+    }
+    Ok(triangles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point3;
+
+    // Two triangles forming a unit square, sharing an edge.
+    fn square() -> Vec<[Point3; 3]> {
+        let (a, b, c, d) = (
+            point3(0.0, 0.0, 0.0),
+            point3(1.0, 0.0, 0.0),
+            point3(1.0, 1.0, 0.0),
+            point3(0.0, 1.0, 0.0),
+        );
+        vec![[a, b, c], [a, c, d]]
+    }
+
+    #[test]
+    fn reads_binary_and_ascii() {
+        // The header starts with "solid" to check that the size decides.
+        let mut binary = b"solid exported by a slicer".to_vec();
+        binary.resize(HEADER_LEN, 0);
+        binary.extend(2u32.to_le_bytes());
+        for triangle in square() {
+            binary.extend([0.0f32, 0.0, 1.0].iter().flat_map(|v| v.to_le_bytes()));
+            for p in triangle {
+                binary.extend(p.to_array().iter().flat_map(|v| v.to_le_bytes()));
+            }
+            binary.extend([0, 0]);
+        }
+        assert_eq!(parse(&binary).unwrap(), square());
+
+        let ascii = "solid square
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 1 1 0
+    endloop
+  endfacet
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1.0 1e0 0
+      vertex 0 1 -0.0
+    endloop
+  endfacet
+endsolid square
+";
+        assert_eq!(parse(ascii.as_bytes()).unwrap(), square());
+        assert!(parse(b"solid x\nouter loop\nvertex 0 0 0\nendloop\n").is_err());
+        assert!(parse(b"not an stl file").is_err());
+    }
+}
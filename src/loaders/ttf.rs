@@ -0,0 +1,518 @@
+use anyhow::{anyhow, bail, Context, Result};
+use glam::{Affine2, Mat2, Vec2};
+use std::path::Path;
+
+// Line segments each quadratic curve of an outline is split into.
+const CURVE_SEGMENTS: usize = 8;
+// Composite glyphs nested deeper than this are assumed to be cyclic.
+const MAX_DEPTH: u32 = 8;
+
+// Lays out `text` in a TrueType font and returns the outlines of its
+// glyphs, flattened into polygons. The text runs along +x from the origin
+// with its baseline on y = 0 and `size` units to the em; lines are broken at
+// newlines. There is no kerning or shaping, and CFF based OpenType fonts
+// are not supported.
+pub fn outlines(path: &Path, text: &str, size: f32) -> Result<Vec<Vec<Vec2>>> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    layout(&bytes, text, size).with_context(|| format!("in {}", path.display()))
+}
+
+fn layout(bytes: &[u8], text: &str, size: f32) -> Result<Vec<Vec<Vec2>>> {
+    let font = Font::parse(bytes)?;
+    let scale = size / font.units_per_em as f32;
+    let line_height = font.line_height as f32 * scale;
+    let mut contours = vec![];
+    let mut pen = Vec2::ZERO;
+    for c in text.chars() {
+        if c == '\n' {
+            pen = Vec2::new(0.0, pen.y - line_height);
+            continue;
+        }
+        let glyph = font.glyph_index(c as u32)?;
+        let to_text = Affine2::from_scale_angle_translation(Vec2::splat(scale), 0.0, pen);
+        font.glyph(glyph, to_text, 0, &mut contours)?;
+        pen.x += font.advance(glyph)? as f32 * scale;
+    }
+    Ok(contours)
+}
+
+struct Font<'a> {
+    bytes: &'a [u8],
+    units_per_em: u16,
+    line_height: i32,
+    long_offsets: bool,
+    glyphs: u16,
+    metrics: u16,
+    hmtx: usize,
+    loca: usize,
+    glyf: usize,
+    cmap: Cmap,
+}
+
+// The character map subtable in use, by its offset.
+enum Cmap {
+    SegmentDeltas(usize),
+    Groups(usize),
+}
+
+fn u16_at(bytes: &[u8], pos: usize) -> Result<u16> {
+    bytes
+        .get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| anyhow!("truncated font"))
+}
+
+fn i16_at(bytes: &[u8], pos: usize) -> Result<i16> {
+    u16_at(bytes, pos).map(|v| v as i16)
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> Result<u32> {
+    Ok((u16_at(bytes, pos)? as u32) << 16 | u16_at(bytes, pos + 2)? as u32)
+}
+
+impl<'a> Font<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Font<'a>> {
+        // Collections hold several fonts; the first one is used.
+        let start = match bytes.get(..4) {
+            Some(b"ttcf") => u32_at(bytes, 12)? as usize,
+            _ => 0,
+        };
+        match bytes.get(start..start + 4) {
+            Some([0, 1, 0, 0] | b"true") => {}
+            Some(b"OTTO") => bail!("OpenType fonts with CFF outlines are not supported"),
+            _ => bail!("not a TrueType font"),
+        }
+        let tables = u16_at(bytes, start + 4)?;
+        let table = |tag: &[u8]| -> Result<usize> {
+            for idx in 0..tables as usize {
+                let record = start + 12 + 16 * idx;
+                if bytes.get(record..record + 4) == Some(tag) {
+                    return Ok(u32_at(bytes, record + 8)? as usize);
+                }
+            }
+            bail!("missing '{}' table", String::from_utf8_lossy(tag))
+        };
+
+        let head = table(b"head")?;
+        let hhea = table(b"hhea")?;
+        let units_per_em = u16_at(bytes, head + 18)?;
+        if units_per_em == 0 {
+            bail!("invalid units per em");
+        }
+        let line_height = i16_at(bytes, hhea + 4)? as i32 - i16_at(bytes, hhea + 6)? as i32
+            + i16_at(bytes, hhea + 8)? as i32;
+        Ok(Font {
+            bytes,
+            units_per_em,
+            line_height,
+            long_offsets: i16_at(bytes, head + 50)? != 0,
+            glyphs: u16_at(bytes, table(b"maxp")? + 4)?,
+            metrics: u16_at(bytes, hhea + 34)?,
+            hmtx: table(b"hmtx")?,
+            loca: table(b"loca")?,
+            glyf: table(b"glyf")?,
+            cmap: Self::find_cmap(bytes, table(b"cmap")?)?,
+        })
+    }
+
+    // Prefers the full Unicode subtable and falls back to the one for the
+    // Basic Multilingual Plane.
+    fn find_cmap(bytes: &[u8], cmap: usize) -> Result<Cmap> {
+        let mut bmp = None;
+        for idx in 0..u16_at(bytes, cmap + 2)? as usize {
+            let record = cmap + 4 + 8 * idx;
+            let platform = u16_at(bytes, record)?;
+            let encoding = u16_at(bytes, record + 2)?;
+            let subtable = cmap + u32_at(bytes, record + 4)? as usize;
+            let unicode = platform == 0 || (platform == 3 && matches!(encoding, 1 | 10));
+            match u16_at(bytes, subtable)? {
+                12 if unicode => return Ok(Cmap::Groups(subtable)),
+                4 if unicode => bmp = Some(Cmap::SegmentDeltas(subtable)),
+                _ => {}
+            }
+        }
+        bmp.ok_or_else(|| anyhow!("no Unicode character map"))
+    }
+
+    // Characters the font lacks get glyph 0, which is usually a box.
+    fn glyph_index(&self, c: u32) -> Result<u16> {
+        let bytes = self.bytes;
+        match self.cmap {
+            Cmap::SegmentDeltas(subtable) => {
+                if c > 0xffff {
+                    return Ok(0);
+                }
+                let segments = u16_at(bytes, subtable + 6)? as usize / 2;
+                let ends = subtable + 14;
+                let starts = ends + 2 * segments + 2;
+                let deltas = starts + 2 * segments;
+                let range_offsets = deltas + 2 * segments;
+                for seg in 0..segments {
+                    if (u16_at(bytes, ends + 2 * seg)? as u32) < c {
+                        continue;
+                    }
+                    let start = u16_at(bytes, starts + 2 * seg)? as u32;
+                    if start > c {
+                        return Ok(0);
+                    }
+                    let delta = u16_at(bytes, deltas + 2 * seg)?;
+                    let range_offset = u16_at(bytes, range_offsets + 2 * seg)? as usize;
+                    if range_offset == 0 {
+                        return Ok((c as u16).wrapping_add(delta));
+                    }
+                    let pos = range_offsets + 2 * seg + range_offset + 2 * (c - start) as usize;
+                    let glyph = u16_at(bytes, pos)?;
+                    return Ok(if glyph == 0 {
+                        0
+                    } else {
+                        glyph.wrapping_add(delta)
+                    });
+                }
+                Ok(0)
+            }
+            Cmap::Groups(subtable) => {
+                for idx in 0..u32_at(bytes, subtable + 12)? as usize {
+                    let group = subtable + 16 + 12 * idx;
+                    let start = u32_at(bytes, group)?;
+                    if (start..=u32_at(bytes, group + 4)?).contains(&c) {
+                        return Ok((u32_at(bytes, group + 8)? + c - start) as u16);
+                    }
+                }
+                Ok(0)
+            }
+        }
+    }
+
+    fn advance(&self, glyph: u16) -> Result<u16> {
+        let idx = glyph.min(self.metrics.saturating_sub(1));
+        u16_at(self.bytes, self.hmtx + 4 * idx as usize)
+    }
+
+    fn glyph_range(&self, glyph: u16) -> Result<(usize, usize)> {
+        if glyph >= self.glyphs {
+            bail!("glyph {} out of range", glyph);
+        }
+        let offset = |idx: usize| -> Result<usize> {
+            Ok(if self.long_offsets {
+                u32_at(self.bytes, self.loca + 4 * idx)? as usize
+            } else {
+                2 * u16_at(self.bytes, self.loca + 2 * idx)? as usize
+            })
+        };
+        let idx = glyph as usize;
+        Ok((self.glyf + offset(idx)?, self.glyf + offset(idx + 1)?))
+    }
+
+    // Appends the outlines of a glyph, transformed into text space.
+    fn glyph(
+        &self,
+        glyph: u16,
+        transform: Affine2,
+        depth: u32,
+        contours: &mut Vec<Vec<Vec2>>,
+    ) -> Result<()> {
+        let (start, end) = self.glyph_range(glyph)?;
+        // Glyphs without outlines, such as spaces, take no room in 'glyf'.
+        if start >= end {
+            return Ok(());
+        }
+        let count = i16_at(self.bytes, start)?;
+        if count >= 0 {
+            for contour in self.simple_glyph(start, count as usize)? {
+                contours.push(flatten(&contour, transform));
+            }
+            return Ok(());
+        }
+        if depth >= MAX_DEPTH {
+            bail!("composite glyphs nested too deeply");
+        }
+        self.composite_glyph(start + 10, transform, depth, contours)
+    }
+
+    // Reads the points of each contour with whether they are on the curve.
+    fn simple_glyph(&self, start: usize, count: usize) -> Result<Vec<Vec<(Vec2, bool)>>> {
+        const ON_CURVE: u8 = 0x01;
+        const X_SHORT: u8 = 0x02;
+        const Y_SHORT: u8 = 0x04;
+        const REPEAT: u8 = 0x08;
+        const X_SAME_OR_POSITIVE: u8 = 0x10;
+        const Y_SAME_OR_POSITIVE: u8 = 0x20;
+
+        let bytes = self.bytes;
+        let ends = (0..count)
+            .map(|idx| u16_at(bytes, start + 10 + 2 * idx).map(|end| end as usize))
+            .collect::<Result<Vec<_>>>()?;
+        let points = ends.last().map_or(0, |&end| end + 1);
+        let instructions = start + 10 + 2 * count;
+        let mut pos = instructions + 2 + u16_at(bytes, instructions)? as usize;
+        let byte = |pos: usize| {
+            bytes
+                .get(pos)
+                .copied()
+                .ok_or_else(|| anyhow!("truncated font"))
+        };
+
+        let mut flags = Vec::with_capacity(points);
+        while flags.len() < points {
+            let flag = byte(pos)?;
+            pos += 1;
+            flags.push(flag);
+            if flag & REPEAT != 0 {
+                for _ in 0..byte(pos)? {
+                    flags.push(flag);
+                }
+                pos += 1;
+            }
+        }
+        flags.truncate(points);
+
+        // Coordinates are deltas from the previous point, first all the x
+        // then all the y.
+        let mut coordinates = |short: u8, same_or_positive: u8| -> Result<Vec<f32>> {
+            let mut value = 0i32;
+            let mut values = vec![];
+            for &flag in &flags {
+                if flag & short != 0 {
+                    let delta = byte(pos)? as i32;
+                    pos += 1;
+                    value += if flag & same_or_positive != 0 {
+                        delta
+                    } else {
+                        -delta
+                    };
+                } else if flag & same_or_positive == 0 {
+                    value += i16_at(bytes, pos)? as i32;
+                    pos += 2;
+                }
+                values.push(value as f32);
+            }
+            Ok(values)
+        };
+        let xs = coordinates(X_SHORT, X_SAME_OR_POSITIVE)?;
+        let ys = coordinates(Y_SHORT, Y_SAME_OR_POSITIVE)?;
+
+        let mut contours = vec![];
+        let mut first = 0;
+        for end in ends {
+            if end < first || end >= points {
+                bail!("invalid contour end point {}", end);
+            }
+            contours.push(
+                (first..=end)
+                    .map(|idx| (Vec2::new(xs[idx], ys[idx]), flags[idx] & ON_CURVE != 0))
+                    .collect(),
+            );
+            first = end + 1;
+        }
+        Ok(contours)
+    }
+
+    // Composite glyphs place other glyphs with an offset and an optional
+    // scale or 2x2 matrix. Components positioned by matching points are
+    // placed without an offset.
+    fn composite_glyph(
+        &self,
+        mut pos: usize,
+        transform: Affine2,
+        depth: u32,
+        contours: &mut Vec<Vec<Vec2>>,
+    ) -> Result<()> {
+        const ARGS_ARE_WORDS: u16 = 0x0001;
+        const ARGS_ARE_OFFSETS: u16 = 0x0002;
+        const SCALE: u16 = 0x0008;
+        const MORE_COMPONENTS: u16 = 0x0020;
+        const XY_SCALE: u16 = 0x0040;
+        const MATRIX: u16 = 0x0080;
+
+        let bytes = self.bytes;
+        let f2dot14 = |pos: usize| i16_at(bytes, pos).map(|v| v as f32 / 16384.0);
+        loop {
+            let flags = u16_at(bytes, pos)?;
+            let glyph = u16_at(bytes, pos + 2)?;
+            pos += 4;
+            let offset = if flags & ARGS_ARE_WORDS != 0 {
+                pos += 4;
+                Vec2::new(
+                    i16_at(bytes, pos - 4)? as f32,
+                    i16_at(bytes, pos - 2)? as f32,
+                )
+            } else {
+                pos += 2;
+                let arg = |pos: usize| {
+                    bytes
+                        .get(pos)
+                        .map(|&b| b as i8 as f32)
+                        .ok_or_else(|| anyhow!("truncated font"))
+                };
+                Vec2::new(arg(pos - 2)?, arg(pos - 1)?)
+            };
+            let offset = if flags & ARGS_ARE_OFFSETS != 0 {
+                offset
+            } else {
+                Vec2::ZERO
+            };
+            let matrix = if flags & SCALE != 0 {
+                pos += 2;
+                Mat2::from_diagonal(Vec2::splat(f2dot14(pos - 2)?))
+            } else if flags & XY_SCALE != 0 {
+                pos += 4;
+                Mat2::from_diagonal(Vec2::new(f2dot14(pos - 4)?, f2dot14(pos - 2)?))
+            } else if flags & MATRIX != 0 {
+                pos += 8;
+                Mat2::from_cols(
+                    Vec2::new(f2dot14(pos - 8)?, f2dot14(pos - 6)?),
+                    Vec2::new(f2dot14(pos - 4)?, f2dot14(pos - 2)?),
+                )
+            } else {
+                Mat2::IDENTITY
+            };
+            let component = transform * Affine2::from_mat2_translation(matrix, offset);
+            self.glyph(glyph, component, depth + 1, contours)?;
+            if flags & MORE_COMPONENTS == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Turns a contour of quadratic B-splines into a polygon. Between two
+// off-curve points lies an implied on-curve point halfway.
+fn flatten(points: &[(Vec2, bool)], transform: Affine2) -> Vec<Vec2> {
+    let n = points.len();
+    let Some(first_on) = points.iter().position(|&(_, on)| on) else {
+        // All off-curve: start at an implied point.
+        let start = (points[0].0 + points[1 % n].0) / 2.0;
+        let mut rotated = vec![(start, true)];
+        rotated.extend(points[1 % n..].iter().chain(&points[..1 % n]));
+        return flatten(&rotated, transform);
+    };
+
+    let mut polygon = vec![];
+    let mut current = points[first_on].0;
+    let mut control = None;
+    for k in 1..=n {
+        let (p, on) = points[(first_on + k) % n];
+        match (control, on) {
+            (None, true) => {
+                polygon.push(current);
+                current = p;
+            }
+            (None, false) => control = Some(p),
+            (Some(c), _) => {
+                let end = if on { p } else { (c + p) / 2.0 };
+                for step in 0..CURVE_SEGMENTS {
+                    let t = step as f32 / CURVE_SEGMENTS as f32;
+                    polygon.push(current.lerp(c, t).lerp(c.lerp(end, t), t));
+                }
+                current = end;
+                control = if on { None } else { Some(p) };
+            }
+        }
+    }
+    polygon
+        .into_iter()
+        .map(|p| transform.transform_point2(p))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A font with a 10 unit square with one rounded corner as glyph 1, for
+    // 'a', and the same shape scaled by half and moved by (20, 0) as the
+    // composite glyph 2, for 'b'. The em is 100 units and every glyph
+    // advances by 50.
+    fn font() -> Vec<u8> {
+        let be16 = |values: &[i32]| -> Vec<u8> {
+            values
+                .iter()
+                .flat_map(|&v| (v as u16).to_be_bytes())
+                .collect()
+        };
+        let mut head = vec![0; 54];
+        head[18..20].copy_from_slice(&100u16.to_be_bytes());
+        let mut maxp = vec![0; 6];
+        maxp[4..6].copy_from_slice(&3u16.to_be_bytes());
+        let mut hhea = be16(&[1, 0, 80, -20, 0]);
+        hhea.resize(36, 0);
+        hhea[34..36].copy_from_slice(&1u16.to_be_bytes());
+        let hmtx = be16(&[50, 0]);
+
+        // Points (0,0) (10,0) (10,10) off-curve (0,10): each x and y as a
+        // positive or negative byte.
+        let mut square = be16(&[1, 0, 0, 10, 10, 3, 0]);
+        square.extend([0x31, 0x33, 0x35, 0x22]);
+        square.extend([10, 10]);
+        square.extend([10]);
+        let mut composite = be16(&[-1, 0, 0, 10, 10]);
+        composite.extend(be16(&[0x0002 | 0x0008, 1]));
+        composite.extend([20, 0]);
+        composite.extend(be16(&[0x2000]));
+        let mut glyf = square.clone();
+        glyf.resize(square.len().next_multiple_of(2), 0);
+        let loca = be16(&[
+            0,
+            0,
+            glyf.len() as i32 / 2,
+            (glyf.len() + composite.len()) as i32 / 2,
+        ]);
+        glyf.extend(composite);
+
+        // Format 4 with a segment for 'a' and 'b' and the closing one.
+        let mut cmap = be16(&[0, 1, 3, 1, 0, 12]);
+        cmap.extend(be16(&[4, 32, 0, 4, 0, 0, 0]));
+        cmap.extend(be16(&[b'b' as i32, 0xffff, 0, b'a' as i32, 0xffff]));
+        cmap.extend(be16(&[1 - b'a' as i32, 1, 0, 0]));
+
+        let tables: [(&[u8], Vec<u8>); 7] = [
+            (b"cmap", cmap),
+            (b"glyf", glyf),
+            (b"head", head),
+            (b"hhea", hhea),
+            (b"hmtx", hmtx),
+            (b"loca", loca),
+            (b"maxp", maxp),
+        ];
+        let mut bytes = be16(&[1, 0, tables.len() as i32, 0, 0, 0]);
+        let mut data = vec![];
+        for (tag, table) in &tables {
+            bytes.extend(*tag);
+            bytes.extend([0; 4]);
+            let offset = 12 + 16 * tables.len() + data.len();
+            bytes.extend((offset as u32).to_be_bytes());
+            bytes.extend((table.len() as u32).to_be_bytes());
+            data.extend(table);
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+        bytes.extend(data);
+        bytes
+    }
+
+    fn bounds(contour: &[Vec2]) -> (Vec2, Vec2) {
+        let min = contour.iter().copied().reduce(Vec2::min).unwrap();
+        let max = contour.iter().copied().reduce(Vec2::max).unwrap();
+        (min, max)
+    }
+
+    #[test]
+    fn lays_out_simple_and_composite_glyphs() {
+        let contours = layout(&font(), "a b\na", 10.0).unwrap();
+        assert_eq!(contours.len(), 3);
+        // The rounded corner is flattened.
+        assert_eq!(contours[0].len(), 2 + CURVE_SEGMENTS);
+        let (min, max) = bounds(&contours[0]);
+        assert!(min.distance(Vec2::ZERO) < 1e-5 && max.distance(Vec2::ONE) < 1e-5);
+        // After two advances, scaled and moved by the composite.
+        let (min, max) = bounds(&contours[1]);
+        assert!(min.distance(Vec2::new(12.0, 0.0)) < 1e-5);
+        assert!(max.distance(Vec2::new(12.5, 0.5)) < 1e-5);
+        // The next line is a line height lower.
+        assert!(bounds(&contours[2]).0.distance(Vec2::new(0.0, -10.0)) < 1e-5);
+
+        let mut cff = font();
+        cff[..4].copy_from_slice(b"OTTO");
+        assert!(layout(&cff, "a", 1.0).is_err());
+    }
+}
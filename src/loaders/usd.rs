@@ -0,0 +1,1456 @@
+use super::Resolve;
+use crate::canvas::Canvas;
+use crate::hittables::Mesh;
+use crate::materials::Material;
+use crate::textures::{ImageTexture, SolidColor, Texture};
+use crate::{Color3, Point3};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::DeflateDecoder;
+use glam::{vec2, Affine3A, Mat3, Mat4, Quat, Vec2, Vec3};
+use std::collections::HashMap;
+use std::f32::consts::{FRAC_PI_2, TAU};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const USDC_MAGIC: &[u8] = b"PXR-USDC";
+// Segments around disk and sphere lights, and rings from pole to pole.
+const LIGHT_SEGMENTS: u32 = 32;
+const LIGHT_RINGS: u32 = 16;
+
+// Loads the meshes of a USD stage into a single mesh, with prim transforms
+// baked into the vertices: a text layer (`.usda`), or a `.usdz` package
+// whose first layer is one. The layer is read on its own; references,
+// payloads, variants and binary (`usdc`) layers aren't supported, nor are
+// cameras, animation or lights other than rect, disk and sphere lights.
+// Polygons are split into fans; `normals` and the `st` primvar (or the
+// first texture coordinate primvar) are kept, per vertex or per face
+// corner. Materials bound to a mesh, an ancestor or a `GeomSubset` of its
+// faces are mapped from their `UsdPreviewSurface` like glTF materials, with
+// a `UsdUVTexture` diffuse color read as an image; faces without one get
+// `default`. Lights become faces emitting their color times their
+// intensity, scaled by 2 to the power of their exposure. `resolve` turns
+// asset paths outside the package into paths.
+pub fn load(path: &Path, default: Material, resolve: Resolve) -> Result<Mesh> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    parse(&bytes, default, resolve).with_context(|| format!("in {}", path.display()))
+}
+
+fn parse(
+    bytes: &[u8],
+    default: Material,
+    resolve: impl Fn(&str) -> Result<PathBuf>,
+) -> Result<Mesh> {
+    let package = match bytes.starts_with(ZIP_MAGIC) {
+        true => Some(Package::read(bytes).context("in the USDZ package")?),
+        false => None,
+    };
+    let layer = match &package {
+        Some(package) => package.root_layer()?,
+        None => bytes,
+    };
+    if layer.starts_with(USDC_MAGIC) {
+        bail!("binary USD layers are not supported; save the stage as .usda");
+    }
+    let text = std::str::from_utf8(layer).context("USD layers must be UTF-8 text")?;
+    let stage = Parser::new(text).stage()?;
+
+    // Files in the package first, then next to it.
+    let images = |asset: &str| -> Result<Canvas> {
+        match package.as_ref().and_then(|package| package.file(asset)) {
+            Some(bytes) => Canvas::decode(bytes).with_context(|| format!("in {}", asset)),
+            None => Canvas::load(&resolve(asset)?),
+        }
+    };
+    let mut builder = Builder {
+        prims: HashMap::new(),
+        materials: vec![default],
+        bound: HashMap::new(),
+        images: &images,
+        mesh: MeshData::default(),
+    };
+    for prim in &stage.prims {
+        builder.index(prim);
+    }
+    // Scenes are Y up.
+    let up = match stage.metadata.get("upAxis") {
+        Some(Value::Text(axis)) if axis == "Z" => Affine3A::from_rotation_x(-FRAC_PI_2),
+        _ => Affine3A::IDENTITY,
+    };
+    for prim in &stage.prims {
+        builder
+            .add_prim(prim, up, None)
+            .with_context(|| format!("in {}", prim.path))?;
+    }
+
+    let Builder {
+        materials, mesh, ..
+    } = builder;
+    let MeshData {
+        positions,
+        normals,
+        uvs,
+        any_uvs,
+        faces,
+        face_materials,
+    } = mesh;
+    let mut mesh = Mesh::new(positions, faces, materials, face_materials)?;
+    if any_uvs {
+        mesh = mesh.with_uvs(uvs)?;
+    }
+    if normals.iter().any(|&n| n != Vec3::ZERO) {
+        mesh = mesh.with_normals(normals)?;
+    }
+    Ok(mesh)
+}
+
+// The files of a USDZ package, a zip archive that normally stores them
+// uncompressed; deflated ones are inflated.
+struct Package {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl Package {
+    fn read(bytes: &[u8]) -> Result<Package> {
+        let u16_at = |pos: usize| -> Result<usize> {
+            let bytes = bytes
+                .get(pos..pos + 2)
+                .ok_or_else(|| anyhow!("truncated"))?;
+            Ok(u16::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+        let u32_at = |pos: usize| -> Result<usize> {
+            let bytes = bytes
+                .get(pos..pos + 4)
+                .ok_or_else(|| anyhow!("truncated"))?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+        // The end of central directory record, before a comment of up to
+        // 64 KiB.
+        let end = (0..=bytes.len().saturating_sub(22))
+            .rev()
+            .take(22 + 0xFFFF)
+            .find(|&pos| bytes[pos..].starts_with(b"PK\x05\x06"))
+            .ok_or_else(|| anyhow!("no zip central directory"))?;
+        let mut entry = u32_at(end + 16)?;
+        let mut files = vec![];
+        for _ in 0..u16_at(end + 10)? {
+            if u32_at(entry)? != 0x02014B50 {
+                bail!("corrupt zip central directory");
+            }
+            let method = u16_at(entry + 10)?;
+            let size = u32_at(entry + 20)?;
+            let name_len = u16_at(entry + 28)?;
+            let name = bytes
+                .get(entry + 46..entry + 46 + name_len)
+                .ok_or_else(|| anyhow!("truncated"))?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            let local = u32_at(entry + 42)?;
+            let start = local + 30 + u16_at(local + 26)? + u16_at(local + 28)?;
+            let data = bytes
+                .get(start..start + size)
+                .ok_or_else(|| anyhow!("{} runs past the end", name))?;
+            let data = match method {
+                0 => data.to_vec(),
+                8 => {
+                    let mut inflated = vec![];
+                    DeflateDecoder::new(data)
+                        .read_to_end(&mut inflated)
+                        .with_context(|| format!("in {}", name))?;
+                    inflated
+                }
+                _ => bail!("{} uses unsupported compression method {}", name, method),
+            };
+            files.push((name, data));
+            entry += 46 + name_len + u16_at(entry + 30)? + u16_at(entry + 32)?;
+        }
+        Ok(Package { files })
+    }
+
+    // The stage is the first layer in the package.
+    fn root_layer(&self) -> Result<&[u8]> {
+        self.files
+            .iter()
+            .find(|(name, _)| {
+                [".usda", ".usdc", ".usd"]
+                    .iter()
+                    .any(|ext| name.ends_with(ext))
+            })
+            .map(|(_, data)| data.as_slice())
+            .ok_or_else(|| anyhow!("no USD layer in the package"))
+    }
+
+    fn file(&self, name: &str) -> Option<&[u8]> {
+        let name = name.trim_start_matches("./");
+        self.files
+            .iter()
+            .find(|(file, _)| file == name)
+            .map(|(_, data)| data.as_slice())
+    }
+}
+
+// Attribute values and metadata. Strings, tokens and asset paths are all
+// text; tuples and arrays are both lists.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Number(f64),
+    Text(String),
+    // A prim or property path, from a relationship or a connection.
+    Path(String),
+    List(Vec<Value>),
+    // Dictionaries, time samples' absence and `None`.
+    Empty,
+}
+
+struct Property {
+    // The value type, such as `float3[]`, or `rel` for relationships.
+    kind: String,
+    value: Value,
+    metadata: HashMap<String, Value>,
+}
+
+struct Prim {
+    // `def`, `over` or `class`.
+    specifier: String,
+    // The schema, such as `Mesh` or `Xform`; empty for typeless prims.
+    kind: String,
+    path: String,
+    properties: HashMap<String, Property>,
+    children: Vec<Prim>,
+}
+
+impl Prim {
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.properties.get(name).map(|property| &property.value)
+    }
+
+    // The first path of a relationship or connection.
+    fn target(&self, name: &str) -> Option<&str> {
+        match self.get(name)? {
+            Value::Path(path) => Some(path),
+            Value::List(paths) => match paths.first()? {
+                Value::Path(path) => Some(path),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn number(&self, name: &str) -> Result<Option<f32>> {
+        match self.get(name) {
+            None | Some(Value::Empty) => Ok(None),
+            Some(value) => Ok(Some(
+                number(value).with_context(|| format!("in '{}'", name))?,
+            )),
+        }
+    }
+
+    fn vec3(&self, name: &str) -> Result<Option<Vec3>> {
+        match self.get(name) {
+            None | Some(Value::Empty) => Ok(None),
+            Some(value) => Ok(Some(Vec3::from_array(
+                numbers(value).with_context(|| format!("in '{}'", name))?,
+            ))),
+        }
+    }
+
+    fn text(&self, name: &str) -> Option<&str> {
+        match self.get(name)? {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+struct Stage {
+    metadata: HashMap<String, Value>,
+    prims: Vec<Prim>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Asset(String),
+    Path(String),
+    Number(f64),
+    Punct(char),
+}
+
+// Reads the text format, keeping what loading needs: prims with their
+// properties, and the layer's metadata. Property and prim metadata is kept
+// where it matters (`interpolation`), the rest skipped.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Parser {
+        Parser {
+            tokens: tokenize(text),
+            pos: 0,
+        }
+    }
+
+    fn stage(mut self) -> Result<Stage> {
+        let result = self.parse_stage();
+        match (result, self.tokens.get(self.pos.saturating_sub(1))) {
+            (Err(error), Some((_, line))) => Err(error.context(format!("on line {}", line))),
+            (result, _) => result,
+        }
+    }
+
+    fn parse_stage(&mut self) -> Result<Stage> {
+        let metadata = match self.peek() {
+            Some(Token::Punct('(')) => self.metadata()?,
+            _ => HashMap::new(),
+        };
+        let mut prims = vec![];
+        while self.peek().is_some() {
+            prims.push(self.prim("")?);
+        }
+        Ok(Stage { metadata, prims })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .map(|(token, _)| token.clone())
+            .ok_or_else(|| anyhow!("unexpected end of file"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, punct: char) -> Result<()> {
+        match self.next()? {
+            Token::Punct(c) if c == punct => Ok(()),
+            token => bail!("expected '{}', got {:?}", punct, token),
+        }
+    }
+
+    fn word(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::Word(word) => Ok(word),
+            token => bail!("expected a name, got {:?}", token),
+        }
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        let found = self.peek() == Some(&Token::Punct(punct));
+        self.pos += found as usize;
+        found
+    }
+
+    // `def Mesh "name" (metadata) { properties and children }`.
+    fn prim(&mut self, parent: &str) -> Result<Prim> {
+        let specifier = self.word()?;
+        if !["def", "over", "class"].contains(&specifier.as_str()) {
+            bail!("expected a prim, got '{}'", specifier);
+        }
+        let kind = match self.peek() {
+            Some(Token::Word(_)) => self.word()?,
+            _ => String::new(),
+        };
+        let name = match self.next()? {
+            Token::Text(name) => name,
+            token => bail!("expected the prim's name, got {:?}", token),
+        };
+        let path = format!("{}/{}", parent, name);
+        if self.peek() == Some(&Token::Punct('(')) {
+            self.metadata()?;
+        }
+        self.expect('{')?;
+        let mut prim = Prim {
+            specifier,
+            kind,
+            path,
+            properties: HashMap::new(),
+            children: vec![],
+        };
+        while !self.eat('}') {
+            match self.peek() {
+                Some(Token::Word(word)) if ["def", "over", "class"].contains(&word.as_str()) => {
+                    let child = self.prim(&prim.path)?;
+                    prim.children.push(child);
+                }
+                Some(Token::Word(word)) if word == "variantSet" => {
+                    bail!("variant sets are not supported")
+                }
+                _ => {
+                    let (name, property) = self.property()?;
+                    prim.properties.insert(name, property);
+                }
+            }
+        }
+        Ok(prim)
+    }
+
+    // `[custom] [uniform] type name [= value] [(metadata)]`, or
+    // `rel name [= targets]`. Time samples keep their first value.
+    fn property(&mut self) -> Result<(String, Property)> {
+        let mut kind = self.word()?;
+        while [
+            "custom", "uniform", "varying", "config", "prepend", "append", "delete", "add",
+        ]
+        .contains(&kind.as_str())
+        {
+            kind = self.word()?;
+        }
+        if self.eat('[') {
+            self.expect(']')?;
+            kind.push_str("[]");
+        }
+        let mut name = self.word()?;
+        let mut value = Value::Empty;
+        if self.eat('=') {
+            value = self.value()?;
+        }
+        if let Some(attribute) = name.strip_suffix(".timeSamples") {
+            name = attribute.to_string();
+            value = match value {
+                Value::List(samples) => samples.into_iter().next().unwrap_or(Value::Empty),
+                value => value,
+            };
+        }
+        let metadata = match self.peek() {
+            Some(Token::Punct('(')) => self.metadata()?,
+            _ => HashMap::new(),
+        };
+        let property = Property {
+            kind,
+            value,
+            metadata,
+        };
+        Ok((name, property))
+    }
+
+    // Parenthesized `key = value` pairs, with doc strings and list edits.
+    fn metadata(&mut self) -> Result<HashMap<String, Value>> {
+        self.expect('(')?;
+        let mut metadata = HashMap::new();
+        loop {
+            match self.next()? {
+                Token::Punct(')') => return Ok(metadata),
+                Token::Punct(';') | Token::Text(_) => {}
+                Token::Word(mut key) => {
+                    if ["prepend", "append", "delete", "add", "reorder"].contains(&key.as_str()) {
+                        key = self.word()?;
+                    }
+                    self.expect('=')?;
+                    metadata.insert(key, self.value()?);
+                }
+                token => bail!("unexpected {:?} in metadata", token),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        Ok(match self.next()? {
+            Token::Number(number) => Value::Number(number),
+            Token::Text(text) | Token::Asset(text) => Value::Text(text),
+            Token::Path(path) => Value::Path(path),
+            Token::Word(word) if word == "None" => Value::Empty,
+            Token::Word(word) => match word.as_str() {
+                "inf" => Value::Number(f64::INFINITY),
+                "-inf" => Value::Number(f64::NEG_INFINITY),
+                "nan" => Value::Number(f64::NAN),
+                "true" => Value::Number(1.0),
+                "false" => Value::Number(0.0),
+                _ => Value::Text(word),
+            },
+            Token::Punct(open @ ('(' | '[')) => {
+                let close = if open == '(' { ')' } else { ']' };
+                let mut items = vec![];
+                while !self.eat(close) {
+                    items.push(self.value()?);
+                    if !self.eat(',') {
+                        self.expect(close)?;
+                        break;
+                    }
+                }
+                Value::List(items)
+            }
+            // Dictionaries and time samples, which become their values in
+            // order.
+            Token::Punct('{') => {
+                let mut values = vec![];
+                while !self.eat('}') {
+                    match self.next()? {
+                        Token::Punct(':') | Token::Punct(',') | Token::Punct(';') => {}
+                        Token::Punct('=') => values.push(self.value()?),
+                        Token::Punct('{') => {
+                            self.pos -= 1;
+                            self.value()?;
+                        }
+                        _ if self.peek() == Some(&Token::Punct(':')) => {
+                            self.pos += 1;
+                            values.push(self.value()?);
+                        }
+                        _ => {}
+                    }
+                }
+                Value::List(values)
+            }
+            token => bail!("expected a value, got {:?}", token),
+        })
+    }
+}
+
+// Splits the text into tokens with their line numbers, dropping comments
+// (and with them the `#usda 1.0` header).
+fn tokenize(text: &str) -> Vec<(Token, usize)> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut pos = 0;
+    // Everything up to `end`, or the rest of the text if it never comes.
+    let until = |pos: usize, end: &str| -> (String, usize) {
+        let end: Vec<char> = end.chars().collect();
+        let mut idx = pos;
+        while idx < chars.len() && !chars[idx..].starts_with(&end) {
+            idx += 1;
+        }
+        (
+            chars[pos..idx].iter().collect(),
+            (idx + end.len()).min(chars.len()),
+        )
+    };
+    while pos < chars.len() {
+        let c = chars[pos];
+        let start_line = line;
+        let token = match c {
+            '\n' => {
+                line += 1;
+                pos += 1;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            '#' => {
+                while pos < chars.len() && chars[pos] != '\n' {
+                    pos += 1;
+                }
+                continue;
+            }
+            '"' | '\'' => {
+                let triple: String = [c; 3].iter().collect();
+                let (text, end) = if chars[pos..].starts_with(&[c; 3]) {
+                    until(pos + 3, &triple)
+                } else {
+                    let mut text = String::new();
+                    let mut idx = pos + 1;
+                    while idx < chars.len() && chars[idx] != c {
+                        if chars[idx] == '\\' && idx + 1 < chars.len() {
+                            idx += 1;
+                        }
+                        text.push(chars[idx]);
+                        idx += 1;
+                    }
+                    (text, idx + 1)
+                };
+                line += text.matches('\n').count();
+                pos = end;
+                Token::Text(text)
+            }
+            '@' => {
+                let (text, end) = if chars[pos..].starts_with(&['@'; 3]) {
+                    until(pos + 3, "@@@")
+                } else {
+                    until(pos + 1, "@")
+                };
+                pos = end;
+                Token::Asset(text)
+            }
+            '<' => {
+                let (text, end) = until(pos + 1, ">");
+                pos = end;
+                Token::Path(text)
+            }
+            c if c.is_ascii_digit()
+                || (c == '-' || c == '.' || c == '+')
+                    && chars
+                        .get(pos + 1)
+                        .is_some_and(|&next| next.is_ascii_digit() || next == '.') =>
+            {
+                let start = pos;
+                pos += 1;
+                while pos < chars.len()
+                    && (chars[pos].is_ascii_alphanumeric()
+                        || chars[pos] == '.'
+                        || (matches!(chars[pos], '-' | '+') && matches!(chars[pos - 1], 'e' | 'E')))
+                {
+                    pos += 1;
+                }
+                let text: String = chars[start..pos].iter().collect();
+                match text.parse() {
+                    Ok(number) => Token::Number(number),
+                    Err(_) => Token::Word(text),
+                }
+            }
+            c if c.is_alphabetic() || c == '_' || c == '!' || c == '-' => {
+                let start = pos;
+                pos += 1;
+                while pos < chars.len()
+                    && (chars[pos].is_alphanumeric() || matches!(chars[pos], '_' | ':' | '.' | '!'))
+                {
+                    pos += 1;
+                }
+                Token::Word(chars[start..pos].iter().collect())
+            }
+            c => {
+                pos += 1;
+                Token::Punct(c)
+            }
+        };
+        tokens.push((token, start_line));
+    }
+    tokens
+}
+
+#[derive(Default)]
+struct MeshData {
+    positions: Vec<Point3>,
+    // Zero where the prim has no normals, which stays flat.
+    normals: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+    any_uvs: bool,
+    faces: Vec<[u32; 3]>,
+    face_materials: Vec<u32>,
+}
+
+struct Builder<'a> {
+    prims: HashMap<&'a str, &'a Prim>,
+    // Material 0 is the default one.
+    materials: Vec<Material>,
+    bound: HashMap<String, u32>,
+    images: &'a dyn Fn(&str) -> Result<Canvas>,
+    mesh: MeshData,
+}
+
+impl<'a> Builder<'a> {
+    fn index(&mut self, prim: &'a Prim) {
+        self.prims.insert(&prim.path, prim);
+        for child in &prim.children {
+            self.index(child);
+        }
+    }
+
+    // Adds `prim` and its descendants, placed by `parent`. Abstract prims
+    // and overs on their own describe nothing, and hidden prims, guides
+    // and proxies aren't rendered.
+    fn add_prim<'p>(
+        &mut self,
+        prim: &'p Prim,
+        parent: Affine3A,
+        binding: Option<&'p str>,
+    ) -> Result<()> {
+        if prim.specifier != "def"
+            || prim.text("visibility") == Some("invisible")
+            || matches!(prim.text("purpose"), Some("guide" | "proxy"))
+        {
+            return Ok(());
+        }
+        let to_world = transform(prim, parent)?;
+        let binding = prim.target("material:binding").or(binding);
+        match prim.kind.as_str() {
+            "Mesh" => self.add_mesh(prim, to_world, binding)?,
+            "RectLight" | "DiskLight" | "SphereLight" => self.add_light(prim, to_world)?,
+            _ => {}
+        }
+        for child in &prim.children {
+            self.add_prim(child, to_world, binding)
+                .with_context(|| format!("in {}", child.path))?;
+        }
+        Ok(())
+    }
+
+    fn add_mesh(&mut self, prim: &Prim, to_world: Affine3A, binding: Option<&str>) -> Result<()> {
+        let points: Vec<Vec3> = match prim.get("points") {
+            Some(points) => vectors(points)
+                .context("in 'points'")?
+                .into_iter()
+                .map(Vec3::from_array)
+                .collect(),
+            None => return Ok(()),
+        };
+        let counts = integers(prim.get("faceVertexCounts"), "faceVertexCounts")?;
+        let indices = integers(prim.get("faceVertexIndices"), "faceVertexIndices")?;
+        if counts.iter().sum::<u32>() as usize != indices.len() {
+            bail!("'faceVertexCounts' don't add up to the 'faceVertexIndices'");
+        }
+        if let Some(&idx) = indices.iter().find(|&&idx| idx as usize >= points.len()) {
+            bail!("vertex {} past the {} points", idx, points.len());
+        }
+        let normals = primvar::<3>(
+            prim,
+            &["normals", "primvars:normals"],
+            &indices,
+            points.len(),
+        )?;
+        let st_name = ["primvars:st"]
+            .into_iter()
+            .chain(prim.properties.iter().filter_map(|(name, property)| {
+                (name.starts_with("primvars:") && property.kind.starts_with("texCoord2"))
+                    .then_some(name.as_str())
+            }))
+            .find(|name| prim.properties.contains_key(*name));
+        let uvs = match st_name {
+            Some(name) => primvar::<2>(prim, &[name], &indices, points.len())?,
+            None => None,
+        };
+
+        // Per corner if anything varies by corner, or else per point.
+        let by_corner =
+            [normals.as_ref().map(|n| n.1), uvs.as_ref().map(|uv| uv.1)].contains(&Some(true));
+        // The mesh vertex of each face corner, and the point of each vertex.
+        let vertex = |corner: usize| match by_corner {
+            true => corner,
+            false => indices[corner] as usize,
+        };
+        let point = |vertex: usize| match by_corner {
+            true => indices[vertex] as usize,
+            false => vertex,
+        };
+        let first = self.mesh.positions.len() as u32;
+        let normal_to_world = Mat3::from(to_world.matrix3).inverse().transpose();
+        let count = if by_corner {
+            indices.len()
+        } else {
+            points.len()
+        };
+        for idx in 0..count {
+            let value = |corners: bool| if corners { idx } else { point(idx) };
+            self.mesh
+                .positions
+                .push(to_world.transform_point3(points[point(idx)]));
+            let normal = match &normals {
+                Some((normals, corners)) => {
+                    let n = Vec3::from_array(normals[value(*corners)]);
+                    (normal_to_world * n).normalize_or_zero()
+                }
+                None => Vec3::ZERO,
+            };
+            self.mesh.normals.push(normal);
+            let uv = match &uvs {
+                Some((uvs, corners)) => {
+                    let [u, v] = uvs[value(*corners)];
+                    vec2(u, v)
+                }
+                None => Vec2::ZERO,
+            };
+            self.mesh.uvs.push(uv);
+        }
+        self.mesh.any_uvs |= uvs.is_some();
+
+        // Materials by face, from the prim's binding and its subsets'.
+        let default = match binding {
+            Some(path) => self.material(path)?,
+            None => 0,
+        };
+        let mut face_materials = vec![default; counts.len()];
+        for subset in prim
+            .children
+            .iter()
+            .filter(|child| child.kind == "GeomSubset")
+        {
+            let Some(path) = subset.target("material:binding") else {
+                continue;
+            };
+            let material = self
+                .material(path)
+                .with_context(|| format!("in {}", subset.path))?;
+            for face in integers(subset.get("indices"), "indices")? {
+                *face_materials
+                    .get_mut(face as usize)
+                    .ok_or_else(|| anyhow!("{} has no face {}", subset.path, face))? = material;
+            }
+        }
+
+        let left_handed = prim.text("orientation") == Some("leftHanded");
+        let mut corner = 0;
+        for (&count, &material) in counts.iter().zip(&face_materials) {
+            let corners: Vec<u32> = (corner..corner + count as usize)
+                .map(|corner| first + vertex(corner) as u32)
+                .collect();
+            for idx in 1..corners.len().saturating_sub(1) {
+                let (b, c) = (corners[idx], corners[idx + 1]);
+                self.mesh.faces.push(match left_handed {
+                    true => [corners[0], c, b],
+                    false => [corners[0], b, c],
+                });
+                self.mesh.face_materials.push(material);
+            }
+            corner += count as usize;
+        }
+        Ok(())
+    }
+
+    // Rect lights lie in their xy plane facing down z, disks too, and
+    // spheres surround their origin; all emit from both sides.
+    fn add_light(&mut self, prim: &Prim, to_world: Affine3A) -> Result<()> {
+        // Lights from before USD 21.02 lack the `inputs:` prefix.
+        let input = |name: &str| -> Result<Option<f32>> {
+            Ok(prim
+                .number(&format!("inputs:{}", name))?
+                .or(prim.number(name)?))
+        };
+        let color = match prim.vec3("inputs:color")? {
+            Some(color) => color,
+            None => prim.vec3("color")?.unwrap_or(Color3::ONE),
+        };
+        let emit =
+            color * input("intensity")?.unwrap_or(1.0) * input("exposure")?.unwrap_or(0.0).exp2();
+        let (mut points, mut faces) = (vec![], vec![]);
+        match prim.kind.as_str() {
+            "RectLight" => {
+                let width = input("width")?.unwrap_or(1.0) / 2.0;
+                let height = input("height")?.unwrap_or(1.0) / 2.0;
+                for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                    points.push(Vec3::new(x * width, y * height, 0.0));
+                }
+                faces = vec![[0, 1, 2], [0, 2, 3]];
+            }
+            "DiskLight" => {
+                let radius = input("radius")?.unwrap_or(0.5);
+                points.push(Vec3::ZERO);
+                for idx in 0..LIGHT_SEGMENTS {
+                    let (sin, cos) = (idx as f32 / LIGHT_SEGMENTS as f32 * TAU).sin_cos();
+                    points.push(Vec3::new(cos, sin, 0.0) * radius);
+                    faces.push([0, idx + 1, (idx + 1) % LIGHT_SEGMENTS + 1]);
+                }
+            }
+            _ => {
+                let radius = input("radius")?.unwrap_or(0.5);
+                for ring in 0..=LIGHT_RINGS {
+                    let (sin_theta, cos_theta) =
+                        (ring as f32 / LIGHT_RINGS as f32 * TAU / 2.0).sin_cos();
+                    for idx in 0..LIGHT_SEGMENTS {
+                        let (sin, cos) = (idx as f32 / LIGHT_SEGMENTS as f32 * TAU).sin_cos();
+                        points
+                            .push(Vec3::new(cos * sin_theta, cos_theta, sin * sin_theta) * radius);
+                    }
+                }
+                let at = |ring: u32, idx: u32| ring * LIGHT_SEGMENTS + idx % LIGHT_SEGMENTS;
+                for ring in 0..LIGHT_RINGS {
+                    for idx in 0..LIGHT_SEGMENTS {
+                        let (a, b) = (at(ring, idx), at(ring, idx + 1));
+                        let (c, d) = (at(ring + 1, idx), at(ring + 1, idx + 1));
+                        // Leaving out the triangles pinched to a pole.
+                        if ring > 0 {
+                            faces.push([a, c, b]);
+                        }
+                        if ring < LIGHT_RINGS - 1 {
+                            faces.push([b, c, d]);
+                        }
+                    }
+                }
+            }
+        }
+        self.materials.push(Material::DiffuseLight {
+            emit,
+            spread: 180.0,
+        });
+        let material = self.materials.len() as u32 - 1;
+        let first = self.mesh.positions.len() as u32;
+        for point in points {
+            self.mesh.positions.push(to_world.transform_point3(point));
+            self.mesh.normals.push(Vec3::ZERO);
+            self.mesh.uvs.push(Vec2::ZERO);
+        }
+        for face in faces {
+            self.mesh.faces.push(face.map(|idx| first + idx));
+            self.mesh.face_materials.push(material);
+        }
+        Ok(())
+    }
+
+    // The index of the material at `path`, converted on first use.
+    fn material(&mut self, path: &str) -> Result<u32> {
+        if let Some(&idx) = self.bound.get(path) {
+            return Ok(idx);
+        }
+        let material = self
+            .convert_material(path)
+            .with_context(|| format!("in material {}", path))?;
+        self.materials.push(material);
+        let idx = self.materials.len() as u32 - 1;
+        self.bound.insert(path.to_string(), idx);
+        Ok(idx)
+    }
+
+    // Emissive surfaces become lights, transparent ones glass, metallic
+    // ones metal with their roughness as fuzz, and everything else
+    // Lambertian, as for glTF.
+    fn convert_material(&self, path: &str) -> Result<Material> {
+        let material = self
+            .prims
+            .get(path)
+            .filter(|prim| prim.kind == "Material")
+            .ok_or_else(|| anyhow!("not a material"))?;
+        let shader = match material.target("outputs:surface.connect") {
+            Some(output) => self.connected(output)?,
+            None => material
+                .children
+                .iter()
+                .find(|child| child.text("info:id") == Some("UsdPreviewSurface"))
+                .ok_or_else(|| anyhow!("no UsdPreviewSurface"))?,
+        };
+        if shader.text("info:id") != Some("UsdPreviewSurface") {
+            bail!("{} is not a UsdPreviewSurface", shader.path);
+        }
+        let emission = shader.vec3("inputs:emissiveColor")?.unwrap_or(Color3::ZERO);
+        let diffuse = shader
+            .vec3("inputs:diffuseColor")?
+            .unwrap_or(Color3::splat(0.18));
+        let metallic = shader.number("inputs:metallic")?.unwrap_or(0.0);
+        let roughness = shader.number("inputs:roughness")?.unwrap_or(0.5);
+        let opacity = shader.number("inputs:opacity")?.unwrap_or(1.0);
+        Ok(if emission.max_element() > 0.0 {
+            Material::DiffuseLight {
+                emit: emission,
+                spread: 180.0,
+            }
+        } else if opacity < 0.5 {
+            Material::new_dielectric(shader.number("inputs:ior")?.unwrap_or(1.5))
+        } else if metallic >= 0.5 {
+            Material::Metal {
+                albedo: diffuse,
+                fuzz: roughness.clamp(0.0, 1.0),
+            }
+        } else {
+            let albedo: Arc<dyn Texture> = match shader.target("inputs:diffuseColor.connect") {
+                Some(output) => self.texture(self.connected(output)?)?,
+                None => Arc::new(SolidColor::new(diffuse)),
+            };
+            Material::Lambertian { albedo }
+        })
+    }
+
+    // A `UsdUVTexture` read by the mesh's texture coordinates.
+    fn texture(&self, shader: &Prim) -> Result<Arc<dyn Texture>> {
+        if shader.text("info:id") != Some("UsdUVTexture") {
+            bail!("{} is not a UsdUVTexture", shader.path);
+        }
+        let file = shader
+            .text("inputs:file")
+            .ok_or_else(|| anyhow!("{} has no 'inputs:file'", shader.path))?;
+        Ok(Arc::new(ImageTexture::new((self.images)(file)?)))
+    }
+
+    // The shader whose output `output` names, such as
+    // `/Looks/Red/Surface.outputs:surface`.
+    fn connected(&self, output: &str) -> Result<&'a Prim> {
+        let (path, _) = output
+            .rsplit_once('.')
+            .ok_or_else(|| anyhow!("<{}> is not a shader output", output))?;
+        self.prims
+            .get(path)
+            .copied()
+            .ok_or_else(|| anyhow!("undefined prim <{}>", path))
+    }
+}
+
+// `parent` followed by the transform of the prim's `xformOpOrder`, whose
+// first operation is applied last.
+fn transform(prim: &Prim, parent: Affine3A) -> Result<Affine3A> {
+    let order = match prim.get("xformOpOrder") {
+        Some(Value::List(order)) => order,
+        _ => return Ok(parent),
+    };
+    let mut result = parent;
+    for op in order {
+        let Value::Text(op) = op else {
+            bail!("'xformOpOrder' must list operation names");
+        };
+        if op == "!resetXformStack!" {
+            result = Affine3A::IDENTITY;
+            continue;
+        }
+        let (name, invert) = match op.strip_prefix("!invert!") {
+            Some(name) => (name, true),
+            None => (op.as_str(), false),
+        };
+        let value = prim
+            .get(name)
+            .ok_or_else(|| anyhow!("'xformOpOrder' names missing '{}'", name))?;
+        let matrix = xform_op(name, value).with_context(|| format!("in '{}'", name))?;
+        result *= if invert { matrix.inverse() } else { matrix };
+    }
+    Ok(result)
+}
+
+fn xform_op(name: &str, value: &Value) -> Result<Affine3A> {
+    let kind = name
+        .strip_prefix("xformOp:")
+        .ok_or_else(|| anyhow!("not a transform operation"))?;
+    let kind = kind.split(':').next().unwrap_or_default();
+    let rotation = |axis: char, degrees: f32| match axis {
+        'X' => Affine3A::from_rotation_x(degrees.to_radians()),
+        'Y' => Affine3A::from_rotation_y(degrees.to_radians()),
+        _ => Affine3A::from_rotation_z(degrees.to_radians()),
+    };
+    Ok(match kind {
+        "translate" => Affine3A::from_translation(Vec3::from_array(numbers(value)?)),
+        "scale" => Affine3A::from_scale(Vec3::from_array(numbers(value)?)),
+        "rotateX" | "rotateY" | "rotateZ" => rotation(kind.chars().last().unwrap(), number(value)?),
+        // Rotates about the first axis first.
+        "rotateXYZ" | "rotateXZY" | "rotateYXZ" | "rotateYZX" | "rotateZXY" | "rotateZYX" => {
+            let angles: [f32; 3] = numbers(value)?;
+            kind[6..]
+                .chars()
+                .zip(angles)
+                .fold(Affine3A::IDENTITY, |acc, (axis, angle)| {
+                    rotation(axis, angle) * acc
+                })
+        }
+        "orient" => {
+            let [w, x, y, z] = numbers(value)?;
+            Affine3A::from_quat(Quat::from_xyzw(x, y, z, w).normalize())
+        }
+        // Rows of a matrix for row vectors, the columns of one for columns.
+        "transform" => {
+            let rows = vectors::<4>(value)?;
+            let rows: [[f32; 4]; 4] = rows.try_into().map_err(|_| anyhow!("expected 4 rows"))?;
+            Affine3A::from_mat4(Mat4::from_cols_array_2d(&rows))
+        }
+        _ => bail!("unsupported transform operation"),
+    })
+}
+
+// A primvar's values, and whether they are per face corner rather than per
+// point.
+type Primvar<const N: usize> = (Vec<[f32; N]>, bool);
+
+// The values of the first of `names` the prim has, with indexed primvars
+// expanded.
+fn primvar<const N: usize>(
+    prim: &Prim,
+    names: &[&str],
+    indices: &[u32],
+    points: usize,
+) -> Result<Option<Primvar<N>>> {
+    let Some((name, property)) = names
+        .iter()
+        .find_map(|&name| Some((name, prim.properties.get(name)?)))
+    else {
+        return Ok(None);
+    };
+    let mut values = vectors::<N>(&property.value).with_context(|| format!("in '{}'", name))?;
+    if let Some(value) = prim.get(&format!("{}:indices", name)) {
+        let idx = integers(Some(value), "indices")?;
+        values = idx
+            .iter()
+            .map(|&idx| values.get(idx as usize).copied())
+            .collect::<Option<_>>()
+            .ok_or_else(|| anyhow!("'{}:indices' past its values", name))?;
+    }
+    let corners = match property.metadata.get("interpolation") {
+        Some(Value::Text(interpolation)) => interpolation == "faceVarying",
+        _ => values.len() != points,
+    };
+    let expected = if corners { indices.len() } else { points };
+    if values.len() != expected {
+        bail!(
+            "{} values in '{}' for {} vertices",
+            values.len(),
+            name,
+            expected
+        );
+    }
+    Ok(Some((values, corners)))
+}
+
+fn number(value: &Value) -> Result<f32> {
+    match value {
+        Value::Number(number) => Ok(*number as f32),
+        _ => bail!("expected a number, got {:?}", value),
+    }
+}
+
+fn numbers<const N: usize>(value: &Value) -> Result<[f32; N]> {
+    match value {
+        Value::List(items) if items.len() == N => {
+            let mut result = [0.0; N];
+            for (result, item) in result.iter_mut().zip(items) {
+                *result = number(item)?;
+            }
+            Ok(result)
+        }
+        _ => bail!("expected {} numbers", N),
+    }
+}
+
+fn vectors<const N: usize>(value: &Value) -> Result<Vec<[f32; N]>> {
+    match value {
+        Value::List(items) => items.iter().map(numbers).collect(),
+        _ => bail!("expected a list of vectors"),
+    }
+}
+
+fn integers(value: Option<&Value>, name: &str) -> Result<Vec<u32>> {
+    let Some(Value::List(items)) = value else {
+        bail!("missing '{}'", name);
+    };
+    items
+        .iter()
+        .map(|item| match item {
+            Value::Number(number) if *number >= 0.0 && number.fract() == 0.0 => Ok(*number as u32),
+            _ => bail!("'{}' must hold non-negative integers", name),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::{Hittable, Interval};
+    use crate::point3;
+    use crate::render::Ray;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn no_files(asset: &str) -> Result<PathBuf> {
+        panic!("unexpected file reference '{}'", asset)
+    }
+
+    // Two squares side by side, the second of them a glass subset of the
+    // mesh, and a steel triangle, placed by transforms of every kind, with a
+    // hidden mesh and an abstract one that aren't rendered.
+    const STAGE: &str = r#"#usda 1.0
+(
+    upAxis = "Y"
+    doc = """A "test" stage,
+    over two lines"""
+)
+
+def Xform "World" (
+    kind = "component"
+)
+{
+    double3 xformOp:translate = (0, 0, 5)
+    uniform token[] xformOpOrder = ["xformOp:translate"]
+
+    def Mesh "Squares" (
+        prepend apiSchemas = ["MaterialBindingAPI"]
+    )
+    {
+        int[] faceVertexCounts = [4, 4]
+        int[] faceVertexIndices = [0, 1, 4, 3, 1, 2, 5, 4]
+        point3f[] points = [(0, 0, 0), (1, 0, 0), (2, 0, 0), (0, 1, 0), (1, 1, 0), (2, 1, 0)]
+        texCoord2f[] primvars:st = [(0, 0), (1, 0), (1, 1), (0, 1), (0, 0), (1, 0), (1, 1), (0, 1)] (
+            interpolation = "faceVarying"
+        )
+        rel material:binding = </World/Looks/Red>
+        double3 xformOp:translate = (1, 0, 0)
+        float3 xformOp:scale = (2, 2, 1)
+        uniform token[] xformOpOrder = ["xformOp:translate", "xformOp:scale"]
+
+        def GeomSubset "Glass"
+        {
+            uniform token elementType = "face"
+            uniform token familyName = "materialBind"
+            int[] indices = [1]
+            rel material:binding = </World/Looks/Glass>
+        }
+    }
+
+    def Mesh "Triangle"
+    {
+        int[] faceVertexCounts = [3]
+        int[] faceVertexIndices = [0, 1, 2]
+        point3f[] points = [(0, 0, 0), (1, 0, 0), (0, 1, 0)]
+        uniform token orientation = "leftHanded"
+        rel material:binding = </World/Looks/Steel>
+        matrix4d xformOp:transform = ( (1, 0, 0, 0), (0, 1, 0, 0), (0, 0, 1, 0), (10, 0, 0, 1) )
+        float3 xformOp:rotateXYZ = (0, 0, 90)
+        uniform token[] xformOpOrder = ["xformOp:transform", "xformOp:rotateXYZ"]
+    }
+
+    def Mesh "Hidden"
+    {
+        int[] faceVertexCounts = [3]
+        int[] faceVertexIndices = [0, 1, 2]
+        point3f[] points = [(-9, -9, -1), (9, -9, -1), (0, 9, -1)]
+        token visibility = "invisible"
+    }
+
+    def Scope "Looks"
+    {
+        def Material "Red"
+        {
+            token outputs:surface.connect = </World/Looks/Red/Surface.outputs:surface>
+
+            def Shader "Surface"
+            {
+                uniform token info:id = "UsdPreviewSurface"
+                color3f inputs:diffuseColor = (0.8, 0.1, 0.1)
+                float inputs:roughness = 0.4
+                token outputs:surface
+            }
+        }
+
+        def Material "Glass"
+        {
+            token outputs:surface.connect = </World/Looks/Glass/Surface.outputs:surface>
+
+            def Shader "Surface"
+            {
+                uniform token info:id = "UsdPreviewSurface"
+                float inputs:opacity = 0.1
+                float inputs:ior = 1.45
+            }
+        }
+
+        def Material "Steel"
+        {
+            def Shader "Surface"
+            {
+                uniform token info:id = "UsdPreviewSurface"
+                color3f inputs:diffuseColor = (0.5, 0.5, 0.6)
+                float inputs:metallic = 1
+                float inputs:roughness.timeSamples = {
+                    0: 0.25,
+                    10: 0.75,
+                }
+            }
+        }
+    }
+}
+
+class Mesh "Template"
+{
+    int[] faceVertexCounts = [3]
+    int[] faceVertexIndices = [0, 1, 2]
+    point3f[] points = [(-9, -9, 9), (9, -9, 9), (0, 9, 9)]
+}
+"#;
+
+    fn hit(mesh: &Mesh, x: f32, y: f32) -> Option<crate::hittables::Hit<'_>> {
+        let ray = Ray::new(point3(x, y, -1.0), Vec3::Z);
+        mesh.hit(&ray, Interval::new(0.001, f32::INFINITY))
+    }
+
+    #[test]
+    fn loads_transformed_meshes_and_their_materials() {
+        let gray = Material::new_lambertian(0.5, 0.5, 0.5);
+        let mesh = parse(STAGE.as_bytes(), gray, no_files).unwrap();
+
+        // The squares span x from 1 to 5 and y from 0 to 2, at z = 5.
+        let red = hit(&mesh, 2.0, 1.5).unwrap();
+        assert!((red.t - 6.0).abs() < 1e-5);
+        let Material::Lambertian { albedo } = red.material else {
+            panic!("{}", red.material.type_name());
+        };
+        assert_eq!(albedo.value(red.uv, red.p), Color3::new(0.8, 0.1, 0.1));
+        assert!(red.uv.distance(vec2(0.5, 0.75)) < 1e-5);
+        let glass = hit(&mesh, 4.0, 1.0).unwrap();
+        assert!(
+            matches!(glass.material, Material::Dielectric { refract_idx } if *refract_idx == 1.45)
+        );
+        assert!(hit(&mesh, 0.9, 1.0).is_none());
+        assert!(hit(&mesh, 5.1, 1.0).is_none());
+
+        // The triangle, turned a quarter around z, spans x from 9 to 10.
+        let steel = hit(&mesh, 9.8, 0.5).unwrap();
+        assert!(matches!(steel.material, Material::Metal { fuzz, .. } if *fuzz == 0.25));
+        assert!(hit(&mesh, 10.2, 0.5).is_none());
+        // Left handed, so facing the other way.
+        assert!(!red.front_face);
+        assert!(steel.front_face);
+
+        // Neither the hidden mesh nor the class.
+        assert!(hit(&mesh, 0.0, -5.0).is_none());
+    }
+
+    #[test]
+    fn lights_become_emissive_faces() {
+        let stage = r#"#usda 1.0
+(
+    upAxis = "Z"
+)
+def RectLight "Panel"
+{
+    float inputs:width = 2
+    float inputs:height = 1
+    float inputs:intensity = 5
+    float inputs:exposure = 1
+    color3f inputs:color = (1, 0.5, 0.5)
+    double3 xformOp:translate = (0, 0, 10)
+    uniform token[] xformOpOrder = ["xformOp:translate"]
+}
+def SphereLight "Bulb"
+{
+    float radius = 2
+    double3 xformOp:translate = (20, 0, 0)
+    uniform token[] xformOpOrder = ["xformOp:translate"]
+}
+"#;
+        let gray = Material::new_lambertian(0.5, 0.5, 0.5);
+        let mesh = parse(stage.as_bytes(), gray, no_files).unwrap();
+        assert!(mesh.is_emissive());
+        // With z up turned into y up, the panel is at y = 10 facing down.
+        let ray_t = Interval::new(0.001, f32::INFINITY);
+        let panel = |x, z| mesh.hit(&Ray::new(point3(x, 0.0, z), Vec3::Y), ray_t);
+        let hit = panel(0.9, 0.4).unwrap();
+        assert!((hit.t - 10.0).abs() < 1e-5);
+        assert!(
+            matches!(hit.material, Material::DiffuseLight { emit, .. } if *emit == Vec3::new(10.0, 5.0, 5.0))
+        );
+        assert!(panel(1.1, 0.0).is_none());
+        assert!(panel(0.0, 0.6).is_none());
+        // The bulb is a sphere of radius 2, here from an older light schema.
+        let bulb = mesh
+            .hit(&Ray::new(point3(20.05, 10.0, 0.03), -Vec3::Y), ray_t)
+            .unwrap();
+        assert!((bulb.t - 8.0).abs() < 0.01);
+        assert!(matches!(bulb.material, Material::DiffuseLight { emit, .. } if *emit == Vec3::ONE));
+    }
+
+    // A zip archive of `files`, deflated or stored as they are.
+    fn zip(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let (mut zip, mut directory) = (vec![], vec![]);
+        for &(name, data, deflate) in files {
+            let stored = match deflate {
+                true => {
+                    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+                    encoder.write_all(data).unwrap();
+                    encoder.finish().unwrap()
+                }
+                false => data.to_vec(),
+            };
+            let method: u16 = if deflate { 8 } else { 0 };
+            let offset = zip.len() as u32;
+            // Flags, method, time, date and checksum (left out), sizes and
+            // name and extra field lengths, after the versions.
+            let fields = |out: &mut Vec<u8>| {
+                out.extend([0; 2]);
+                out.extend(method.to_le_bytes());
+                out.extend([0; 8]);
+                out.extend((stored.len() as u32).to_le_bytes());
+                out.extend((data.len() as u32).to_le_bytes());
+                out.extend((name.len() as u16).to_le_bytes());
+                out.extend([0; 2]);
+            };
+            zip.extend(0x04034B50u32.to_le_bytes());
+            zip.extend([0; 2]);
+            fields(&mut zip);
+            zip.extend(name.as_bytes());
+            zip.extend(&stored);
+
+            directory.extend(0x02014B50u32.to_le_bytes());
+            directory.extend([0; 4]);
+            fields(&mut directory);
+            // Comment length, disk, attributes and the local header.
+            directory.extend([0; 10]);
+            directory.extend(offset.to_le_bytes());
+            directory.extend(name.as_bytes());
+        }
+        let start = zip.len() as u32;
+        zip.extend(&directory);
+        zip.extend(0x06054B50u32.to_le_bytes());
+        zip.extend([0; 4]);
+        zip.extend((files.len() as u16).to_le_bytes());
+        zip.extend((files.len() as u16).to_le_bytes());
+        zip.extend((directory.len() as u32).to_le_bytes());
+        zip.extend(start.to_le_bytes());
+        zip.extend([0; 2]);
+        zip
+    }
+
+    #[test]
+    fn reads_packages_with_their_textures() {
+        let path = std::env::temp_dir().join(format!("usdz-{}.png", std::process::id()));
+        let mut canvas = Canvas::new(2, 1);
+        canvas.set(0, 0, Color3::X);
+        canvas.set(1, 0, Color3::Z);
+        canvas.save(&path).unwrap();
+        let png = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let stage = r#"#usda 1.0
+def Mesh "Square"
+{
+    int[] faceVertexCounts = [4]
+    int[] faceVertexIndices = [0, 1, 2, 3]
+    point3f[] points = [(0, 0, 0), (1, 0, 0), (1, 1, 0), (0, 1, 0)]
+    texCoord2f[] primvars:UVMap = [(0, 0), (1, 0), (1, 1), (0, 1)] (
+        interpolation = "vertex"
+    )
+    rel material:binding = </Looks/Painted>
+}
+def Scope "Looks"
+{
+    def Material "Painted"
+    {
+        token outputs:surface.connect = </Looks/Painted/Surface.outputs:surface>
+        def Shader "Surface"
+        {
+            uniform token info:id = "UsdPreviewSurface"
+            color3f inputs:diffuseColor.connect = </Looks/Painted/Image.outputs:rgb>
+        }
+        def Shader "Image"
+        {
+            uniform token info:id = "UsdUVTexture"
+            asset inputs:file = @./textures/paint.png@
+            token inputs:wrapS = "repeat"
+        }
+    }
+}
+"#;
+        let package = zip(&[
+            ("scene.usda", stage.as_bytes(), false),
+            ("textures/paint.png", &png, true),
+        ]);
+        let gray = Material::new_lambertian(0.5, 0.5, 0.5);
+        let mesh = parse(&package, gray, no_files).unwrap();
+        let color = |x| {
+            let hit = hit(&mesh, x, 0.5).unwrap();
+            let Material::Lambertian { albedo } = hit.material else {
+                panic!("{}", hit.material.type_name());
+            };
+            albedo.value(hit.uv, hit.p)
+        };
+        assert_eq!(color(0.25), Color3::X);
+        assert_eq!(color(0.75), Color3::Z);
+    }
+
+    #[test]
+    fn unsupported_stages_say_why() {
+        let error = |bytes: &[u8]| {
+            let gray = Material::new_lambertian(0.5, 0.5, 0.5);
+            format!("{:#}", parse(bytes, gray, no_files).err().unwrap())
+        };
+        assert!(error(b"PXR-USDC\0\0").contains("binary USD"));
+        assert!(error(&zip(&[("a.usdc", b"PXR-USDC", false)])).contains("binary USD"));
+        assert!(error(&zip(&[("a.png", b"", false)])).contains("no USD layer"));
+        let mesh = |indices: &str, binding: &str| {
+            format!(
+                "#usda 1.0\ndef Mesh \"M\" {{\n int[] faceVertexCounts = [3]\n \
+                 int[] faceVertexIndices = {}\n point3f[] points = [(0, 0, 0), (1, 0, 0), (0, 1, 0)]\n \
+                 rel material:binding = <{}>\n}}\ndef Xform \"X\" {{}}\n",
+                indices, binding
+            )
+        };
+        assert!(error(mesh("[0, 1, 3]", "").as_bytes()).contains("vertex 3 past the 3 points"));
+        let not_material = error(mesh("[0, 1, 2]", "/X").as_bytes());
+        assert!(
+            not_material.contains("in material /X: not a material"),
+            "{}",
+            not_material
+        );
+        let unclosed =
+            error(b"#usda 1.0\ndef Xform \"X\" {\n  double3 xformOp:translate = (1, 2\n}");
+        assert!(unclosed.contains("on line 4"), "{}", unclosed);
+        assert!(error(b"def Xform \"X\" { variantSet \"v\" = {} }").contains("variant sets"));
+    }
+}
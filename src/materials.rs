@@ -1,21 +1,68 @@
 use crate::hittables::Hit;
+use crate::json::Json;
+use crate::measured::MeasuredBrdf;
+use crate::onb::Onb;
+use crate::random;
 use crate::render::Ray;
+use crate::textures::{SolidColor, Texture};
 use crate::{color3, Color3};
 use glam::{vec3, Vec3};
-use rand::Rng;
+use std::f32::consts::PI;
+use std::sync::Arc;
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub enum Material {
-    Lambertian { albedo: Color3 },
-    Metal { albedo: Color3, fuzz: f32 },
-    Dielectric { refract_idx: f32 },
-    DiffuseLight { emit: Vec3 },
+    Lambertian {
+        albedo: Arc<dyn Texture>,
+    },
+    Metal {
+        albedo: Color3,
+        fuzz: f32,
+    },
+    Dielectric {
+        refract_idx: f32,
+    },
+    // Lights emit from both sides unless `spread`, the full angle of their
+    // beam in degrees, is under 180: then only the front emits, fading
+    // out towards the edge of the beam like a softbox or recessed fixture.
+    DiffuseLight {
+        emit: Vec3,
+        spread: f32,
+    },
+    // The phase function of participating media, scattering equally in all
+    // directions.
+    Isotropic {
+        albedo: Arc<dyn Texture>,
+    },
+    Measured {
+        brdf: Arc<MeasuredBrdf>,
+    },
+    Custom(Arc<dyn CustomMaterial>),
+    // Any material with a temperature, which only thermal renders see; it
+    // otherwise behaves exactly like `base`.
+    Heated {
+        temperature: f32,
+        base: Box<Material>,
+    },
+}
+
+// Materials defined outside this crate, see `registry::Registry`.
+pub trait CustomMaterial: Send + Sync {
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scattered>;
+
+    fn emitted(&self) -> Color3 {
+        Color3::ZERO
+    }
+
+    fn temperature(&self) -> f32 {
+        0.0
+    }
 }
 
 impl Material {
     pub fn new_lambertian(r: f32, g: f32, b: f32) -> Material {
         Material::Lambertian {
-            albedo: color3(r, g, b),
+            albedo: Arc::new(SolidColor::new(color3(r, g, b))),
         }
     }
 
@@ -33,30 +80,27 @@ impl Material {
     pub fn new_light(r: f32, g: f32, b: f32) -> Material {
         Material::DiffuseLight {
             emit: color3(r, g, b),
+            spread: 180.0,
         }
     }
 
     pub fn scatter(ray: &Ray, hit: &Hit) -> Option<Scattered> {
-        match hit.material {
-            Material::Lambertian { albedo } => {
-                let mut scatter_dir = hit.normal + random_sphere_vec3();
-                if is_near_zero(scatter_dir) {
-                    scatter_dir = hit.normal;
-                }
-
+        let scattered = match *hit.material {
+            Material::Lambertian { ref albedo } => {
+                let scatter_dir = Onb::new(hit.shading_normal).to_world(random::cosine_direction());
                 Some(Scattered {
                     ray: Ray::new(hit.p, scatter_dir),
-                    attenuation: albedo,
+                    attenuation: albedo.shade(hit, ray.dir()) * hit.color,
                 })
             }
             Material::Metal { albedo, fuzz } => {
                 let fuzz = if fuzz < 1.0 { fuzz } else { 1.0 };
-                let reflected = reflect(ray.dir().normalize(), hit.normal);
+                let reflected = reflect(ray.dir().normalize(), hit.shading_normal);
                 let scattered = Ray::new(hit.p, reflected + fuzz * random_sphere_vec3());
                 if scattered.dir().dot(hit.normal) > 0.0 {
                     Some(Scattered {
                         ray: scattered,
-                        attenuation: albedo,
+                        attenuation: albedo * hit.color,
                     })
                 } else {
                     None
@@ -69,7 +113,7 @@ impl Material {
                     refract_idx
                 };
                 let unit_dir = ray.dir().normalize();
-                let cos_theta = (-unit_dir).dot(hit.normal).min(1.0);
+                let cos_theta = (-unit_dir).dot(hit.shading_normal).min(1.0);
                 let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
                 let reflectance = {
@@ -79,10 +123,10 @@ impl Material {
                 };
 
                 let cannot_refract = refract_ratio * sin_theta > 1.0;
-                let dir = if cannot_refract || reflectance > rand::random::<f32>() {
-                    reflect(unit_dir, hit.normal)
+                let dir = if cannot_refract || reflectance > random::f32() {
+                    reflect(unit_dir, hit.shading_normal)
                 } else {
-                    refract(unit_dir, hit.normal, refract_ratio)
+                    refract(unit_dir, hit.shading_normal, refract_ratio)
                 };
 
                 Some(Scattered {
@@ -91,15 +135,208 @@ impl Material {
                 })
             }
             Material::DiffuseLight { .. } => None,
+            Material::Isotropic { ref albedo } => Some(Scattered {
+                ray: Ray::new(hit.p, random_sphere_vec3()),
+                attenuation: albedo.value(hit.uv, hit.p) * hit.color,
+            }),
+            Material::Measured { ref brdf } => brdf.scatter(ray, hit),
+            Material::Custom(ref material) => material.scatter(ray, hit),
+            Material::Heated { ref base, .. } => Material::scatter(
+                ray,
+                &Hit {
+                    material: base,
+                    ..*hit
+                },
+            ),
+        };
+        if let Some(scattered) = &scattered {
+            debug_check_scatter(hit, scattered);
+        }
+        scattered
+    }
+
+    // The reflectance of a surface and the lobe `scatter` picks its
+    // directions from, when the renderer can evaluate the lobe's density and
+    // so also light the hit directly. Such surfaces scatter with the lobe's
+    // density and a constant attenuation, so the lobe's value times the
+    // cosine is the reflectance times the density.
+    pub fn direct_lobe(ray: &Ray, hit: &Hit) -> Option<(Color3, Lobe)> {
+        match *hit.material.base() {
+            Material::Lambertian { ref albedo } => Some((
+                albedo.shade(hit, ray.dir()) * hit.color,
+                Lobe::Diffuse {
+                    normal: hit.shading_normal,
+                },
+            )),
+            // Perfect mirrors only reflect a single direction, which light
+            // samples never pick.
+            Material::Metal { albedo, fuzz } if fuzz > 0.0 => Some((
+                albedo * hit.color,
+                Lobe::Glossy {
+                    reflected: reflect(ray.dir().normalize(), hit.shading_normal),
+                    fuzz: fuzz.min(1.0),
+                },
+            )),
+            _ => None,
         }
     }
 
     pub fn emitted(&self) -> Color3 {
         match self {
-            Material::DiffuseLight { emit } => *emit,
+            Material::DiffuseLight { emit, .. } => *emit,
+            Material::Custom(material) => material.emitted(),
+            Material::Heated { base, .. } => base.emitted(),
             _ => color3(0.0, 0.0, 0.0),
         }
     }
+
+    // The light leaving `hit` along `dir`: `emitted` within the beam of a
+    // light with a spread, falling off with the tangent of the angle from
+    // the normal until it is gone at the beam's edge.
+    pub fn emission(&self, hit: &Hit, dir: Vec3) -> Color3 {
+        let emission = match self {
+            Material::DiffuseLight { emit, spread } if *spread < 180.0 => {
+                if !hit.front_face {
+                    return Color3::ZERO;
+                }
+                let cosine = dir.normalize().dot(hit.normal);
+                if cosine <= 0.0 {
+                    return Color3::ZERO;
+                }
+                let tangent = (1.0 - cosine * cosine).max(0.0).sqrt() / cosine;
+                let falloff = 1.0 - tangent / (spread.to_radians() / 2.0).tan();
+                *emit * falloff.max(0.0)
+            }
+            Material::Heated { base, .. } => base.emission(hit, dir),
+            _ => self.emitted(),
+        };
+        debug_assert!(
+            emission.is_finite() && emission.cmpge(Color3::ZERO).all(),
+            "{} material emits {}",
+            self.type_name(),
+            emission
+        );
+        emission
+    }
+
+    // The material as a scene file definition, if it can be written as one.
+    pub fn to_json(&self) -> Option<Json> {
+        Some(match self {
+            Material::Lambertian { albedo } => {
+                Json::object([("type", "lambertian".into()), ("albedo", albedo.to_json()?)])
+            }
+            Material::Metal { albedo, fuzz } => Json::object([
+                ("type", "metal".into()),
+                ("albedo", (*albedo).into()),
+                ("fuzz", (*fuzz).into()),
+            ]),
+            Material::Dielectric { refract_idx } => Json::object([
+                ("type", "dielectric".into()),
+                ("ior", (*refract_idx).into()),
+            ]),
+            Material::DiffuseLight { emit, spread } => {
+                let def = Json::object([("type", "light".into()), ("emit", (*emit).into())]);
+                if *spread < 180.0 {
+                    def.with("spread", (*spread).into())
+                } else {
+                    def
+                }
+            }
+            Material::Heated { base, temperature } => {
+                base.to_json()?.with("temperature", (*temperature).into())
+            }
+            _ => return None,
+        })
+    }
+
+    // Scalar intensity emitted in thermal renders, in arbitrary units.
+    pub fn temperature(&self) -> f32 {
+        match self {
+            Material::Heated { temperature, .. } => *temperature,
+            Material::Custom(material) => material.temperature(),
+            _ => 0.0,
+        }
+    }
+
+    // The material that actually scatters, looking through `Heated`.
+    pub fn base(&self) -> &Material {
+        match self {
+            Material::Heated { base, .. } => base.base(),
+            _ => self,
+        }
+    }
+
+    // How far from a mirror the material scatters, from 0 for mirrors and
+    // glass to 1 for diffuse surfaces and anything else.
+    pub fn roughness(&self) -> f32 {
+        match self.base() {
+            Material::Metal { fuzz, .. } => fuzz.clamp(0.0, 1.0),
+            Material::Dielectric { .. } => 0.0,
+            _ => 1.0,
+        }
+    }
+
+    // The scene file type of the base material; registered types are all
+    // "custom".
+    pub fn type_name(&self) -> &'static str {
+        match self.base() {
+            Material::Lambertian { .. } => "lambertian",
+            Material::Metal { .. } => "metal",
+            Material::Dielectric { .. } => "dielectric",
+            Material::DiffuseLight { .. } => "light",
+            Material::Isotropic { .. } => "isotropic",
+            Material::Measured { .. } => "measured",
+            Material::Custom(_) => "custom",
+            Material::Heated { .. } => unreachable!(),
+        }
+    }
+}
+
+pub const TYPE_NAMES: [&str; 7] = [
+    "lambertian",
+    "metal",
+    "dielectric",
+    "light",
+    "isotropic",
+    "measured",
+    "custom",
+];
+
+#[derive(Clone, Copy)]
+pub enum Lobe {
+    // Cosine-weighted about the normal.
+    Diffuse { normal: Vec3 },
+    // The mirror direction plus `fuzz` times a random unit vector, as metal
+    // scatters.
+    Glossy { reflected: Vec3, fuzz: f32 },
+}
+
+impl Lobe {
+    // The density of scattering towards `dir`, per unit solid angle.
+    pub fn pdf(&self, dir: Vec3) -> f32 {
+        let dir = dir.normalize();
+        match *self {
+            Lobe::Diffuse { normal } => (dir.dot(normal) / PI).max(0.0),
+            Lobe::Glossy { reflected, fuzz } => {
+                // The offset points lie uniformly on a sphere of radius fuzz
+                // around the mirror direction; the line along `dir` crosses
+                // it at distances b -+ root, where its surface is seen at a
+                // cosine of root / fuzz.
+                let b = dir.dot(reflected);
+                let discriminant = b * b - reflected.length_squared() + fuzz * fuzz;
+                if discriminant <= 0.0 {
+                    return 0.0;
+                }
+                let root = discriminant.sqrt();
+                let t_squared: f32 = [b - root, b + root]
+                    .into_iter()
+                    .filter(|&t| t > 0.0)
+                    .map(|t| t * t)
+                    .sum();
+                t_squared / (4.0 * PI * fuzz * root)
+            }
+        }
+    }
 }
 
 pub struct Scattered {
@@ -107,6 +344,35 @@ pub struct Scattered {
     pub attenuation: Color3,
 }
 
+// Halts debug builds at a scatter that can't be right, rather than leaving
+// odd pixels to trace back. Analytic materials never reflect more light than
+// arrives; measured and custom ones may weight their samples above 1.
+fn debug_check_scatter(hit: &Hit, scattered: &Scattered) {
+    let attenuation = scattered.attenuation;
+    debug_assert!(
+        attenuation.is_finite() && attenuation.cmpge(Color3::ZERO).all(),
+        "{} material scattered with attenuation {}",
+        hit.material.type_name(),
+        attenuation
+    );
+    debug_assert!(
+        matches!(
+            hit.material.base(),
+            Material::Measured { .. } | Material::Custom(_)
+        ) || attenuation.cmple(Color3::ONE).all(),
+        "{} material scattered with attenuation {}",
+        hit.material.type_name(),
+        attenuation
+    );
+    let dir = scattered.ray.dir();
+    debug_assert!(
+        dir.is_finite() && dir != Vec3::ZERO,
+        "{} material scattered towards {}",
+        hit.material.type_name(),
+        dir
+    );
+}
+
 fn reflect(v: Vec3, normal: Vec3) -> Vec3 {
     v - 2.0 * v.dot(normal) * normal
 }
@@ -118,20 +384,187 @@ fn refract(uv: Vec3, normal: Vec3, etai_over_etat: f32) -> Vec3 {
     r_out_perp + r_out_parallel
 }
 
-fn is_near_zero(v: Vec3) -> bool {
-    const EPSILON: f32 = 1e-8;
-    v.x.abs() < EPSILON && v.y.abs() < EPSILON && v.z.abs() < EPSILON
-}
-
 fn random_sphere_vec3() -> Vec3 {
     loop {
         let v = vec3(
-            rand::thread_rng().gen_range(-1.0..1.0),
-            rand::thread_rng().gen_range(-1.0..1.0),
-            rand::thread_rng().gen_range(-1.0..1.0),
+            random::range(-1.0..1.0),
+            random::range(-1.0..1.0),
+            random::range(-1.0..1.0),
         );
         if v.length_squared() < 1.0 {
             return v.normalize();
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::hittables::{Hittable, HittableVec, Interval, Quad, Sphere};
+    use crate::point3;
+    use crate::render::Camera;
+    use glam::DVec3;
+
+    // Renders a unit sphere of `material` filling the whole view inside a
+    // uniform white environment. A convex object never sees itself, so every
+    // path that leaves it carries radiance 1 and the image averages out to
+    // the material's albedo; anything else is the material gaining or losing
+    // energy.
+    pub fn furnace(material: Material) -> Color3 {
+        furnace_with_splits(material, 1)
+    }
+
+    fn furnace_with_splits(material: Material, splits: u32) -> Color3 {
+        const SIZE: u32 = 8;
+        const SAMPLES: u32 = 256;
+        let material_type = material.type_name();
+        let world: HittableVec = vec![Box::new(Sphere::new(point3(0.0, 0.0, 0.0), 1.0, material))];
+        let camera = Camera::builder(SIZE, SIZE)
+            .samples(SAMPLES)
+            .max_depth(50)
+            .background(color3(1.0, 1.0, 1.0))
+            .look_from(point3(0.0, 0.0, -4.0))
+            .vert_fov(20.0)
+            .seed(Some(0))
+            .splits(material_type, splits)
+            .unwrap()
+            .build();
+
+        let mut sum = Color3::ZERO;
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let mut pixel = DVec3::ZERO;
+                camera.add_samples(x, y, &world, 0..SAMPLES, &mut pixel);
+                sum += camera.pixel_color(pixel);
+            }
+        }
+        sum / (SIZE * SIZE) as f32
+    }
+
+    pub fn assert_furnace(material: Material, expected: Color3, tolerance: f32) {
+        let color = furnace(material);
+        assert!(
+            (color - expected).abs().max_element() <= tolerance,
+            "furnace gave {:?}, expected {:?}",
+            color,
+            expected
+        );
+    }
+
+    #[test]
+    fn lambertian_furnace() {
+        assert_furnace(
+            Material::new_lambertian(0.2, 0.5, 0.8),
+            color3(0.2, 0.5, 0.8),
+            1e-4,
+        );
+    }
+
+    #[test]
+    fn metal_furnace() {
+        assert_furnace(
+            Material::new_metal(0.9, 0.6, 0.3, 0.0),
+            color3(0.9, 0.6, 0.3),
+            1e-4,
+        );
+    }
+
+    #[test]
+    fn dielectric_furnace() {
+        assert_furnace(Material::new_dielectric(1.5), color3(1.0, 1.0, 1.0), 1e-4);
+    }
+
+    #[test]
+    fn splits_keep_energy() {
+        for (material, expected) in [
+            (
+                Material::new_lambertian(0.2, 0.5, 0.8),
+                color3(0.2, 0.5, 0.8),
+            ),
+            (Material::new_dielectric(1.5), color3(1.0, 1.0, 1.0)),
+        ] {
+            let color = furnace_with_splits(material, 4);
+            assert!(
+                (color - expected).abs().max_element() <= 1e-4,
+                "{:?}",
+                color
+            );
+        }
+    }
+
+    #[test]
+    fn glossy_pdf_matches_metal_scattering() {
+        const SAMPLES: u32 = 100_000;
+        let reflected = Vec3::Z;
+        for fuzz in [0.2, 0.6, 1.0] {
+            let lobe = Lobe::Glossy { reflected, fuzz };
+            // The density over a cone around the mirror direction, integrated
+            // by the midpoint rule, against the share of scattered
+            // directions that land in it.
+            let cos_max = 0.95_f32;
+            let steps = 100_000;
+            let dtheta = cos_max.acos() / steps as f32;
+            let in_cone: f32 = (0..steps)
+                .map(|i| {
+                    let theta = (i as f32 + 0.5) * dtheta;
+                    let dir = vec3(theta.sin(), 0.0, theta.cos());
+                    lobe.pdf(dir) * 2.0 * PI * theta.sin() * dtheta
+                })
+                .sum();
+            let hits = (0..SAMPLES)
+                .filter(|_| {
+                    let dir = (reflected + fuzz * random_sphere_vec3()).normalize();
+                    dir.z > cos_max
+                })
+                .count();
+            let expected = hits as f32 / SAMPLES as f32;
+            assert!(
+                (in_cone - expected).abs() < 0.01,
+                "fuzz {}: {} instead of {}",
+                fuzz,
+                in_cone,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn spread_narrows_the_beam_of_the_front_face() {
+        let light = Material::DiffuseLight {
+            emit: color3(1.0, 1.0, 1.0),
+            spread: 90.0,
+        };
+        // Facing down, with its front towards -y.
+        let quad = Quad::new(point3(-1.0, 1.0, -1.0), 2.0 * Vec3::X, 2.0 * Vec3::Z, light);
+        let center = point3(0.0, 1.0, 0.0);
+        let emission = |from: Vec3| {
+            let hit = quad
+                .hit(
+                    &Ray::new(from, center - from),
+                    Interval::new(0.0, f32::INFINITY),
+                )
+                .unwrap();
+            hit.material.emission(&hit, from - center).x
+        };
+        // Straight down, halfway to the beam's edge, past it and from behind.
+        let below = |degrees: f32| {
+            let angle = degrees.to_radians();
+            center + 2.0 * vec3(angle.sin(), -angle.cos(), 0.0)
+        };
+        assert!((emission(below(0.0)) - 1.0).abs() < 1e-4);
+        let halfway = 1.0 - 22.5f32.to_radians().tan();
+        assert!((emission(below(22.5)) - halfway).abs() < 1e-4);
+        assert_eq!(emission(below(60.0)), 0.0);
+        assert_eq!(emission(point3(0.0, 3.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "attenuation")]
+    fn debug_builds_halt_on_energy_gain() {
+        let sphere = Sphere::new(Vec3::ZERO, 1.0, Material::new_lambertian(2.0, 1.0, 1.0));
+        let ray = Ray::new(point3(0.0, 0.0, 3.0), -Vec3::Z);
+        let hit = sphere.hit(&ray, Interval::new(0.0, f32::INFINITY)).unwrap();
+        Material::scatter(&ray, &hit);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::hittables::Hit;
+use crate::materials::Scattered;
+use crate::onb::Onb;
+use crate::random;
+use crate::render::Ray;
+use crate::{color3, Color3};
+use anyhow::{bail, Context, Result};
+use glam::{vec3, Vec3};
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::path::Path;
+
+const THETA_H_RES: usize = 90;
+const THETA_D_RES: usize = 90;
+const PHI_D_RES: usize = 180;
+const TABLE_LEN: usize = THETA_H_RES * THETA_D_RES * PHI_D_RES;
+const SCALE: [f64; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+// Fraction of scatter directions drawn from the cosine lobe instead of the
+// table, so directions the table underestimates still get sampled.
+const COSINE_FRACTION: f32 = 0.2;
+
+// Isotropic BRDF tabulated in the Rusinkiewicz half/difference
+// parameterization, as distributed by the MERL database.
+pub struct MeasuredBrdf {
+    data: Vec<Color3>,
+    theta_h_cdf: Vec<f32>,
+}
+
+impl MeasuredBrdf {
+    pub fn load(path: &Path) -> Result<MeasuredBrdf> {
+        let bytes =
+            std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Self::from_merl(&bytes).with_context(|| format!("invalid MERL file {}", path.display()))
+    }
+
+    fn from_merl(bytes: &[u8]) -> Result<MeasuredBrdf> {
+        if bytes.len() < 12 {
+            bail!("truncated header");
+        }
+        let dims: Vec<usize> = bytes[..12]
+            .chunks_exact(4)
+            .map(|c| i32::from_le_bytes(c.try_into().unwrap()) as usize)
+            .collect();
+        if dims != [THETA_H_RES, THETA_D_RES, PHI_D_RES] {
+            bail!("unexpected table dimensions {:?}", dims);
+        }
+        if bytes.len() != 12 + 3 * TABLE_LEN * 8 {
+            bail!(
+                "expected {} bytes, found {}",
+                12 + 3 * TABLE_LEN * 8,
+                bytes.len()
+            );
+        }
+
+        let values: Vec<f64> = bytes[12..]
+            .chunks_exact(8)
+            .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        let channel = |c: usize, idx: usize| (values[c * TABLE_LEN + idx] * SCALE[c]).max(0.0);
+        let data: Vec<Color3> = (0..TABLE_LEN)
+            .map(|idx| {
+                color3(
+                    channel(0, idx) as f32,
+                    channel(1, idx) as f32,
+                    channel(2, idx) as f32,
+                )
+            })
+            .collect();
+
+        let mut theta_h_cdf = Vec::with_capacity(THETA_H_RES);
+        let mut total = 0.0;
+        for bin in 0..THETA_H_RES {
+            let slice = &data[bin * THETA_D_RES * PHI_D_RES..(bin + 1) * THETA_D_RES * PHI_D_RES];
+            let mean = slice.iter().map(|c| luminance(*c)).sum::<f32>() / slice.len() as f32;
+            let (cos_lo, cos_hi) = theta_h_bin_cos(bin);
+            total += mean * (cos_hi - cos_lo);
+            theta_h_cdf.push(total);
+        }
+        if total <= 0.0 {
+            bail!("table contains no reflectance");
+        }
+        theta_h_cdf.iter_mut().for_each(|v| *v /= total);
+
+        Ok(MeasuredBrdf { data, theta_h_cdf })
+    }
+
+    pub fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.data.capacity() * std::mem::size_of::<Color3>()
+            + self.theta_h_cdf.capacity() * std::mem::size_of::<f32>()
+    }
+
+    pub fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scattered> {
+        let onb = Onb::new(hit.shading_normal);
+        let wo = onb.to_local(-ray.dir().normalize());
+
+        let wi = if random::f32() < COSINE_FRACTION {
+            random::cosine_direction()
+        } else {
+            let h = self.sample_half_vector();
+            2.0 * wo.dot(h) * h - wo
+        };
+        if wi.z <= 0.0 || wo.z <= 0.0 {
+            return None;
+        }
+
+        let pdf = COSINE_FRACTION * wi.z / PI + (1.0 - COSINE_FRACTION) * self.wi_pdf(wi, wo);
+        let attenuation = self.eval(wi, wo) * wi.z / pdf;
+        Some(Scattered {
+            ray: Ray::new(hit.p, onb.to_world(wi)),
+            attenuation,
+        })
+    }
+
+    fn eval(&self, wi: Vec3, wo: Vec3) -> Color3 {
+        let (theta_h, theta_d, phi_d) = half_diff_angles(wi, wo);
+        let h_idx = theta_h_index(theta_h);
+        let d_idx = ((theta_d / FRAC_PI_2 * THETA_D_RES as f32) as usize).min(THETA_D_RES - 1);
+        // Reciprocity makes phi_d and phi_d + pi equivalent.
+        let phi_d = if phi_d < 0.0 { phi_d + PI } else { phi_d };
+        let p_idx = ((phi_d / PI * PHI_D_RES as f32) as usize).min(PHI_D_RES - 1);
+        self.data[(h_idx * THETA_D_RES + d_idx) * PHI_D_RES + p_idx]
+    }
+
+    fn sample_half_vector(&self) -> Vec3 {
+        let u = random::f32();
+        let bin = self
+            .theta_h_cdf
+            .partition_point(|&c| c < u)
+            .min(THETA_H_RES - 1);
+        let (cos_lo, cos_hi) = theta_h_bin_cos(bin);
+        let cos_theta = cos_lo + (cos_hi - cos_lo) * random::f32();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * random::f32();
+        vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+    fn wi_pdf(&self, wi: Vec3, wo: Vec3) -> f32 {
+        let h = (wi + wo).normalize();
+        let bin = theta_h_index(h.z.clamp(-1.0, 1.0).acos());
+        let prev = if bin == 0 {
+            0.0
+        } else {
+            self.theta_h_cdf[bin - 1]
+        };
+        let (cos_lo, cos_hi) = theta_h_bin_cos(bin);
+        let solid_angle = 2.0 * PI * (cos_hi - cos_lo);
+        let pdf_h = (self.theta_h_cdf[bin] - prev) / solid_angle;
+        pdf_h / (4.0 * wo.dot(h).abs())
+    }
+}
+
+// theta_h is stored with a square-root mapping to resolve the specular peak.
+fn theta_h_index(theta_h: f32) -> usize {
+    let idx = ((theta_h / FRAC_PI_2).max(0.0).sqrt() * THETA_H_RES as f32) as usize;
+    idx.min(THETA_H_RES - 1)
+}
+
+// Cosines of the bin's lower and upper theta_h bound, smallest first.
+fn theta_h_bin_cos(bin: usize) -> (f32, f32) {
+    let angle = |idx: usize| {
+        let x = idx as f32 / THETA_H_RES as f32;
+        x * x * FRAC_PI_2
+    };
+    (angle(bin + 1).cos(), angle(bin).cos())
+}
+
+fn half_diff_angles(wi: Vec3, wo: Vec3) -> (f32, f32, f32) {
+    let h = (wi + wo).normalize();
+    let theta_h = h.z.clamp(-1.0, 1.0).acos();
+    let phi_h = h.y.atan2(h.x);
+    let diff = rotate(rotate(wi, Vec3::Z, -phi_h), Vec3::Y, -theta_h);
+    let theta_d = diff.z.clamp(-1.0, 1.0).acos();
+    let phi_d = diff.y.atan2(diff.x);
+    (theta_h, theta_d, phi_d)
+}
+
+fn rotate(v: Vec3, axis: Vec3, angle: f32) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    v * cos + axis * axis.dot(v) * (1.0 - cos) + axis.cross(v) * sin
+}
+
+fn luminance(c: Color3) -> f32 {
+    c.dot(vec3(0.2126, 0.7152, 0.0722))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::tests::assert_furnace;
+    use crate::materials::Material;
+    use std::sync::Arc;
+
+    // A MERL table holding a constant (Lambertian) BRDF of the given albedo.
+    fn lambertian_table(albedo: Color3) -> Vec<u8> {
+        let mut bytes = vec![];
+        for dim in [THETA_H_RES, THETA_D_RES, PHI_D_RES] {
+            bytes.extend((dim as i32).to_le_bytes());
+        }
+        for c in 0..3 {
+            let value = albedo[c] as f64 / std::f64::consts::PI / SCALE[c];
+            for _ in 0..TABLE_LEN {
+                bytes.extend(value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn lambertian_table_furnace() {
+        let albedo = color3(0.2, 0.5, 0.8);
+        let brdf = MeasuredBrdf::from_merl(&lambertian_table(albedo)).unwrap();
+        assert_furnace(
+            Material::Measured {
+                brdf: Arc::new(brdf),
+            },
+            albedo,
+            0.02,
+        );
+    }
+}
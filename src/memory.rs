@@ -0,0 +1,149 @@
+use anyhow::{bail, Context, Result};
+use std::fmt;
+
+// Bytes held by each subsystem once the scene is built. The framebuffer is
+// estimated up front so its budget can be checked before it is allocated.
+#[derive(Default, Copy, Clone)]
+pub struct MemoryUsage {
+    pub geometry: usize,
+    pub bvh: usize,
+    pub textures: usize,
+    pub framebuffer: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.geometry + self.bvh + self.textures + self.framebuffer
+    }
+
+    // Budgets are (subsystem, size) pairs as given on the command line, with
+    // sizes like "512M". Fails on the first subsystem over its budget.
+    pub fn check(&self, budgets: &[(String, String)]) -> Result<()> {
+        for (key, value) in budgets {
+            let used = match key.as_str() {
+                "geometry" => self.geometry,
+                "bvh" => self.bvh,
+                "textures" => self.textures,
+                "framebuffer" => self.framebuffer,
+                "total" => self.total(),
+                _ => bail!("unknown memory budget '{}'", key),
+            };
+            let budget = parse_size(value)
+                .with_context(|| format!("invalid size '{}' for {} budget", value, key))?;
+            if used > budget {
+                bail!(
+                    "{} needs {}, over its {} budget",
+                    key,
+                    Bytes(used),
+                    Bytes(budget)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "geometry {}, bvh {}, textures {}, framebuffer {}, total {}",
+            Bytes(self.geometry),
+            Bytes(self.bvh),
+            Bytes(self.textures),
+            Bytes(self.framebuffer),
+            Bytes(self.total())
+        )
+    }
+}
+
+struct Bytes(usize);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} B", self.0)
+        } else {
+            write!(f, "{:.1} {}", value, UNITS[unit])
+        }
+    }
+}
+
+// Plain bytes, or a number with a K, M or G (binary) suffix.
+pub fn parse_size(value: &str) -> Result<usize> {
+    let (digits, shift) = match value.to_ascii_uppercase().chars().last() {
+        Some('K') => (&value[..value.len() - 1], 10),
+        Some('M') => (&value[..value.len() - 1], 20),
+        Some('G') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    let number: f64 = digits.parse()?;
+    if number.is_nan() || number < 0.0 {
+        bail!("size must be non-negative");
+    }
+    Ok((number * (1u64 << shift) as f64) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budgets(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn subsystems_over_their_budgets_are_refused() {
+        let usage = MemoryUsage {
+            geometry: 1000,
+            bvh: 200,
+            textures: 3 << 20,
+            framebuffer: 4096,
+        };
+        let check = |pairs| {
+            usage
+                .check(&budgets(pairs))
+                .map_err(|err| format!("{:#}", err))
+        };
+        assert_eq!(check(&[]), Ok(()));
+        assert_eq!(check(&[("textures", "3m"), ("bvh", "200")]), Ok(()));
+        assert_eq!(
+            check(&[("geometry", "1K"), ("textures", "2.5M")]),
+            Err("textures needs 3.0 MiB, over its 2.5 MiB budget".to_string())
+        );
+        // The total counts every subsystem, and a budget it exactly meets
+        // is enough.
+        assert_eq!(usage.total(), 1200 + (3 << 20) + 4096);
+        assert_eq!(check(&[("total", "3151024")]), Ok(()));
+        assert!(check(&[("total", "3151023")])
+            .unwrap_err()
+            .starts_with("total needs"));
+
+        assert_eq!(
+            check(&[("vram", "1G")]),
+            Err("unknown memory budget 'vram'".to_string())
+        );
+        assert!(check(&[("bvh", "lots")])
+            .unwrap_err()
+            .starts_with("invalid size 'lots' for bvh budget"));
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("1.5k").unwrap(), 1536);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("").is_err());
+    }
+}
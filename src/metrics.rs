@@ -0,0 +1,361 @@
+use crate::canvas::Canvas;
+use crate::color3;
+use anyhow::{bail, Result};
+use glam::{Mat3, Vec3};
+use rayon::prelude::*;
+use std::f32::consts::PI;
+
+pub struct Comparison {
+    pub rmse: f32,
+    pub rel_mse: f32,
+    pub flip: f32,
+}
+
+// Pixels per degree of visual angle for a 0.7 m wide 4K monitor viewed from
+// 0.7 m, the default viewing condition of the FLIP paper.
+const FLIP_PPD: f32 = 67.0;
+
+// With `block` above 1, both images are first averaged down over blocks of
+// that many pixels square. Sampling noise averages out while errors spanning
+// many pixels stay, so a noisy render can still be held to a converged
+// reference.
+pub fn compare(reference: &Canvas, test: &Canvas, block: usize) -> Result<Comparison> {
+    if (reference.width(), reference.height()) != (test.width(), test.height()) {
+        bail!(
+            "image sizes differ: {}x{} vs {}x{}",
+            reference.width(),
+            reference.height(),
+            test.width(),
+            test.height()
+        );
+    }
+    if block == 0 || block > reference.width().min(reference.height()) as usize {
+        bail!("cannot average over blocks of {} pixels", block);
+    }
+    let reference = Image::from_canvas(reference).downsample(block);
+    let test = Image::from_canvas(test).downsample(block);
+
+    let mut squared = 0.0;
+    let mut relative = 0.0;
+    for (r, t) in reference.pixels.iter().zip(&test.pixels) {
+        let diff = (*t - *r) * (*t - *r);
+        squared += diff.dot(Vec3::ONE) as f64;
+        relative += (diff / (*r * *r + Vec3::splat(0.01))).dot(Vec3::ONE) as f64;
+    }
+    let count = reference.pixels.len() as f64 * 3.0;
+
+    Ok(Comparison {
+        rmse: (squared / count).sqrt() as f32,
+        rel_mse: (relative / count) as f32,
+        flip: flip(&reference, &test, FLIP_PPD),
+    })
+}
+
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl Image {
+    fn from_canvas(canvas: &Canvas) -> Self {
+        let (width, height) = (canvas.width(), canvas.height());
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| canvas.pixel(x, y))
+            .collect();
+        Image {
+            width: width as usize,
+            height: height as usize,
+            pixels,
+        }
+    }
+
+    // Partial blocks at the right and bottom edges are dropped.
+    fn downsample(self, block: usize) -> Image {
+        if block == 1 {
+            return self;
+        }
+        let (width, height) = (self.width / block, self.height / block);
+        let pixels = (0..width * height)
+            .map(|idx| {
+                let (x, y) = (idx % width * block, idx / width * block);
+                let sum: Vec3 = (y..y + block)
+                    .flat_map(|y| &self.pixels[y * self.width + x..y * self.width + x + block])
+                    .sum();
+                sum / (block * block) as f32
+            })
+            .collect();
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    fn map(&self, f: impl Fn(Vec3) -> Vec3 + Sync) -> Image {
+        Image {
+            width: self.width,
+            height: self.height,
+            pixels: self.pixels.par_iter().map(|p| f(*p)).collect(),
+        }
+    }
+
+    fn at(&self, x: isize, y: isize) -> Vec3 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    // Convolves every channel with its own kernel; kernels are square with
+    // side 2 * radius + 1, stored row-major as one Vec3 weight per tap.
+    fn convolve(&self, kernel: &[Vec3], radius: isize) -> Image {
+        let side = 2 * radius + 1;
+        let pixels = (0..self.height * self.width)
+            .into_par_iter()
+            .map(|idx| {
+                let (x, y) = ((idx % self.width) as isize, (idx / self.width) as isize);
+                let mut sum = Vec3::ZERO;
+                for ky in -radius..=radius {
+                    for kx in -radius..=radius {
+                        let w = kernel[((ky + radius) * side + kx + radius) as usize];
+                        sum += w * self.at(x + kx, y + ky);
+                    }
+                }
+                sum
+            })
+            .collect();
+        Image {
+            width: self.width,
+            height: self.height,
+            pixels,
+        }
+    }
+}
+
+// LDR-FLIP (Andersson et al. 2020): per-pixel perceptual error in [0, 1],
+// averaged over the image.
+fn flip(reference: &Image, test: &Image, ppd: f32) -> f32 {
+    const QC: f32 = 0.7;
+    const QF: f32 = 0.5;
+    const PC: f32 = 0.4;
+    const PT: f32 = 0.95;
+
+    let ref_ycc = reference.map(linear_rgb_to_ycxcz);
+    let test_ycc = test.map(linear_rgb_to_ycxcz);
+
+    let (csf, csf_radius) = csf_kernel(ppd);
+    let to_hunt_lab = |p: Vec3| hunt(xyz_to_lab(rgb_to_xyz() * ycxcz_to_linear_rgb(p)));
+    let ref_lab = ref_ycc.convolve(&csf, csf_radius).map(to_hunt_lab);
+    let test_lab = test_ycc.convolve(&csf, csf_radius).map(to_hunt_lab);
+
+    let green = hunt(xyz_to_lab(rgb_to_xyz() * color3(0.0, 1.0, 0.0)));
+    let blue = hunt(xyz_to_lab(rgb_to_xyz() * color3(0.0, 0.0, 1.0)));
+    let cmax = hyab(green, blue).powf(QC);
+
+    let (edge, point, feature_radius) = feature_kernels(ppd);
+    let features = |ycc: &Image| {
+        let gray = ycc.map(|p| Vec3::splat((p.x + 16.0) / 116.0));
+        (
+            gray.convolve(&edge, feature_radius),
+            gray.convolve(&point, feature_radius),
+        )
+    };
+    let (ref_edges, ref_points) = features(&ref_ycc);
+    let (test_edges, test_points) = features(&test_ycc);
+
+    let total: f64 = (0..reference.pixels.len())
+        .into_par_iter()
+        .map(|idx| {
+            let dist = hyab(ref_lab.pixels[idx], test_lab.pixels[idx]).powf(QC);
+            let color_err = if dist < PC * cmax {
+                PT / (PC * cmax) * dist
+            } else {
+                PT + (dist - PC * cmax) / (cmax - PC * cmax) * (1.0 - PT)
+            };
+
+            // Edge responses live in x/y, point responses likewise.
+            let magnitude = |v: Vec3| (v.x * v.x + v.y * v.y).sqrt();
+            let edge_diff =
+                (magnitude(ref_edges.pixels[idx]) - magnitude(test_edges.pixels[idx])).abs();
+            let point_diff =
+                (magnitude(ref_points.pixels[idx]) - magnitude(test_points.pixels[idx])).abs();
+            let feature_err = (edge_diff.max(point_diff) / 2f32.sqrt()).powf(QF);
+
+            color_err.powf(1.0 - feature_err) as f64
+        })
+        .sum();
+    (total / reference.pixels.len() as f64) as f32
+}
+
+// Contrast sensitivity filters for the Y, Cx and Cz channels, each a sum of
+// two Gaussians given in the frequency domain by (a1, b1, a2, b2).
+fn csf_kernel(ppd: f32) -> (Vec<Vec3>, isize) {
+    const PARAMS: [[f32; 4]; 3] = [
+        [1.0, 0.0047, 0.0, 1e-5],
+        [1.0, 0.0053, 0.0, 1e-5],
+        [34.1, 0.04, 13.5, 0.025],
+    ];
+    let max_b = 0.04f32;
+    let radius = (3.0 * (max_b / (2.0 * PI * PI)).sqrt() * ppd).ceil() as isize;
+    let gauss = |a: f32, b: f32, r2: f32| a * (PI / b).sqrt() * (-PI * PI * r2 / b).exp();
+
+    let mut kernel = vec![];
+    for y in -radius..=radius {
+        for x in -radius..=radius {
+            let r2 = ((x * x + y * y) as f32) / (ppd * ppd);
+            let w = PARAMS.map(|[a1, b1, a2, b2]| gauss(a1, b1, r2) + gauss(a2, b2, r2));
+            kernel.push(Vec3::from_array(w));
+        }
+    }
+    let sum = kernel.iter().fold(Vec3::ZERO, |acc, w| acc + *w);
+    kernel.iter_mut().for_each(|w| *w /= sum);
+    (kernel, radius)
+}
+
+// First (edge) and second (point) derivative of Gaussian kernels, with the x
+// derivative in the first channel and y in the second. Positive and negative
+// weights are normalized to sum to +1 and -1 respectively.
+fn feature_kernels(ppd: f32) -> (Vec<Vec3>, Vec<Vec3>, isize) {
+    const FEATURE_WIDTH: f32 = 0.082;
+    let sigma = 0.5 * FEATURE_WIDTH * ppd;
+    let radius = (3.0 * sigma).ceil() as isize;
+
+    let mut edge = vec![];
+    let mut point = vec![];
+    for y in -radius..=radius {
+        for x in -radius..=radius {
+            let (xf, yf) = (x as f32, y as f32);
+            let g = (-(xf * xf + yf * yf) / (2.0 * sigma * sigma)).exp();
+            let s2 = sigma * sigma;
+            edge.push(Vec3::new(-xf * g, -yf * g, 0.0));
+            point.push(Vec3::new(
+                (xf * xf / s2 - 1.0) * g,
+                (yf * yf / s2 - 1.0) * g,
+                0.0,
+            ));
+        }
+    }
+    normalize_signed(&mut edge);
+    normalize_signed(&mut point);
+    (edge, point, radius)
+}
+
+fn normalize_signed(kernel: &mut [Vec3]) {
+    let pos = kernel
+        .iter()
+        .fold(Vec3::ZERO, |acc, w| acc + w.max(Vec3::ZERO));
+    let neg = kernel
+        .iter()
+        .fold(Vec3::ZERO, |acc, w| acc - w.min(Vec3::ZERO));
+    for w in kernel.iter_mut() {
+        for c in 0..2 {
+            w[c] /= if w[c] > 0.0 { pos[c] } else { neg[c] };
+        }
+    }
+}
+
+fn rgb_to_xyz() -> Mat3 {
+    Mat3::from_cols_array(&[
+        0.4124564, 0.2126729, 0.0193339, 0.3575761, 0.7151522, 0.119192, 0.1804375, 0.0721750,
+        0.9503041,
+    ])
+}
+
+fn white_xyz() -> Vec3 {
+    rgb_to_xyz() * Vec3::ONE
+}
+
+fn linear_rgb_to_ycxcz(rgb: Vec3) -> Vec3 {
+    let xyz = rgb_to_xyz() * rgb / white_xyz();
+    Vec3::new(
+        116.0 * xyz.y - 16.0,
+        500.0 * (xyz.x - xyz.y),
+        200.0 * (xyz.y - xyz.z),
+    )
+}
+
+fn ycxcz_to_linear_rgb(ycc: Vec3) -> Vec3 {
+    let y = (ycc.x + 16.0) / 116.0;
+    let xyz = Vec3::new(ycc.y / 500.0 + y, y, y - ycc.z / 200.0) * white_xyz();
+    (rgb_to_xyz().inverse() * xyz).clamp(Vec3::ZERO, Vec3::ONE)
+}
+
+fn xyz_to_lab(xyz: Vec3) -> Vec3 {
+    const DELTA: f32 = 6.0 / 29.0;
+    let f = |t: f32| {
+        if t > DELTA * DELTA * DELTA {
+            t.cbrt()
+        } else {
+            t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+        }
+    };
+    let n = xyz / white_xyz();
+    let (fx, fy, fz) = (f(n.x), f(n.y), f(n.z));
+    Vec3::new(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+fn hunt(lab: Vec3) -> Vec3 {
+    Vec3::new(lab.x, 0.01 * lab.x * lab.y, 0.01 * lab.x * lab.z)
+}
+
+fn hyab(a: Vec3, b: Vec3) -> f32 {
+    let d = a - b;
+    d.x.abs() + (d.y * d.y + d.z * d.z).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Color3;
+
+    #[test]
+    fn downsampling_averages_out_noise() {
+        // Pixel-sized black and white checks against flat gray, plus an odd
+        // row and column that don't fill a block.
+        let (mut noisy, mut flat) = (Canvas::new(5, 5), Canvas::new(5, 5));
+        for y in 0..5 {
+            for x in 0..5 {
+                noisy.set(x, y, Color3::splat(((x + y) % 2) as f32));
+                flat.set(x, y, Color3::splat(0.5f32.sqrt()));
+            }
+        }
+        assert!(compare(&flat, &noisy, 1).unwrap().rmse > 0.4);
+        let averaged = compare(&flat, &noisy, 2).unwrap();
+        assert!(averaged.rmse < 1e-2, "{}", averaged.rmse);
+        assert!(compare(&flat, &noisy, 0).is_err());
+        assert!(compare(&flat, &noisy, 6).is_err());
+    }
+
+    #[test]
+    fn identical_images_have_no_error() {
+        let mut image = Canvas::new(9, 7);
+        for y in 0..7 {
+            for x in 0..9 {
+                image.set(x, y, color3(x as f32 / 8.0, y as f32 / 6.0, 0.3));
+            }
+        }
+        let same = compare(&image, &image, 1).unwrap();
+        assert_eq!((same.rmse, same.rel_mse, same.flip), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn errors_match_known_values() {
+        // White in every other column against black: half the pixels are off
+        // by 1 in every channel, relative to the 0.01 floor of relMSE.
+        let (black, mut stripes) = (Canvas::new(8, 4), Canvas::new(8, 4));
+        for y in 0..4 {
+            for x in (0..8).step_by(2) {
+                stripes.set(x, y, Color3::ONE);
+            }
+        }
+        let result = compare(&black, &stripes, 1).unwrap();
+        assert!(
+            (result.rmse - 0.5f32.sqrt()).abs() < 1e-6,
+            "{}",
+            result.rmse
+        );
+        assert!((result.rel_mse - 50.0).abs() < 1e-3, "{}", result.rel_mse);
+        assert!(result.flip > 0.0 && result.flip <= 1.0, "{}", result.flip);
+    }
+}
@@ -0,0 +1,75 @@
+use glam::Vec3;
+
+// An orthonormal basis around a unit normal `w`, with `u` and `v` spanning
+// the tangent plane. Materials sample directions in local coordinates, with
+// the normal along z, and go through this to and from world space so they all
+// agree on the tangent directions.
+#[derive(Copy, Clone, Debug)]
+pub struct Onb {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Onb {
+    pub fn new(normal: Vec3) -> Self {
+        let a = if normal.x.abs() > 0.9 {
+            Vec3::Y
+        } else {
+            Vec3::X
+        };
+        let u = normal.cross(a).normalize();
+        Self {
+            u,
+            v: normal.cross(u),
+            w: normal,
+        }
+    }
+
+    pub fn u(&self) -> Vec3 {
+        self.u
+    }
+
+    pub fn v(&self) -> Vec3 {
+        self.v
+    }
+
+    pub fn w(&self) -> Vec3 {
+        self.w
+    }
+
+    pub fn to_world(&self, local: Vec3) -> Vec3 {
+        local.x * self.u + local.y * self.v + local.z * self.w
+    }
+
+    pub fn to_local(&self, world: Vec3) -> Vec3 {
+        Vec3::new(world.dot(self.u), world.dot(self.v), world.dot(self.w))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_for_any_normal() {
+        for normal in [
+            Vec3::X,
+            -Vec3::X,
+            Vec3::Y,
+            -Vec3::Z,
+            Vec3::new(1.0, 2.0, -3.0).normalize(),
+            Vec3::new(0.95, 0.1, 0.0).normalize(),
+        ] {
+            let onb = Onb::new(normal);
+            let (u, v, w) = (onb.u(), onb.v(), onb.w());
+            for (a, b) in [(u, v), (v, w), (w, u)] {
+                assert!(a.dot(b).abs() < 1e-6);
+            }
+            assert!(u.cross(v).distance(w) < 1e-6, "left-handed for {}", normal);
+            assert_eq!(onb.to_world(Vec3::Z), normal);
+            let p = Vec3::new(0.3, -0.5, 0.8);
+            assert!(onb.to_local(onb.to_world(p)).distance(p) < 1e-6);
+        }
+    }
+}
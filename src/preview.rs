@@ -0,0 +1,148 @@
+use crate::canvas::Canvas;
+use crate::Color3;
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::sync::mpsc::{self, Receiver};
+
+// The image as text `columns` characters wide, for watching a render in the
+// terminal. Each character is an upper half block with the color of the
+// upper of two cells as its foreground and the lower as its background, in
+// 24-bit ANSI color; cells are squares of pixels, averaged.
+pub fn text(canvas: &Canvas, columns: u32) -> String {
+    let (width, height) = (canvas.width(), canvas.height());
+    let columns = columns.clamp(1, width);
+    let cell = width as f32 / columns as f32;
+    let rows = ((height as f32 / cell).round() as u32).max(1);
+
+    // Averaged in linear light, then converted back to display values.
+    let average = |column: u32, row: u32| {
+        let (x0, x1) = span(column, cell, width);
+        let (y0, y1) = span(row, cell, height);
+        let mut sum = Color3::ZERO;
+        for y in y0..y1 {
+            for x in x0..x1 {
+                sum += canvas.pixel(x, y);
+            }
+        }
+        let mean = sum / ((x1 - x0) * (y1 - y0)) as f32;
+        mean.to_array()
+            .map(|c| (c.sqrt().clamp(0.0, 1.0) * 255.0).round() as u8)
+    };
+
+    let mut text = String::new();
+    for row in (0..rows).step_by(2) {
+        for column in 0..columns {
+            let [r, g, b] = average(column, row);
+            write!(text, "\x1b[38;2;{};{};{}m", r, g, b).unwrap();
+            // An odd last row leaves the terminal's own background below it.
+            if row + 1 < rows {
+                let [r, g, b] = average(column, row + 1);
+                write!(text, "\x1b[48;2;{};{};{}m", r, g, b).unwrap();
+            } else {
+                text.push_str("\x1b[49m");
+            }
+            text.push('▀');
+        }
+        text.push_str("\x1b[0m\n");
+    }
+    text
+}
+
+// Settings typed into the terminal while rendering, one KEY=VALUE a line,
+// such as `exposure=1` or `max_depth=4`. Standard input is read on a thread
+// of its own, so rendering never waits for it; the settings typed during a
+// pass are taken once it ends, to apply to the passes after it.
+pub struct Controls {
+    lines: Receiver<String>,
+}
+
+impl Controls {
+    pub fn spawn() -> Controls {
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Controls { lines }
+    }
+
+    // The settings typed since the last call, in order; blank lines are
+    // skipped, and lines without a `=` are errors.
+    pub fn take(&self) -> Vec<Result<(String, String)>> {
+        self.lines
+            .try_iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| setting(&line))
+            .collect()
+    }
+}
+
+fn setting(line: &str) -> Result<(String, String)> {
+    let (key, value) = line
+        .split_once('=')
+        .with_context(|| format!("expected KEY=VALUE, got '{}'", line.trim()))?;
+    Ok((key.trim().to_string(), value.trim().to_string()))
+}
+
+// The pixels a cell covers along an axis, at least one.
+fn span(index: u32, cell: f32, size: u32) -> (u32, u32) {
+    let start = ((index as f32 * cell) as u32).min(size - 1);
+    let end = (((index + 1) as f32 * cell) as u32).clamp(start + 1, size);
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color3;
+
+    #[test]
+    fn cells_average_pixels_into_half_blocks() {
+        // Red over blue over green, in bands two pixels high.
+        let mut canvas = Canvas::new(4, 6);
+        for y in 0..6 {
+            for x in 0..4 {
+                let color = [Color3::X, Color3::Z, Color3::Y][y as usize / 2];
+                canvas.set(x, y, color);
+            }
+        }
+        let red = "\x1b[38;2;255;0;0m";
+        let blue = "\x1b[48;2;0;0;255m";
+        let green = "\x1b[38;2;0;255;0m\x1b[49m";
+        let expected = format!(
+            "{0}▀{0}▀\x1b[0m\n{1}▀{1}▀\x1b[0m\n",
+            format!("{}{}", red, blue),
+            green
+        );
+        assert_eq!(text(&canvas, 2), expected);
+
+        // Halves of white and black average to mid gray in linear light.
+        let mut canvas = Canvas::new(2, 2);
+        canvas.set(0, 0, color3(1.0, 1.0, 1.0));
+        canvas.set(0, 1, color3(1.0, 1.0, 1.0));
+        assert!(text(&canvas, 1).starts_with("\x1b[38;2;180;180;180m"));
+        assert_eq!(text(&canvas, 1).lines().count(), 1);
+    }
+
+    #[test]
+    fn typed_settings_are_split_at_the_equals_sign() {
+        let (sender, lines) = mpsc::channel();
+        let controls = Controls { lines };
+        for line in [" exposure = 1.5", "", "max_depth=4", "clamp"] {
+            sender.send(line.to_string()).unwrap();
+        }
+        let taken = controls.take();
+        assert_eq!(taken.len(), 3);
+        let pair = |key: &str, value: &str| (key.to_string(), value.to_string());
+        assert_eq!(taken[0].as_ref().unwrap(), &pair("exposure", "1.5"));
+        assert_eq!(taken[1].as_ref().unwrap(), &pair("max_depth", "4"));
+        assert!(taken[2].is_err());
+        assert!(controls.take().is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+use glam::{vec2, vec3, Vec2, Vec3};
+use std::cell::Cell;
+use std::f32::consts::PI;
+use std::ops::Range;
+
+// Random numbers are a pure function of (key, dimension): the key identifies
+// a sample stream and the dimension counts the values drawn from it. Keying
+// streams by (seed, pixel, sample) means any subset of samples can be drawn
+// in any order, on any thread or machine, and still produce exactly the
+// values a single full render would have used.
+#[derive(Copy, Clone)]
+struct Stream {
+    key: u64,
+    dimension: u64,
+}
+
+thread_local! {
+    static STREAM: Cell<Stream> = Cell::new(Stream {
+        key: rand::random(),
+        dimension: 0,
+    });
+}
+
+// Starts the stream for one sample of one pixel.
+pub fn seed_sample(seed: u64, x: u32, y: u32, sample: u32) {
+    let mut key = seed;
+    for v in [x, y, sample] {
+        key = mix(key ^ v as u64);
+    }
+    STREAM.with(|stream| stream.set(Stream { key, dimension: 0 }));
+}
+
+pub fn f32() -> f32 {
+    let bits = STREAM.with(|stream| {
+        let Stream { key, dimension } = stream.get();
+        stream.set(Stream {
+            key,
+            dimension: dimension + 1,
+        });
+        pcg(key.wrapping_add(mix(dimension)))
+    });
+    unit(bits)
+}
+
+// Point `index` of `count` points spread evenly over the unit square (a
+// Hammersley set), shifted around the square by an offset that is random
+// per pixel but the same for all its samples. Each point is still uniformly
+// distributed, but a pixel's samples together leave no clumps or holes.
+pub fn stratified(seed: u64, x: u32, y: u32, index: u32, count: u32) -> Vec2 {
+    let key = mix(mix(seed ^ x as u64) ^ y as u64);
+    let offset = vec2(unit(pcg(key)), unit(pcg(!key)));
+    // The base 2 radical inverse, to the 24 bits an f32 holds.
+    let radical_inverse = (index.reverse_bits() >> 8) as f32 / (1u32 << 24) as f32;
+    let point = vec2(index as f32 / count as f32, radical_inverse);
+    (point + offset).fract()
+}
+
+pub fn range(range: Range<f32>) -> f32 {
+    range.start + (range.end - range.start) * f32()
+}
+
+// A direction in the hemisphere around +z, with density proportional to the
+// cosine of its angle to z.
+pub fn cosine_direction() -> Vec3 {
+    let r1 = f32();
+    let r2 = f32();
+    let phi = 2.0 * PI * r1;
+    let r = r2.sqrt();
+    vec3(phi.cos() * r, phi.sin() * r, (1.0 - r2).sqrt())
+}
+
+// A direction uniformly distributed over the cone around +z whose half-angle
+// has cosine 1 - `cap`, the height of the cap it cuts from the unit sphere;
+// 2 covers the whole sphere. Working from the cap keeps the narrow cones of
+// small, distant lights from rounding to a line.
+pub fn cone_direction(cap: f32) -> Vec3 {
+    let r1 = f32();
+    let r2 = f32();
+    let phi = 2.0 * PI * r1;
+    let height = r2 * cap;
+    let r = (height * (2.0 - height)).max(0.0).sqrt();
+    vec3(phi.cos() * r, phi.sin() * r, 1.0 - height)
+}
+
+// The top 24 bits fill an f32 mantissa exactly, keeping the result < 1.
+fn unit(bits: u64) -> f32 {
+    (bits >> 40) as f32 * (1.0 / (1u64 << 24) as f32)
+}
+
+// splitmix64 finalizer.
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// One step of PCG's 64-bit LCG followed by its RXS-M-XS output permutation.
+fn pcg(counter: u64) -> u64 {
+    let state = counter
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    let word = ((state >> ((state >> 59) + 5)) ^ state).wrapping_mul(12605985483714917509);
+    (word >> 43) ^ word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draws(seed: u64, x: u32, y: u32, sample: u32) -> Vec<f32> {
+        seed_sample(seed, x, y, sample);
+        (0..64).map(|_| f32()).collect()
+    }
+
+    #[test]
+    fn reseeding_repeats_the_stream() {
+        let first = draws(7, 3, 4, 5);
+        // Other streams in between don't disturb it.
+        let other = draws(7, 3, 4, 6);
+        assert_eq!(draws(7, 3, 4, 5), first);
+        assert_ne!(other, first);
+        assert_ne!(draws(8, 3, 4, 5), first);
+        assert_ne!(draws(7, 4, 3, 5), first);
+    }
+
+    #[test]
+    fn values_are_uniform_in_the_unit_interval() {
+        let mut sum = 0.0;
+        let mut count = 0;
+        for sample in 0..256 {
+            for value in draws(1, 0, 0, sample) {
+                assert!((0.0..1.0).contains(&value), "{}", value);
+                sum += value as f64;
+                count += 1;
+            }
+        }
+        let mean = sum / count as f64;
+        assert!((mean - 0.5).abs() < 0.01, "{}", mean);
+    }
+
+    #[test]
+    fn stratified_points_fill_distinct_strata() {
+        const COUNT: u32 = 16;
+        for (x, y) in [(0, 0), (5, 9)] {
+            let points: Vec<Vec2> = (0..COUNT)
+                .map(|index| stratified(3, x, y, index, COUNT))
+                .collect();
+            for axis in 0..2 {
+                let mut strata: Vec<u32> = points
+                    .iter()
+                    .map(|p| (p[axis] * COUNT as f32) as u32)
+                    .collect();
+                strata.sort();
+                assert_eq!(strata, (0..COUNT).collect::<Vec<_>>(), "{:?}", points);
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Path length histograms are kept for this many bounces, with this many bins
+// of a given width each; longer paths fall outside the range gate and aren't
+// counted.
+pub const BOUNCES: usize = 4;
+pub const BINS: usize = 32;
+
+// Time-of-flight data for one pixel: the first-hit distance summed over the
+// samples that hit anything, and for each bounce a histogram of the distance
+// travelled along the path from the camera to that bounce's hit.
+#[derive(Clone)]
+pub struct PixelRange {
+    pub distance_sum: f64,
+    pub hits: u32,
+    pub histograms: [[u32; BINS]; BOUNCES],
+}
+
+impl Default for PixelRange {
+    fn default() -> Self {
+        Self {
+            distance_sum: 0.0,
+            hits: 0,
+            histograms: [[0; BINS]; BOUNCES],
+        }
+    }
+}
+
+impl PixelRange {
+    pub fn record(&mut self, bounce: usize, length: f32, bin_width: f32) {
+        if bounce == 0 {
+            self.distance_sum += length as f64;
+            self.hits += 1;
+        }
+        let bin = (length / bin_width) as usize;
+        if bounce < BOUNCES && bin < BINS {
+            self.histograms[bounce][bin] += 1;
+        }
+    }
+
+    // Mean first-hit distance, 0 where nothing was hit (no return).
+    pub fn distance(&self) -> f32 {
+        if self.hits == 0 {
+            0.0
+        } else {
+            (self.distance_sum / self.hits as f64) as f32
+        }
+    }
+}
+
+// Single-channel PFM, which stores rows bottom to top.
+pub fn save_range_image(path: &Path, width: u32, height: u32, pixels: &[PixelRange]) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    // A negative scale marks the data as little-endian.
+    write!(w, "Pf\n{} {}\n-1.0\n", width, height)?;
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let distance = pixels[(y * width + x) as usize].distance();
+            w.write_all(&distance.to_le_bytes())?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+// Little-endian u32 width, height, bounces and bins, the f32 bin width, then
+// the u32 counts for each pixel in row-major order, bounce by bounce.
+pub fn save_histograms(
+    path: &Path,
+    width: u32,
+    height: u32,
+    bin_width: f32,
+    pixels: &[PixelRange],
+) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    for v in [width, height, BOUNCES as u32, BINS as u32] {
+        w.write_all(&v.to_le_bytes())?;
+    }
+    w.write_all(&bin_width.to_le_bytes())?;
+    for pixel in pixels {
+        for count in pixel.histograms.iter().flatten() {
+            w.write_all(&count.to_le_bytes())?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use crate::hittables::Hittable;
+use crate::json::Json;
+use crate::materials::Material;
+use crate::scene::SceneLoader;
+use anyhow::Result;
+use std::collections::HashMap;
+
+type HittableFactory =
+    Box<dyn Fn(&Json, &SceneLoader<'_>) -> Result<Box<dyn Hittable>> + Send + Sync>;
+type MaterialFactory = Box<dyn Fn(&Json, &SceneLoader<'_>) -> Result<Material> + Send + Sync>;
+
+// Object and material types beyond the built-in ones, constructible from
+// scene files by their `type`. A factory gets the node or material definition
+// as parsed JSON, and the loader to resolve its material, child nodes and
+// asset paths the same way built-in types do. Built-in types take precedence.
+#[derive(Default)]
+pub struct Registry {
+    hittables: HashMap<String, HittableFactory>,
+    materials: HashMap<String, MaterialFactory>,
+}
+
+impl Registry {
+    pub fn hittable<F>(mut self, name: &str, factory: F) -> Self
+    where
+        F: Fn(&Json, &SceneLoader<'_>) -> Result<Box<dyn Hittable>> + Send + Sync + 'static,
+    {
+        self.hittables.insert(name.to_string(), Box::new(factory));
+        self
+    }
+
+    pub fn material<F>(mut self, name: &str, factory: F) -> Self
+    where
+        F: Fn(&Json, &SceneLoader<'_>) -> Result<Material> + Send + Sync + 'static,
+    {
+        self.materials.insert(name.to_string(), Box::new(factory));
+        self
+    }
+
+    pub(crate) fn make_hittable(
+        &self,
+        name: &str,
+        node: &Json,
+        loader: &SceneLoader<'_>,
+    ) -> Option<Result<Box<dyn Hittable>>> {
+        self.hittables
+            .get(name)
+            .map(|factory| factory(node, loader))
+    }
+
+    pub(crate) fn make_material(
+        &self,
+        name: &str,
+        def: &Json,
+        loader: &SceneLoader<'_>,
+    ) -> Option<Result<Material>> {
+        self.materials.get(name).map(|factory| factory(def, loader))
+    }
+}
@@ -1,17 +1,84 @@
-use crate::hittables::{Hittable, HittableVec, Interval};
-use crate::materials::Material;
+use crate::caustics::{is_specular, PhotonMap};
+use crate::denoise::Guide;
+use crate::environment::Environment;
+use crate::hittables::{Aabb, Bvh, Hit, Hittable, HittableVec, Interval};
+use crate::json::Json;
+use crate::light_paths::{Component, COMPONENTS};
+use crate::materials::{Lobe, Material, Scattered, TYPE_NAMES};
+use crate::random;
+use crate::range::PixelRange;
 use crate::{color3, point3, Color3, Point3};
-use glam::{vec3, Vec3};
-use rand::Rng;
+use anyhow::{bail, Context, Result};
+use glam::{vec2, vec3, DVec3, Vec2, Vec3};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+use std::ops::Range;
+use std::sync::Arc;
+
+// How much a bounce off a fully rough surface widens a ray's cone, per unit
+// of distance: about the angle the bulk of a diffuse lobe covers.
+const ROUGH_SPREAD: f32 = 0.5;
+
+thread_local! {
+    // Rays traced on this thread so far, for the benchmark.
+    static RAYS: Cell<u64> = const { Cell::new(0) };
+}
+
+pub(crate) fn rays_traced() -> u64 {
+    RAYS.with(Cell::get)
+}
 
 pub struct Ray {
     origin: Point3,
     dir: Vec3,
+    // When the ray was cast, within the camera's shutter interval, for
+    // objects that move.
+    time: f32,
+    // The cone around the ray that a pixel's worth of rays fill: its width
+    // at the origin, and how much that grows per unit of distance. Hits
+    // pass its width on to textures, to filter them over it; rays without
+    // one have both zero.
+    width: f32,
+    spread: f32,
 }
 
 impl Ray {
     pub fn new(origin: Point3, dir: Vec3) -> Self {
-        Self { origin, dir }
+        Self {
+            origin,
+            dir,
+            time: 0.0,
+            width: 0.0,
+            spread: 0.0,
+        }
+    }
+
+    pub fn with_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
+    pub fn with_cone(mut self, width: f32, spread: f32) -> Self {
+        self.width = width;
+        self.spread = spread;
+        self
+    }
+
+    // The same ray from another origin along another direction, as in an
+    // object's own space: its time and cone carry over, with the width
+    // scaled like lengths along the direction.
+    pub fn moved(&self, origin: Point3, dir: Vec3) -> Self {
+        let scale = match self.width {
+            0.0 => 0.0,
+            _ => dir.length() / self.dir.length(),
+        };
+        Self {
+            origin,
+            dir,
+            width: self.width * scale,
+            ..*self
+        }
     }
 
     pub fn origin(&self) -> Point3 {
@@ -22,23 +89,77 @@ impl Ray {
         self.dir
     }
 
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
     pub fn at(&self, t: f32) -> Point3 {
         self.origin + self.dir * t
     }
+
+    pub fn spread(&self) -> f32 {
+        self.spread
+    }
+
+    // The width of the ray's cone at `at(t)`.
+    pub fn footprint(&self, t: f32) -> f32 {
+        self.width + self.spread * t * self.dir.length()
+    }
+}
+
+// How rays leave the camera.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Projection {
+    Perspective,
+    // Omni-directional stereo: an over-under pair of equirectangular images,
+    // left eye on top. Each ray starts half the interpupillary distance `ipd`
+    // to the side of the center, across its own horizontal direction, which
+    // keeps the stereo baseline correct all around. `span` is the horizontal
+    // field of view in degrees, 360 for full panoramas or 180 for VR180.
+    Stereo { ipd: f32, span: f32 },
 }
 
 pub struct Camera {
-    samples_per_pixel: u32,
+    samples_per_pass: u32,
+    passes: u32,
     max_depth: u32,
-    background: Color3,
+    // Bounces of each kind a path may take, by diffuse, glossy and
+    // transmission component, within `max_depth`.
+    max_bounces: [u32; 3],
+    background: Environment,
+    // Emissive objects to sample directly, copies of ones in the world.
+    lights: Arc<HittableVec>,
+    max_radiance: f32,
+    max_distance: f32,
+    seed: Option<u64>,
+    thermal: bool,
+    projection: Projection,
+    image_width: u32,
+    image_height: u32,
 
     center: Point3,
+    // Camera basis: right, up and backwards.
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
     pixel00_loc: Point3,
     pixel_delta_u: Vec3,
     pixel_delta_v: Vec3,
+    // The angle a pixel covers, which primary rays' cones spread by.
+    pixel_spread: f32,
     defocus_angle: f32,
     defocus_disk_u: Vec3,
     defocus_disk_v: Vec3,
+    // Split diopter: pixels on the positive side of the line through the
+    // image center with this normal (in pixels, y down) focus at the focus
+    // distance times the scale.
+    split: Option<(Vec2, f32)>,
+    // Scatter rays traced at the first hit, by material type name; one when
+    // missing.
+    splits: HashMap<&'static str, u32>,
+    // When the shutter opens and closes, as fractions of the frame.
+    shutter: (f32, f32),
+    caustics: Option<PhotonMap>,
 }
 
 impl Camera {
@@ -46,15 +167,27 @@ impl Camera {
         CameraBuilder {
             image_width,
             image_height,
-            samples_per_pixel: 10,
+            samples_per_pass: 10,
+            passes: 1,
             max_depth: 10,
-            background: color3(1.0, 1.0, 1.0),
+            max_bounces: [u32::MAX; 3],
+            background: Environment::Constant(color3(1.0, 1.0, 1.0)),
+            lights: Arc::new(vec![]),
             v_fov: 90.0,
             look_from: point3(0.0, 0.0, -1.0),
             look_at: point3(0.0, 0.0, 0.0),
             vup: vec3(0.0, 1.0, 0.0),
             defocus_angle: 0.0,
             focus_dist: 10.0,
+            max_radiance: f32::INFINITY,
+            max_distance: f32::INFINITY,
+            seed: None,
+            thermal: false,
+            projection: Projection::Perspective,
+            split_focus_dist: None,
+            split_angle: 0.0,
+            splits: HashMap::new(),
+            shutter: (0.0, 0.0),
         }
     }
 
@@ -83,110 +216,681 @@ impl Camera {
             center - (builder.focus_dist * w) - viewport_u / 2.0 - viewport_v / 2.0;
         let pixel00_loc = viewport_upper_left + 0.5 * (pixel_delta_u + pixel_delta_v);
 
+        let pixel_spread = match builder.projection {
+            Projection::Perspective => pixel_delta_u.length() / builder.focus_dist,
+            Projection::Stereo { span, .. } => span.to_radians() / builder.image_width as f32,
+        };
+
         let defocus_radius = builder.focus_dist * (builder.defocus_angle.to_radians() / 2.0).tan();
         let defocus_disk_u = u * defocus_radius;
         let defocus_disk_v = v * defocus_radius;
+        let split = builder.split_focus_dist.map(|dist| {
+            let angle = builder.split_angle.to_radians();
+            (
+                Vec2::new(angle.cos(), angle.sin()),
+                dist / builder.focus_dist,
+            )
+        });
 
         Self {
-            samples_per_pixel: builder.samples_per_pixel,
+            samples_per_pass: builder.samples_per_pass,
+            passes: builder.passes,
             max_depth: builder.max_depth,
+            max_bounces: builder.max_bounces,
             background: builder.background,
+            lights: builder.lights,
+            max_radiance: builder.max_radiance,
+            max_distance: builder.max_distance,
+            seed: builder.seed,
+            thermal: builder.thermal,
+            projection: builder.projection,
+            image_width: builder.image_width,
+            image_height: builder.image_height,
             center,
+            u,
+            v,
+            w,
             pixel00_loc,
             pixel_delta_u,
             pixel_delta_v,
+            pixel_spread,
             defocus_angle: builder.defocus_angle,
             defocus_disk_u,
             defocus_disk_v,
+            split,
+            splits: builder.splits,
+            shutter: builder.shutter,
+            caustics: None,
         }
     }
 
-    pub fn render(&self, x: u32, y: u32, world: &HittableVec) -> Color3 {
-        let mut color = Color3::ZERO;
+    // Previews caustics with a map of `photons` photons traced from the
+    // lights, in place of the paths that would slowly find them.
+    pub fn trace_caustics(&mut self, world: &Bvh, photons: u32) -> usize {
+        let map = PhotonMap::trace(world, &self.lights, photons, self.max_depth, self.seed);
+        let stored = map.len();
+        self.caustics = Some(map);
+        stored
+    }
 
-        for _ in 0..self.samples_per_pixel {
-            let ray = self.get_ray(x, y);
-            color += self.ray_color(&ray, self.max_depth, world)
-        }
-        color /= self.samples_per_pixel as f32;
+    // Width and height of the image in pixels.
+    pub fn size(&self) -> (u32, u32) {
+        (self.image_width, self.image_height)
+    }
 
-        color
+    // Samples per pixel over all passes.
+    pub fn samples(&self) -> u32 {
+        self.samples_per_pass * self.passes
+    }
+
+    pub fn samples_per_pass(&self) -> u32 {
+        self.samples_per_pass
     }
 
-    fn ray_color(&self, ray: &Ray, depth: u32, world: &HittableVec) -> Color3 {
-        const EPSILON: f32 = 0.001;
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
 
-        if depth == 0 {
-            return color3(0.0, 0.0, 0.0);
+    // The samples making up pass `pass`; none past the last pass.
+    pub fn pass_samples(&self, pass: u32) -> Range<u32> {
+        let pass = pass.min(self.passes);
+        pass * self.samples_per_pass..(pass + 1).min(self.passes) * self.samples_per_pass
+    }
+
+    // Adds the pixel's samples in `samples` to `sum`, in sample order. Every
+    // renderer goes through here and passes ranges in ascending order, so the
+    // sum is bit-identical however the samples were split up.
+    pub fn add_samples(
+        &self,
+        x: u32,
+        y: u32,
+        world: &dyn Hittable,
+        samples: Range<u32>,
+        sum: &mut DVec3,
+    ) {
+        for sample in samples {
+            if let Some(seed) = self.seed {
+                random::seed_sample(seed, x, y, sample);
+            }
+            let ray = self.get_ray(x, y, sample);
+            let color = self.ray_color(ray, self.max_depth, world, None, Chain::Other, [0; 3]);
+            // Checked before clamping, which would hide a NaN.
+            debug_assert!(
+                color.is_finite() && color.cmpge(Color3::ZERO).all(),
+                "pixel ({}, {}) sample {} has radiance {}",
+                x,
+                y,
+                sample,
+                color
+            );
+            // Clamping trades a little energy for not letting rare, very bright
+            // paths turn into fireflies.
+            let color = color.min(Vec3::splat(self.max_radiance));
+            *sum += color.as_dvec3();
+        }
+    }
+
+    // Follows the pixel's sample paths as add_samples does, recording the
+    // distance travelled up to each hit instead of the radiance carried back.
+    pub(crate) fn add_ranges(
+        &self,
+        x: u32,
+        y: u32,
+        world: &dyn Hittable,
+        bin_width: f32,
+        range: &mut PixelRange,
+    ) {
+        for sample in 0..self.samples() {
+            if let Some(seed) = self.seed {
+                random::seed_sample(seed, x, y, sample);
+            }
+            let mut ray = self.get_ray(x, y, sample);
+            let mut length = 0.0;
+            for bounce in 0..self.max_depth as usize {
+                let hit = match self.trace(&ray, world) {
+                    Some(hit) => hit,
+                    None => break,
+                };
+                length += hit.t * ray.dir().length();
+                range.record(bounce, length, bin_width);
+                match self.scatter(&ray, &hit, bounce as u32, world) {
+                    Some(scattered) => ray = scattered.ray,
+                    None => break,
+                }
+            }
         }
+    }
 
-        let hit = match world.hit(ray, Interval::new(EPSILON, f32::INFINITY)) {
+    // Follows the pixel's sample paths as add_samples does, adding their
+    // radiance to `sums` split into light path components.
+    pub(crate) fn add_light_paths(
+        &self,
+        x: u32,
+        y: u32,
+        world: &dyn Hittable,
+        sums: &mut [DVec3; COMPONENTS],
+    ) {
+        for sample in 0..self.samples() {
+            if let Some(seed) = self.seed {
+                random::seed_sample(seed, x, y, sample);
+            }
+            let ray = self.get_ray(x, y, sample);
+            let components = self.ray_components(&ray, world);
+            let total: Color3 = components.iter().sum();
+            // The clamp scales all components alike, so they still add up.
+            let clamped = total.min(Vec3::splat(self.max_radiance));
+            let scale = Vec3::select(total.cmpgt(clamped), clamped / total, Vec3::ONE);
+            for (sum, color) in sums.iter_mut().zip(components) {
+                *sum += (color * scale).as_dvec3();
+            }
+        }
+    }
+
+    // ray_color, split by what the path did at its first hit.
+    fn ray_components(&self, ray: &Ray, world: &dyn Hittable) -> [Color3; COMPONENTS] {
+        let mut components = [Color3::ZERO; COMPONENTS];
+        if self.max_depth == 0 {
+            return components;
+        }
+        let emission = &mut components[Component::Emission as usize];
+        let hit = match self.trace(ray, world) {
             Some(hit) => hit,
             None => {
-                return self.background;
+                if !self.thermal {
+                    *emission = self.background.radiance(ray.dir());
+                }
+                return components;
             }
         };
+        *emission = if self.thermal {
+            Vec3::splat(hit.material.temperature())
+        } else {
+            hit.material.emission(&hit, -ray.dir())
+        };
+        let splits = self.first_hit_splits(hit.material);
+        let lit = self.lit_directly(ray, &hit);
+        components[Component::Diffuse as usize] += self.caustic(&hit, lit);
+        let chain = self.next_chain(Chain::Other, &hit, lit);
+        for _ in 0..splits {
+            if let Some((reflectance, lobe)) = lit {
+                components[Component::of_lobe(lobe) as usize] +=
+                    self.direct_light(ray, &hit, reflectance, lobe, world) / splits as f32;
+            }
+            if let Some(scattered) = self.scatter(ray, &hit, 0, world) {
+                let component = Component::of_scatter(&hit, &scattered);
+                let mut bounces = [0; 3];
+                if !self.count_bounce(&mut bounces, &hit, &scattered) {
+                    continue;
+                }
+                let scatter_pdf = lit.map(|(_, lobe)| lobe.pdf(scattered.ray.dir()));
+                components[component as usize] += scattered.attenuation
+                    * self.ray_color(
+                        scattered.ray,
+                        self.max_depth - 1,
+                        world,
+                        scatter_pdf,
+                        chain,
+                        bounces,
+                    )
+                    / splits as f32;
+            }
+        }
+        components
+    }
+
+    // Changes `max_depth` or `max_radiance` of a camera that is rendering,
+    // for the samples it takes from then on.
+    pub fn adjust(&mut self, key: &str, value: &str) -> Result<()> {
+        let invalid = || format!("invalid value '{}' for {}", value, key);
+        match key {
+            "max_depth" => self.max_depth = value.parse().ok().with_context(invalid)?,
+            "max_radiance" => {
+                let max: f32 = value.parse().ok().with_context(invalid)?;
+                if max.is_nan() || max <= 0.0 {
+                    bail!("max_radiance must be positive");
+                }
+                self.max_radiance = max;
+            }
+            _ => bail!("'{}' can't be changed while rendering", key),
+        }
+        Ok(())
+    }
+
+    // A representative ray through the pixel, the same every time.
+    pub(crate) fn primary_ray(&self, x: u32, y: u32) -> Ray {
+        random::seed_sample(self.seed.unwrap_or(0), x, y, 0);
+        self.get_ray(x, y, 0)
+    }
 
-        let emission_color = hit.material.emitted();
-        let scatter_color = match Material::scatter(ray, &hit) {
-            Some(scattered) => {
-                scattered.attenuation * self.ray_color(&scattered.ray, depth - 1, world)
+    // Whether the pixel's representative ray first meets `world` on
+    // `surface`, a copy of one of its objects. Both are searched as when
+    // rendering, and the two hits are one within the distance rays leave
+    // surfaces by.
+    pub(crate) fn sees(
+        &self,
+        x: u32,
+        y: u32,
+        world: &dyn Hittable,
+        surface: &dyn Hittable,
+    ) -> bool {
+        let ray = self.primary_ray(x, y);
+        match (self.trace(&ray, world), self.trace(&ray, surface)) {
+            (Some(hit), Some(on_surface)) => {
+                hit.p.distance(on_surface.p) <= world.bounded_box().ray_offset()
             }
-            None => color3(0.0, 0.0, 0.0),
+            _ => false,
+        }
+    }
+
+    // The surface the pixel's representative ray hits, for the denoiser.
+    pub(crate) fn guide(&self, x: u32, y: u32, world: &dyn Hittable) -> Guide {
+        let ray = self.primary_ray(x, y);
+        match self.trace(&ray, world) {
+            Some(hit) => Guide {
+                normal: hit.shading_normal,
+                depth: hit.t * ray.dir().length(),
+            },
+            None => Guide::BACKGROUND,
+        }
+    }
+
+    pub fn pixel_color(&self, sum: DVec3) -> Color3 {
+        self.partial_color(sum, self.passes)
+    }
+
+    // The pixel's color from the sum of its samples in the first `passes`
+    // passes.
+    pub fn partial_color(&self, sum: DVec3, passes: u32) -> Color3 {
+        let samples = passes.min(self.passes) * self.samples_per_pass;
+        (sum / samples as f64).as_vec3()
+    }
+
+    // Follows a path for up to `depth` more bounces, adding up the light
+    // found along it weighted by the path's throughput so far. `scatter_pdf`
+    // is the density a bounce that also sampled lights directly picked the
+    // ray's direction with, `chain` how the path got to the ray and
+    // `bounces` how many bounces of each kind it took.
+    fn ray_color(
+        &self,
+        mut ray: Ray,
+        depth: u32,
+        world: &dyn Hittable,
+        mut scatter_pdf: Option<f32>,
+        mut chain: Chain,
+        mut bounces: [u32; 3],
+    ) -> Color3 {
+        let mut radiance = Color3::ZERO;
+        let mut throughput = Color3::ONE;
+        for bounce in self.max_depth - depth..self.max_depth {
+            let Some(hit) = self.trace(&ray, world) else {
+                if !self.thermal {
+                    radiance += throughput * self.escaped(&ray, scatter_pdf);
+                }
+                break;
+            };
+            let lit = self.lit_directly(&ray, &hit);
+            radiance += throughput
+                * if self.thermal {
+                    Vec3::splat(hit.material.temperature())
+                } else if chain == Chain::Focused {
+                    Color3::ZERO
+                } else {
+                    self.emitted(&ray, &hit, scatter_pdf)
+                };
+            radiance += throughput * self.caustic(&hit, lit);
+            chain = self.next_chain(chain, &hit, lit);
+
+            // Split paths branch at the first hit and each branch continues
+            // on its own.
+            let splits = if bounce == 0 {
+                self.first_hit_splits(hit.material)
+            } else {
+                1
+            };
+            if splits > 1 {
+                let mut split_color = Color3::ZERO;
+                for _ in 0..splits {
+                    if let Some((reflectance, lobe)) = lit {
+                        split_color += self.direct_light(&ray, &hit, reflectance, lobe, world);
+                    }
+                    if let Some(scattered) = self.scatter(&ray, &hit, bounce, world) {
+                        let mut bounces = bounces;
+                        if !self.count_bounce(&mut bounces, &hit, &scattered) {
+                            continue;
+                        }
+                        let scatter_pdf = lit.map(|(_, lobe)| lobe.pdf(scattered.ray.dir()));
+                        split_color += scattered.attenuation
+                            * self.ray_color(
+                                scattered.ray,
+                                depth - 1,
+                                world,
+                                scatter_pdf,
+                                chain,
+                                bounces,
+                            );
+                    }
+                }
+                return radiance + throughput * split_color / splits as f32;
+            }
+
+            if let Some((reflectance, lobe)) = lit {
+                radiance += throughput * self.direct_light(&ray, &hit, reflectance, lobe, world);
+            }
+            let Some(scattered) = self.scatter(&ray, &hit, bounce, world) else {
+                break;
+            };
+            if !self.count_bounce(&mut bounces, &hit, &scattered) {
+                break;
+            }
+            scatter_pdf = lit.map(|(_, lobe)| lobe.pdf(scattered.ray.dir()));
+            throughput *= scattered.attenuation;
+            // Nothing further along the path can be seen.
+            if throughput == Color3::ZERO {
+                break;
+            }
+            ray = scattered.ray;
+        }
+        radiance
+    }
+
+    // Counts a scatter off `hit` in `bounces`, false if that is one more of
+    // its kind than paths may take.
+    fn count_bounce(&self, bounces: &mut [u32; 3], hit: &Hit, scattered: &Scattered) -> bool {
+        let kind = Component::of_scatter(hit, scattered) as usize;
+        bounces[kind] += 1;
+        bounces[kind] <= self.max_bounces[kind]
+    }
+
+    // The photon map's estimate of the light focused onto a diffuse hit.
+    fn caustic(&self, hit: &Hit, lit: Option<(Color3, Lobe)>) -> Color3 {
+        match (&self.caustics, lit) {
+            (Some(map), Some((reflectance, Lobe::Diffuse { .. }))) => {
+                map.radiance(hit, reflectance)
+            }
+            _ => Color3::ZERO,
+        }
+    }
+
+    // Paths going from a diffuse hit through mirrors or glass to a light
+    // carry what the photon map already added at the diffuse hit.
+    fn next_chain(&self, chain: Chain, hit: &Hit, lit: Option<(Color3, Lobe)>) -> Chain {
+        if self.caustics.is_none() {
+            return Chain::Other;
+        }
+        match lit {
+            Some((_, Lobe::Diffuse { .. })) => Chain::Diffuse,
+            _ if is_specular(hit.material) && chain != Chain::Other => Chain::Focused,
+            _ => Chain::Other,
+        }
+    }
+
+    // Material::scatter, plus the checks and logging of debug builds.
+    #[cfg_attr(not(feature = "debug-render"), allow(unused_variables))]
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit: &Hit,
+        bounce: u32,
+        world: &dyn Hittable,
+    ) -> Option<Scattered> {
+        // Cones keep on from the footprint of the hit, spreading faster off
+        // rough surfaces, whose scattered rays spread over a wider lobe.
+        let spread = ray.spread() + hit.material.roughness() * ROUGH_SPREAD;
+        let scattered = Material::scatter(ray, hit).map(|scattered| {
+            let dir = scattered.ray.dir();
+            Scattered {
+                ray: Ray::new(leave(hit, dir, world), dir)
+                    .with_time(ray.time())
+                    .with_cone(hit.footprint, spread),
+                ..scattered
+            }
+        });
+        #[cfg(feature = "audit")]
+        crate::audit::check(hit, scattered.as_ref());
+        #[cfg(feature = "debug-render")]
+        crate::debug::trace_bounce(bounce, ray, hit, scattered.as_ref());
+        scattered
+    }
+
+    // Small bright sources, like the sun in an environment map or a lamp, are
+    // rarely found by scattered rays alone. Diffuse and glossy hits also
+    // sample them directly, and the estimates are combined by multiple
+    // importance sampling. This is the hit's reflectance and scattering lobe
+    // when that applies.
+    fn lit_directly(&self, ray: &Ray, hit: &Hit) -> Option<(Color3, Lobe)> {
+        let has_lights =
+            matches!(self.background, Environment::Map { .. }) || !self.lights.is_empty();
+        if self.thermal || !has_lights {
+            return None;
+        }
+        Material::direct_lobe(ray, hit)
+    }
+
+    fn direct_light(
+        &self,
+        ray: &Ray,
+        hit: &Hit,
+        reflectance: Color3,
+        lobe: Lobe,
+        world: &dyn Hittable,
+    ) -> Color3 {
+        let mut color = Color3::ZERO;
+        if matches!(self.background, Environment::Map { .. }) {
+            color += self.environment_light(ray, hit, reflectance, lobe, world);
+        }
+        if !self.lights.is_empty() {
+            color += self.emitter_light(ray, hit, reflectance, lobe, world);
+        }
+        color
+    }
+
+    fn environment_light(
+        &self,
+        ray: &Ray,
+        hit: &Hit,
+        reflectance: Color3,
+        lobe: Lobe,
+        world: &dyn Hittable,
+    ) -> Color3 {
+        let Some((dir, pdf)) = self.background.sample() else {
+            return Color3::ZERO;
+        };
+        let shadow_ray = Ray::new(leave(hit, dir, world), dir).with_time(ray.time());
+        if dir.dot(hit.normal) <= 0.0 || self.trace(&shadow_ray, world).is_some() {
+            return Color3::ZERO;
+        }
+        // The lobe's value times the cosine is reflectance times its density.
+        let scatter_pdf = lobe.pdf(dir);
+        reflectance * scatter_pdf * self.background.radiance(dir) / pdf
+            * power_heuristic(pdf, scatter_pdf)
+    }
+
+    // Whatever emits light in the direction of a random point on a light.
+    fn emitter_light(
+        &self,
+        ray: &Ray,
+        hit: &Hit,
+        reflectance: Color3,
+        lobe: Lobe,
+        world: &dyn Hittable,
+    ) -> Color3 {
+        // Only directions above the surface count, so rays start above it.
+        let origin = leave(hit, hit.normal, world);
+        let dir = self.lights.random(origin);
+        let pdf = self.lights.pdf_value(origin, dir);
+        if !(pdf > 0.0 && pdf.is_finite()) || dir.dot(hit.normal) <= 0.0 {
+            return Color3::ZERO;
+        }
+        let shadow_ray = Ray::new(origin, dir).with_time(ray.time());
+        let Some(light_hit) = self.trace(&shadow_ray, world) else {
+            return Color3::ZERO;
         };
+        let scatter_pdf = lobe.pdf(dir);
+        reflectance * scatter_pdf * light_hit.material.emission(&light_hit, -dir) / pdf
+            * power_heuristic(pdf, scatter_pdf)
+    }
+
+    // Light emitted by the surface a ray hit, weighted by its share of the
+    // light sampling estimate if the ray came off a directly lit hit.
+    fn emitted(&self, ray: &Ray, hit: &Hit, scatter_pdf: Option<f32>) -> Color3 {
+        let emitted = hit.material.emission(hit, -ray.dir());
+        match scatter_pdf {
+            Some(pdf) if emitted != Color3::ZERO => {
+                emitted * power_heuristic(pdf, self.lights.pdf_value(ray.origin(), ray.dir()))
+            }
+            _ => emitted,
+        }
+    }
+
+    // The background seen by a ray leaving the scene, weighted by its share
+    // of the environment map's estimate if the ray came off a directly lit
+    // hit.
+    fn escaped(&self, ray: &Ray, scatter_pdf: Option<f32>) -> Color3 {
+        let radiance = self.background.radiance(ray.dir());
+        match (&self.background, scatter_pdf) {
+            (Environment::Map { .. }, Some(pdf)) => {
+                radiance * power_heuristic(pdf, self.background.pdf(ray.dir()))
+            }
+            _ => radiance,
+        }
+    }
+
+    // Searches only as far as the far clip, and no further than where the
+    // ray leaves the scene's bounds, which also keeps intersection tests away
+    // from huge distances. Rays missing the bounds skip the search entirely.
+    fn trace<'w>(&self, ray: &Ray, world: &'w dyn Hittable) -> Option<Hit<'w>> {
+        // Lets surfaces lying on the bounds survive rounding in the exit
+        // distance.
+        const SLACK: f32 = 1.0 + 1e-4;
 
-        emission_color + scatter_color
+        RAYS.with(|rays| rays.set(rays.get() + 1));
+        let far = self.max_distance / ray.dir().length();
+        let inside =
+            world
+                .bounding_box()
+                .clip(ray.origin(), ray.dir().recip(), Interval::new(0.0, far))?;
+        world.hit(ray, Interval::new(0.0, (inside.max * SLACK).min(far)))
     }
 
-    fn get_ray(&self, x: u32, y: u32) -> Ray {
+    fn first_hit_splits(&self, material: &Material) -> u32 {
+        self.splits.get(material.type_name()).copied().unwrap_or(1)
+    }
+
+    fn get_ray(&self, x: u32, y: u32, sample: u32) -> Ray {
+        if let Projection::Stereo { ipd, span } = self.projection {
+            return self
+                .stereo_ray(x, y, ipd, span)
+                .with_time(self.random_time())
+                .with_cone(0.0, self.pixel_spread);
+        }
         let pixel_center =
             self.pixel00_loc + (x as f32 * self.pixel_delta_u) + (y as f32 * self.pixel_delta_v);
-        let pixel_sample = pixel_center + self.random_pixel_sample();
+        let mut pixel_sample = pixel_center + self.random_pixel_sample();
+        if let Some((normal, scale)) = self.split {
+            let from_center = vec2(
+                x as f32 + 0.5 - self.image_width as f32 / 2.0,
+                y as f32 + 0.5 - self.image_height as f32 / 2.0,
+            );
+            // Moving the target along the primary ray moves the plane of
+            // focus without changing the aperture.
+            if from_center.dot(normal) > 0.0 {
+                pixel_sample = self.center + (pixel_sample - self.center) * scale;
+            }
+        }
         let ray_origin = if self.defocus_angle <= 0.0 {
             self.center
         } else {
-            self.defocus_disk_sample()
+            self.defocus_disk_sample(x, y, sample)
         };
         Ray::new(ray_origin, pixel_sample - ray_origin)
+            .with_time(self.random_time())
+            .with_cone(0.0, self.pixel_spread)
+    }
+
+    // A moment while the shutter is open. Cameras with an instant shutter
+    // draw no random number, so their seeded renders don't change.
+    fn random_time(&self) -> f32 {
+        let (open, close) = self.shutter;
+        if close <= open {
+            return open;
+        }
+        open + random::f32() * (close - open)
+    }
+
+    fn stereo_ray(&self, x: u32, y: u32, ipd: f32, span: f32) -> Ray {
+        let top = self.image_height / 2;
+        let (eye, row, rows) = if y < top {
+            (-1.0, y, top)
+        } else {
+            (1.0, y - top, self.image_height - top)
+        };
+        let px = (x as f32 + random::f32()) / self.image_width as f32;
+        let py = (row as f32 + random::f32()) / rows as f32;
+        let longitude = (px - 0.5) * span.to_radians();
+        let latitude = (0.5 - py) * std::f32::consts::PI;
+
+        let forward = -self.w;
+        let horizontal = forward * longitude.cos() + self.u * longitude.sin();
+        let dir = horizontal * latitude.cos() + self.v * latitude.sin();
+        let side = self.u * longitude.cos() - forward * longitude.sin();
+        Ray::new(self.center + side * (eye * ipd / 2.0), dir)
     }
 
     fn random_pixel_sample(&self) -> Vec3 {
-        let px = rand::random::<f32>() - 0.5;
-        let py = rand::random::<f32>() - 0.5;
+        let px = random::f32() - 0.5;
+        let py = random::f32() - 0.5;
         (px * self.pixel_delta_u) + (py * self.pixel_delta_v)
     }
 
-    fn defocus_disk_sample(&self) -> Point3 {
-        let p = Self::random_in_unit_disk();
-        self.center + (p[0] * self.defocus_disk_u) + (p[1] * self.defocus_disk_v)
+    // A pixel's samples are spread evenly over the lens rather than drawn
+    // independently, so strong defocus blur converges with fewer samples.
+    fn defocus_disk_sample(&self, x: u32, y: u32, sample: u32) -> Point3 {
+        let square = random::stratified(self.seed.unwrap_or(0), x, y, sample, self.samples());
+        let p = concentric_disk(square);
+        self.center + (p.x * self.defocus_disk_u) + (p.y * self.defocus_disk_v)
     }
+}
 
-    fn random_in_unit_disk() -> Vec3 {
-        loop {
-            let v = vec3(
-                rand::thread_rng().gen_range(-1.0..1.0),
-                rand::thread_rng().gen_range(-1.0..1.0),
-                0.0,
-            );
-            if v.length_squared() < 1.0 {
-                return v;
-            }
-        }
+// Shirley and Chiu's mapping of the unit square onto the unit disk, which
+// keeps evenly spread points evenly spread.
+fn concentric_disk(p: Vec2) -> Vec2 {
+    let p = 2.0 * p - Vec2::ONE;
+    if p == Vec2::ZERO {
+        return Vec2::ZERO;
     }
+    let (r, theta) = if p.x.abs() > p.y.abs() {
+        (p.x, FRAC_PI_4 * (p.y / p.x))
+    } else {
+        (p.y, FRAC_PI_2 - FRAC_PI_4 * (p.x / p.y))
+    };
+    r * vec2(theta.cos(), theta.sin())
 }
 
+#[derive(Clone)]
 pub struct CameraBuilder {
     image_width: u32,
     image_height: u32,
-    samples_per_pixel: u32,
+    samples_per_pass: u32,
+    passes: u32,
     max_depth: u32,
-    background: Color3,
+    max_bounces: [u32; 3],
+    background: Environment,
+    lights: Arc<HittableVec>,
     v_fov: f32,
     look_from: Point3,
     look_at: Point3,
     vup: Vec3,
     defocus_angle: f32,
     focus_dist: f32,
+    max_radiance: f32,
+    max_distance: f32,
+    seed: Option<u64>,
+    thermal: bool,
+    projection: Projection,
+    split_focus_dist: Option<f32>,
+    split_angle: f32,
+    splits: HashMap<&'static str, u32>,
+    shutter: (f32, f32),
 }
 
 impl CameraBuilder {
@@ -194,8 +898,108 @@ impl CameraBuilder {
         Camera::new(self)
     }
 
-    pub fn samples(mut self, samples_per_pixel: u32) -> Self {
-        self.samples_per_pixel = samples_per_pixel;
+    // The settings as a scene file `camera`, if they can be written as one:
+    // environment maps and stereo projections can't.
+    pub fn to_json(&self) -> Option<Json> {
+        let Environment::Constant(background) = self.background else {
+            return None;
+        };
+        if self.projection != Projection::Perspective {
+            return None;
+        }
+        let mut camera = Json::object([
+            ("width", self.image_width.into()),
+            ("height", self.image_height.into()),
+            ("samples", self.samples_per_pass.into()),
+            ("max_depth", self.max_depth.into()),
+            ("background", background.into()),
+            ("vfov", self.v_fov.into()),
+            ("look_from", self.look_from.into()),
+            ("look_at", self.look_at.into()),
+            ("up", self.vup.into()),
+            ("defocus_angle", self.defocus_angle.into()),
+            ("focus_dist", self.focus_dist.into()),
+        ]);
+        if self.passes > 1 {
+            camera = camera.with("passes", self.passes.into());
+        }
+        for (key, value) in [
+            ("max_radiance", self.max_radiance),
+            ("max_distance", self.max_distance),
+        ] {
+            if value.is_finite() {
+                camera = camera.with(key, value.into());
+            }
+        }
+        let max_bounces: BTreeMap<_, _> = Component::ALL[..3]
+            .iter()
+            .filter(|&&kind| self.max_bounces[kind as usize] != u32::MAX)
+            .map(|&kind| {
+                (
+                    kind.name().to_string(),
+                    self.max_bounces[kind as usize].into(),
+                )
+            })
+            .collect();
+        if !max_bounces.is_empty() {
+            camera = camera.with("max_bounces", Json::Object(max_bounces));
+        }
+        if !self.splits.is_empty() {
+            let splits = self
+                .splits
+                .iter()
+                .map(|(&name, &count)| (name.to_string(), count.into()))
+                .collect();
+            camera = camera.with("splits", Json::Object(splits));
+        }
+        if let Some(dist) = self.split_focus_dist {
+            camera = camera
+                .with("split_focus_dist", dist.into())
+                .with("split_angle", self.split_angle.into());
+        }
+        if self.shutter != (0.0, 0.0) {
+            let (open, close) = self.shutter;
+            camera = camera.with("shutter", Json::Array(vec![open.into(), close.into()]));
+        }
+        Some(camera)
+    }
+
+    pub fn setting(self, key: &str, value: &str) -> Result<Self> {
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+            value
+                .parse()
+                .ok()
+                .with_context(|| format!("invalid value '{}' for {}", value, key))
+        }
+        Ok(match key {
+            "samples" => self.samples(parse(key, value)?),
+            "passes" => self.passes(parse(key, value)?),
+            "max_depth" => self.max_depth(parse(key, value)?),
+            "max_radiance" => self.max_radiance(parse(key, value)?),
+            "max_distance" => self.max_distance(parse(key, value)?),
+            _ => match key.strip_prefix("max_bounces.") {
+                Some(kind) => self.max_bounces(kind, parse(key, value)?)?,
+                None => bail!("unknown camera setting '{}'", key),
+            },
+        })
+    }
+
+    pub fn size(mut self, image_width: u32, image_height: u32) -> Self {
+        self.image_width = image_width;
+        self.image_height = image_height;
+        self
+    }
+
+    // Samples per pixel in each pass.
+    pub fn samples(mut self, samples_per_pass: u32) -> Self {
+        self.samples_per_pass = samples_per_pass;
+        self
+    }
+
+    // Renders take `passes` times the samples, in that many rounds over the
+    // whole image; the image is complete, if noisier, after each of them.
+    pub fn passes(mut self, passes: u32) -> Self {
+        self.passes = passes;
         self
     }
 
@@ -204,8 +1008,30 @@ impl CameraBuilder {
         self
     }
 
+    // Limits the bounces of one kind, "diffuse", "glossy" or "transmission",
+    // that a path may take, as production renderers do: glass may need many
+    // more bounces to come out clear than diffuse light needs to settle.
+    // Paths end at whichever limit they reach first, `max_depth` included.
+    pub fn max_bounces(mut self, kind: &str, max: u32) -> Result<Self> {
+        let Some(component) = Component::ALL[..3].iter().find(|c| c.name() == kind) else {
+            bail!("bounce kinds are \"diffuse\", \"glossy\" and \"transmission\"");
+        };
+        self.max_bounces[*component as usize] = max;
+        Ok(self)
+    }
+
     pub fn background(mut self, background: Color3) -> Self {
-        self.background = background;
+        self.background = Environment::Constant(background);
+        self
+    }
+
+    pub fn environment(mut self, environment: Environment) -> Self {
+        self.background = environment;
+        self
+    }
+
+    pub fn lights(mut self, lights: HittableVec) -> Self {
+        self.lights = Arc::new(lights);
         self
     }
 
@@ -238,4 +1064,809 @@ impl CameraBuilder {
         self.focus_dist = dist;
         self
     }
+
+    // Points the camera at the center of the bounding sphere, keeping the
+    // current viewing direction, and picks the distance and field of view so
+    // the whole sphere fits in the image with a small margin.
+    pub fn frame(mut self, bounds: Aabb) -> Result<Self> {
+        const DISTANCE: f32 = 3.0; // in sphere radii
+        const MARGIN: f32 = 1.05;
+
+        if bounds.is_empty() {
+            bail!("cannot frame an empty scene");
+        }
+        if !bounds.is_finite() {
+            bail!("cannot frame a scene without finite bounds");
+        }
+        let (center, radius) = bounds.bounding_sphere();
+        let radius = radius.max(1e-3) * MARGIN;
+        let dir = (self.look_from - self.look_at).normalize();
+        let dir = if dir.is_finite() { dir } else { -Vec3::Z };
+
+        // The sphere subtends this half-angle seen from DISTANCE radii away;
+        // it has to fit the narrower of the two image dimensions.
+        let half_angle = (1.0 / DISTANCE).asin();
+        let aspect = self.image_width as f32 / self.image_height as f32;
+        let half_height = half_angle.tan() / aspect.min(1.0);
+
+        self.look_at = center;
+        self.look_from = center + dir * DISTANCE * radius;
+        self.focus_dist = DISTANCE * radius;
+        self.v_fov = 2.0 * half_height.atan().to_degrees();
+        Ok(self)
+    }
+
+    pub fn max_radiance(mut self, max_radiance: f32) -> Self {
+        self.max_radiance = max_radiance;
+        self
+    }
+
+    // How far rays travel, in scene units, before they count as escaped.
+    pub fn max_distance(mut self, max_distance: f32) -> Self {
+        self.max_distance = max_distance;
+        self
+    }
+
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // Thermal renders carry material temperatures instead of light: surfaces
+    // emit their temperature, and reflect others' with the same attenuation
+    // as light; rays escaping the scene read zero.
+    pub fn thermal(mut self, thermal: bool) -> Self {
+        self.thermal = thermal;
+        self
+    }
+
+    pub fn projection(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    // Split-diopter look: the part of the image right of a line through its
+    // center, tilted clockwise by `angle` degrees from vertical, focuses at
+    // `dist` instead of the focus distance.
+    pub fn split_focus(mut self, dist: f32, angle: f32) -> Self {
+        self.split_focus_dist = Some(dist);
+        self.split_angle = angle;
+        self
+    }
+
+    // Traces `count` scatter rays instead of one where a path first hits a
+    // material of this type, averaging them. More for noisy materials, such
+    // as rough metals and glass, spends time where it removes most noise.
+    pub fn splits(mut self, material_type: &str, count: u32) -> Result<Self> {
+        let Some(&name) = TYPE_NAMES.iter().find(|&&name| name == material_type) else {
+            bail!("unknown material type '{}'", material_type);
+        };
+        if count == 0 {
+            bail!("the splits for {} must be at least 1", material_type);
+        }
+        self.splits.insert(name, count);
+        Ok(self)
+    }
+
+    // Rays are cast at random times from `open` to `close`, fractions of the
+    // frame from 0 to 1, so objects moving during the frame blur along
+    // their path.
+    pub fn shutter(mut self, open: f32, close: f32) -> Result<Self> {
+        if !(0.0..=1.0).contains(&open) || !(open..=1.0).contains(&close) {
+            bail!("the shutter must open and close between 0 and 1, in order");
+        }
+        self.shutter = (open, close);
+        Ok(self)
+    }
+}
+
+// The cameras of a split-screen render, for side-by-side comparisons: the
+// left half of the image is seen by `left` and the right half by `right`,
+// which has the split's settings on top. Without any, the split is at the
+// right edge and every column is seen by `left`.
+pub struct SplitScreen {
+    pub left: Camera,
+    pub right: Camera,
+    split_x: u32,
+}
+
+impl SplitScreen {
+    pub fn new(builder: CameraBuilder, settings: &[(String, String)]) -> Result<Self> {
+        let split_x = if settings.is_empty() {
+            builder.image_width
+        } else {
+            builder.image_width / 2
+        };
+        let mut right = builder.clone();
+        for (key, value) in settings {
+            right = right.setting(key, value)?;
+        }
+        Ok(Self {
+            left: builder.build(),
+            right: right.build(),
+            split_x,
+        })
+    }
+
+    // The camera seeing column `x`.
+    pub fn at(&self, x: u32) -> &Camera {
+        if x < self.split_x {
+            &self.left
+        } else {
+            &self.right
+        }
+    }
+}
+
+// Where a ray leaving `hit` in direction `dir` starts: just off the
+// surface, on the side the ray heads to, so it can't hit the surface again.
+pub(crate) fn leave(hit: &Hit, dir: Vec3, world: &dyn Hittable) -> Point3 {
+    // Planes and the like would make any world's box infinite.
+    let offset = world.bounded_box().ray_offset();
+    hit.p + hit.normal * offset.copysign(dir.dot(hit.normal))
+}
+
+// How a path reached its current ray, as far as caustics go: straight off a
+// diffuse surface, or from one by way of mirrors and glass only.
+#[derive(Copy, Clone, PartialEq)]
+enum Chain {
+    Other,
+    Diffuse,
+    Focused,
+}
+
+// The weight for one of two sampling strategies given both densities.
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    if pdf <= 0.0 {
+        return 0.0;
+    }
+    let ratio = other_pdf / pdf;
+    1.0 / (1.0 + ratio * ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::{HittableVec, Quad, Sphere};
+    use std::f32::consts::PI;
+    use std::sync::Arc;
+
+    #[test]
+    fn far_clip_lets_rays_escape() {
+        // A black sphere three units away, filling the view.
+        let world: HittableVec = vec![Box::new(Sphere::new(
+            Point3::ZERO,
+            1.0,
+            Material::new_lambertian(0.0, 0.0, 0.0),
+        ))];
+        let color = |max_distance| {
+            let camera = Camera::builder(1, 1)
+                .samples(1)
+                .look_from(point3(0.0, 0.0, -4.0))
+                .vert_fov(10.0)
+                .max_distance(max_distance)
+                .build();
+            let mut sum = DVec3::ZERO;
+            camera.add_samples(0, 0, &world, 0..1, &mut sum);
+            camera.pixel_color(sum)
+        };
+        assert_eq!(color(f32::INFINITY), Color3::ZERO);
+        assert_eq!(color(3.5), Color3::ZERO);
+        assert_eq!(color(2.5), Color3::ONE);
+    }
+
+    #[test]
+    fn rays_leave_surfaces_far_from_the_origin() {
+        // A small ball a thousand kilometers out, along the direction it is
+        // seen from, over an endless floor that makes the world's box
+        // infinite.
+        let center = point3(0.0, 0.0, 1e6);
+        let mat = Material::new_lambertian(0.5, 0.5, 0.5);
+        let world = Bvh::new(vec![
+            Box::new(Sphere::new(center, 1.0, mat.clone())),
+            Box::new(crate::hittables::Plane::new(point3(0.0, -10.0, 0.0), Vec3::Y, mat).unwrap()),
+        ]);
+        for idx in 0..500 {
+            random::seed_sample(0, idx, 0, 0);
+            let aim = center + vec3(random::range(-0.5..0.5), random::range(-0.5..0.5), 0.0);
+            let ray = Ray::new(aim + 5.0 * Vec3::Z, -Vec3::Z);
+            let hit = world.hit(&ray, Interval::new(0.0, f32::INFINITY)).unwrap();
+            let dir = crate::onb::Onb::new(hit.normal).to_world(random::cosine_direction());
+            let next = Ray::new(leave(&hit, dir, &world), dir);
+            // The ball is convex, so only the floor is left to hit.
+            if let Some(again) = world.hit(&next, Interval::new(0.0, f32::INFINITY)) {
+                assert!(
+                    again.t > 1.0,
+                    "ray #{} hit its own surface at {}",
+                    idx,
+                    again.t
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn range_paths_leave_the_surface() {
+        // Inside a closed unit ball, every bounce crosses it to another
+        // point of the wall, never the one it left.
+        let world: HittableVec = vec![Box::new(Sphere::new(
+            Point3::ZERO,
+            1.0,
+            Material::new_lambertian(0.5, 0.5, 0.5),
+        ))];
+        let camera = Camera::builder(1, 1)
+            .samples(64)
+            .max_depth(2)
+            .look_from(Point3::ZERO)
+            .look_at(point3(0.0, 0.0, -1.0))
+            .seed(Some(0))
+            .build();
+        let bin_width = 0.1;
+        let mut range = crate::range::PixelRange::default();
+        camera.add_ranges(0, 0, &world, bin_width, &mut range);
+        assert!(
+            (range.distance() - 1.0).abs() < 1e-4,
+            "{}",
+            range.distance()
+        );
+        // The first hits all land in the bin at distance 1; the second
+        // bounce adds the length of a chord, rarely under a bin's width.
+        let first_bin = (1.0 / bin_width) as usize;
+        let second = range.histograms[1];
+        let total: u32 = second.iter().sum();
+        assert_eq!(total, 64);
+        assert!(
+            second[..=first_bin].iter().sum::<u32>() <= 2,
+            "{:?}",
+            second
+        );
+    }
+
+    #[test]
+    fn bounce_limits_apply_by_kind() {
+        // A glass ball filling the view: light from the white background gets
+        // through it in two transmissions, or glances off it.
+        let world: HittableVec = vec![Box::new(Sphere::new(
+            Point3::ZERO,
+            1.0,
+            Material::new_dielectric(1.5),
+        ))];
+        let color = |kind: &str, max: u32| {
+            let camera = Camera::builder(1, 1)
+                .samples(64)
+                .seed(Some(0))
+                .look_from(point3(0.0, 0.0, -4.0))
+                .vert_fov(5.0)
+                .max_bounces(kind, max)
+                .unwrap()
+                .build();
+            let mut sum = DVec3::ZERO;
+            camera.add_samples(0, 0, &world, 0..64, &mut sum);
+            camera.pixel_color(sum).x
+        };
+        assert!(color("transmission", 2) > 0.9);
+        assert!(color("transmission", 1) < 0.1);
+        assert!(color("diffuse", 0) > 0.9);
+        assert!(Camera::builder(1, 1).max_bounces("specular", 1).is_err());
+    }
+
+    #[test]
+    fn ray_cones_spread_by_a_pixel_and_faster_off_rough_surfaces() {
+        // Pixels 0.2 units across at the focus distance of 10.
+        let camera = Camera::builder(100, 100)
+            .look_from(Point3::ZERO)
+            .look_at(-Vec3::Z)
+            .vert_fov(90.0)
+            .focus_dist(10.0)
+            .build();
+        let ray = camera.get_ray(50, 50, 0);
+        assert!((ray.footprint(50.0 / ray.dir().length()) - 1.0).abs() < 1e-3);
+
+        for (material, spread) in [
+            (Material::new_dielectric(1.5), 0.02),
+            (Material::new_metal(1.0, 1.0, 1.0, 0.0), 0.02),
+            (
+                Material::new_metal(1.0, 1.0, 1.0, 0.5),
+                0.02 + ROUGH_SPREAD / 2.0,
+            ),
+            (Material::new_lambertian(0.5, 0.5, 0.5), 0.02 + ROUGH_SPREAD),
+        ] {
+            let world: HittableVec = vec![Box::new(Quad::new(
+                point3(-10.0, -10.0, -50.0),
+                20.0 * Vec3::X,
+                20.0 * Vec3::Y,
+                material,
+            ))];
+            let hit = world.hit(&ray, Interval::new(0.0, f32::INFINITY)).unwrap();
+            assert!((hit.footprint - 1.0).abs() < 1e-3);
+            let scattered = camera.scatter(&ray, &hit, 0, &world).unwrap();
+            assert!((scattered.ray.spread() - spread).abs() < 1e-5);
+            assert_eq!(scattered.ray.footprint(0.0), hit.footprint);
+        }
+    }
+
+    #[test]
+    fn viewport_spans_field_of_view() {
+        // A 90 degree field of view makes the viewport one focus distance
+        // tall either side of center, and the aspect stretches it sideways.
+        let camera = Camera::builder(4, 2)
+            .look_from(Point3::ZERO)
+            .look_at(-Vec3::Z)
+            .focus_dist(1.0)
+            .build();
+        assert!(camera.pixel_delta_u.distance(Vec3::X) < 1e-6);
+        assert!(camera.pixel_delta_v.distance(-Vec3::Y) < 1e-6);
+        assert!(camera.pixel00_loc.distance(point3(-1.5, 0.5, -1.0)) < 1e-6);
+
+        // Scaling the focus distance scales the viewport with it, leaving the
+        // directions through each pixel alone.
+        let far = Camera::builder(4, 2)
+            .look_from(Point3::ZERO)
+            .look_at(-Vec3::Z)
+            .focus_dist(10.0)
+            .build();
+        assert!(far.pixel00_loc.distance(10.0 * camera.pixel00_loc) < 1e-5);
+        assert!(far.pixel_delta_u.distance(10.0 * camera.pixel_delta_u) < 1e-5);
+    }
+
+    #[test]
+    fn corner_rays_hit_corner_pixels() {
+        // Looking down -x from three units away, so the image's right is -z
+        // and the focus plane is x = 0. A 60 degree field of view over two
+        // rows makes each pixel tan(30) * 3 units across.
+        let camera = Camera::builder(3, 2)
+            .look_from(point3(3.0, 0.0, 0.0))
+            .look_at(Point3::ZERO)
+            .vert_fov(60.0)
+            .focus_dist(3.0)
+            .build();
+        let size = 30f32.to_radians().tan() * 3.0;
+        for (x, y, center) in [
+            (0, 0, point3(0.0, 0.5, 1.0)),
+            (2, 0, point3(0.0, 0.5, -1.0)),
+            (0, 1, point3(0.0, -0.5, 1.0)),
+            (2, 1, point3(0.0, -0.5, -1.0)),
+        ] {
+            let center = center * size;
+            let ray = camera.primary_ray(x, y);
+            assert_eq!(ray.origin(), point3(3.0, 0.0, 0.0));
+            let p = ray.at(-ray.origin().x / ray.dir().x);
+            let offset = (p - center).abs();
+            assert!(
+                offset.x < 1e-5 && offset.y <= size / 2.0 && offset.z <= size / 2.0,
+                "pixel ({}, {}) landed at {}, expected near {}",
+                x,
+                y,
+                p,
+                center
+            );
+        }
+    }
+
+    #[test]
+    fn environment_sampling_finds_the_sun() {
+        // A dim sky with a small, very bright sun high up, over a gray floor.
+        let (width, height) = (64, 32);
+        let mut pixels = vec![Color3::splat(0.1); width * height];
+        for y in 8..12 {
+            pixels[y * width + 40..y * width + 44].fill(Color3::splat(2000.0));
+        }
+        let map = crate::environment::EnvironmentMap::new(width, height, pixels);
+        let sky = Environment::map(Arc::new(map), 0.0, 0.0);
+        let world: HittableVec = vec![Box::new(Quad::new(
+            point3(-10.0, 0.0, -10.0),
+            20.0 * Vec3::X,
+            20.0 * Vec3::Z,
+            Material::new_lambertian(0.5, 0.5, 0.5),
+        ))];
+
+        // The floor reflects albedo / PI of the irradiance from the sky.
+        let (n_theta, n_phi) = (512, 2048);
+        let mut irradiance = 0.0;
+        for i in 0..n_theta {
+            let theta = (i as f32 + 0.5) / n_theta as f32 * PI / 2.0;
+            for j in 0..n_phi {
+                let phi = (j as f32 + 0.5) / n_phi as f32 * 2.0 * PI;
+                let dir = vec3(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                irradiance += sky.radiance(dir).x * theta.cos() * theta.sin();
+            }
+        }
+        irradiance *= (PI / 2.0 / n_theta as f32) * (2.0 * PI / n_phi as f32);
+        let expected = 0.5 / PI * irradiance;
+
+        let camera = Camera::builder(1, 1)
+            .samples(256)
+            .seed(Some(1))
+            .environment(sky)
+            .look_from(point3(0.0, 1.0, 0.0))
+            .look_at(Point3::ZERO)
+            .look_up(Vec3::Z)
+            .vert_fov(1.0)
+            .build();
+        // Scattered rays alone would find the sun only a handful of times.
+        let mut sum = DVec3::ZERO;
+        camera.add_samples(0, 0, &world, 0..camera.samples(), &mut sum);
+        let color = camera.pixel_color(sum);
+        assert!(
+            (color.x - expected).abs() < 0.05 * expected,
+            "{} instead of {}",
+            color.x,
+            expected
+        );
+    }
+
+    #[test]
+    fn lights_are_sampled_directly() {
+        // A gray floor lit only by a small spherical lamp straight above,
+        // which scattered rays alone would hit a few percent of the time.
+        let (radius, height, radiance) = (0.5, 3.0, 10.0);
+        let lamp = || {
+            Box::new(Sphere::new(
+                point3(0.0, height, 0.0),
+                radius,
+                Material::new_light(radiance, radiance, radiance),
+            ))
+        };
+        let world: HittableVec = vec![
+            Box::new(Quad::new(
+                point3(-10.0, 0.0, -10.0),
+                20.0 * Vec3::X,
+                20.0 * Vec3::Z,
+                Material::new_lambertian(0.5, 0.5, 0.5),
+            )),
+            lamp(),
+        ];
+
+        // The sphere's irradiance is PI times its radiance times the square
+        // of the sine of its angular radius, of which the floor reflects
+        // albedo / PI.
+        let expected = 0.5 * radiance * (radius / height).powi(2);
+        let camera = Camera::builder(1, 1)
+            .samples(256)
+            .seed(Some(1))
+            .background(Color3::ZERO)
+            .lights(vec![lamp()])
+            .look_from(point3(0.0, 1.0, 0.0))
+            .look_at(Point3::ZERO)
+            .look_up(Vec3::Z)
+            .vert_fov(1.0)
+            .build();
+        let mut sum = DVec3::ZERO;
+        camera.add_samples(0, 0, &world, 0..camera.samples(), &mut sum);
+        let color = camera.pixel_color(sum);
+        assert!(
+            (color.x - expected).abs() < 0.02 * expected,
+            "{} instead of {}",
+            color.x,
+            expected
+        );
+    }
+
+    #[test]
+    fn glossy_hits_sample_lights_too() {
+        // A rough metal floor under a small lamp, seen straight down. With
+        // fuzz 1 the lobe is cosine-weighted about the mirror direction, so
+        // the floor reflects exactly like a diffuse one would.
+        let (radius, height, radiance) = (0.5, 3.0, 10.0);
+        let lamp = || {
+            Box::new(Sphere::new(
+                point3(0.0, height, 0.0),
+                radius,
+                Material::new_light(radiance, radiance, radiance),
+            ))
+        };
+        let world: HittableVec = vec![
+            Box::new(Quad::new(
+                point3(-10.0, 0.0, -10.0),
+                20.0 * Vec3::X,
+                20.0 * Vec3::Z,
+                Material::new_metal(0.5, 0.5, 0.5, 1.0),
+            )),
+            lamp(),
+        ];
+
+        let expected = 0.5 * radiance * (radius / height).powi(2);
+        let camera = Camera::builder(1, 1)
+            .samples(256)
+            .seed(Some(1))
+            .background(Color3::ZERO)
+            .lights(vec![lamp()])
+            .look_from(point3(0.0, 1.0, 0.0))
+            .look_at(Point3::ZERO)
+            .look_up(Vec3::Z)
+            .vert_fov(1.0)
+            .build();
+        let mut sum = DVec3::ZERO;
+        camera.add_samples(0, 0, &world, 0..camera.samples(), &mut sum);
+        let color = camera.pixel_color(sum);
+        assert!(
+            (color.x - expected).abs() < 0.02 * expected,
+            "{} instead of {}",
+            color.x,
+            expected
+        );
+    }
+
+    #[test]
+    fn scene_scale_changes_nothing() {
+        // A ball resting on a floor under a lamp, seen where the floor meets
+        // the ball, at very different scales. Paths use the same random
+        // numbers at every scale, so the colors only differ by rounding.
+        let color = |scale: f32| {
+            let lamp = || {
+                Box::new(Sphere::new(
+                    point3(1.0, 4.0, 1.0) * scale,
+                    0.5 * scale,
+                    Material::new_light(20.0, 20.0, 20.0),
+                ))
+            };
+            let world: HittableVec = vec![
+                Box::new(Quad::new(
+                    point3(-4.0, 0.0, -4.0) * scale,
+                    8.0 * scale * Vec3::X,
+                    8.0 * scale * Vec3::Z,
+                    Material::new_lambertian(0.5, 0.5, 0.5),
+                )),
+                Box::new(Sphere::new(
+                    point3(0.0, 1.0, 0.0) * scale,
+                    scale,
+                    Material::new_lambertian(0.5, 0.5, 0.5),
+                )),
+                lamp(),
+            ];
+            let camera = Camera::builder(1, 1)
+                .samples(64)
+                .seed(Some(1))
+                .background(Color3::ZERO)
+                .lights(vec![lamp()])
+                .look_from(point3(3.0, 1.0, 3.0) * scale)
+                .look_at(point3(0.2, 0.0, 0.2) * scale)
+                .vert_fov(2.0)
+                .build();
+            let mut sum = DVec3::ZERO;
+            camera.add_samples(0, 0, &world, 0..camera.samples(), &mut sum);
+            camera.pixel_color(sum).x
+        };
+        let expected = color(1.0);
+        for scale in [1e-4, 1e4] {
+            let actual = color(scale);
+            assert!(
+                (actual - expected).abs() < 0.01 * expected,
+                "{} at scale {} instead of {}",
+                actual,
+                scale,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn lens_samples_spread_over_the_aperture() {
+        // Independent points on the unit disk would often land almost on top
+        // of each other; a pixel's samples keep their distance.
+        const SAMPLES: u32 = 16;
+        for (x, y) in [(0, 0), (7, 3), (100, 42)] {
+            let points: Vec<Vec2> = (0..SAMPLES)
+                .map(|i| concentric_disk(random::stratified(0, x, y, i, SAMPLES)))
+                .collect();
+            assert!(points.iter().all(|p| p.length() <= 1.0));
+            let closest = points
+                .iter()
+                .enumerate()
+                .flat_map(|(i, a)| points[i + 1..].iter().map(move |b| a.distance(*b)))
+                .fold(f32::INFINITY, f32::min);
+            assert!(closest > 0.1, "{}", closest);
+        }
+    }
+
+    #[test]
+    fn stereo_eyes_are_an_ipd_apart() {
+        const IPD: f32 = 0.064;
+        let center = point3(1.0, 2.0, 3.0);
+        for span in [360.0, 180.0] {
+            let camera = Camera::builder(16, 8)
+                .look_from(center)
+                .look_at(point3(4.0, 2.0, -1.0))
+                .projection(Projection::Stereo { ipd: IPD, span })
+                .build();
+            for x in [0, 5, 15] {
+                // The same column and row in the left (top) and right images,
+                // with the same jitter.
+                random::seed_sample(0, x, 0, 0);
+                let left = camera.get_ray(x, 1, 0);
+                random::seed_sample(0, x, 0, 0);
+                let right = camera.get_ray(x, 5, 0);
+                assert_eq!(left.dir(), right.dir());
+                let baseline = right.origin() - left.origin();
+                assert!((baseline.length() - IPD).abs() < 1e-5, "{}", baseline);
+                assert!((left.origin() + right.origin()) / 2.0 == center);
+                // Level, and across the horizontal direction of the ray.
+                assert!(baseline.y.abs() < 1e-6 && baseline.dot(right.dir()).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn split_diopter_focuses_each_side_at_its_distance() {
+        const FOCUS: f32 = 10.0;
+        const SPLIT: f32 = 2.0;
+        let camera = Camera::builder(8, 8)
+            .samples(16)
+            .look_from(Point3::ZERO)
+            .look_at(-Vec3::Z)
+            .defocus_angle(10.0)
+            .focus_dist(FOCUS)
+            .split_focus(SPLIT, 0.0)
+            .build();
+        // Where a pixel's rays, through different parts of the lens but with
+        // the same jitter, cross the plane `depth` in front of the camera.
+        let spread = |x: u32, depth: f32| {
+            let points: Vec<Point3> = (0..16)
+                .map(|sample| {
+                    random::seed_sample(0, x, 4, 0);
+                    let ray = camera.get_ray(x, 4, sample);
+                    ray.at((-depth - ray.origin().z) / ray.dir().z)
+                })
+                .collect();
+            points
+                .iter()
+                .map(|p| p.distance(points[0]))
+                .fold(0.0, f32::max)
+        };
+        // The right half focuses at the split distance, the left half at
+        // the focus distance.
+        assert!(spread(6, SPLIT) < 1e-4, "{}", spread(6, SPLIT));
+        assert!(spread(6, FOCUS) > 0.1);
+        assert!(spread(1, FOCUS) < 1e-4, "{}", spread(1, FOCUS));
+        assert!(spread(1, SPLIT) > 0.1);
+    }
+
+    #[test]
+    fn cameras_see_surfaces_only_where_nothing_is_in_front() {
+        // A card far from the origin, with a ball in front of its middle.
+        let origin = point3(1e5, 0.0, 0.0);
+        let mat = Material::new_lambertian(0.5, 0.5, 0.5);
+        let card = || {
+            Quad::new(
+                origin + vec3(-1.0, -1.0, 0.0),
+                2.0 * Vec3::X,
+                2.0 * Vec3::Y,
+                mat.clone(),
+            )
+        };
+        let world = Bvh::new(vec![
+            Box::new(card()),
+            Box::new(Sphere::new(origin + Vec3::Z, 0.3, mat.clone())),
+        ]);
+        let camera = Camera::builder(9, 9)
+            .look_from(origin + 5.0 * Vec3::Z)
+            .look_at(origin)
+            .vert_fov(40.0)
+            .build();
+        let card = card();
+        assert!(camera.sees(2, 4, &world, &card));
+        assert!(camera.sees(4, 6, &world, &card));
+        assert!(!camera.sees(4, 4, &world, &card), "behind the ball");
+        assert!(!camera.sees(0, 0, &world, &card), "off the card");
+    }
+
+    #[test]
+    fn split_screens_give_the_right_half_its_settings() {
+        let settings = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        let builder = || Camera::builder(5, 2).samples(4);
+        let screen = SplitScreen::new(builder(), &[]).unwrap();
+        assert!((0..5).all(|x| std::ptr::eq(screen.at(x), &screen.left)));
+
+        // The odd column goes to the right.
+        let screen = SplitScreen::new(builder(), &settings(&[("samples", "9")])).unwrap();
+        let sides = (0..5).map(|x| screen.at(x).samples()).collect::<Vec<_>>();
+        assert_eq!(sides, [4, 4, 9, 9, 9]);
+        assert!(std::ptr::eq(screen.at(2), &screen.right));
+
+        let error = SplitScreen::new(builder(), &settings(&[("zoom", "2")]));
+        assert!(error.is_err());
+    }
+
+    #[test]
+    fn max_radiance_clamps_each_sample_and_light_paths_alike() {
+        let sky = color3(8.0, 1.0, 0.5);
+        let builder = || {
+            Camera::builder(1, 1)
+                .samples(1)
+                .look_from(Point3::ZERO)
+                .look_at(-Vec3::Z)
+                .background(sky)
+                .max_radiance(2.0)
+        };
+        // Each channel on its own, so bright skies keep their hue below it.
+        let camera = builder().build();
+        let mut sum = DVec3::ZERO;
+        camera.add_samples(0, 0, &vec![], 0..1, &mut sum);
+        assert_eq!(camera.pixel_color(sum), color3(2.0, 1.0, 0.5));
+
+        // A mirror showing the sky in the glossy component, clamped as the
+        // image is.
+        let world: HittableVec = vec![Box::new(Quad::new(
+            point3(-1.0, -1.0, -1.0),
+            2.0 * Vec3::X,
+            2.0 * Vec3::Y,
+            Material::new_metal(0.5, 0.5, 0.5, 0.0),
+        ))];
+        let mut sums = [DVec3::ZERO; COMPONENTS];
+        builder()
+            .seed(Some(0))
+            .build()
+            .add_light_paths(0, 0, &world, &mut sums);
+        let total: DVec3 = sums.iter().sum();
+        assert_eq!(total.as_vec3(), color3(2.0, 0.5, 0.25));
+        assert_eq!(sums[Component::Glossy as usize], total);
+    }
+
+    #[test]
+    fn framing_fits_the_bounds_in_view() {
+        let bounds = Aabb::new(point3(10.0, -2.0, 3.0), point3(14.0, 6.0, 4.0));
+        for (width, height) in [(200, 100), (100, 200)] {
+            let builder = Camera::builder(width, height)
+                .look_from(point3(0.0, 0.0, 10.0))
+                .look_at(Point3::ZERO)
+                .frame(bounds)
+                .unwrap();
+            assert_eq!(builder.look_at, bounds.centroid());
+            let dir = (builder.look_from - builder.look_at).normalize();
+            assert!(dir.distance(Vec3::Z) < 1e-6, "{}", dir);
+
+            // Every corner is in front of the camera and within the field of
+            // view both ways.
+            let w = dir;
+            let u = builder.vup.cross(w).normalize();
+            let v = w.cross(u);
+            let tan_y = (builder.v_fov.to_radians() / 2.0).tan();
+            let tan_x = tan_y * width as f32 / height as f32;
+            for corner in 0..8 {
+                let mask = glam::BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0);
+                let p = Vec3::select(mask, bounds.max, bounds.min) - builder.look_from;
+                let depth = -p.dot(w);
+                assert!(depth > 0.0);
+                assert!((p.dot(u) / depth).abs() < tan_x, "{}x{}", width, height);
+                assert!((p.dot(v) / depth).abs() < tan_y, "{}x{}", width, height);
+            }
+        }
+
+        let frame = |bounds| Camera::builder(10, 10).frame(bounds).err().unwrap();
+        assert!(frame(Aabb::EMPTY).to_string().contains("empty"));
+        let unbounded = Aabb::new(Point3::ZERO, point3(1.0, f32::INFINITY, 1.0));
+        assert!(frame(unbounded).to_string().contains("finite"));
+    }
+
+    #[test]
+    fn adjusted_settings_apply_to_later_samples() {
+        let world: HittableVec = vec![];
+        let mut camera = Camera::builder(1, 1)
+            .samples(1)
+            .background(Color3::splat(5.0))
+            .build();
+        let color = |camera: &Camera| {
+            let mut sum = DVec3::ZERO;
+            camera.add_samples(0, 0, &world, 0..1, &mut sum);
+            camera.pixel_color(sum)
+        };
+        assert_eq!(color(&camera), Color3::splat(5.0));
+        camera.adjust("max_radiance", "2").unwrap();
+        assert_eq!(color(&camera), Color3::splat(2.0));
+        camera.adjust("max_depth", "0").unwrap();
+        assert_eq!(color(&camera), Color3::ZERO);
+
+        assert!(camera.adjust("max_radiance", "-1").is_err());
+        assert!(camera.adjust("max_depth", "deep").is_err());
+        assert!(camera.adjust("samples", "4").is_err());
+    }
 }
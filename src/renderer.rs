@@ -0,0 +1,144 @@
+use crate::denoise;
+use crate::hittables::Hittable;
+use crate::render::Camera;
+use crate::tiles::{self, Order};
+use crate::Color3;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+
+// A rendered image: the linear radiance of each pixel, averaged over its
+// samples, before any exposure, grading or conversion for display.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageBuffer {
+    width: u32,
+    height: u32,
+    // Row-major from the top left.
+    pixels: Vec<Color3>,
+}
+
+impl ImageBuffer {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Color3 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn pixels(&self) -> &[Color3] {
+        &self.pixels
+    }
+
+    pub fn into_pixels(self) -> Vec<Color3> {
+        self.pixels
+    }
+}
+
+// How `Renderer::render` goes about it, beyond what the camera sets.
+#[derive(Clone, Default)]
+pub struct RenderSettings {
+    focus: Option<(u32, u32)>,
+    denoise: bool,
+    progress: bool,
+}
+
+impl RenderSettings {
+    // Renders outward from this pixel rather than most expensive tiles
+    // first. Only the order changes, not the image.
+    pub fn focus(mut self, x: u32, y: u32) -> Self {
+        self.focus = Some((x, y));
+        self
+    }
+
+    pub fn denoise(mut self, denoise: bool) -> Self {
+        self.denoise = denoise;
+        self
+    }
+
+    // Shows a progress bar on the terminal.
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+}
+
+// Renders into memory for programs using the renderer as a library, such as
+// viewers, tests or bindings, which post-process the image themselves rather
+// than have it written to a file.
+pub struct Renderer;
+
+impl Renderer {
+    // Renders `world` as `camera` sees it, with all of the camera's passes.
+    // Seeded cameras give the same image on every run.
+    pub fn render(world: &dyn Hittable, camera: &Camera, settings: &RenderSettings) -> ImageBuffer {
+        let (width, height) = camera.size();
+        let order = match settings.focus {
+            Some((x, y)) => Order::Focus(x, y),
+            None => Order::Cost,
+        };
+        let bar = match settings.progress {
+            true => ProgressBar::new(0),
+            false => ProgressBar::hidden(),
+        };
+        let sums = tiles::render(
+            width,
+            height,
+            camera.samples(),
+            order,
+            &bar,
+            |x, y, pass, sum| camera.add_samples(x, y, world, pass.samples(camera.samples()), sum),
+        );
+        bar.finish();
+        let mut pixels: Vec<Color3> = sums.iter().map(|&sum| camera.pixel_color(sum)).collect();
+        if settings.denoise {
+            let guides: Vec<_> = (0..height)
+                .into_par_iter()
+                .flat_map_iter(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| camera.guide(x, y, world))
+                .collect();
+            denoise::denoise(width, height, &mut pixels, &guides);
+        }
+        ImageBuffer {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::Sphere;
+    use crate::materials::Material;
+    use crate::{color3, point3, Point3};
+
+    #[test]
+    fn renders_into_memory() {
+        let world = Sphere::new(
+            point3(0.0, 0.0, -3.0),
+            1.0,
+            Material::new_light(4.0, 4.0, 4.0),
+        );
+        let camera = Camera::builder(8, 6)
+            .background(color3(0.5, 0.25, 0.125))
+            .look_from(Point3::ZERO)
+            .look_at(point3(0.0, 0.0, -1.0))
+            .samples(2)
+            .seed(Some(1))
+            .build();
+        let image = Renderer::render(&world, &camera, &RenderSettings::default());
+        assert_eq!((image.width(), image.height()), (8, 6));
+        assert_eq!(image.pixels().len(), 48);
+        // The light fills the middle; the corners see the background.
+        assert_eq!(image.pixel(0, 0), color3(0.5, 0.25, 0.125));
+        assert_eq!(image.pixel(4, 3), color3(4.0, 4.0, 4.0));
+        // The tile order doesn't change a seeded render.
+        let focused = RenderSettings::default().focus(7, 5);
+        assert_eq!(Renderer::render(&world, &camera, &focused), image);
+    }
+}
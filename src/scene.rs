@@ -0,0 +1,2065 @@
+use crate::assets::AssetResolver;
+use crate::csg::{Csg, Operation};
+use crate::environment::{Environment, EnvironmentMap};
+use crate::extrude::extrude;
+use crate::grade::Grade;
+use crate::graph::{Fresnel, Mix, Multiply};
+use crate::hittables::{
+    make_box, Aabb, BilinearPatch, Bvh, BvhQuality, Hittable, HittableVec, Instance, Medium, Mesh,
+    Plane, Quad, Sphere, Spheres, Transform, Triangle, VoxelGrid,
+};
+use crate::json::Json;
+use crate::loaders::{gltf, obj, ply, stl, ttf, usd, Resolve};
+use crate::materials::Material;
+use crate::measured::MeasuredBrdf;
+use crate::registry::Registry;
+use crate::render::{Camera, CameraBuilder, Projection};
+use crate::sdf::{Field, Sdf};
+use crate::shapes::{Capsule, Cone, Cylinder};
+use crate::textures::{
+    BrickTexture, CheckerTexture, GridTexture, ImageCache, ImageTexture, NoiseStyle, NoiseTexture,
+    SolidColor, Texture,
+};
+use crate::{color3, Color3};
+use anyhow::{anyhow, bail, Context, Result};
+use glam::{Affine3A, Mat4, Vec2, Vec3};
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub struct Scene {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub world: HittableVec,
+    pub camera: CameraBuilder,
+    // Bytes of tabulated material data, such as measured BRDFs, and of
+    // images, or of their cache if there is one.
+    pub texture_bytes: usize,
+    pub grade: Grade,
+    pub bvh: BvhQuality,
+    // A second copy of the object marked as the gray card, if any, to find
+    // which pixels see it.
+    pub gray_card: Option<Box<dyn Hittable>>,
+}
+
+impl Scene {
+    // `defines` fill in `${name}` placeholders in the scene and its includes.
+    // Image textures are decoded once and kept, unless an `image_cache` is
+    // given to hold them instead.
+    pub fn load(
+        path: &Path,
+        resolver: &AssetResolver,
+        defines: &[(String, String)],
+        registry: &Registry,
+        image_cache: Option<Arc<ImageCache>>,
+    ) -> Result<Scene> {
+        let path = resolver.resolve(&path.to_string_lossy(), Path::new(""))?;
+        let mut base_dirs = vec![];
+        let doc = load_document(&path, resolver, defines, &mut vec![], &mut base_dirs)?;
+        Self::from_json(
+            &doc,
+            path.clone(),
+            &base_dirs,
+            resolver,
+            registry,
+            image_cache,
+        )
+        .with_context(|| format!("invalid scene {}", path.display()))
+    }
+
+    fn from_json(
+        doc: &Json,
+        path: PathBuf,
+        base_dirs: &[PathBuf],
+        resolver: &AssetResolver,
+        registry: &Registry,
+        image_cache: Option<Arc<ImageCache>>,
+    ) -> Result<Scene> {
+        let cam = doc.get("camera").unwrap_or(&Json::Null);
+        let width = opt_u32(cam, "width")?.unwrap_or(800);
+        let height = opt_u32(cam, "height")?.unwrap_or(width);
+        let mut camera = parse_camera(cam, Camera::builder(width, height))?;
+        let grade = match doc.get("grade") {
+            Some(grade) => parse_grade(grade).context("in 'grade'")?,
+            None => Grade::default(),
+        };
+        let bvh = match doc.get("bvh") {
+            None => BvhQuality::default(),
+            Some(value) => match value.as_str() {
+                Some("median") => BvhQuality::Median,
+                Some("sah") => BvhQuality::Sah,
+                _ => bail!("'bvh' must be \"median\" or \"sah\""),
+            },
+        };
+
+        let mut loader = SceneLoader {
+            resolver,
+            registry,
+            base_dirs,
+            materials: HashMap::new(),
+            texture_bytes: Cell::new(0),
+            bvh,
+            prototypes: HashMap::new(),
+            emissive_meshes: RefCell::new(HashMap::new()),
+            images: HashMap::new(),
+            image_cache,
+            environments: HashMap::new(),
+            brdfs: HashMap::new(),
+            models: RefCell::new(HashMap::new()),
+            graphs: RefCell::new(vec![]),
+        };
+        let bar = ProgressBar::new(0);
+        loader.preload_textures(doc, &bar);
+        if let Some(def) = cam
+            .get("background")
+            .filter(|def| def.as_object().is_some())
+        {
+            let environment = loader.environment(def).context("in 'background'")?;
+            camera = camera.environment(environment);
+        }
+        if let Some(defs) = doc.get("materials") {
+            let defs = defs
+                .as_object()
+                .ok_or_else(|| anyhow!("'materials' must be an object"))?;
+            for (name, def) in defs {
+                let mat = loader
+                    .parse_material(def)
+                    .with_context(|| format!("material '{}'", name))?;
+                loader.materials.insert(name.clone(), mat);
+            }
+        }
+
+        loader.preload_models(doc, &bar);
+        bar.finish_and_clear();
+
+        if let Some(defs) = doc.get("prototypes") {
+            let defs = defs
+                .as_object()
+                .ok_or_else(|| anyhow!("'prototypes' must be an object"))?;
+            for (name, node) in defs {
+                let object = loader
+                    .parse_node(node)
+                    .with_context(|| format!("prototype '{}'", name))?;
+                let bvh = Bvh::with_quality(vec![object], bvh);
+                loader.prototypes.insert(name.clone(), Arc::new(bvh));
+            }
+        }
+
+        let mut world: HittableVec = vec![];
+        let mut lights: HittableVec = vec![];
+        let mut gray_card = None;
+        for (idx, node) in array(doc, "objects")?.iter().enumerate() {
+            let object = || {
+                loader
+                    .parse_node(node)
+                    .with_context(|| format!("object #{}", idx))
+            };
+            if opt_bool(node, "gray_card")?.unwrap_or(false) {
+                if gray_card.is_some() {
+                    bail!("only one object can be the gray card");
+                }
+                gray_card = Some(object()?);
+            }
+            world.push(object()?);
+            lights.extend(
+                loader
+                    .parse_lights(node)
+                    .with_context(|| format!("object #{}", idx))?,
+            );
+        }
+        camera = camera.lights(lights);
+        if opt_bool(cam, "auto_frame")?.unwrap_or(false) {
+            // Planes go on forever, so they are left out.
+            let bounds = world
+                .iter()
+                .map(|obj| obj.bounding_box())
+                .filter(Aabb::is_finite)
+                .fold(Aabb::EMPTY, Aabb::union);
+            camera = camera.frame(bounds)?;
+        }
+
+        let cache_bytes = loader
+            .image_cache
+            .as_ref()
+            .map_or(0, |cache| cache.memory());
+        let texture_bytes = loader.texture_bytes.get() + cache_bytes;
+        Ok(Scene {
+            path,
+            width,
+            height,
+            world,
+            camera,
+            texture_bytes,
+            grade,
+            bvh,
+            gray_card,
+        })
+    }
+}
+
+// Reads a scene document and everything it includes, merged into one. The
+// includes are merged in order and the including document goes on top, so
+// it can override anything they define.
+fn load_document(
+    path: &Path,
+    resolver: &AssetResolver,
+    defines: &[(String, String)],
+    stack: &mut Vec<PathBuf>,
+    base_dirs: &mut Vec<PathBuf>,
+) -> Result<Json> {
+    if stack.iter().any(|p| p == path) {
+        bail!("include cycle through {}", path.display());
+    }
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read scene {}", path.display()))?;
+    let text = substitute(&text, defines).with_context(|| format!("in {}", path.display()))?;
+    let mut doc = Json::parse(&text).with_context(|| format!("in {}", path.display()))?;
+    let base_dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    if !base_dirs.contains(&base_dir) {
+        base_dirs.push(base_dir.clone());
+    }
+
+    let includes = match &mut doc {
+        Json::Object(map) => map.remove("include"),
+        _ => None,
+    };
+    let includes = match includes {
+        None => vec![],
+        Some(Json::String(include)) => vec![include],
+        Some(Json::Array(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                Json::String(include) => Ok(include),
+                _ => bail!("'include' must contain strings"),
+            })
+            .collect::<Result<_>>()
+            .with_context(|| format!("in {}", path.display()))?,
+        Some(_) => bail!("'include' must be a string or an array of strings"),
+    };
+
+    stack.push(path.to_path_buf());
+    let mut merged = Json::Object(Default::default());
+    for include in includes {
+        let included = resolver.resolve(&include, &base_dir)?;
+        let included = load_document(&included, resolver, defines, stack, base_dirs)
+            .with_context(|| format!("included from {}", path.display()))?;
+        merge(&mut merged, included);
+    }
+    stack.pop();
+    merge(&mut merged, doc);
+    Ok(merged)
+}
+
+// Replaces `${name}` with the value defined for `name`, or `${name:-default}`
+// with `default` when `name` isn't defined. Substitution is textual, so a
+// placeholder can stand for a number (`"samples": ${samples:-50}`) as well as
+// part of a string.
+fn substitute(text: &str, defines: &[(String, String)]) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated placeholder"))?;
+        let placeholder = &rest[start + 2..start + end];
+        let (name, default) = match placeholder.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (placeholder, None),
+        };
+        let value = defines
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .or(default)
+            .ok_or_else(|| anyhow!("'{}' is not defined, pass --define {}=VALUE", name, name))?;
+        out.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Objects merge key by key. Node arrays (`objects` and `children`) merge
+// nodes that share a `name` and append the rest. Anything else in `over`
+// replaces what was there.
+fn merge(base: &mut Json, over: Json) {
+    match (base, over) {
+        (Json::Object(base), Json::Object(over)) => {
+            for (key, value) in over {
+                match base.get_mut(&key) {
+                    Some(existing) if key == "objects" || key == "children" => {
+                        merge_nodes(existing, value)
+                    }
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+fn merge_nodes(base: &mut Json, over: Json) {
+    match (base, over) {
+        (Json::Array(base), Json::Array(over)) => {
+            for node in over {
+                let name = node.get("name").and_then(Json::as_str);
+                let existing = name.and_then(|name| {
+                    base.iter_mut()
+                        .find(|b| b.get("name").and_then(Json::as_str) == Some(name))
+                });
+                match existing {
+                    Some(existing) => merge(existing, node),
+                    None => base.push(node),
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+fn parse_camera(cam: &Json, mut builder: CameraBuilder) -> Result<CameraBuilder> {
+    if let Some(samples) = opt_u32(cam, "samples")? {
+        builder = builder.samples(samples);
+    }
+    if let Some(passes) = opt_u32(cam, "passes")? {
+        builder = builder.passes(passes);
+    }
+    if let Some(depth) = opt_u32(cam, "max_depth")? {
+        builder = builder.max_depth(depth);
+    }
+    if let Some(limits) = cam.get("max_bounces") {
+        let limits = limits
+            .as_object()
+            .ok_or_else(|| anyhow!("'max_bounces' must map bounce kinds to counts"))?;
+        for (kind, max) in limits {
+            let max = max
+                .as_u32()
+                .ok_or_else(|| anyhow!("'max_bounces' must map bounce kinds to counts"))?;
+            builder = builder.max_bounces(kind, max)?;
+        }
+    }
+    // Environment maps need the loader to find their files; from_json
+    // reads those.
+    if cam.get("background").and_then(Json::as_object).is_none() {
+        if let Some(background) = opt_vec3(cam, "background")? {
+            builder = builder.background(background);
+        }
+    }
+    if let Some(max) = opt_f32(cam, "max_radiance")? {
+        builder = builder.max_radiance(max);
+    }
+    if let Some(max) = opt_f32(cam, "max_distance")? {
+        if max <= 0.0 {
+            bail!("'max_distance' must be positive");
+        }
+        builder = builder.max_distance(max);
+    }
+    if let Some(fov) = opt_f32(cam, "vfov")? {
+        builder = builder.vert_fov(fov);
+    }
+    if let Some(from) = opt_vec3(cam, "look_from")? {
+        builder = builder.look_from(from);
+    }
+    if let Some(at) = opt_vec3(cam, "look_at")? {
+        builder = builder.look_at(at);
+    }
+    if let Some(up) = opt_vec3(cam, "up")? {
+        builder = builder.look_up(up);
+    }
+    if let Some(angle) = opt_f32(cam, "defocus_angle")? {
+        builder = builder.defocus_angle(angle);
+    }
+    if let Some(dist) = opt_f32(cam, "focus_dist")? {
+        builder = builder.focus_dist(dist);
+    }
+    if let Some(dist) = opt_f32(cam, "split_focus_dist")? {
+        let angle = opt_f32(cam, "split_angle")?.unwrap_or(0.0);
+        builder = builder.split_focus(dist, angle);
+    }
+    if let Some(splits) = cam.get("splits") {
+        let splits = splits
+            .as_object()
+            .ok_or_else(|| anyhow!("'splits' must map material types to counts"))?;
+        for (material_type, count) in splits {
+            let count = count
+                .as_u32()
+                .ok_or_else(|| anyhow!("'splits' must map material types to counts"))?;
+            builder = builder.splits(material_type, count)?;
+        }
+    }
+    if let Some(shutter) = cam.get("shutter") {
+        let [open, close] =
+            numbers(shutter, Json::as_f32).ok_or_else(|| anyhow!("'shutter' must be 2 numbers"))?;
+        builder = builder.shutter(open, close)?;
+    }
+    if let Some(projection) = cam.get("projection") {
+        let ipd = opt_f32(cam, "ipd")?.unwrap_or(0.064);
+        builder = builder.projection(match projection.as_str() {
+            Some("perspective") => Projection::Perspective,
+            Some("ods") => Projection::Stereo { ipd, span: 360.0 },
+            Some("vr180") => Projection::Stereo { ipd, span: 180.0 },
+            _ => bail!("'projection' must be \"perspective\", \"ods\" or \"vr180\""),
+        });
+    }
+    Ok(builder)
+}
+
+fn parse_grade(grade: &Json) -> Result<Grade> {
+    let mut result = Grade::default();
+    if let Some(stops) = opt_f32(grade, "exposure")? {
+        result = result.exposure(stops);
+    }
+    if let Some(white_balance) = opt_channels(grade, "white_balance")? {
+        result = result.white_balance(white_balance);
+    }
+    if let Some(lift) = opt_channels(grade, "lift")? {
+        result = result.lift(lift);
+    }
+    if let Some(gamma) = opt_channels(grade, "gamma")? {
+        result = result.gamma(gamma);
+    }
+    if let Some(gain) = opt_channels(grade, "gain")? {
+        result = result.gain(gain);
+    }
+    if let Some(saturation) = opt_f32(grade, "saturation")? {
+        result = result.saturation(saturation);
+    }
+    if let Some(contrast) = opt_f32(grade, "contrast")? {
+        result = result.contrast(contrast);
+    }
+    Ok(result)
+}
+
+// Builds materials and objects from their JSON definitions; also handed to
+// the factories of registered types so they can do the same for theirs.
+pub struct SceneLoader<'a> {
+    resolver: &'a AssetResolver,
+    registry: &'a Registry,
+    // Directories of the scene file and of every file it includes, searched
+    // in that order for relative references.
+    base_dirs: &'a [PathBuf],
+    materials: HashMap<String, Material>,
+    texture_bytes: Cell<usize>,
+    bvh: BvhQuality,
+    // Shared geometry for `instance` nodes, built once.
+    prototypes: HashMap<String, Arc<Bvh>>,
+    // Meshes with emissive faces by the node they came from, so they are
+    // loaded once for both the world and the lights.
+    emissive_meshes: RefCell<HashMap<*const Json, Arc<Mesh>>>,
+    // Files decoded ahead of parsing, several at a time, by path.
+    images: HashMap<PathBuf, Arc<ImageTexture>>,
+    image_cache: Option<Arc<ImageCache>>,
+    environments: HashMap<PathBuf, Arc<EnvironmentMap>>,
+    brdfs: HashMap<PathBuf, Arc<MeasuredBrdf>>,
+    // Models loaded ahead of parsing by the node that loads them, taken when
+    // it is parsed.
+    models: RefCell<HashMap<*const Json, Mesh>>,
+    // The nodes of the material graphs being parsed, innermost last.
+    graphs: RefCell<Vec<GraphNodes>>,
+}
+
+// Node definitions by name, and the nodes built from them so far, each
+// shared by everything using it.
+struct GraphNodes {
+    defs: BTreeMap<String, Json>,
+    built: HashMap<String, Arc<dyn Texture>>,
+    // Being built, to catch nodes that depend on themselves.
+    building: HashSet<String>,
+}
+
+impl<'a> SceneLoader<'a> {
+    pub fn parse_material(&self, def: &Json) -> Result<Material> {
+        let color = |key| -> Result<Color3> { vec3(def, key) };
+        let material = match string(def, "type")? {
+            "lambertian" => Material::Lambertian {
+                albedo: self.texture(def, "albedo")?,
+            },
+            "metal" => Material::Metal {
+                albedo: color("albedo")?,
+                fuzz: opt_f32(def, "fuzz")?.unwrap_or(0.0),
+            },
+            "dielectric" => Material::new_dielectric(f32_field(def, "ior")?),
+            "graph" => self.parse_graph(def)?,
+            "marble" => Material::Lambertian {
+                albedo: Arc::new(NoiseTexture::new(
+                    NoiseStyle::Marble,
+                    opt_f32(def, "scale")?.unwrap_or(4.0),
+                    opt_vec3(def, "color")?.unwrap_or(color3(0.92, 0.9, 0.86)),
+                )),
+            },
+            "wood" => {
+                let rings = opt_f32(def, "rings")?.unwrap_or(8.0);
+                Material::Lambertian {
+                    albedo: Arc::new(Mix::new(
+                        self.texture_or(def, "light", color3(0.72, 0.52, 0.32))?,
+                        self.texture_or(def, "dark", color3(0.42, 0.26, 0.14))?,
+                        Arc::new(NoiseTexture::new(NoiseStyle::Wood, rings, Color3::ONE)),
+                    )),
+                }
+            }
+            "brick" => {
+                let size = match opt_vec3(def, "size")? {
+                    Some(size) => Vec2::new(size.x, size.y),
+                    None => Vec2::new(0.215, 0.065),
+                };
+                let joint = opt_f32(def, "joint")?.unwrap_or(0.01);
+                if size.min_element().is_nan() || size.min_element() <= joint || joint < 0.0 {
+                    bail!("brick 'size' must be larger than the 'joint', which can't be negative");
+                }
+                Material::Lambertian {
+                    albedo: Arc::new(BrickTexture::new(
+                        size,
+                        joint,
+                        self.texture_or(def, "brick", color3(0.55, 0.2, 0.12))?,
+                        self.texture_or(def, "mortar", color3(0.75, 0.73, 0.68))?,
+                    )),
+                }
+            }
+            "light" => {
+                let spread = opt_f32(def, "spread")?.unwrap_or(180.0);
+                if !(spread > 0.0 && spread <= 180.0) {
+                    bail!("a light's 'spread' must be above 0 and at most 180");
+                }
+                Material::DiffuseLight {
+                    emit: color("emit")? * opt_f32(def, "strength")?.unwrap_or(1.0),
+                    spread,
+                }
+            }
+            "measured" => {
+                let path = self.resolve(string(def, "file")?)?;
+                let brdf = match self.brdfs.get(&path) {
+                    Some(brdf) => brdf.clone(),
+                    None => {
+                        let brdf = MeasuredBrdf::load(&path)?;
+                        self.texture_bytes
+                            .set(self.texture_bytes.get() + brdf.memory());
+                        Arc::new(brdf)
+                    }
+                };
+                Material::Measured { brdf }
+            }
+            other => match self.registry.make_material(other, def, self) {
+                Some(material) => material?,
+                None => bail!("unknown material type '{}'", other),
+            },
+        };
+        Ok(match opt_f32(def, "temperature")? {
+            Some(temperature) => Material::Heated {
+                temperature,
+                base: Box::new(material),
+            },
+            None => material,
+        })
+    }
+
+    // Material graphs name their nodes, so the textures of the graph's
+    // `output` material and of other nodes can share them.
+    fn parse_graph(&self, def: &Json) -> Result<Material> {
+        let defs = match def.get("nodes") {
+            Some(nodes) => nodes
+                .as_object()
+                .ok_or_else(|| anyhow!("'nodes' must be an object"))?
+                .clone(),
+            None => BTreeMap::new(),
+        };
+        let names: Vec<String> = defs.keys().cloned().collect();
+        self.graphs.borrow_mut().push(GraphNodes {
+            defs,
+            built: HashMap::new(),
+            building: HashSet::new(),
+        });
+        let material = (|| {
+            // Unused nodes are checked as well.
+            for name in &names {
+                self.graph_node(name)?;
+            }
+            let output = def.get("output").context("missing 'output'")?;
+            self.parse_material(output).context("in 'output'")
+        })();
+        self.graphs.borrow_mut().pop();
+        material
+    }
+
+    fn graph_node(&self, name: &str) -> Result<Arc<dyn Texture>> {
+        let def = {
+            let mut graphs = self.graphs.borrow_mut();
+            let Some(graph) = graphs.last_mut() else {
+                bail!("'{}' names a node, which only material graphs have", name);
+            };
+            if let Some(node) = graph.built.get(name) {
+                return Ok(node.clone());
+            }
+            let def = graph
+                .defs
+                .get(name)
+                .ok_or_else(|| anyhow!("undefined node '{}'", name))?
+                .clone();
+            if !graph.building.insert(name.to_string()) {
+                bail!("node '{}' depends on itself", name);
+            }
+            def
+        };
+        let node = self
+            .texture_value(&def)
+            .with_context(|| format!("node '{}'", name))?;
+        let mut graphs = self.graphs.borrow_mut();
+        let graph = graphs.last_mut().unwrap();
+        graph.building.remove(name);
+        graph.built.insert(name.to_string(), node.clone());
+        Ok(node)
+    }
+
+    // A color, a number for a gray, an object with a "type" for a varying
+    // texture, or within a material graph the name of one of its nodes.
+    fn texture_or(&self, obj: &Json, key: &str, default: Color3) -> Result<Arc<dyn Texture>> {
+        match obj.get(key) {
+            Some(_) => self.texture(obj, key),
+            None => Ok(Arc::new(SolidColor::new(default))),
+        }
+    }
+
+    pub fn texture(&self, obj: &Json, key: &str) -> Result<Arc<dyn Texture>> {
+        let value = obj.get(key).ok_or_else(|| anyhow!("missing '{}'", key))?;
+        self.texture_value(value)
+            .with_context(|| format!("in '{}'", key))
+    }
+
+    fn texture_value(&self, value: &Json) -> Result<Arc<dyn Texture>> {
+        let def = match value {
+            Json::String(name) => return self.graph_node(name),
+            Json::Object(_) => value,
+            _ => {
+                let color = match value.as_f32() {
+                    Some(gray) => Vec3::splat(gray),
+                    None => numbers(value, Json::as_f32)
+                        .map(Vec3::from_array)
+                        .context("expected a color, a texture or a node name")?,
+                };
+                return Ok(Arc::new(SolidColor::new(color)));
+            }
+        };
+        let texture: Arc<dyn Texture> = match string(def, "type")? {
+            "checker" => {
+                let scale = f32_field(def, "scale")?;
+                if scale <= 0.0 {
+                    bail!("checker 'scale' must be positive");
+                }
+                Arc::new(CheckerTexture::new(
+                    scale,
+                    self.texture(def, "even")?,
+                    self.texture(def, "odd")?,
+                ))
+            }
+            "image" => {
+                let path = self.resolve(string(def, "file")?)?;
+                match self.images.get(&path) {
+                    Some(image) => image.clone(),
+                    None => {
+                        let image = ImageTexture::load(&path, self.image_cache.as_ref())?;
+                        self.texture_bytes
+                            .set(self.texture_bytes.get() + image.memory());
+                        Arc::new(image)
+                    }
+                }
+            }
+            "noise" => {
+                let style = match def.get("style").map(Json::as_str) {
+                    None | Some(Some("smooth")) => NoiseStyle::Smooth,
+                    Some(Some("turbulence")) => NoiseStyle::Turbulence,
+                    Some(Some("marble")) => NoiseStyle::Marble,
+                    Some(Some("wood")) => NoiseStyle::Wood,
+                    _ => {
+                        bail!("'style' must be \"smooth\", \"turbulence\", \"marble\" or \"wood\"")
+                    }
+                };
+                Arc::new(NoiseTexture::new(
+                    style,
+                    opt_f32(def, "scale")?.unwrap_or(1.0),
+                    opt_vec3(def, "color")?.unwrap_or(Vec3::ONE),
+                ))
+            }
+            "grid" => {
+                let grid = self.parse_grid(def)?;
+                self.texture_bytes
+                    .set(self.texture_bytes.get() + grid.memory());
+                Arc::new(grid)
+            }
+            "multiply" => {
+                let inputs = array(def, "inputs")?;
+                if inputs.is_empty() {
+                    bail!("'inputs' must list the textures to multiply");
+                }
+                Arc::new(Multiply::new(
+                    inputs
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, input)| {
+                            self.texture_value(input)
+                                .with_context(|| format!("input #{}", idx))
+                        })
+                        .collect::<Result<_>>()?,
+                ))
+            }
+            "mix" => Arc::new(Mix::new(
+                self.texture(def, "a")?,
+                self.texture(def, "b")?,
+                self.texture(def, "factor")?,
+            )),
+            "fresnel" => {
+                let ior = opt_f32(def, "ior")?.unwrap_or(1.5);
+                if ior.is_nan() || ior <= 0.0 {
+                    bail!("fresnel 'ior' must be positive");
+                }
+                Arc::new(Fresnel::new(ior))
+            }
+            other => bail!("unknown texture type '{}'", other),
+        };
+        Ok(texture)
+    }
+
+    fn environment(&self, def: &Json) -> Result<Environment> {
+        let path = self.resolve(string(def, "file")?)?;
+        let azimuth = opt_f32(def, "azimuth")?.unwrap_or(0.0);
+        let exposure = opt_f32(def, "exposure")?.unwrap_or(0.0);
+        let blur = opt_f32(def, "blur")?.unwrap_or(0.0);
+        if blur.is_nan() || blur < 0.0 {
+            bail!("environment 'blur' must not be negative");
+        }
+        let mut map = match self.environments.get(&path) {
+            Some(map) => map.clone(),
+            None => {
+                let map = EnvironmentMap::load(&path)?;
+                self.texture_bytes
+                    .set(self.texture_bytes.get() + map.memory());
+                Arc::new(map)
+            }
+        };
+        if blur > 0.0 {
+            let blurred = map.blurred(blur);
+            self.texture_bytes
+                .set(self.texture_bytes.get() + blurred.memory());
+            map = Arc::new(blurred);
+        }
+        Ok(Environment::map(map, azimuth, exposure))
+    }
+
+    // Decodes the image textures, measured materials and environment map
+    // `doc` refers to on all threads, so parsing finds them ready rather than
+    // reading them one by one. Each file is read once however many materials
+    // use it. Files that fail to load are left for parsing to report, with
+    // its context.
+    fn preload_textures(&mut self, doc: &Json, bar: &ProgressBar) {
+        let mut files = vec![];
+        for section in ["materials", "prototypes", "objects"] {
+            if let Some(value) = doc.get(section) {
+                texture_files(value, &mut files);
+            }
+        }
+        let background = doc.get("camera").and_then(|cam| cam.get("background"));
+        if let Some(file) = background.and_then(|def| def.get("file")?.as_str()) {
+            files.push(("environment", file));
+        }
+        let mut jobs: Vec<_> = files
+            .into_iter()
+            .filter_map(|(kind, file)| Some((kind, self.resolve(file).ok()?)))
+            .collect();
+        jobs.sort();
+        jobs.dedup();
+        if jobs.is_empty() {
+            return;
+        }
+        bar.inc_length(jobs.len() as u64);
+        let cache = self.image_cache.as_ref();
+        let loaded: Vec<_> = jobs
+            .into_par_iter()
+            .map(|(kind, path)| {
+                let loaded = match kind {
+                    "image" => ImageTexture::load(&path, cache).map(Preloaded::Image),
+                    "measured" => MeasuredBrdf::load(&path).map(Preloaded::Brdf),
+                    _ => EnvironmentMap::load(&path).map(Preloaded::Environment),
+                };
+                bar.inc(1);
+                (path, loaded)
+            })
+            .collect();
+        let mut bytes = 0;
+        for (path, loaded) in loaded {
+            match loaded {
+                Ok(Preloaded::Image(image)) => {
+                    bytes += image.memory();
+                    self.images.insert(path, Arc::new(image));
+                }
+                Ok(Preloaded::Brdf(brdf)) => {
+                    bytes += brdf.memory();
+                    self.brdfs.insert(path, Arc::new(brdf));
+                }
+                Ok(Preloaded::Environment(map)) => {
+                    bytes += map.memory();
+                    self.environments.insert(path, Arc::new(map));
+                }
+                Err(_) => {}
+            }
+        }
+        self.texture_bytes.set(self.texture_bytes.get() + bytes);
+    }
+
+    // Loads the models among `doc`'s objects and prototypes on all threads,
+    // like `preload_textures`. Their default materials come from the
+    // scene's, so those must be parsed first.
+    fn preload_models(&self, doc: &Json, bar: &ProgressBar) {
+        let mut nodes = vec![];
+        for section in ["prototypes", "objects"] {
+            if let Some(value) = doc.get(section) {
+                model_nodes(value, &mut nodes);
+            }
+        }
+        let jobs: Vec<_> = nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, node)| {
+                let load = model_loader(string(node, "type").ok()?)?;
+                let path = self.resolve(string(node, "file").ok()?).ok()?;
+                Some((idx, path, self.model_material(node).ok()?, load))
+            })
+            .collect();
+        if jobs.is_empty() {
+            return;
+        }
+        bar.inc_length(jobs.len() as u64);
+        let (resolver, base_dirs) = (self.resolver, self.base_dirs);
+        let loaded: Vec<_> = jobs
+            .into_par_iter()
+            .map(|(idx, path, default, load)| {
+                let mesh = load_model_file(resolver, base_dirs, &path, default, load);
+                bar.inc(1);
+                (idx, mesh)
+            })
+            .collect();
+        let mut models = self.models.borrow_mut();
+        for (idx, mesh) in loaded {
+            if let Ok(mesh) = mesh {
+                models.insert(nodes[idx] as *const Json, mesh);
+            }
+        }
+    }
+
+    pub fn node_material(&self, node: &Json) -> Result<Material> {
+        match node.get("material") {
+            Some(material) => self.material_ref(material),
+            None => bail!("missing 'material'"),
+        }
+    }
+
+    // The name of an entry in `materials`, or an inline definition.
+    fn material_ref(&self, material: &Json) -> Result<Material> {
+        match material {
+            Json::String(name) => self
+                .materials
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("undefined material '{}'", name)),
+            def @ Json::Object(_) => self.parse_material(def),
+            _ => bail!("'material' must be a name or an inline definition"),
+        }
+    }
+
+    fn material_list(&self, node: &Json) -> Result<Vec<Material>> {
+        array(node, "materials")?
+            .iter()
+            .enumerate()
+            .map(|(idx, m)| {
+                self.material_ref(m)
+                    .with_context(|| format!("material #{}", idx))
+            })
+            .collect()
+    }
+
+    // Cells come from a raw file of one byte per cell, or inline.
+    fn parse_grid(&self, def: &Json) -> Result<GridTexture> {
+        let dims = def
+            .get("dims")
+            .and_then(|dims| numbers(dims, Json::as_u32))
+            .ok_or_else(|| anyhow!("'dims' must be 3 cell counts"))?;
+        let values = match def.get("file") {
+            Some(_) => {
+                let path = self.resolve(string(def, "file")?)?;
+                std::fs::read(&path)
+                    .with_context(|| format!("failed to read {}", path.display()))?
+                    .into_iter()
+                    .map(|byte| byte as f32 / 255.0)
+                    .collect()
+            }
+            None => array(def, "values")?
+                .iter()
+                .map(|value| {
+                    value
+                        .as_f32()
+                        .ok_or_else(|| anyhow!("'values' must be numbers"))
+                })
+                .collect::<Result<_>>()?,
+        };
+        GridTexture::new(
+            vec3(def, "min")?,
+            opt_f32(def, "size")?.unwrap_or(1.0),
+            dims,
+            values,
+        )
+    }
+
+    fn parse_voxels(&self, node: &Json) -> Result<VoxelGrid> {
+        let dims = node
+            .get("dims")
+            .and_then(|dims| numbers(dims, Json::as_u32))
+            .ok_or_else(|| anyhow!("'dims' must be 3 cell counts"))?;
+        let cells = match node.get("file") {
+            Some(_) => {
+                let path = self.resolve(string(node, "file")?)?;
+                std::fs::read(&path)
+                    .with_context(|| format!("failed to read {}", path.display()))?
+            }
+            None => array(node, "cells")?
+                .iter()
+                .map(|value| {
+                    value
+                        .as_u32()
+                        .and_then(|value| u8::try_from(value).ok())
+                        .ok_or_else(|| anyhow!("'cells' must be numbers from 0 to 255"))
+                })
+                .collect::<Result<_>>()?,
+        };
+        let materials = self.material_list(node)?;
+        if materials.len() > 255 {
+            bail!("a voxel grid can have at most 255 materials");
+        }
+        VoxelGrid::new(
+            vec3(node, "min")?,
+            opt_f32(node, "size")?.unwrap_or(1.0),
+            dims,
+            cells,
+            materials,
+        )
+    }
+
+    fn parse_mesh(&self, node: &Json) -> Result<Mesh> {
+        let vectors = |key: &str| -> Result<Vec<Vec3>> {
+            array(node, key)?
+                .iter()
+                .enumerate()
+                .map(|(idx, p)| {
+                    let [x, y, z] = numbers(p, Json::as_f32)
+                        .with_context(|| format!("'{}' #{} must be 3 numbers", key, idx))?;
+                    Ok(Vec3::new(x, y, z))
+                })
+                .collect()
+        };
+        let positions = vectors("positions")?;
+        let faces = array(node, "faces")?
+            .iter()
+            .enumerate()
+            .map(|(idx, f)| {
+                numbers(f, Json::as_u32)
+                    .with_context(|| format!("face #{} must be 3 vertex indices", idx))
+            })
+            .collect::<Result<_>>()?;
+        let materials = match node.get("materials") {
+            Some(_) => self.material_list(node)?,
+            None => vec![self.node_material(node)?],
+        };
+        let face_materials = match node.get("face_materials") {
+            Some(_) => array(node, "face_materials")?
+                .iter()
+                .map(|idx| {
+                    idx.as_u32()
+                        .ok_or_else(|| anyhow!("'face_materials' must be material indices"))
+                })
+                .collect::<Result<_>>()?,
+            None => vec![],
+        };
+        let mut mesh = Mesh::new(positions, faces, materials, face_materials)?;
+        if node.get("normals").is_some() {
+            mesh = mesh.with_normals(vectors("normals")?)?;
+        }
+        if let Some(uvs) = node.get("uvs") {
+            mesh = mesh.with_uvs(points(uvs).context("in 'uvs'")?)?;
+        }
+        Ok(mesh)
+    }
+
+    // Outlines come from `points`, or several from `contours`, or for text
+    // nodes from laying out `text` in a `font`.
+    fn parse_extrusion(&self, node: &Json) -> Result<Mesh> {
+        let contours = if string(node, "type")? == "text" {
+            ttf::outlines(
+                &self.resolve(string(node, "font")?)?,
+                string(node, "text")?,
+                opt_f32(node, "size")?.unwrap_or(1.0),
+            )?
+        } else if node.get("contours").is_some() {
+            array(node, "contours")?
+                .iter()
+                .enumerate()
+                .map(|(idx, contour)| points(contour).with_context(|| format!("contour #{}", idx)))
+                .collect::<Result<_>>()?
+        } else {
+            vec![points(
+                node.get("points")
+                    .ok_or_else(|| anyhow!("missing 'points' or 'contours'"))?,
+            )?]
+        };
+        extrude(
+            &contours,
+            f32_field(node, "depth")?,
+            self.node_material(node)?,
+        )
+    }
+
+    // Loads the file of an `obj`, `gltf`, `stl`, `ply` or `usd` node, unless
+    // it was preloaded.
+    fn load_model(&self, node: &Json, load: LoadModel) -> Result<Mesh> {
+        if let Some(mesh) = self.models.borrow_mut().remove(&(node as *const Json)) {
+            return Ok(mesh);
+        }
+        let path = self.resolve(string(node, "file")?)?;
+        let default = self.model_material(node)?;
+        load_model_file(self.resolver, self.base_dirs, &path, default, load)
+    }
+
+    // The material of a model's faces that its file doesn't give one.
+    fn model_material(&self, node: &Json) -> Result<Material> {
+        match node.get("material") {
+            Some(material) => self.material_ref(material),
+            None => Ok(Material::new_lambertian(0.8, 0.8, 0.8)),
+        }
+    }
+
+    // Keeps emissive meshes for `parse_lights`.
+    fn mesh_node(&self, node: &Json, mesh: Mesh) -> Box<dyn Hittable> {
+        if !mesh.is_emissive() {
+            return Box::new(mesh);
+        }
+        let mesh = Arc::new(mesh);
+        self.emissive_meshes
+            .borrow_mut()
+            .insert(node as *const Json, mesh.clone());
+        Box::new(mesh)
+    }
+
+    pub fn parse_node(&self, node: &Json) -> Result<Box<dyn Hittable>> {
+        // Instances take their transform as a matrix rather than wrapped in
+        // a Transform.
+        if string(node, "type")? == "instance" {
+            let name = string(node, "prototype")?;
+            let prototype = self
+                .prototypes
+                .get(name)
+                .ok_or_else(|| anyhow!("undefined prototype '{}'", name))?;
+            let to_world = match node.get("transform") {
+                Some(transform) => transform_matrix(transform)?,
+                None => Affine3A::IDENTITY,
+            };
+            return Ok(Box::new(Instance::new(prototype.clone(), to_world)));
+        }
+
+        let object: Box<dyn Hittable> = match string(node, "type")? {
+            "sphere" => match opt_vec3(node, "center1")? {
+                Some(center1) => Box::new(Sphere::moving(
+                    vec3(node, "center")?,
+                    center1,
+                    f32_field(node, "radius")?,
+                    self.node_material(node)?,
+                )),
+                None => Box::new(Sphere::new(
+                    vec3(node, "center")?,
+                    f32_field(node, "radius")?,
+                    self.node_material(node)?,
+                )),
+            },
+            "quad" => Box::new(Quad::new(
+                vec3(node, "q")?,
+                vec3(node, "u")?,
+                vec3(node, "v")?,
+                self.node_material(node)?,
+            )),
+            "mesh" => self.mesh_node(node, self.parse_mesh(node)?),
+            "voxels" => Box::new(self.parse_voxels(node)?),
+            kind @ ("obj" | "gltf" | "stl" | "ply" | "usd") => {
+                let load = model_loader(kind).unwrap();
+                self.mesh_node(node, self.load_model(node, load)?)
+            }
+            "extrude" | "text" => self.mesh_node(node, self.parse_extrusion(node)?),
+            "cylinder" => Box::new(Cylinder::new(
+                vec3(node, "base")?,
+                vec3(node, "top")?,
+                f32_field(node, "radius")?,
+                opt_bool(node, "caps")?.unwrap_or(true),
+                self.node_material(node)?,
+            )?),
+            "cone" => Box::new(Cone::new(
+                vec3(node, "base")?,
+                vec3(node, "top")?,
+                f32_field(node, "radius")?,
+                opt_f32(node, "top_radius")?.unwrap_or(0.0),
+                opt_bool(node, "caps")?.unwrap_or(true),
+                self.node_material(node)?,
+            )?),
+            "sdf" => {
+                let shape = node
+                    .get("shape")
+                    .ok_or_else(|| anyhow!("missing 'shape'"))?;
+                let field = parse_field(shape).context("invalid 'shape'")?;
+                Box::new(Sdf::new(field, self.node_material(node)?)?)
+            }
+            "capsule" => Box::new(Capsule::new(
+                vec3(node, "base")?,
+                vec3(node, "top")?,
+                f32_field(node, "radius")?,
+                self.node_material(node)?,
+            )?),
+            "triangle" => Box::new(Triangle::new(
+                vec3(node, "p0")?,
+                vec3(node, "p1")?,
+                vec3(node, "p2")?,
+                self.node_material(node)?,
+            )),
+            "ellipse" => Box::new(Quad::ellipse(
+                vec3(node, "center")?,
+                vec3(node, "u")?,
+                vec3(node, "v")?,
+                self.node_material(node)?,
+            )),
+            "plane" => Box::new(Plane::new(
+                vec3(node, "point")?,
+                vec3(node, "normal")?,
+                self.node_material(node)?,
+            )?),
+            "disk" => Box::new(Quad::disk(
+                vec3(node, "center")?,
+                vec3(node, "normal")?,
+                f32_field(node, "radius")?,
+                opt_f32(node, "inner_radius")?.unwrap_or(0.0),
+                self.node_material(node)?,
+            )?),
+            "polygon" => {
+                let sides = opt_u32(node, "sides")?.ok_or_else(|| anyhow!("missing 'sides'"))?;
+                if sides < 3 {
+                    bail!("a polygon needs at least 3 sides");
+                }
+                Box::new(Quad::polygon(
+                    vec3(node, "center")?,
+                    vec3(node, "u")?,
+                    vec3(node, "v")?,
+                    sides,
+                    self.node_material(node)?,
+                ))
+            }
+            "patch" => Box::new(BilinearPatch::new(
+                vec3(node, "p00")?,
+                vec3(node, "p10")?,
+                vec3(node, "p01")?,
+                vec3(node, "p11")?,
+                self.node_material(node)?,
+            )),
+            "box" => Box::new(make_box(
+                vec3(node, "min")?,
+                vec3(node, "max")?,
+                self.node_material(node)?,
+            )),
+            "medium" => {
+                let boundary = node
+                    .get("boundary")
+                    .ok_or_else(|| anyhow!("missing 'boundary'"))?;
+                let density = f32_field(node, "density")?;
+                if density.is_nan() || density <= 0.0 {
+                    bail!("a medium's density must be positive");
+                }
+                let mut medium = Medium::new(
+                    self.parse_node(boundary).context("in 'boundary'")?,
+                    density,
+                    self.texture(node, "albedo")?,
+                );
+                if node.get("density_map").is_some() {
+                    medium = medium.with_density_map(self.texture(node, "density_map")?);
+                }
+                Box::new(medium)
+            }
+            "group" if plain_spheres(node)? => Box::new(self.parse_spheres(node)?),
+            "group" => {
+                let mut children: HittableVec = vec![];
+                for (idx, child) in array(node, "children")?.iter().enumerate() {
+                    children.push(
+                        self.parse_node(child)
+                            .with_context(|| format!("child #{}", idx))?,
+                    );
+                }
+                Box::new(Bvh::with_quality(children, self.bvh))
+            }
+            // More than two children combine from the first on, so a
+            // difference cuts all the others out of the first.
+            kind @ ("union" | "intersection" | "difference") => {
+                let operation = Operation::parse(kind).unwrap();
+                let children = array(node, "children")?;
+                if children.len() < 2 {
+                    bail!("'{}' needs at least 2 children", kind);
+                }
+                let mut children = children.iter().enumerate().map(|(idx, child)| {
+                    self.parse_node(child)
+                        .with_context(|| format!("child #{}", idx))
+                });
+                let first = children.next().unwrap()?;
+                children.try_fold(first, |csg, child| -> Result<Box<dyn Hittable>> {
+                    Ok(Box::new(Csg::new(operation, csg, child?)))
+                })?
+            }
+            other => match self.registry.make_hittable(other, node, self) {
+                Some(object) => object?,
+                None => bail!("unknown object type '{}'", other),
+            },
+        };
+
+        match node.get("transform") {
+            Some(transform) => apply_transform(transform, object),
+            None => Ok(object),
+        }
+    }
+
+    // A group of spheres that don't move as one `Spheres`, with children
+    // naming or defining the same material sharing it.
+    fn parse_spheres(&self, node: &Json) -> Result<Spheres> {
+        let (mut centers, mut radii, mut sphere_materials) = (vec![], vec![], vec![]);
+        let (mut defs, mut materials): (Vec<&Json>, _) = (vec![], vec![]);
+        for (idx, child) in array(node, "children")?.iter().enumerate() {
+            let mut parse = || -> Result<_> {
+                let def = child
+                    .get("material")
+                    .ok_or_else(|| anyhow!("missing 'material'"))?;
+                let mat = match defs.iter().position(|&known| known == def) {
+                    Some(mat) => mat,
+                    None => {
+                        materials.push(self.material_ref(def)?);
+                        defs.push(def);
+                        defs.len() - 1
+                    }
+                };
+                Ok((vec3(child, "center")?, f32_field(child, "radius")?, mat))
+            };
+            let (center, radius, mat) = parse().with_context(|| format!("child #{}", idx))?;
+            centers.push(center);
+            radii.push(radius);
+            sphere_materials.push(mat as u32);
+        }
+        Spheres::new(centers, radii, materials, sphere_materials)
+    }
+
+    // Second copies of the emissive spheres, quads, ellipses, polygons and
+    // triangles in `node`, including those in groups, for the camera to
+    // sample directly. Lights in meshes, models and instances are only found
+    // by chance.
+    fn parse_lights(&self, node: &Json) -> Result<HittableVec> {
+        let lights = match string(node, "type")? {
+            // Moving spheres would be sampled where they are at time 0 only.
+            "sphere" if node.get("center1").is_some() => return Ok(vec![]),
+            "sphere" | "quad" | "ellipse" | "disk" | "polygon" | "triangle" => {
+                if self.node_material(node)?.emitted() == Color3::ZERO {
+                    return Ok(vec![]);
+                }
+                // Already transformed.
+                return Ok(vec![self.parse_node(node)?]);
+            }
+            // Parsed just before, as part of the world.
+            "mesh" | "obj" | "gltf" | "stl" | "ply" | "usd" | "extrude" | "text" => {
+                let mesh = self
+                    .emissive_meshes
+                    .borrow()
+                    .get(&(node as *const Json))
+                    .cloned();
+                match mesh {
+                    Some(mesh) => vec![Box::new(mesh) as Box<dyn Hittable>],
+                    None => return Ok(vec![]),
+                }
+            }
+            "group" => {
+                let mut lights = vec![];
+                for child in array(node, "children")? {
+                    lights.extend(self.parse_lights(child)?);
+                }
+                lights
+            }
+            _ => return Ok(vec![]),
+        };
+        match node.get("transform") {
+            Some(transform) => lights
+                .into_iter()
+                .map(|light| apply_transform(transform, light))
+                .collect(),
+            None => Ok(lights),
+        }
+    }
+
+    pub fn resolve(&self, reference: &str) -> Result<PathBuf> {
+        resolve(self.resolver, self.base_dirs, reference)
+    }
+}
+
+// Looks `reference` up next to each of `base_dirs` in turn.
+fn resolve(resolver: &AssetResolver, base_dirs: &[PathBuf], reference: &str) -> Result<PathBuf> {
+    let mut result = resolver.resolve(reference, &base_dirs[0]);
+    for dir in &base_dirs[1..] {
+        if result.is_ok() {
+            break;
+        }
+        result = resolver.resolve(reference, dir);
+    }
+    result
+}
+
+// Whether a group holds nothing but spheres that don't move, to load as
+// one `Spheres`.
+fn plain_spheres(node: &Json) -> Result<bool> {
+    let children = array(node, "children")?;
+    Ok(!children.is_empty()
+        && children.iter().all(|child| {
+            matches!(child.get("type"), Some(Json::String(kind)) if kind == "sphere")
+                && child.get("center1").is_none()
+                && child.get("transform").is_none()
+        }))
+}
+
+type LoadModel = fn(&Path, Material, Resolve) -> Result<Mesh>;
+
+fn model_loader(kind: &str) -> Option<LoadModel> {
+    Some(match kind {
+        "obj" => obj::load,
+        "gltf" => gltf::load,
+        "stl" => stl::load,
+        "ply" => ply::load,
+        "usd" => usd::load,
+        _ => return None,
+    })
+}
+
+// Files a model refers to are looked up next to it first.
+fn load_model_file(
+    resolver: &AssetResolver,
+    base_dirs: &[PathBuf],
+    path: &Path,
+    default: Material,
+    load: LoadModel,
+) -> Result<Mesh> {
+    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    load(path, default, &|reference| {
+        resolver
+            .resolve(reference, &dir)
+            .or_else(|_| resolve(resolver, base_dirs, reference))
+    })
+}
+
+// A file decoded by `SceneLoader::preload_textures`.
+enum Preloaded {
+    Image(ImageTexture),
+    Brdf(MeasuredBrdf),
+    Environment(EnvironmentMap),
+}
+
+// The files of the image textures and measured materials anywhere in
+// `value`, with their types.
+fn texture_files<'j>(value: &'j Json, files: &mut Vec<(&'static str, &'j str)>) {
+    match value {
+        Json::Object(fields) => {
+            let kind = match value.get("type").and_then(Json::as_str) {
+                Some("image") => Some("image"),
+                Some("measured") => Some("measured"),
+                _ => None,
+            };
+            if let (Some(kind), Some(file)) = (kind, value.get("file").and_then(Json::as_str)) {
+                files.push((kind, file));
+            }
+            fields
+                .values()
+                .for_each(|value| texture_files(value, files));
+        }
+        Json::Array(values) => values.iter().for_each(|value| texture_files(value, files)),
+        _ => {}
+    }
+}
+
+// The `obj`, `gltf`, `stl`, `ply` and `usd` nodes anywhere in `value`.
+fn model_nodes<'j>(value: &'j Json, nodes: &mut Vec<&'j Json>) {
+    match value {
+        Json::Object(fields) => {
+            let kind = value.get("type").and_then(Json::as_str);
+            if kind.and_then(model_loader).is_some() {
+                nodes.push(value);
+            }
+            fields.values().for_each(|value| model_nodes(value, nodes));
+        }
+        Json::Array(values) => values.iter().for_each(|value| model_nodes(value, nodes)),
+        _ => {}
+    }
+}
+
+// A distance field shape of an `sdf` node.
+fn parse_field(shape: &Json) -> Result<Field> {
+    Ok(match string(shape, "type")? {
+        "sphere" => Field::Sphere {
+            center: vec3(shape, "center")?,
+            radius: f32_field(shape, "radius")?,
+        },
+        "box" => Field::Box {
+            center: vec3(shape, "center")?,
+            half_size: vec3(shape, "size")? / 2.0,
+        },
+        "torus" => Field::Torus {
+            center: vec3(shape, "center")?,
+            major: f32_field(shape, "radius")?,
+            minor: f32_field(shape, "tube_radius")?,
+        },
+        "mandelbulb" => Field::Mandelbulb {
+            center: vec3(shape, "center")?,
+            radius: f32_field(shape, "radius")?,
+            power: opt_f32(shape, "power")?.unwrap_or(8.0),
+            iterations: opt_u32(shape, "iterations")?.unwrap_or(10),
+        },
+        // More than two children blend from the first on.
+        "smooth_union" => {
+            let smoothness = f32_field(shape, "smoothness")?;
+            let children = array(shape, "children")?;
+            if children.len() < 2 {
+                bail!("'smooth_union' needs at least 2 children");
+            }
+            let mut children = children
+                .iter()
+                .enumerate()
+                .map(|(idx, child)| parse_field(child).with_context(|| format!("child #{}", idx)));
+            let first = children.next().unwrap()?;
+            children.try_fold(first, |blend, child| -> Result<Field> {
+                Ok(Field::SmoothUnion(
+                    Box::new(blend),
+                    Box::new(child?),
+                    smoothness,
+                ))
+            })?
+        }
+        other => bail!("unknown distance field type '{}'", other),
+    })
+}
+
+fn apply_transform(transform: &Json, object: Box<dyn Hittable>) -> Result<Box<dyn Hittable>> {
+    Ok(Box::new(Transform::new(
+        transform_matrix(transform)?,
+        object,
+    )))
+}
+
+// The matrix of a transform object, or of an array of them applied first to
+// last.
+fn transform_matrix(transform: &Json) -> Result<Affine3A> {
+    if let Some(steps) = transform.as_array() {
+        let mut matrix = Affine3A::IDENTITY;
+        for (idx, step) in steps.iter().enumerate() {
+            matrix =
+                transform_matrix(step).with_context(|| format!("transform #{}", idx))? * matrix;
+        }
+        return Ok(matrix);
+    }
+    let mut matrix = match opt_channels(transform, "scale")? {
+        Some(scale) => Affine3A::from_scale(scale),
+        None => Affine3A::IDENTITY,
+    };
+    if let Some(rows) = transform.get("matrix") {
+        matrix = matrix_rows(rows)? * matrix;
+    }
+    for (key, axis) in [
+        ("rotate_x", Vec3::X),
+        ("rotate_y", Vec3::Y),
+        ("rotate_z", Vec3::Z),
+    ] {
+        if let Some(angle) = opt_f32(transform, key)? {
+            matrix = Affine3A::from_axis_angle(axis, angle.to_radians()) * matrix;
+        }
+    }
+    if let Some(offset) = opt_vec3(transform, "translate")? {
+        matrix = Affine3A::from_translation(offset) * matrix;
+    }
+    if matrix.matrix3.determinant() == 0.0 {
+        bail!("a transform must be invertible");
+    }
+    Ok(matrix)
+}
+
+// A matrix given as 3 or 4 rows of 4 numbers; a fourth row must be
+// [0, 0, 0, 1].
+fn matrix_rows(value: &Json) -> Result<Affine3A> {
+    let rows = value
+        .as_array()
+        .ok_or_else(|| anyhow!("'matrix' must be an array of rows"))?;
+    let mut matrix = [
+        [0.0, 0.0, 0.0, 0.0],
+        [0.0, 0.0, 0.0, 0.0],
+        [0.0, 0.0, 0.0, 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ];
+    if !(3..=4).contains(&rows.len()) {
+        bail!("'matrix' must have 3 or 4 rows");
+    }
+    for (idx, row) in rows.iter().enumerate() {
+        matrix[idx] = numbers(row, Json::as_f32)
+            .with_context(|| format!("'matrix' row #{} must be 4 numbers", idx))?;
+    }
+    if matrix[3] != [0.0, 0.0, 0.0, 1.0] {
+        bail!("the last row of 'matrix' must be [0, 0, 0, 1]");
+    }
+    Ok(Affine3A::from_mat4(
+        Mat4::from_cols_array_2d(&matrix).transpose(),
+    ))
+}
+
+fn string<'a>(obj: &'a Json, key: &str) -> Result<&'a str> {
+    obj.get(key)
+        .ok_or_else(|| anyhow!("missing '{}'", key))?
+        .as_str()
+        .ok_or_else(|| anyhow!("'{}' must be a string", key))
+}
+
+fn array<'a>(obj: &'a Json, key: &str) -> Result<&'a [Json]> {
+    match obj.get(key) {
+        Some(value) => value
+            .as_array()
+            .ok_or_else(|| anyhow!("'{}' must be an array", key)),
+        None => Ok(&[]),
+    }
+}
+
+fn f32_field(obj: &Json, key: &str) -> Result<f32> {
+    opt_f32(obj, key)?.ok_or_else(|| anyhow!("missing '{}'", key))
+}
+
+fn opt_f32(obj: &Json, key: &str) -> Result<Option<f32>> {
+    obj.get(key)
+        .map(|v| {
+            v.as_f32()
+                .ok_or_else(|| anyhow!("'{}' must be a number", key))
+        })
+        .transpose()
+}
+
+fn opt_bool(obj: &Json, key: &str) -> Result<Option<bool>> {
+    obj.get(key)
+        .map(|v| {
+            v.as_bool()
+                .ok_or_else(|| anyhow!("'{}' must be a boolean", key))
+        })
+        .transpose()
+}
+
+fn opt_u32(obj: &Json, key: &str) -> Result<Option<u32>> {
+    obj.get(key)
+        .map(|v| {
+            v.as_u32()
+                .ok_or_else(|| anyhow!("'{}' must be a non-negative integer", key))
+        })
+        .transpose()
+}
+
+fn vec3(obj: &Json, key: &str) -> Result<Vec3> {
+    opt_vec3(obj, key)?.ok_or_else(|| anyhow!("missing '{}'", key))
+}
+
+// A number applying to all three channels, or a vec3.
+fn opt_channels(obj: &Json, key: &str) -> Result<Option<Vec3>> {
+    match obj.get(key).and_then(Json::as_f32) {
+        Some(v) => Ok(Some(Vec3::splat(v))),
+        None => opt_vec3(obj, key),
+    }
+}
+
+// An array of exactly N values of the type `get` extracts.
+fn numbers<T, const N: usize>(value: &Json, get: fn(&Json) -> Option<T>) -> Option<[T; N]> {
+    let values: Vec<T> = value.as_array()?.iter().map(get).collect::<Option<_>>()?;
+    values.try_into().ok()
+}
+
+fn points(value: &Json) -> Result<Vec<Vec2>> {
+    value
+        .as_array()
+        .ok_or_else(|| anyhow!("expected an array of [x, y] points"))?
+        .iter()
+        .enumerate()
+        .map(|(idx, p)| {
+            let [x, y] = numbers(p, Json::as_f32)
+                .with_context(|| format!("point #{} must be 2 numbers", idx))?;
+            Ok(Vec2::new(x, y))
+        })
+        .collect()
+}
+
+fn opt_vec3(obj: &Json, key: &str) -> Result<Option<Vec3>> {
+    let value = match obj.get(key) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.as_array() {
+        Some([x, y, z]) => match (x.as_f32(), y.as_f32(), z.as_f32()) {
+            (Some(x), Some(y), Some(z)) => Ok(Some(Vec3::new(x, y, z))),
+            _ => bail!("'{}' must contain numbers", key),
+        },
+        _ => bail!("'{}' must be an array of 3 numbers", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittables::Interval;
+    use crate::render::Ray;
+    use crate::{color3, point3, Point3};
+
+    fn from_text(text: &str) -> Result<Scene> {
+        from_text_with(text, &Registry::default())
+    }
+
+    fn from_text_with(text: &str, registry: &Registry) -> Result<Scene> {
+        let doc = Json::parse(text)?;
+        Scene::from_json(
+            &doc,
+            PathBuf::from("test.json"),
+            &[],
+            &AssetResolver::from_env(),
+            registry,
+            None,
+        )
+    }
+
+    // Writes `files` into a directory of their own for one test, and loads
+    // the first as a scene document.
+    fn load_files(test: &str, files: &[(&str, &str)]) -> Result<Json> {
+        let dir = std::env::temp_dir().join(format!("scene-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        for (name, text) in files {
+            std::fs::write(dir.join(name), text)?;
+        }
+        let path = dir.join(files[0].0);
+        let doc = load_document(
+            &path,
+            &AssetResolver::from_env(),
+            &[],
+            &mut vec![],
+            &mut vec![],
+        );
+        std::fs::remove_dir_all(&dir)?;
+        doc
+    }
+
+    fn camera_setting(doc: &Json, key: &str) -> Option<u32> {
+        doc.get("camera")?.get(key)?.as_u32()
+    }
+
+    #[test]
+    fn including_documents_override_their_includes() {
+        let doc = load_files(
+            "override",
+            &[
+                (
+                    "main.json",
+                    r#"{ "include": "base.json", "camera": { "width": 20 } }"#,
+                ),
+                (
+                    "base.json",
+                    r#"{ "camera": { "width": 10, "height": 10 }, "bvh": "median" }"#,
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(camera_setting(&doc, "width"), Some(20));
+        assert_eq!(camera_setting(&doc, "height"), Some(10));
+        assert_eq!(doc.get("bvh").and_then(Json::as_str), Some("median"));
+        assert!(doc.get("include").is_none());
+    }
+
+    #[test]
+    fn includes_merge_in_order() {
+        let doc = load_files(
+            "order",
+            &[
+                (
+                    "main.json",
+                    r#"{ "include": ["a.json", "b.json"], "objects": [{ "name": "c" }] }"#,
+                ),
+                (
+                    "a.json",
+                    r#"{ "camera": { "width": 1, "samples": 1 }, "objects": [{ "name": "a", "radius": 1 }] }"#,
+                ),
+                (
+                    "b.json",
+                    r#"{ "camera": { "width": 2 }, "objects": [{ "name": "b" }, { "name": "a", "radius": 2 }] }"#,
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!(camera_setting(&doc, "width"), Some(2));
+        assert_eq!(camera_setting(&doc, "samples"), Some(1));
+        // Nodes of the same name merge in place; the rest are appended.
+        let objects = doc.get("objects").and_then(Json::as_array).unwrap();
+        let names: Vec<_> = objects
+            .iter()
+            .map(|node| node.get("name").and_then(Json::as_str).unwrap())
+            .collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(objects[0].get("radius").and_then(Json::as_u32), Some(2));
+    }
+
+    #[test]
+    fn self_includes_are_cycles() {
+        let error =
+            load_files("cycle", &[("main.json", r#"{ "include": "main.json" }"#)]).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("include cycle"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn placeholders_take_defined_values_or_defaults() {
+        let defines = [
+            ("ior".to_string(), "1.5".to_string()),
+            ("ior".to_string(), "1.7".to_string()),
+            ("name".to_string(), "glass".to_string()),
+        ];
+        // The last definition of a name wins.
+        assert_eq!(
+            substitute(r#"{"ior": ${ior}, "name": "${name}-ball"}"#, &defines).unwrap(),
+            r#"{"ior": 1.7, "name": "glass-ball"}"#
+        );
+        assert_eq!(
+            substitute("${samples:-50} ${ior:-1.0}", &defines).unwrap(),
+            "50 1.7"
+        );
+        assert_eq!(
+            substitute("no placeholders", &[]).unwrap(),
+            "no placeholders"
+        );
+    }
+
+    #[test]
+    fn undefined_placeholders_are_errors() {
+        let error = substitute("${samples}", &[]).unwrap_err().to_string();
+        assert!(error.contains("'samples' is not defined"), "{}", error);
+        assert!(error.contains("--define samples=VALUE"), "{}", error);
+        let error = substitute("${samples", &[]).unwrap_err().to_string();
+        assert!(error.contains("unterminated placeholder"), "{}", error);
+    }
+
+    #[test]
+    fn builds_objects_and_materials() {
+        let scene = from_text(
+            r#"{
+                "camera": { "width": 40, "height": 20, "samples": 8 },
+                "materials": { "red": { "type": "lambertian", "albedo": [0.8, 0.1, 0.1] } },
+                "objects": [
+                    { "type": "sphere", "center": [0, 0, -5], "radius": 1, "material": "red" },
+                    { "type": "quad", "q": [-1, -1, -10], "u": [2, 0, 0], "v": [0, 2, 0],
+                      "material": { "type": "metal", "albedo": [0.9, 0.9, 0.9], "fuzz": 0.25 } },
+                    { "type": "sphere", "center": [5, 5, 5], "radius": 0.5,
+                      "material": { "type": "light", "emit": [4, 4, 4] } }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!((scene.width, scene.height), (40, 20));
+        assert_eq!(scene.world.len(), 3);
+
+        let ray_t = Interval::new(0.0, f32::INFINITY);
+        let hit = scene
+            .world
+            .hit(&Ray::new(Point3::ZERO, -Vec3::Z), ray_t)
+            .unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-5);
+        let Material::Lambertian { albedo } = hit.material else {
+            panic!("{}", hit.material.type_name());
+        };
+        assert_eq!(albedo.value(hit.uv, hit.p), color3(0.8, 0.1, 0.1));
+        // Past the sphere, in the corner of the quad behind it.
+        let hit = scene
+            .world
+            .hit(&Ray::new(point3(0.9, 0.9, 0.0), -Vec3::Z), ray_t)
+            .unwrap();
+        assert!(matches!(hit.material, Material::Metal { fuzz, .. } if *fuzz == 0.25));
+        assert_eq!(
+            scene.world[2].bounding_box().centroid(),
+            point3(5.0, 5.0, 5.0)
+        );
+    }
+
+    #[test]
+    fn sphere_groups_share_their_materials() {
+        let scene = from_text(
+            r#"{
+                "materials": { "red": { "type": "lambertian", "albedo": [0.8, 0.1, 0.1] } },
+                "objects": [
+                    { "type": "group", "children": [
+                        { "type": "sphere", "center": [0, 0, -5], "radius": 1, "material": "red" },
+                        { "type": "sphere", "center": [3, 0, -5], "radius": 1, "material": "red" },
+                        { "type": "sphere", "center": [6, 0, -5], "radius": 1,
+                          "material": { "type": "metal", "albedo": [0.9, 0.9, 0.9], "fuzz": 0.25 } }
+                    ] },
+                    { "type": "group", "children": [
+                        { "type": "sphere", "center": [0, 5, -5], "radius": 1, "material": "red" },
+                        { "type": "sphere", "center": [0, 8, -5], "center1": [0, 9, -5],
+                          "radius": 1, "material": "red" }
+                    ] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let ray_t = Interval::new(0.0, f32::INFINITY);
+        let material = |x: f32, y: f32| {
+            let ray = Ray::new(point3(x, y, 0.0), -Vec3::Z);
+            scene.world.hit(&ray, ray_t).unwrap().material.type_name()
+        };
+        assert_eq!(material(3.0, 0.0), "lambertian");
+        assert_eq!(material(6.0, 0.0), "metal");
+        // A group with a sphere that moves loads as before.
+        assert_eq!(material(0.0, 5.0), "lambertian");
+        assert_eq!(material(0.0, 8.0), "lambertian");
+
+        let error = from_text(
+            r#"{ "objects": [{ "type": "group", "children": [
+                { "type": "sphere", "center": [0, 0, 0], "radius": 1, "material": "red" }
+            ] }] }"#,
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{:#}", error).contains("child #0: undefined material 'red'"));
+    }
+
+    #[test]
+    fn scales_stretch_objects_along_each_axis() {
+        // An ellipsoid x^2 / 4 + y^2 + (z + 10)^2 / 0.25 = 1, a box 3 times
+        // as large about its corner at the origin and a sphere mirrored from
+        // x = 2 to x = -2.
+        let scene = from_text(
+            r#"{
+                "materials": { "white": { "type": "lambertian", "albedo": [0.8, 0.8, 0.8] } },
+                "objects": [
+                    { "type": "sphere", "center": [0, 0, 0], "radius": 1, "material": "white",
+                      "transform": { "scale": [2, 1, 0.5], "translate": [0, 0, -10] } },
+                    { "type": "box", "min": [0, 0, 0], "max": [1, 1, 1], "material": "white",
+                      "transform": { "scale": 3, "translate": [4, 0, -10] } },
+                    { "type": "sphere", "center": [2, 5, -10], "radius": 1, "material": "white",
+                      "transform": { "scale": [-1, 1, 1] } }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let ray_t = Interval::new(0.0, f32::INFINITY);
+        let hit_from = |x: f32, y: f32| {
+            let ray = Ray::new(point3(x, y, 0.0), -Vec3::Z);
+            let hit = scene.world.hit(&ray, ray_t).unwrap();
+            assert!((hit.normal.length() - 1.0).abs() < 1e-5);
+            assert!((hit.shading_normal.length() - 1.0).abs() < 1e-5);
+            assert!(hit.front_face);
+            hit
+        };
+
+        // Halfway out along x, the ellipsoid's z is sqrt(0.75) / 2 short of
+        // its center, and the gradient (x / 4, y, 4 (z + 10)) is the normal.
+        let hit = hit_from(1.0, 0.0);
+        let dz = 0.75f32.sqrt() / 2.0;
+        assert!((hit.t - (10.0 - dz)).abs() < 1e-4, "{}", hit.t);
+        assert!(hit.p.distance(point3(1.0, 0.0, -10.0 + dz)) < 1e-4);
+        let gradient = Vec3::new(0.25, 0.0, 4.0 * dz).normalize();
+        assert!(hit.normal.distance(gradient) < 1e-4, "{}", hit.normal);
+        let hit = hit_from(0.0, 0.9);
+        assert!(hit.p.z > -10.0 && (hit.p.y - 0.9).abs() < 1e-5);
+
+        // The box now spans 0 to 3 from its corner, so its front is at z = -7.
+        let hit = hit_from(6.5, 2.5);
+        assert!((hit.t - 7.0).abs() < 1e-4, "{}", hit.t);
+        assert!(hit.normal.distance(Vec3::Z) < 1e-5);
+        assert!(scene
+            .world
+            .hit(&Ray::new(point3(7.5, 0.5, 0.0), -Vec3::Z), ray_t)
+            .is_none());
+
+        // Mirrored surfaces still face out.
+        let hit = hit_from(-2.0, 5.0);
+        assert!((hit.t - 9.0).abs() < 1e-4, "{}", hit.t);
+        assert!(hit.normal.distance(Vec3::Z) < 1e-5);
+        assert!(scene
+            .world
+            .hit(&Ray::new(point3(2.0, 5.0, 0.0), -Vec3::Z), ray_t)
+            .is_none());
+
+        let error = from_text(
+            r#"{ "objects": [{ "type": "sphere", "center": [0, 0, 0], "radius": 1,
+                 "material": { "type": "lambertian", "albedo": [1, 1, 1] },
+                 "transform": { "scale": [1, 0, 1] } }] }"#,
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{:#}", error).contains("must be invertible"));
+    }
+
+    // The albedo of a material on a wall at z = 0, at points on it.
+    fn albedo_at(material: &str, points: &[(f32, f32)]) -> Result<Vec<Color3>> {
+        let scene = from_text(&format!(
+            r#"{{ "objects": [{{ "type": "quad", "q": [-5, -5, 0], "u": [10, 0, 0],
+                  "v": [0, 10, 0], "material": {} }}] }}"#,
+            material
+        ))?;
+        let ray_t = Interval::new(0.0, f32::INFINITY);
+        Ok(points
+            .iter()
+            .map(|&(x, y)| {
+                let ray = Ray::new(point3(x, y, 1.0), -Vec3::Z);
+                let hit = scene.world.hit(&ray, ray_t).unwrap();
+                let Material::Lambertian { albedo } = hit.material else {
+                    panic!("{}", hit.material.type_name());
+                };
+                albedo.value(hit.uv, hit.p)
+            })
+            .collect())
+    }
+
+    #[test]
+    fn marble_preset_veins_its_color() {
+        let points: Vec<_> = (0..100).map(|idx| (idx as f32 * 0.05, 0.3)).collect();
+        let colors = albedo_at(r#"{ "type": "marble", "color": [1, 0.5, 0] }"#, &points).unwrap();
+        assert!(colors.iter().all(|c| c.z == 0.0 && c.y == c.x / 2.0));
+        let (dark, light) = colors
+            .iter()
+            .fold((1f32, 0f32), |(lo, hi), c| (lo.min(c.x), hi.max(c.x)));
+        assert!(dark < 0.3 && light > 0.7, "{} {}", dark, light);
+        let defaults = albedo_at(r#"{ "type": "marble" }"#, &points).unwrap();
+        assert!(defaults.iter().all(|c| c.x <= 0.92 && c.z <= 0.86));
+    }
+
+    #[test]
+    fn wood_preset_rings_between_its_colors() {
+        let points: Vec<_> = (0..100).map(|idx| (idx as f32 * 0.01, 0.0)).collect();
+        let wood = r#"{ "type": "wood", "rings": 10, "light": [1, 1, 1], "dark": [0, 0, 1] }"#;
+        let colors = albedo_at(wood, &points).unwrap();
+        assert!(colors.iter().all(|c| c.z == 1.0 && c.x == c.y));
+        let (dark, light) = colors
+            .iter()
+            .fold((1f32, 0f32), |(lo, hi), c| (lo.min(c.x), hi.max(c.x)));
+        assert!(dark < 0.1 && light > 0.9, "{} {}", dark, light);
+        // Rings are circles around the y axis: on the wall, columns.
+        let shifted: Vec<_> = points.iter().map(|&(x, _)| (x, 0.001)).collect();
+        let nearby = albedo_at(wood, &shifted).unwrap();
+        let drift = colors
+            .iter()
+            .zip(&nearby)
+            .map(|(a, b)| a.distance(*b))
+            .fold(0.0, f32::max);
+        assert!(drift < 0.1, "{}", drift);
+    }
+
+    #[test]
+    fn brick_preset_lays_courses_in_mortar() {
+        let brick = r#"{ "type": "brick", "size": [0.2, 0.1, 0], "joint": 0.02,
+                         "brick": [0.6, 0.2, 0.1], "mortar": 0.8 }"#;
+        let (red, mortar) = (color3(0.6, 0.2, 0.1), Color3::splat(0.8));
+        let colors = albedo_at(
+            brick,
+            &[(0.1, 0.05), (0.1, 0.11), (0.21, 0.05), (0.21, 0.15)],
+        )
+        .unwrap();
+        assert_eq!(colors, vec![red, mortar, mortar, red]);
+        let defaults = albedo_at(r#"{ "type": "brick" }"#, &[(0.1, 0.03)]).unwrap();
+        assert_eq!(defaults[0], color3(0.55, 0.2, 0.12));
+        let error = albedo_at(r#"{ "type": "brick", "size": [0.2, 0.01, 0] }"#, &[]);
+        assert!(format!("{:#}", error.err().unwrap()).contains("'joint'"));
+    }
+
+    #[test]
+    fn malformed_documents_say_what_is_wrong() {
+        let error = |text: &str| format!("{:#}", from_text(text).err().unwrap());
+        let message = error(r#"{ "objects": [{ "type": "sphere", "center": [0, 0, 0] }] }"#);
+        assert!(message.contains("object #0") && message.contains("missing 'radius'"));
+        let message = error(
+            r#"{ "objects": [{ "type": "sphere", "center": [0, 0, 0], "radius": 1, "material": "gold" }] }"#,
+        );
+        assert!(message.contains("undefined material 'gold'"), "{}", message);
+        let message = error(r#"{ "materials": { "odd": { "type": "velvet" } } }"#);
+        assert!(
+            message.contains("unknown material type 'velvet'"),
+            "{}",
+            message
+        );
+        let message = error(r#"{ "camera": { "width": "wide" } }"#);
+        assert!(message.contains("'width'"), "{}", message);
+        assert!(error("{ \"objects\": [ }").contains("JSON parse error"));
+    }
+
+    #[test]
+    fn registered_types_are_built_from_scene_files() {
+        // A gray material by its shade, and a unit ball at a given height
+        // with its material resolved the way built-in objects do.
+        let registry = Registry::default()
+            .material("gray", |def, _| {
+                let shade = opt_f32(def, "shade")?.context("missing 'shade'")?;
+                Ok(Material::new_lambertian(shade, shade, shade))
+            })
+            .hittable("ball", |node, loader| {
+                let height = opt_f32(node, "height")?.unwrap_or(0.0);
+                let material = loader.node_material(node)?;
+                Ok(Box::new(Sphere::new(
+                    point3(0.0, height, 0.0),
+                    1.0,
+                    material,
+                )))
+            })
+            .hittable("sphere", |_, _| bail!("built-in types come first"));
+        let scene = from_text_with(
+            r#"{
+                "materials": { "dark": { "type": "gray", "shade": 0.2 } },
+                "objects": [
+                    { "type": "ball", "height": 5, "material": "dark" },
+                    { "type": "sphere", "center": [0, -5, 0], "radius": 1,
+                      "material": { "type": "gray", "shade": 0.7 } }
+                ]
+            }"#,
+            &registry,
+        )
+        .unwrap();
+        assert_eq!(scene.world.len(), 2);
+
+        let ray_t = Interval::new(0.0, f32::INFINITY);
+        let albedo_at = |y: f32| {
+            let ray = Ray::new(point3(0.0, y, 10.0), -Vec3::Z);
+            let hit = scene.world.hit(&ray, ray_t).unwrap();
+            assert!((hit.t - 9.0).abs() < 1e-5, "{}", hit.t);
+            match hit.material {
+                Material::Lambertian { albedo } => albedo.value(Vec2::ZERO, hit.p),
+                _ => panic!("not the registered material"),
+            }
+        };
+        assert_eq!(albedo_at(5.0), Color3::splat(0.2));
+        assert_eq!(albedo_at(-5.0), Color3::splat(0.7));
+
+        // Errors from a factory are reported with the object they came from,
+        // and types registered nowhere are still refused.
+        let error = |object: &str| {
+            let text = format!(r#"{{ "objects": [{}] }}"#, object);
+            format!("{:#}", from_text_with(&text, &registry).err().unwrap())
+        };
+        let missing = error(r#"{ "type": "ball", "material": { "type": "gray" } }"#);
+        assert!(missing.contains("missing 'shade'"), "{}", missing);
+        let unknown = error(r#"{ "type": "cube", "material": { "type": "gray", "shade": 1 } }"#);
+        assert!(
+            unknown.contains("unknown object type 'cube'"),
+            "{}",
+            unknown
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use crate::hittables::{Aabb, Hit, Hittable, Interval};
+use crate::json::Json;
+use crate::materials::Material;
+use crate::render::Ray;
+use crate::Point3;
+use anyhow::{bail, Result};
+use glam::{vec2, vec3, Vec3};
+use std::sync::Arc;
+
+// Steps a ray may take before it counts as a miss; grazing rays near the
+// surface of fractals use the most.
+const MAX_STEPS: u32 = 512;
+// How close to the surface, as a fraction of the size of the field's box,
+// counts as on it.
+const TOLERANCE: f32 = 1e-4;
+
+// The distance from a point to a surface, negative inside it. Ray marching
+// only needs it never to overestimate.
+pub type DistanceFn = Arc<dyn Fn(Point3) -> f32 + Send + Sync>;
+
+// Shapes given by their distance fields.
+#[derive(Clone)]
+pub enum Field {
+    Sphere {
+        center: Point3,
+        radius: f32,
+    },
+    Box {
+        center: Point3,
+        half_size: Vec3,
+    },
+    // A ring round the y axis: a tube of `minor` radius whose center circle
+    // has `major` radius.
+    Torus {
+        center: Point3,
+        major: f32,
+        minor: f32,
+    },
+    // The power-`power` Mandelbulb fractal scaled to about `radius`, with
+    // more detail the more `iterations`.
+    Mandelbulb {
+        center: Point3,
+        radius: f32,
+        power: f32,
+        iterations: u32,
+    },
+    // The union of the two, blended over about `smoothness` where they meet.
+    SmoothUnion(Box<Field>, Box<Field>, f32),
+    // Any distance function, with a box the whole surface lies in.
+    Custom(DistanceFn, Aabb),
+}
+
+impl Field {
+    pub fn distance(&self, p: Point3) -> f32 {
+        match self {
+            Field::Sphere { center, radius } => p.distance(*center) - radius,
+            Field::Box { center, half_size } => {
+                let q = (p - *center).abs() - *half_size;
+                q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+            }
+            Field::Torus {
+                center,
+                major,
+                minor,
+            } => {
+                let q = p - *center;
+                vec2(vec2(q.x, q.z).length() - major, q.y).length() - minor
+            }
+            Field::Mandelbulb {
+                center,
+                radius,
+                power,
+                iterations,
+            } => radius * mandelbulb((p - *center) / *radius, *power, *iterations),
+            Field::SmoothUnion(a, b, smoothness) => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                let h = (0.5 + 0.5 * (b - a) / smoothness).clamp(0.0, 1.0);
+                b + (a - b) * h - smoothness * h * (1.0 - h)
+            }
+            Field::Custom(distance, _) => distance(p),
+        }
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        let around = |center: Point3, extent: Vec3| Aabb::new(center - extent, center + extent);
+        match self {
+            Field::Sphere { center, radius } => around(*center, Vec3::splat(*radius)),
+            Field::Box { center, half_size } => around(*center, *half_size),
+            Field::Torus {
+                center,
+                major,
+                minor,
+            } => around(*center, vec3(major + minor, *minor, major + minor)),
+            // The bulbs of the usual powers reach to about 1.15.
+            Field::Mandelbulb { center, radius, .. } => around(*center, Vec3::splat(1.25 * radius)),
+            // The blend only adds material within the smoothness of both.
+            Field::SmoothUnion(a, b, smoothness) => {
+                let both = a.bounding_box().union(b.bounding_box());
+                Aabb::new(both.min - *smoothness, both.max + *smoothness)
+            }
+            Field::Custom(_, bounds) => *bounds,
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        let positive = |value: f32| value > 0.0 && value.is_finite();
+        match self {
+            Field::Sphere { radius, .. } if !positive(*radius) => {
+                bail!("a sphere needs a positive radius")
+            }
+            Field::Box { half_size, .. } if !half_size.to_array().into_iter().all(positive) => {
+                bail!("a box needs a positive size")
+            }
+            Field::Torus { major, minor, .. } if !(positive(*major) && positive(*minor)) => {
+                bail!("a torus needs positive radii")
+            }
+            Field::Mandelbulb {
+                radius,
+                power,
+                iterations,
+                ..
+            } => {
+                if !positive(*radius) {
+                    bail!("a mandelbulb needs a positive radius");
+                }
+                if !(power.is_finite() && *power > 1.0) {
+                    bail!("a mandelbulb's power must be more than 1");
+                }
+                if *iterations == 0 {
+                    bail!("a mandelbulb needs at least 1 iteration");
+                }
+                Ok(())
+            }
+            Field::SmoothUnion(a, b, smoothness) => {
+                if !positive(*smoothness) {
+                    bail!("a smooth union needs a positive smoothness");
+                }
+                a.check()?;
+                b.check()
+            }
+            Field::Custom(_, bounds) if bounds.is_empty() || !bounds.is_finite() => {
+                bail!("a distance function needs a finite box around its surface")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(match self {
+            Field::Sphere { center, radius } => Json::object([
+                ("type", "sphere".into()),
+                ("center", (*center).into()),
+                ("radius", (*radius).into()),
+            ]),
+            Field::Box { center, half_size } => Json::object([
+                ("type", "box".into()),
+                ("center", (*center).into()),
+                ("size", (2.0 * *half_size).into()),
+            ]),
+            Field::Torus {
+                center,
+                major,
+                minor,
+            } => Json::object([
+                ("type", "torus".into()),
+                ("center", (*center).into()),
+                ("radius", (*major).into()),
+                ("tube_radius", (*minor).into()),
+            ]),
+            Field::Mandelbulb {
+                center,
+                radius,
+                power,
+                iterations,
+            } => Json::object([
+                ("type", "mandelbulb".into()),
+                ("center", (*center).into()),
+                ("radius", (*radius).into()),
+                ("power", (*power).into()),
+                ("iterations", (*iterations).into()),
+            ]),
+            Field::SmoothUnion(a, b, smoothness) => Json::object([
+                ("type", "smooth_union".into()),
+                ("children", Json::Array(vec![a.to_json()?, b.to_json()?])),
+                ("smoothness", (*smoothness).into()),
+            ]),
+            // Code can't be written to a scene file.
+            Field::Custom(..) => return None,
+        })
+    }
+}
+
+// The usual distance estimate for the Mandelbulb, from how fast the point
+// escapes when iterated.
+fn mandelbulb(p: Point3, power: f32, iterations: u32) -> f32 {
+    let mut z = p;
+    let mut dr = 1.0;
+    let mut r = z.length();
+    for _ in 0..iterations {
+        if r > 2.0 {
+            break;
+        }
+        dr = power * r.powf(power - 1.0) * dr + 1.0;
+        // The origin stays put whatever its angles.
+        let cos_theta = if r > 0.0 { z.z / r } else { 1.0 };
+        let theta = cos_theta.clamp(-1.0, 1.0).acos() * power;
+        let phi = z.y.atan2(z.x) * power;
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        z = r.powf(power) * vec3(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta) + p;
+        r = z.length();
+    }
+    if r == 0.0 {
+        return 0.0;
+    }
+    0.5 * r.ln() * r / dr
+}
+
+// A surface found by sphere tracing its distance field: each step goes as
+// far as the distance to the surface, which can't overshoot it. Suits
+// shapes with no closed-form intersection, such as fractals and blends.
+pub struct Sdf {
+    field: Field,
+    bounds: Aabb,
+    tolerance: f32,
+    mat: Material,
+}
+
+impl Sdf {
+    pub fn new(field: Field, mat: Material) -> Result<Sdf> {
+        field.check()?;
+        let bounds = field.bounding_box();
+        Ok(Sdf {
+            tolerance: TOLERANCE * (bounds.max - bounds.min).length(),
+            bounds,
+            field,
+            mat,
+        })
+    }
+
+    // The surface where `distance` is zero, which must lie in `bounds`.
+    pub fn from_fn(
+        distance: impl Fn(Point3) -> f32 + Send + Sync + 'static,
+        bounds: Aabb,
+        mat: Material,
+    ) -> Result<Sdf> {
+        Self::new(Field::Custom(Arc::new(distance), bounds), mat)
+    }
+
+    // The field's gradient, by differences at the corners of a tetrahedron
+    // about the tolerance across: the outward normal at the surface.
+    fn gradient(&self, p: Point3) -> Vec3 {
+        [
+            vec3(1.0, -1.0, -1.0),
+            vec3(-1.0, -1.0, 1.0),
+            vec3(-1.0, 1.0, -1.0),
+            vec3(1.0, 1.0, 1.0),
+        ]
+        .into_iter()
+        .fold(Vec3::ZERO, |sum, k| {
+            sum + k * self.field.distance(p + self.tolerance * k)
+        })
+    }
+}
+
+impl Hittable for Sdf {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let span = self.bounds.clip(ray.origin(), ray.dir().recip(), ray_t)?;
+        let speed = ray.dir().length();
+        let mut t = span.min;
+        // Marching from inside looks for where the field turns positive.
+        // Rays leaving the surface, such as scattered ones, go by which way
+        // they head and only stop once they have got clear of it.
+        let start = self.field.distance(ray.at(t));
+        let (sign, mut clear) = if start.abs() >= self.tolerance {
+            (start.signum(), true)
+        } else if self.gradient(ray.at(t)).dot(ray.dir()) >= 0.0 {
+            (1.0, false)
+        } else {
+            (-1.0, false)
+        };
+        for _ in 0..MAX_STEPS {
+            let distance = sign * self.field.distance(ray.at(t));
+            if distance < self.tolerance && clear {
+                let p = ray.at(t);
+                let normal = self
+                    .gradient(p)
+                    .try_normalize()
+                    .unwrap_or(-ray.dir() / speed);
+                return Some(Hit::new(p, normal, ray, t, &self.mat));
+            }
+            clear |= distance >= self.tolerance;
+            t += distance.max(self.tolerance) / speed;
+            if t > span.max {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bounds
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(Json::object([
+            ("type", "sdf".into()),
+            ("shape", self.field.to_json()?),
+            ("material", self.mat.to_json()?),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point3;
+
+    fn sdf(field: Field) -> Sdf {
+        Sdf::new(field, Material::new_lambertian(1.0, 1.0, 1.0)).unwrap()
+    }
+
+    fn first(object: &Sdf, origin: Point3, dir: Vec3) -> Option<Hit<'_>> {
+        object.hit(&Ray::new(origin, dir), Interval::new(0.001, f32::INFINITY))
+    }
+
+    #[test]
+    fn spheres_match_the_exact_intersection() {
+        let ball = sdf(Field::Sphere {
+            center: Point3::ZERO,
+            radius: 1.0,
+        });
+        let hit = first(&ball, point3(0.3, 0.2, -5.0), Vec3::Z).unwrap();
+        let exact = 5.0 - (1.0f32 - 0.3 * 0.3 - 0.2 * 0.2).sqrt();
+        assert!((hit.t - exact).abs() < 1e-3, "{} != {}", hit.t, exact);
+        assert!(hit.front_face);
+        assert!((hit.normal - hit.p.normalize()).length() < 1e-2);
+
+        // Rays leaving the surface get clear of it: one heading out misses,
+        // one heading in finds the far side.
+        let normal = hit.normal;
+        assert!(first(&ball, hit.p, normal).is_none());
+        let through = first(&ball, hit.p, Vec3::Z).unwrap();
+        assert!(!through.front_face);
+        assert!((through.p.length() - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn smooth_unions_fill_the_gap() {
+        let pair = |smoothness| {
+            sdf(Field::SmoothUnion(
+                Box::new(Field::Sphere {
+                    center: point3(-1.1, 0.0, 0.0),
+                    radius: 1.0,
+                }),
+                Box::new(Field::Sphere {
+                    center: point3(1.1, 0.0, 0.0),
+                    radius: 1.0,
+                }),
+                smoothness,
+            ))
+        };
+        let between = point3(0.0, 0.0, -5.0);
+        assert!(first(&pair(0.01), between, Vec3::Z).is_none());
+        assert!(first(&pair(1.0), between, Vec3::Z).is_some());
+    }
+
+    #[test]
+    fn closures_and_fractals_are_marched() {
+        // A slab a tenth thick, as a closure.
+        let slab = Sdf::from_fn(
+            |p| p.y.abs() - 0.05,
+            Aabb::new(point3(-1.0, -0.1, -1.0), point3(1.0, 0.1, 1.0)),
+            Material::new_lambertian(1.0, 1.0, 1.0),
+        )
+        .unwrap();
+        assert!(slab.to_json().is_none());
+        let hit = first(&slab, point3(0.0, 1.0, 0.0), -Vec3::Y).unwrap();
+        assert!((hit.t - 0.95).abs() < 1e-3, "{}", hit.t);
+        assert!((hit.normal - Vec3::Y).length() < 1e-3);
+
+        let bulb = sdf(Field::Mandelbulb {
+            center: Point3::ZERO,
+            radius: 1.0,
+            power: 8.0,
+            iterations: 8,
+        });
+        let hit = first(&bulb, point3(0.0, 0.0, -3.0), Vec3::Z).unwrap();
+        assert!(hit.p.length() < 1.25);
+        assert!(bulb.field.distance(hit.p).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rejects_degenerate_fields() {
+        let mat = Material::new_lambertian(1.0, 1.0, 1.0);
+        let torus = Field::Torus {
+            center: Point3::ZERO,
+            major: 1.0,
+            minor: 0.0,
+        };
+        assert!(Sdf::new(torus, mat.clone()).is_err());
+        assert!(Sdf::from_fn(|p| p.length() - 1.0, Aabb::UNBOUNDED, mat).is_err());
+    }
+}
@@ -0,0 +1,476 @@
+use crate::hittables::{Aabb, Hit, Hittable, Interval};
+use crate::json::Json;
+use crate::materials::Material;
+use crate::onb::Onb;
+use crate::render::Ray;
+use crate::Point3;
+use anyhow::{bail, Result};
+use glam::{vec2, vec3, Vec2, Vec3};
+use std::f32::consts::TAU;
+
+// The axis round which a solid of revolution is built, from `base` to a top
+// `height` above it. Rays are intersected in its own coordinates, with the
+// axis along z and the base at the origin; lengths are the same in both, so
+// t is too.
+struct Axis {
+    base: Point3,
+    onb: Onb,
+    height: f32,
+}
+
+impl Axis {
+    fn new(base: Point3, top: Point3) -> Result<Axis> {
+        let height = base.distance(top);
+        if !(height > 0.0 && height.is_finite()) {
+            bail!("the base and top must be apart");
+        }
+        Ok(Axis {
+            base,
+            onb: Onb::new((top - base) / height),
+            height,
+        })
+    }
+
+    fn local(&self, ray: &Ray) -> (Vec3, Vec3) {
+        (
+            self.onb.to_local(ray.origin() - self.base),
+            self.onb.to_local(ray.dir()),
+        )
+    }
+
+    fn top(&self) -> Point3 {
+        self.base + self.height * self.onb.w()
+    }
+
+    // The box around a disk of `radius` at `center` across the axis.
+    fn disk_box(&self, center: Point3, radius: f32) -> Aabb {
+        let axis = self.onb.w();
+        let extent = radius * (Vec3::ONE - axis * axis).max(Vec3::ZERO).powf(0.5);
+        Aabb::new(center - extent, center + extent)
+    }
+
+    fn hit<'a>(&self, ray: &Ray, found: Found, material: &'a Material) -> Hit<'a> {
+        let normal = self.onb.to_world(found.normal);
+        Hit::new(ray.at(found.t), normal, ray, found.t, material).with_uv(found.uv)
+    }
+}
+
+// The nearest intersection so far, in axis coordinates.
+#[derive(Copy, Clone)]
+struct Found {
+    t: f32,
+    normal: Vec3,
+    uv: Vec2,
+}
+
+// Keeps the nearest of the candidates offered within `ray_t`.
+struct Nearest {
+    ray_t: Interval,
+    found: Option<Found>,
+}
+
+impl Nearest {
+    fn new(ray_t: Interval) -> Nearest {
+        Nearest { ray_t, found: None }
+    }
+
+    fn offer(&mut self, t: f32, normal: impl FnOnce() -> Vec3, uv: impl FnOnce() -> Vec2) {
+        if self.ray_t.surrounds(t) && self.found.is_none_or(|found| t < found.t) {
+            self.found = Some(Found {
+                t,
+                normal: normal(),
+                uv: uv(),
+            });
+        }
+    }
+
+    // The caps facing -z at z = 0 and +z at `height`, of radii `r0` and
+    // `r1`. Their texture coordinates map the disk onto the unit square.
+    fn caps(&mut self, o: Vec3, d: Vec3, height: f32, r0: f32, r1: f32) {
+        if d.z == 0.0 {
+            return;
+        }
+        for (z, radius, normal) in [(0.0, r0, -Vec3::Z), (height, r1, Vec3::Z)] {
+            let t = (z - o.z) / d.z;
+            let p = o + t * d;
+            if radius > 0.0 && p.x * p.x + p.y * p.y <= radius * radius {
+                self.offer(t, || normal, || (p.truncate() / radius + Vec2::ONE) / 2.0);
+            }
+        }
+    }
+}
+
+// The roots of a t^2 + 2 half_b t + c, in increasing order.
+fn roots(a: f32, half_b: f32, c: f32) -> Option<[f32; 2]> {
+    if a == 0.0 {
+        return (half_b != 0.0).then(|| [-c / (2.0 * half_b); 2]);
+    }
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let sqrtd = discriminant.sqrt();
+    let (t0, t1) = ((-half_b - sqrtd) / a, (-half_b + sqrtd) / a);
+    Some([t0.min(t1), t0.max(t1)])
+}
+
+// Around the axis from 0 to 1, starting at the basis' u direction.
+fn around(p: Vec3) -> f32 {
+    (p.y.atan2(p.x) / TAU).rem_euclid(1.0)
+}
+
+// A circular cylinder from `base` to `top`, closed by flat caps unless it is
+// an open tube. Side texture coordinates go around the axis and up it.
+pub struct Cylinder {
+    axis: Axis,
+    radius: f32,
+    caps: bool,
+    mat: Material,
+}
+
+impl Cylinder {
+    pub fn new(base: Point3, top: Point3, radius: f32, caps: bool, mat: Material) -> Result<Self> {
+        if radius.is_nan() || radius <= 0.0 {
+            bail!("a cylinder's radius must be positive");
+        }
+        Ok(Cylinder {
+            axis: Axis::new(base, top)?,
+            radius,
+            caps,
+            mat,
+        })
+    }
+}
+
+impl Hittable for Cylinder {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let (o, d) = self.axis.local(ray);
+        let (r, height) = (self.radius, self.axis.height);
+        let mut nearest = Nearest::new(ray_t);
+        let a = d.x * d.x + d.y * d.y;
+        let half_b = o.x * d.x + o.y * d.y;
+        let c = o.x * o.x + o.y * o.y - r * r;
+        if let Some(ts) = roots(a, half_b, c).filter(|_| a > 0.0) {
+            for t in ts {
+                let p = o + t * d;
+                if (0.0..=height).contains(&p.z) {
+                    nearest.offer(
+                        t,
+                        || vec3(p.x, p.y, 0.0) / r,
+                        || vec2(around(p), p.z / height),
+                    );
+                }
+            }
+        }
+        if self.caps {
+            nearest.caps(o, d, height, r, r);
+        }
+        Some(self.axis.hit(ray, nearest.found?, &self.mat))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.axis
+            .disk_box(self.axis.base, self.radius)
+            .union(self.axis.disk_box(self.axis.top(), self.radius))
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(Json::object([
+            ("type", "cylinder".into()),
+            ("base", self.axis.base.into()),
+            ("top", self.axis.top().into()),
+            ("radius", self.radius.into()),
+            ("caps", Json::Bool(self.caps)),
+            ("material", self.mat.to_json()?),
+        ]))
+    }
+}
+
+// A cone from a disk of `radius` at `base` narrowing to `top_radius` at
+// `top`: a point for a full cone, a frustum otherwise. Texture coordinates
+// are as on a cylinder.
+pub struct Cone {
+    axis: Axis,
+    radius: f32,
+    top_radius: f32,
+    caps: bool,
+    mat: Material,
+}
+
+impl Cone {
+    pub fn new(
+        base: Point3,
+        top: Point3,
+        radius: f32,
+        top_radius: f32,
+        caps: bool,
+        mat: Material,
+    ) -> Result<Self> {
+        if !(radius >= 0.0 && top_radius >= 0.0 && radius + top_radius > 0.0) {
+            bail!("a cone's radii must not be negative, and one must be positive");
+        }
+        Ok(Cone {
+            axis: Axis::new(base, top)?,
+            radius,
+            top_radius,
+            caps,
+            mat,
+        })
+    }
+}
+
+impl Hittable for Cone {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let (o, d) = self.axis.local(ray);
+        let height = self.axis.height;
+        // The radius grows by `slope` per unit up the axis, from `r0` at the
+        // ray's origin height.
+        let slope = (self.top_radius - self.radius) / height;
+        let r0 = self.radius + slope * o.z;
+        let mut nearest = Nearest::new(ray_t);
+        let a = d.x * d.x + d.y * d.y - slope * slope * d.z * d.z;
+        let half_b = o.x * d.x + o.y * d.y - slope * d.z * r0;
+        let c = o.x * o.x + o.y * o.y - r0 * r0;
+        if let Some(ts) = roots(a, half_b, c) {
+            for t in ts {
+                let p = o + t * d;
+                if (0.0..=height).contains(&p.z) {
+                    let r = self.radius + slope * p.z;
+                    nearest.offer(
+                        t,
+                        || {
+                            vec3(p.x, p.y, -slope * r)
+                                .try_normalize()
+                                .unwrap_or(Vec3::Z)
+                        },
+                        || vec2(around(p), p.z / height),
+                    );
+                }
+            }
+        }
+        if self.caps {
+            nearest.caps(o, d, height, self.radius, self.top_radius);
+        }
+        Some(self.axis.hit(ray, nearest.found?, &self.mat))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.axis
+            .disk_box(self.axis.base, self.radius)
+            .union(self.axis.disk_box(self.axis.top(), self.top_radius))
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(Json::object([
+            ("type", "cone".into()),
+            ("base", self.axis.base.into()),
+            ("top", self.axis.top().into()),
+            ("radius", self.radius.into()),
+            ("top_radius", self.top_radius.into()),
+            ("caps", Json::Bool(self.caps)),
+            ("material", self.mat.to_json()?),
+        ]))
+    }
+}
+
+// A cylinder from `base` to `top` with a hemisphere on each end: every point
+// within `radius` of the segment between them. Texture coordinates go
+// around the axis and along the whole length, ends included.
+pub struct Capsule {
+    axis: Axis,
+    radius: f32,
+    mat: Material,
+}
+
+impl Capsule {
+    pub fn new(base: Point3, top: Point3, radius: f32, mat: Material) -> Result<Self> {
+        if radius.is_nan() || radius <= 0.0 {
+            bail!("a capsule's radius must be positive");
+        }
+        Ok(Capsule {
+            axis: Axis::new(base, top)?,
+            radius,
+            mat,
+        })
+    }
+}
+
+impl Hittable for Capsule {
+    fn hit(&self, ray: &Ray, ray_t: Interval) -> Option<Hit<'_>> {
+        let (o, d) = self.axis.local(ray);
+        let (r, height) = (self.radius, self.axis.height);
+        let length = height + 2.0 * r;
+        let mut nearest = Nearest::new(ray_t);
+        let a = d.x * d.x + d.y * d.y;
+        let half_b = o.x * d.x + o.y * d.y;
+        let c = o.x * o.x + o.y * o.y - r * r;
+        if let Some(ts) = roots(a, half_b, c).filter(|_| a > 0.0) {
+            for t in ts {
+                let p = o + t * d;
+                if (0.0..=height).contains(&p.z) {
+                    nearest.offer(
+                        t,
+                        || vec3(p.x, p.y, 0.0) / r,
+                        || vec2(around(p), (p.z + r) / length),
+                    );
+                }
+            }
+        }
+        // Each end only counts beyond its side of the cylinder.
+        for (center, beyond) in [(0.0, -1.0), (height, 1.0)] {
+            let oc = o - vec3(0.0, 0.0, center);
+            let Some(ts) = roots(d.dot(d), oc.dot(d), oc.dot(oc) - r * r) else {
+                continue;
+            };
+            for t in ts {
+                let p = o + t * d;
+                if (p.z - center) * beyond >= 0.0 {
+                    nearest.offer(
+                        t,
+                        || (p - vec3(0.0, 0.0, center)) / r,
+                        || vec2(around(p), (p.z + r) / length),
+                    );
+                }
+            }
+        }
+        Some(self.axis.hit(ray, nearest.found?, &self.mat))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = Vec3::splat(self.radius);
+        let (base, top) = (self.axis.base, self.axis.top());
+        Aabb::new(base - r, base + r).union(Aabb::new(top - r, top + r))
+    }
+
+    fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(Json::object([
+            ("type", "capsule".into()),
+            ("base", self.axis.base.into()),
+            ("top", self.axis.top().into()),
+            ("radius", self.radius.into()),
+            ("material", self.mat.to_json()?),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point3;
+    use crate::random;
+
+    fn material() -> Material {
+        Material::new_lambertian(0.5, 0.5, 0.5)
+    }
+
+    fn hit(object: &dyn Hittable, from: Point3, dir: Vec3) -> Option<(f32, Vec3)> {
+        object
+            .hit(&Ray::new(from, dir), Interval::new(0.001, f32::INFINITY))
+            .map(|hit| (hit.t, hit.normal))
+    }
+
+    #[test]
+    fn cylinder_hits_its_side_and_caps() {
+        // Standing on the origin, 2 tall with radius 0.5.
+        let cylinder = Cylinder::new(Point3::ZERO, 2.0 * Vec3::Y, 0.5, true, material()).unwrap();
+        let (t, normal) = hit(&cylinder, point3(-3.0, 1.0, 0.0), Vec3::X).unwrap();
+        assert!((t - 2.5).abs() < 1e-5);
+        assert!(normal.distance(-Vec3::X) < 1e-5);
+        let (t, normal) = hit(&cylinder, point3(0.2, 5.0, 0.1), -Vec3::Y).unwrap();
+        assert!((t - 3.0).abs() < 1e-5);
+        assert!(normal.distance(Vec3::Y) < 1e-5);
+        // Past the side, and through the open end of a tube.
+        assert!(hit(&cylinder, point3(-3.0, 2.5, 0.0), Vec3::X).is_none());
+        let tube = Cylinder::new(Point3::ZERO, 2.0 * Vec3::Y, 0.5, false, material()).unwrap();
+        let (t, normal) = hit(&tube, point3(0.0, 3.0, 0.0), point3(0.25, -1.0, 0.0)).unwrap();
+        assert!((t - 2.0).abs() < 1e-5);
+        // Seen from inside, the normal faces the ray.
+        assert!(normal.distance(-Vec3::X) < 1e-5);
+    }
+
+    #[test]
+    fn cone_normals_lean_towards_the_tip() {
+        // Radius 1 at the base, a point 1 above it: the side is at 45 degrees.
+        let cone = Cone::new(Point3::ZERO, Vec3::Y, 1.0, 0.0, true, material()).unwrap();
+        let (t, normal) = hit(&cone, point3(-3.0, 0.5, 0.0), Vec3::X).unwrap();
+        assert!((t - 2.5).abs() < 1e-5);
+        let expected = vec3(-1.0, 1.0, 0.0).normalize();
+        assert!(normal.distance(expected) < 1e-5, "{}", normal);
+        let (t, normal) = hit(&cone, point3(0.5, -1.0, 0.0), Vec3::Y).unwrap();
+        assert!((t - 1.0).abs() < 1e-5);
+        assert!(normal.distance(-Vec3::Y) < 1e-5);
+        assert!(hit(&cone, point3(-3.0, 0.9, 0.5), Vec3::X).is_none());
+    }
+
+    #[test]
+    fn capsule_ends_are_hemispheres() {
+        let capsule = Capsule::new(Point3::ZERO, 2.0 * Vec3::X, 0.5, material()).unwrap();
+        let (t, normal) = hit(&capsule, point3(-3.0, 0.0, 0.0), Vec3::X).unwrap();
+        assert!((t - 2.5).abs() < 1e-5);
+        assert!(normal.distance(-Vec3::X) < 1e-5);
+        let (t, normal) = hit(&capsule, point3(1.0, 3.0, 0.0), -Vec3::Y).unwrap();
+        assert!((t - 2.5).abs() < 1e-5);
+        assert!(normal.distance(Vec3::Y) < 1e-5);
+        // Through the rounded corner a box would have had.
+        assert!(hit(&capsule, point3(-0.45, 0.45, 3.0), -Vec3::Z).is_none());
+    }
+
+    #[test]
+    fn hits_stay_on_the_surface_and_in_the_box() {
+        let (base, top) = (point3(0.3, -0.2, 0.1), point3(-0.5, 0.8, 0.6));
+        let shapes: Vec<(&str, Box<dyn Hittable>)> = vec![
+            (
+                "cylinder",
+                Box::new(Cylinder::new(base, top, 0.4, true, material()).unwrap()),
+            ),
+            (
+                "cone",
+                Box::new(Cone::new(base, top, 0.6, 0.2, true, material()).unwrap()),
+            ),
+            (
+                "capsule",
+                Box::new(Capsule::new(base, top, 0.3, material()).unwrap()),
+            ),
+        ];
+        for (name, shape) in &shapes {
+            let bbox = shape.bounding_box();
+            let mut hits = 0;
+            for idx in 0..500 {
+                random::seed_sample(5, idx, 0, 0);
+                let v = || random::range(-1.0..1.0);
+                let origin = point3(v(), v(), v()) * 3.0;
+                let ray = Ray::new(origin, point3(v(), v(), v()) * 0.5 - origin);
+                let Some(hit) = shape.hit(&ray, Interval::new(0.001, f32::INFINITY)) else {
+                    continue;
+                };
+                hits += 1;
+                let eps = Vec3::splat(1e-4);
+                assert!(
+                    hit.p.cmpge(bbox.min - eps).all() && hit.p.cmple(bbox.max + eps).all(),
+                    "{} ray #{}",
+                    name,
+                    idx
+                );
+                assert!((0.0..=1.0).contains(&hit.uv.x) && (0.0..=1.0).contains(&hit.uv.y));
+                assert!(hit.normal.dot(ray.dir()) <= 0.0, "{} ray #{}", name, idx);
+                // Stepping back along the normal leaves the surface.
+                let off = Ray::new(hit.p + hit.normal * 1e-3, hit.normal);
+                let again = shape.hit(&off, Interval::new(0.0, 1e-3));
+                assert!(again.is_none(), "{} ray #{}", name, idx);
+            }
+            assert!(hits > 100, "{} was hit {} times", name, hits);
+        }
+    }
+}
@@ -0,0 +1,764 @@
+use crate::canvas::Canvas;
+use crate::hittables::Hit;
+use crate::json::Json;
+use crate::{Color3, Point3};
+use anyhow::{bail, Result};
+use glam::{ivec3, vec2, vec3, Vec2, Vec3};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+// A color that varies over a surface, looked up by texture coordinates or
+// by the point itself.
+pub trait Texture: Send + Sync {
+    fn value(&self, uv: Vec2, p: Point3) -> Color3;
+
+    // The color at `hit` as seen by a ray travelling along `dir`. Only
+    // textures depending on the view, such as material graphs' fresnel
+    // nodes, need more than `value`.
+    fn shade(&self, hit: &Hit, _dir: Vec3) -> Color3 {
+        self.value(hit.uv, hit.p)
+    }
+
+    // The texture as written in a scene file, if it can be.
+    fn to_json(&self) -> Option<Json> {
+        None
+    }
+}
+
+pub struct SolidColor {
+    color: Color3,
+}
+
+impl SolidColor {
+    pub fn new(color: Color3) -> Self {
+        Self { color }
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _uv: Vec2, _p: Point3) -> Color3 {
+        self.color
+    }
+
+    fn to_json(&self) -> Option<Json> {
+        Some(self.color.into())
+    }
+}
+
+// Alternates between two textures in cubes of side `scale` filling space,
+// so it needs no texture coordinates and doesn't stretch on curved
+// surfaces.
+pub struct CheckerTexture {
+    inv_scale: f32,
+    even: Arc<dyn Texture>,
+    odd: Arc<dyn Texture>,
+}
+
+impl CheckerTexture {
+    pub fn new(scale: f32, even: Arc<dyn Texture>, odd: Arc<dyn Texture>) -> Self {
+        Self {
+            inv_scale: 1.0 / scale,
+            even,
+            odd,
+        }
+    }
+
+    pub fn from_colors(scale: f32, even: Color3, odd: Color3) -> Self {
+        Self::new(
+            scale,
+            Arc::new(SolidColor::new(even)),
+            Arc::new(SolidColor::new(odd)),
+        )
+    }
+}
+
+impl CheckerTexture {
+    fn pick(&self, p: Point3) -> &dyn Texture {
+        let cell = (p * self.inv_scale).floor().as_ivec3();
+        if (cell.x + cell.y + cell.z) & 1 == 0 {
+            self.even.as_ref()
+        } else {
+            self.odd.as_ref()
+        }
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, uv: Vec2, p: Point3) -> Color3 {
+        self.pick(p).value(uv, p)
+    }
+
+    fn shade(&self, hit: &Hit, dir: Vec3) -> Color3 {
+        self.pick(hit.p).shade(hit, dir)
+    }
+}
+
+// A PNG or JPEG mapped by the hit's texture coordinates, with v = 0 at the
+// bottom of the image. It repeats outside 0 to 1 and is filtered bilinearly,
+// from the levels of its mipmap that match the footprint of the ray hitting
+// it, so that far away or seen through rough reflections it doesn't alias.
+pub struct ImageTexture {
+    pixels: Pixels,
+}
+
+enum Pixels {
+    Decoded(Mipmap),
+    // Decoded when needed and kept while `cache` has room for it.
+    Cached {
+        cache: Arc<ImageCache>,
+        path: PathBuf,
+    },
+}
+
+impl ImageTexture {
+    // Decodes the file now, and keeps it decoded unless `cache` is given, in
+    // which case it is handed to the cache and decoded again if evicted.
+    pub fn load(path: &Path, cache: Option<&Arc<ImageCache>>) -> Result<Self> {
+        let image = Canvas::load(path)?;
+        Ok(match cache {
+            None => Self::new(image),
+            Some(cache) => {
+                cache.insert(path, Arc::new(Mipmap::new(image)));
+                Self {
+                    pixels: Pixels::Cached {
+                        cache: cache.clone(),
+                        path: path.to_path_buf(),
+                    },
+                }
+            }
+        })
+    }
+
+    pub fn new(image: Canvas) -> Self {
+        Self {
+            pixels: Pixels::Decoded(Mipmap::new(image)),
+        }
+    }
+
+    // Bytes held by this texture alone; cached images count towards their
+    // cache's memory instead.
+    pub fn memory(&self) -> usize {
+        match &self.pixels {
+            Pixels::Decoded(image) => image.memory(),
+            Pixels::Cached { .. } => 0,
+        }
+    }
+}
+
+impl ImageTexture {
+    // The color at `uv` averaged over `footprint`, its extent in texture
+    // coordinates along u and v.
+    fn sample(&self, uv: Vec2, footprint: Vec2) -> Color3 {
+        match &self.pixels {
+            Pixels::Decoded(mipmap) => mipmap.sample(uv, footprint),
+            Pixels::Cached { cache, path } => cache.get(path).sample(uv, footprint),
+        }
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, uv: Vec2, _p: Point3) -> Color3 {
+        self.sample(uv, Vec2::ZERO)
+    }
+
+    fn shade(&self, hit: &Hit, dir: Vec3) -> Color3 {
+        self.sample(hit.uv, hit.uv_footprint(dir))
+    }
+}
+
+// An image and its mipmap: copies each half the size of the one before,
+// down to a single pixel, every pixel the average of four.
+struct Mipmap {
+    levels: Vec<Canvas>,
+}
+
+impl Mipmap {
+    fn new(image: Canvas) -> Self {
+        let mut levels = vec![image];
+        loop {
+            let last = levels.last().unwrap();
+            if last.width() == 1 && last.height() == 1 {
+                break;
+            }
+            let level = halved(last);
+            levels.push(level);
+        }
+        Self { levels }
+    }
+
+    fn memory(&self) -> usize {
+        self.levels.iter().map(Canvas::memory).sum()
+    }
+
+    // Blends the two levels whose pixels are closest in size to the
+    // footprint, so the filtering doesn't jump between them.
+    fn sample(&self, uv: Vec2, footprint: Vec2) -> Color3 {
+        let image = &self.levels[0];
+        let pixels = (footprint * vec2(image.width() as f32, image.height() as f32)).max_element();
+        let level = pixels.max(1.0).log2().min((self.levels.len() - 1) as f32);
+        let below = level as usize;
+        let color = filtered(&self.levels[below], uv);
+        match level - below as f32 {
+            0.0 => color,
+            fraction => color.lerp(filtered(&self.levels[below + 1], uv), fraction),
+        }
+    }
+}
+
+// Half the size, rounded up; odd edges are averaged with themselves.
+fn halved(image: &Canvas) -> Canvas {
+    let (width, height) = (image.width(), image.height());
+    let mut half = Canvas::new(width.div_ceil(2), height.div_ceil(2));
+    for y in 0..half.height() {
+        for x in 0..half.width() {
+            let pixel = |dx: u32, dy: u32| {
+                image.pixel((2 * x + dx).min(width - 1), (2 * y + dy).min(height - 1))
+            };
+            let mean = (pixel(0, 0) + pixel(1, 0) + pixel(0, 1) + pixel(1, 1)) / 4.0;
+            // Averaged in linear light, stored as display values.
+            half.set(x, y, mean.powf(0.5));
+        }
+    }
+    half
+}
+
+// Bilinearly filtered, repeating outside 0 to 1.
+fn filtered(image: &Canvas, uv: Vec2) -> Color3 {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    // Relative to pixel centers.
+    let x = uv.x * width as f32 - 0.5;
+    let y = (1.0 - uv.y) * height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |dx: i32, dy: i32| {
+        image.pixel(
+            (x0 as i32 + dx).rem_euclid(width) as u32,
+            (y0 as i32 + dy).rem_euclid(height) as u32,
+        )
+    };
+    let top = pixel(0, 0).lerp(pixel(1, 0), fx);
+    let bottom = pixel(0, 1).lerp(pixel(1, 1), fx);
+    top.lerp(bottom, fy)
+}
+
+// Decoded images by path, at most `budget` bytes of them; the least
+// recently used are dropped to make room and decoded again from their files
+// when next needed. Lookups share a read lock, so render threads only wait
+// on each other to decode a miss or evict. The most recent image is always
+// kept, even alone over the budget.
+pub struct ImageCache {
+    budget: usize,
+    clock: AtomicU64,
+    state: RwLock<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    images: HashMap<PathBuf, CachedImage>,
+    bytes: usize,
+    // The decoded sizes of every image ever inserted.
+    wanted: HashMap<PathBuf, usize>,
+}
+
+struct CachedImage {
+    image: Arc<Mipmap>,
+    last_used: AtomicU64,
+}
+
+impl ImageCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            clock: AtomicU64::new(0),
+            state: RwLock::default(),
+        }
+    }
+
+    // At most the budget, and less if all the images fit in less.
+    pub fn memory(&self) -> usize {
+        let state = self.state.read().unwrap();
+        state.wanted.values().sum::<usize>().min(self.budget)
+    }
+
+    // Bytes of images decoded now.
+    pub fn resident(&self) -> usize {
+        self.state.read().unwrap().bytes
+    }
+
+    // The image at `path`, decoding it again if it was evicted. Images are
+    // only cached after loading once, so a failure here means the file
+    // changed during the render, which can't go on without it.
+    fn get(&self, path: &Path) -> Arc<Mipmap> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        if let Some(cached) = self.state.read().unwrap().images.get(path) {
+            cached.last_used.store(now, Ordering::Relaxed);
+            return cached.image.clone();
+        }
+        let image = Canvas::load(path)
+            .unwrap_or_else(|error| panic!("texture changed while rendering: {:#}", error));
+        self.insert(path, Arc::new(Mipmap::new(image)))
+    }
+
+    // Adds an image, evicting the least recently used ones it doesn't fit
+    // beside. Another thread may have decoded the same file meanwhile, in
+    // which case its copy wins.
+    fn insert(&self, path: &Path, image: Arc<Mipmap>) -> Arc<Mipmap> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.write().unwrap();
+        if let Some(cached) = state.images.get(path) {
+            cached.last_used.store(now, Ordering::Relaxed);
+            return cached.image.clone();
+        }
+        while state.bytes + image.memory() > self.budget {
+            let Some(oldest) = state
+                .images
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used.load(Ordering::Relaxed))
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            let evicted = state.images.remove(&oldest).unwrap();
+            state.bytes -= evicted.image.memory();
+        }
+        state.bytes += image.memory();
+        state.wanted.insert(path.to_path_buf(), image.memory());
+        state.images.insert(
+            path.to_path_buf(),
+            CachedImage {
+                image: image.clone(),
+                last_used: AtomicU64::new(now),
+            },
+        );
+        image
+    }
+}
+
+// Gray levels sampled on a 3D grid of cubes of side `size` from `min`, such
+// as the densities of a simulated or scanned volume. Values are interpolated
+// trilinearly between cell centers, and are zero outside the grid.
+pub struct GridTexture {
+    min: Point3,
+    inv_size: f32,
+    dims: [u32; 3],
+    values: Vec<f32>,
+}
+
+impl GridTexture {
+    pub fn new(min: Point3, size: f32, dims: [u32; 3], values: Vec<f32>) -> Result<Self> {
+        if size.is_nan() || size <= 0.0 {
+            bail!("grid cell size must be positive");
+        }
+        if dims.contains(&0) {
+            bail!("grid dimensions must be positive");
+        }
+        let count = dims.iter().map(|&d| d as usize).product::<usize>();
+        if values.len() != count {
+            bail!(
+                "{}x{}x{} grid but {} values",
+                dims[0],
+                dims[1],
+                dims[2],
+                values.len()
+            );
+        }
+        Ok(Self {
+            min,
+            inv_size: 1.0 / size,
+            dims,
+            values,
+        })
+    }
+
+    pub fn memory(&self) -> usize {
+        self.values.len() * std::mem::size_of::<f32>()
+    }
+
+    fn value_at(&self, [x, y, z]: [i32; 3]) -> f32 {
+        let [w, h, d] = self.dims.map(|d| d as i32);
+        if x < 0 || y < 0 || z < 0 || x >= w || y >= h || z >= d {
+            return 0.0;
+        }
+        self.values[(x + w * (y + h * z)) as usize]
+    }
+}
+
+impl Texture for GridTexture {
+    fn value(&self, _uv: Vec2, p: Point3) -> Color3 {
+        // Relative to cell centers.
+        let p = (p - self.min) * self.inv_size - 0.5;
+        let cell = p.floor();
+        let w = p - cell;
+        let cell = cell.as_ivec3();
+        let mut sum = 0.0;
+        for corner in 0..8 {
+            let offset = ivec3(corner & 1, corner >> 1 & 1, corner >> 2);
+            let weight = offset.as_vec3() * w + (1 - offset).as_vec3() * (1.0 - w);
+            sum += weight.x * weight.y * weight.z * self.value_at((cell + offset).to_array());
+        }
+        Color3::splat(sum)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum NoiseStyle {
+    // Plain Perlin noise, soft blotches about `1 / scale` across.
+    Smooth,
+    // Several octaves of noise summed into a rougher, cloudier pattern.
+    Turbulence,
+    // Stripes along z every `PI / scale`, bent by turbulence.
+    Marble,
+    // Growth rings around the y axis, `scale` to a unit, bent by turbulence.
+    Wood,
+}
+
+// Procedural Perlin noise tinting `color` from black to full strength.
+pub struct NoiseTexture {
+    noise: Perlin,
+    style: NoiseStyle,
+    scale: f32,
+    color: Color3,
+}
+
+impl NoiseTexture {
+    pub fn new(style: NoiseStyle, scale: f32, color: Color3) -> Self {
+        Self {
+            noise: Perlin::new(),
+            style,
+            scale,
+            color,
+        }
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _uv: Vec2, p: Point3) -> Color3 {
+        const OCTAVES: u32 = 7;
+        let shade = match self.style {
+            NoiseStyle::Smooth => 0.5 * (1.0 + self.noise.noise(self.scale * p)),
+            NoiseStyle::Turbulence => self.noise.turbulence(self.scale * p, OCTAVES),
+            NoiseStyle::Marble => {
+                let phase = self.scale * p.z + 10.0 * self.noise.turbulence(p, OCTAVES);
+                0.5 * (1.0 + phase.sin())
+            }
+            NoiseStyle::Wood => {
+                let radius = p.x.hypot(p.z);
+                let phase = self.scale * radius + 0.5 * self.noise.turbulence(p, OCTAVES);
+                0.5 * (1.0 + (TAU * phase).sin())
+            }
+        };
+        self.color * shade.clamp(0.0, 1.0)
+    }
+}
+
+// Courses of bricks `size.x` long and `size.y` high, laid along x and
+// stacked along y with every other course shifted by half a brick, set in
+// joints of `mortar` `joint` wide. Like the checker it fills space, so walls
+// facing z show the bond; others need turning to face that way.
+pub struct BrickTexture {
+    size: Vec2,
+    joint: f32,
+    brick: Arc<dyn Texture>,
+    mortar: Arc<dyn Texture>,
+}
+
+impl BrickTexture {
+    pub fn new(size: Vec2, joint: f32, brick: Arc<dyn Texture>, mortar: Arc<dyn Texture>) -> Self {
+        Self {
+            size,
+            joint,
+            brick,
+            mortar,
+        }
+    }
+
+    // The joints run along the low x and y edges of each brick's cell.
+    fn pick(&self, p: Point3) -> &dyn Texture {
+        let course = (p.y / self.size.y).floor();
+        let shift = if course.rem_euclid(2.0) == 1.0 {
+            0.5 * self.size.x
+        } else {
+            0.0
+        };
+        let along = (p.x + shift).rem_euclid(self.size.x);
+        let up = p.y.rem_euclid(self.size.y);
+        if along < self.joint || up < self.joint {
+            self.mortar.as_ref()
+        } else {
+            self.brick.as_ref()
+        }
+    }
+}
+
+impl Texture for BrickTexture {
+    fn value(&self, uv: Vec2, p: Point3) -> Color3 {
+        self.pick(p).value(uv, p)
+    }
+
+    fn shade(&self, hit: &Hit, dir: Vec3) -> Color3 {
+        self.pick(hit.p).shade(hit, dir)
+    }
+}
+
+const LATTICE_SIZE: usize = 256;
+
+// Gradient noise on a lattice of random unit vectors, repeating every 256
+// units. The lattice comes from a fixed seed, so every texture and every run
+// sees the same noise.
+struct Perlin {
+    gradients: [Vec3; LATTICE_SIZE],
+    // Per axis permutations hashing lattice points into `gradients`.
+    perms: [[u8; LATTICE_SIZE]; 3],
+}
+
+impl Perlin {
+    fn new() -> Self {
+        let mut rng = StdRng::seed_from_u64(0);
+        let gradients = std::array::from_fn(|_| loop {
+            let v = vec3(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+            );
+            if (1e-4..1.0).contains(&v.length_squared()) {
+                break v.normalize();
+            }
+        });
+        let perms = std::array::from_fn(|_| {
+            let mut perm = std::array::from_fn(|idx| idx as u8);
+            perm.shuffle(&mut rng);
+            perm
+        });
+        Self { gradients, perms }
+    }
+
+    // Between -1 and 1, and zero on lattice points.
+    fn noise(&self, p: Point3) -> f32 {
+        let cell = p.floor();
+        let d = p - cell;
+        // Hermite smoothing hides the lattice.
+        let w = d * d * (3.0 - 2.0 * d);
+        let cell = cell.as_ivec3();
+        let mut sum = 0.0;
+        for corner in 0..8 {
+            let offset = vec3(
+                (corner & 1) as f32,
+                (corner >> 1 & 1) as f32,
+                (corner >> 2) as f32,
+            );
+            let lattice = cell + offset.as_ivec3();
+            let hash = lattice
+                .to_array()
+                .iter()
+                .zip(&self.perms)
+                .fold(0, |hash, (&v, perm)| hash ^ perm[(v & 255) as usize]);
+            let weight = offset * w + (1.0 - offset) * (1.0 - w);
+            sum += weight.x * weight.y * weight.z * self.gradients[hash as usize].dot(d - offset);
+        }
+        sum
+    }
+
+    // Octaves of doubling frequency and halving weight.
+    fn turbulence(&self, p: Point3, octaves: u32) -> f32 {
+        let (mut sum, mut p, mut weight) = (0.0, p, 1.0);
+        for _ in 0..octaves {
+            sum += weight * self.noise(p);
+            weight *= 0.5;
+            p *= 2.0;
+        }
+        sum.abs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{color3, point3};
+
+    #[test]
+    fn checker_alternates_across_cells() {
+        let (black, white) = (Color3::ZERO, Color3::ONE);
+        let checker = CheckerTexture::from_colors(0.5, black, white);
+        let at = |x, y, z| checker.value(Vec2::ZERO, point3(x, y, z));
+        assert_eq!(at(0.1, 0.1, 0.1), black);
+        assert_eq!(at(0.6, 0.1, 0.1), white);
+        assert_eq!(at(0.6, 0.6, 0.1), black);
+        assert_eq!(at(-0.1, 0.1, 0.1), white);
+        assert_eq!(at(-0.1, -0.1, -0.1), white);
+        assert_eq!(at(-0.6, 0.1, 0.1), black);
+    }
+
+    #[test]
+    fn image_filters_between_pixels() {
+        // Black on the left and white on the right, over a row of red.
+        let mut canvas = Canvas::new(2, 2);
+        canvas.set(1, 0, Color3::ONE);
+        canvas.set(0, 1, Color3::X);
+        canvas.set(1, 1, Color3::X);
+        let image = ImageTexture::new(canvas);
+        let at = |u, v| image.value(Vec2::new(u, v), Point3::ZERO);
+        assert_eq!(at(0.25, 0.75), Color3::ZERO);
+        assert_eq!(at(0.75, 0.75), Color3::ONE);
+        assert_eq!(at(0.75, 0.25), Color3::X);
+        assert!(at(0.5, 0.75).distance(Color3::splat(0.5)) < 1e-5);
+        assert!(at(0.75, 0.5).distance(color3(1.0, 0.5, 0.5)) < 1e-5);
+        // Wraps around at the edges.
+        assert!(at(0.0, 0.75).distance(Color3::splat(0.5)) < 1e-5);
+        assert_eq!(at(1.25, 1.75), Color3::ZERO);
+    }
+
+    #[test]
+    fn wide_footprints_sample_smaller_mipmap_levels() {
+        // White on the left, and pixels alternating black and white on the
+        // right.
+        let mut canvas = Canvas::new(4, 4);
+        for y in 0..4 {
+            for x in 0..4 {
+                let white = x < 2 || (x + y) % 2 == 0;
+                canvas.set(x, y, if white { Color3::ONE } else { Color3::ZERO });
+            }
+        }
+        let image = ImageTexture::new(canvas);
+        let Pixels::Decoded(mipmap) = &image.pixels else {
+            unreachable!();
+        };
+        let sizes: Vec<_> = mipmap
+            .levels
+            .iter()
+            .map(|level| (level.width(), level.height()))
+            .collect();
+        assert_eq!(sizes, [(4, 4), (2, 2), (1, 1)]);
+
+        // A pixel's worth of footprint or less sees the pixel itself.
+        let corner = vec2(7.0 / 8.0, 7.0 / 8.0);
+        assert_eq!(image.sample(corner, Vec2::ZERO), Color3::ZERO);
+        assert_eq!(image.sample(corner, vec2(0.25, 0.0)), Color3::ZERO);
+        // Two pixels' worth sees them averaged 2 by 2, in linear light, and
+        // four the whole image.
+        let gray = |uv, footprint| image.sample(uv, footprint).x;
+        let (left, right) = (vec2(0.25, 0.75), vec2(0.75, 0.75));
+        assert!(gray(corner, vec2(0.5, 0.0)) > 0.5);
+        assert!((gray(right, vec2(0.0, 0.5)) - 0.5).abs() < 0.01);
+        assert_eq!(gray(left, vec2(0.0, 0.5)), 1.0);
+        assert!((gray(right, Vec2::ONE) - 0.75).abs() < 0.01);
+        assert_eq!(gray(right, Vec2::ONE), gray(right, Vec2::splat(8.0)));
+        // In between, levels blend.
+        let between = gray(right, vec2(0.75, 0.0));
+        assert!(between > 0.55 && between < 0.7, "{}", between);
+    }
+
+    #[test]
+    fn image_cache_evicts_the_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!("image-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let colors = [Color3::X, Color3::Y, Color3::Z];
+        let paths = [0, 1, 2].map(|image| {
+            let path = dir.join(format!("{}.png", image));
+            let mut canvas = Canvas::new(2, 2);
+            (0..4).for_each(|idx| canvas.set(idx % 2, idx / 2, colors[image]));
+            canvas.save(&path).unwrap();
+            path
+        });
+
+        // Room for two of the three images of 12 bytes, and 3 more for the
+        // single pixel below them in their mipmaps.
+        let cache = Arc::new(ImageCache::new(30));
+        let textures = paths
+            .each_ref()
+            .map(|path| ImageTexture::load(path, Some(&cache)).unwrap());
+        assert_eq!(textures[0].memory(), 0);
+        assert_eq!((cache.resident(), cache.memory()), (30, 30));
+        let resident = |path: &Path| cache.state.read().unwrap().images.contains_key(path);
+        assert!(!resident(&paths[0]));
+
+        // Using the first again brings it back in place of the second, now
+        // the least recently used.
+        let at = |idx: usize| textures[idx].value(Vec2::splat(0.5), Point3::ZERO);
+        assert_eq!(at(0), Color3::X);
+        assert!(resident(&paths[0]) && !resident(&paths[1]) && resident(&paths[2]));
+        assert_eq!(at(2), Color3::Z);
+        assert_eq!(at(1), Color3::Y);
+        assert!(!resident(&paths[0]) && resident(&paths[1]) && resident(&paths[2]));
+        assert_eq!(cache.resident(), 30);
+
+        // Everything fits in a larger cache, which only counts what it holds.
+        let cache = Arc::new(ImageCache::new(1000));
+        for path in &paths {
+            ImageTexture::load(path, Some(&cache)).unwrap();
+        }
+        assert_eq!((cache.resident(), cache.memory()), (45, 45));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn grid_interpolates_between_cell_centers() {
+        let grid = GridTexture::new(Point3::ZERO, 2.0, [2, 1, 1], vec![0.0, 1.0]).unwrap();
+        let at = |x| grid.value(Vec2::ZERO, point3(x, 1.0, 1.0)).x;
+        assert_eq!(at(1.0), 0.0);
+        assert_eq!(at(2.0), 0.5);
+        assert_eq!(at(3.0), 1.0);
+        // Fades to zero over the outer half cells.
+        assert_eq!(at(3.5), 0.75);
+        assert_eq!(at(-1.0), 0.0);
+        assert!(GridTexture::new(Point3::ZERO, 1.0, [2, 2, 1], vec![0.0; 3]).is_err());
+    }
+
+    #[test]
+    fn perlin_noise_is_bounded_and_repeatable() {
+        let (a, b) = (Perlin::new(), Perlin::new());
+        assert_eq!(a.noise(point3(3.0, -7.0, 12.0)), 0.0);
+        let mut spread = (f32::INFINITY, f32::NEG_INFINITY);
+        for idx in 0..1000 {
+            let p = point3(idx as f32 * 0.137, idx as f32 * -0.071, idx as f32 * 0.029);
+            let n = a.noise(p);
+            assert_eq!(n, b.noise(p));
+            assert!((-1.0..=1.0).contains(&n));
+            spread = (spread.0.min(n), spread.1.max(n));
+        }
+        assert!(spread.0 < -0.3 && spread.1 > 0.3, "{:?}", spread);
+    }
+
+    #[test]
+    fn bricks_are_bonded_in_mortar() {
+        let (red, gray) = (Color3::X, Color3::splat(0.5));
+        let wall = BrickTexture::new(
+            Vec2::new(0.2, 0.1),
+            0.01,
+            Arc::new(SolidColor::new(red)),
+            Arc::new(SolidColor::new(gray)),
+        );
+        let at = |x, y| wall.value(Vec2::ZERO, point3(x, y, 0.3));
+        assert_eq!(at(0.1, 0.05), red);
+        // Joints between courses and between the bricks of one.
+        assert_eq!(at(0.1, 0.105), gray);
+        assert_eq!(at(0.205, 0.05), gray);
+        // The next course is shifted by half a brick.
+        assert_eq!(at(0.205, 0.15), red);
+        assert_eq!(at(0.105, 0.15), gray);
+        assert_eq!(at(-0.1, -0.05), gray);
+    }
+
+    #[test]
+    fn wood_rings_circle_the_y_axis() {
+        let wood = NoiseTexture::new(NoiseStyle::Wood, 4.0, Color3::ONE);
+        let at = |x: f32, y: f32, z: f32| wood.value(Vec2::ZERO, point3(x, y, z)).x;
+        let mut spread = (f32::INFINITY, f32::NEG_INFINITY);
+        for idx in 0..200 {
+            let shade = at(idx as f32 * 0.01, 0.0, 0.0);
+            assert!((0.0..=1.0).contains(&shade));
+            spread = (spread.0.min(shade), spread.1.max(shade));
+        }
+        // Several rings light and dark along a radius, and the same all the
+        // way round, up to the turbulence.
+        assert!(spread.0 < 0.1 && spread.1 > 0.9, "{:?}", spread);
+        let averages =
+            |x: f32, z: f32| (0..100).map(|idx| at(x, idx as f32 * 0.37, z)).sum::<f32>() / 100.0;
+        assert!((averages(0.7, 0.0) - averages(0.0, 0.7)).abs() < 0.2);
+    }
+}
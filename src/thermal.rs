@@ -0,0 +1,29 @@
+use crate::{color3, Color3};
+
+// "Ironbow" palette used by thermal cameras, from cold to hot, as display
+// values.
+const PALETTE: [Color3; 5] = [
+    Color3::new(0.0, 0.0, 0.0),
+    Color3::new(0.3, 0.0, 0.55),
+    Color3::new(0.85, 0.1, 0.25),
+    Color3::new(1.0, 0.6, 0.0),
+    Color3::new(1.0, 1.0, 0.85),
+];
+
+// Maps an intensity in `min..max` onto the palette, clamping outside it.
+pub fn false_color(value: f32, min: f32, max: f32) -> Color3 {
+    let t = if max > min {
+        ((value - min) / (max - min)).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let pos = t * (PALETTE.len() - 1) as f32;
+    let idx = (pos as usize).min(PALETTE.len() - 2);
+    PALETTE[idx].lerp(PALETTE[idx + 1], pos - idx as f32)
+}
+
+// A thermal render's per-pixel intensity; channels only differ when colored
+// surfaces reflect heat, so they are weighted like luminance.
+pub fn intensity(color: Color3) -> f32 {
+    color.dot(color3(0.2126, 0.7152, 0.0722))
+}
@@ -0,0 +1,360 @@
+use glam::DVec3;
+use indicatif::ProgressBar;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const TILE_SIZE: u32 = 32;
+const MIN_TILE_SIZE: u32 = 4;
+// Tiles whose measured cost exceeds 1/TILES_PER_THREAD of a thread's fair
+// share of the remaining work get subdivided.
+const TILES_PER_THREAD: u32 = 8;
+
+// Order in which tiles are handed out to workers.
+#[derive(Copy, Clone)]
+pub enum Order {
+    // Most expensive first, for the shortest total render time.
+    Cost,
+    // Nearest to the given pixel first, so the subject shows up early.
+    Focus(u32, u32),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Pass {
+    // A few samples per pixel, timed per tile to estimate its cost.
+    Probe,
+    Main,
+}
+
+impl Pass {
+    pub fn samples(self, samples_per_pixel: u32) -> Range<u32> {
+        let probe = (samples_per_pixel / 8).max(1).min(samples_per_pixel);
+        match self {
+            Pass::Probe => 0..probe,
+            Pass::Main => probe..samples_per_pixel,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Tile {
+    fn pixels(&self) -> u32 {
+        self.width * self.height
+    }
+
+    fn distance_squared(&self, (x, y): (u32, u32)) -> u64 {
+        let dx = (2 * self.x + self.width) as i64 - 2 * x as i64;
+        let dy = (2 * self.y + self.height) as i64 - 2 * y as i64;
+        (dx * dx + dy * dy) as u64
+    }
+
+    fn split(&self) -> Vec<Tile> {
+        let (w0, h0) = (self.width / 2, self.height / 2);
+        let (w1, h1) = (self.width - w0, self.height - h0);
+        [
+            (self.x, self.y, w0, h0),
+            (self.x + w0, self.y, w1, h0),
+            (self.x, self.y + h0, w0, h1),
+            (self.x + w0, self.y + h0, w1, h1),
+        ]
+        .into_iter()
+        .map(|(x, y, width, height)| Tile {
+            x,
+            y,
+            width,
+            height,
+        })
+        .collect()
+    }
+}
+
+// Renders the image in two passes over tiles. `shade(x, y, pass, sum)` adds
+// the pixel's samples for that pass to its running sum, which is carried from
+// the probe pass into the main pass; the returned image holds the final sums.
+// Each pixel is shaded by one worker per pass, so the sums don't depend on
+// tile boundaries, scheduling or thread count.
+// The probe pass measures how expensive each tile is; the main pass then
+// splits tiles that would blow the per-tile time budget and hands tiles out
+// most expensive first, so glass or light-heavy regions don't end up as a
+// long single-threaded tail. With `Order::Focus` both passes go outward
+// from the focus pixel instead.
+pub fn render<F>(
+    width: u32,
+    height: u32,
+    samples: u32,
+    order: Order,
+    bar: &ProgressBar,
+    shade: F,
+) -> Vec<DVec3>
+where
+    F: Fn(u32, u32, Pass, &mut DVec3) + Sync,
+{
+    bar.set_length(width as u64 * height as u64 * samples as u64);
+    let mut image = vec![DVec3::ZERO; (width * height) as usize];
+    render_onto(&mut image, width, height, samples, order, bar, shade);
+    image
+}
+
+// Like `render`, but adds to the sums already in `image`, so an image can be
+// rendered in several rounds of samples. It leaves the progress bar's length
+// to the caller.
+pub fn render_onto<F>(
+    image: &mut [DVec3],
+    width: u32,
+    height: u32,
+    samples: u32,
+    order: Order,
+    bar: &ProgressBar,
+    shade: F,
+) where
+    F: Fn(u32, u32, Pass, &mut DVec3) + Sync,
+{
+    let mut tiles = grid(width, height);
+    if let Order::Focus(x, y) = order {
+        tiles.sort_by_key(|tile| tile.distance_squared((x, y)));
+    }
+    let probed = render_pass(&tiles, Pass::Probe, samples, bar, &shade, image, width);
+
+    let threads = rayon::current_num_threads() as u32;
+    let total_cost: Duration = probed.iter().map(|(_, cost)| *cost).sum();
+    let budget = total_cost / (threads * TILES_PER_THREAD).max(1);
+
+    let mut scheduled = vec![];
+    for (tile, cost) in probed {
+        subdivide(tile, cost, budget, &mut scheduled);
+    }
+    match order {
+        Order::Cost => scheduled.sort_by_key(|(_, cost)| std::cmp::Reverse(*cost)),
+        Order::Focus(x, y) => scheduled.sort_by_key(|(tile, _)| tile.distance_squared((x, y))),
+    }
+    let tiles: Vec<Tile> = scheduled.into_iter().map(|(tile, _)| tile).collect();
+    render_pass(&tiles, Pass::Main, samples, bar, &shade, image, width);
+}
+
+fn grid(width: u32, height: u32) -> Vec<Tile> {
+    let mut tiles = vec![];
+    for y in (0..height).step_by(TILE_SIZE as usize) {
+        for x in (0..width).step_by(TILE_SIZE as usize) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: TILE_SIZE.min(width - x),
+                height: TILE_SIZE.min(height - y),
+            });
+        }
+    }
+    tiles
+}
+
+// Cost is assumed to be spread evenly over the tile's pixels.
+fn subdivide(tile: Tile, cost: Duration, budget: Duration, out: &mut Vec<(Tile, Duration)>) {
+    if cost <= budget || tile.width < 2 * MIN_TILE_SIZE || tile.height < 2 * MIN_TILE_SIZE {
+        out.push((tile, cost));
+        return;
+    }
+    for sub in tile.split() {
+        let sub_cost = cost.mul_f64(sub.pixels() as f64 / tile.pixels() as f64);
+        subdivide(sub, sub_cost, budget, out);
+    }
+}
+
+// Workers pull tiles from the list in order, so the list order is the
+// schedule. Returns every tile with the time it took.
+fn render_pass<F>(
+    tiles: &[Tile],
+    pass: Pass,
+    samples: u32,
+    bar: &ProgressBar,
+    shade: &F,
+    image: &mut [DVec3],
+    width: u32,
+) -> Vec<(Tile, Duration)>
+where
+    F: Fn(u32, u32, Pass, &mut DVec3) + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![]);
+    let pass_samples = pass.samples(samples).len() as u64;
+    let previous: &[DVec3] = image;
+
+    rayon::scope(|s| {
+        for _ in 0..rayon::current_num_threads() {
+            s.spawn(|_| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(tile) = tiles.get(idx) else {
+                    break;
+                };
+                let start = Instant::now();
+                let mut pixels = Vec::with_capacity(tile.pixels() as usize);
+                for y in tile.y..tile.y + tile.height {
+                    for x in tile.x..tile.x + tile.width {
+                        let mut sum = previous[(y * width + x) as usize];
+                        shade(x, y, pass, &mut sum);
+                        pixels.push(sum);
+                    }
+                }
+                let cost = start.elapsed();
+                bar.inc(tile.pixels() as u64 * pass_samples);
+                results.lock().unwrap().push((*tile, cost, pixels));
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+    let mut costs = Vec::with_capacity(results.len());
+    for (tile, cost, pixels) in results {
+        let mut pixels = pixels.into_iter();
+        for y in tile.y..tile.y + tile.height {
+            for x in tile.x..tile.x + tile.width {
+                image[(y * width + x) as usize] = pixels.next().unwrap();
+            }
+        }
+        costs.push((tile, cost));
+    }
+    costs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+
+    // Stands in for a camera: each sample is a seeded random value, added in
+    // sample order like Camera::add_samples does.
+    fn shade(x: u32, y: u32, samples: Range<u32>, sum: &mut DVec3) {
+        for sample in samples {
+            random::seed_sample(7, x, y, sample);
+            *sum += DVec3::new(random::f32() as f64, 0.1, 1e-7 * sample as f64);
+        }
+    }
+
+    #[test]
+    fn sums_match_sequential_for_any_thread_count() {
+        const WIDTH: u32 = 45;
+        const HEIGHT: u32 = 37;
+        const SAMPLES: u32 = 24;
+
+        let mut expected = vec![];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let mut sum = DVec3::ZERO;
+                shade(x, y, 0..SAMPLES, &mut sum);
+                expected.push(sum);
+            }
+        }
+
+        for threads in [1, 3, 8] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let image = pool.install(|| {
+                render(
+                    WIDTH,
+                    HEIGHT,
+                    SAMPLES,
+                    Order::Cost,
+                    &ProgressBar::hidden(),
+                    |x, y, pass, sum| shade(x, y, pass.samples(SAMPLES), sum),
+                )
+            });
+            assert!(image == expected, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn rounds_add_up_to_a_single_render() {
+        const WIDTH: u32 = 20;
+        const HEIGHT: u32 = 11;
+        const ROUNDS: u32 = 3;
+        const SAMPLES: u32 = 8;
+
+        let bar = ProgressBar::hidden();
+        let expected = render(
+            WIDTH,
+            HEIGHT,
+            ROUNDS * SAMPLES,
+            Order::Cost,
+            &bar,
+            |x, y, pass, sum| shade(x, y, pass.samples(ROUNDS * SAMPLES), sum),
+        );
+        let mut image = vec![DVec3::ZERO; (WIDTH * HEIGHT) as usize];
+        for round in 0..ROUNDS {
+            render_onto(
+                &mut image,
+                WIDTH,
+                HEIGHT,
+                SAMPLES,
+                Order::Cost,
+                &bar,
+                |x, y, pass, sum| {
+                    let samples = pass.samples(SAMPLES);
+                    let start = round * SAMPLES;
+                    shade(x, y, start + samples.start..start + samples.end, sum)
+                },
+            );
+        }
+        assert!(image == expected);
+    }
+
+    // Counts how often each pixel of a `width` wide image is covered.
+    fn coverage(tiles: &[Tile], width: u32, height: u32) -> Vec<u32> {
+        let mut counts = vec![0; (width * height) as usize];
+        for tile in tiles {
+            for y in tile.y..tile.y + tile.height {
+                for x in tile.x..tile.x + tile.width {
+                    counts[(y * width + x) as usize] += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn tiles_cover_the_image_exactly_once() {
+        const WIDTH: u32 = 77;
+        const HEIGHT: u32 = 45;
+
+        let tiles = grid(WIDTH, HEIGHT);
+        assert!(coverage(&tiles, WIDTH, HEIGHT).iter().all(|&n| n == 1));
+
+        // With no time to spare, every tile is split down to the minimum size.
+        let mut split = vec![];
+        for tile in tiles {
+            subdivide(tile, Duration::from_millis(1), Duration::ZERO, &mut split);
+        }
+        let split: Vec<Tile> = split.into_iter().map(|(tile, _)| tile).collect();
+        assert!(split.len() > grid(WIDTH, HEIGHT).len());
+        assert!(split.iter().all(|tile| tile.width >= MIN_TILE_SIZE));
+        assert!(coverage(&split, WIDTH, HEIGHT).iter().all(|&n| n == 1));
+
+        // And each pass shades every pixel once, whatever the order.
+        for order in [Order::Cost, Order::Focus(70, 3)] {
+            let counts: Vec<[AtomicUsize; 2]> = (0..WIDTH * HEIGHT)
+                .map(|_| [AtomicUsize::new(0), AtomicUsize::new(0)])
+                .collect();
+            render(
+                WIDTH,
+                HEIGHT,
+                16,
+                order,
+                &ProgressBar::hidden(),
+                |x, y, pass, _| {
+                    counts[(y * WIDTH + x) as usize][(pass == Pass::Main) as usize]
+                        .fetch_add(1, Ordering::Relaxed);
+                },
+            );
+            for count in counts.iter().flatten() {
+                assert_eq!(count.load(Ordering::Relaxed), 1);
+            }
+        }
+    }
+}